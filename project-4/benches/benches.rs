@@ -2,9 +2,11 @@
 extern crate criterion;
 
 use criterion::Criterion;
+use kvs::engines::{KvStoreOptions, LsmEngine};
 use kvs::thread_pool::{RayonThreadPool, SharedQueueThreadPool, ThreadPool};
 use kvs::KvsServer;
-use kvs::{KvStore, SledServer};
+use kvs::{KvStore, KvsEngine, SledServer};
+use std::thread;
 
 use tempfile::TempDir;
 
@@ -74,7 +76,7 @@ fn read_ry_kv(c: &mut Criterion) {
         server.set(format!("key{}", key), "value".to_owned());
     }
     std::thread::sleep(std::time::Duration::from_secs(1));
-    c.bench_function("read_sq_kv", move |b| {
+    c.bench_function("read_ry_kv", move |b| {
         b.iter(|| {
             for key in 1..100 {
                 server.get(format!("key{}", key));
@@ -82,6 +84,72 @@ fn read_ry_kv(c: &mut Criterion) {
         })
     });
 }
+/// 不使用读缓存时读取数据文件，buffered_reads 为 false 时通过内存映射读取
+fn read_ry_kv_uncached(c: &mut Criterion, name: &str, buffered_reads: bool) {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("kvs");
+
+    let store = KvStore::open(&path).unwrap();
+    for key in 1..100 {
+        store
+            .set(format!("key{}", key), "value".to_owned())
+            .unwrap();
+    }
+    drop(store);
+    // 重新打开，使已写入的数据文件被映射
+    let options = KvStoreOptions {
+        cache_size: Some(0),
+        buffered_reads,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(&path, options).unwrap();
+    c.bench_function(name, move |b| {
+        b.iter(|| {
+            for key in 1..100 {
+                store.get(format!("key{}", key)).unwrap();
+            }
+        })
+    });
+}
+
+fn read_ry_kv_mmap(c: &mut Criterion) {
+    read_ry_kv_uncached(c, "read_ry_kv_mmap", false);
+}
+
+fn read_ry_kv_buffered(c: &mut Criterion) {
+    read_ry_kv_uncached(c, "read_ry_kv_buffered", true);
+}
+
+/// 由 1 到 8 个线程并发写入同样数量的 key，比较写入吞吐随线程数的变化
+fn concurrent_set_kv(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path().join("kvs")).unwrap();
+
+    c.bench_function_over_inputs(
+        "concurrent_set_kv",
+        move |b, &threads| {
+            b.iter(|| {
+                let handles: Vec<_> = (0..threads)
+                    .map(|t| {
+                        let store = store.clone();
+                        thread::spawn(move || {
+                            for key in 0..800 / threads {
+                                store
+                                    .set(format!("key{}-{}", t, key), "value".to_owned())
+                                    .unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            })
+        },
+        vec![1, 2, 4, 8],
+    );
+}
+
 fn write_ry_sled(c: &mut Criterion) {
     let temp_dir = TempDir::new().unwrap();
 
@@ -118,6 +186,44 @@ fn read_ry_sled(c: &mut Criterion) {
         })
     });
 }
+
+fn write_ry_lsm(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+
+    let server = KvsServer::new(
+        LsmEngine::open(temp_dir.path().join("lsm")).unwrap(),
+        RayonThreadPool::new(0).unwrap(),
+    );
+
+    c.bench_function("write_ry_lsm", move |b| {
+        b.iter(|| {
+            for key in 1..100 {
+                server.set(format!("key{}", key), "value".to_owned());
+            }
+        })
+    });
+}
+
+fn read_ry_lsm(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+
+    let server = KvsServer::new(
+        LsmEngine::open(temp_dir.path().join("lsm")).unwrap(),
+        RayonThreadPool::new(0).unwrap(),
+    );
+
+    for key in 1..100 {
+        server.set(format!("key{}", key), "value".to_owned());
+    }
+    c.bench_function("read_ry_lsm", move |b| {
+        b.iter(|| {
+            for key in 1..100 {
+                server.get(format!("key{}", key));
+            }
+        })
+    });
+}
+
 // criterion_group!(benches, write_sq_kv, write_ry_kv);
 // criterion_group!(benches1, read_sq_kv, read_ry_kv);
 
@@ -132,8 +238,13 @@ criterion_group!(
     read_sq_kv,
     write_ry_kv,
     read_ry_kv,
+    read_ry_kv_mmap,
+    read_ry_kv_buffered,
+    concurrent_set_kv,
     write_ry_sled,
-    read_ry_sled
+    read_ry_sled,
+    write_ry_lsm,
+    read_ry_lsm
 );
 
 criterion_main!(benches);
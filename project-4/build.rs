@@ -0,0 +1,13 @@
+use std::env;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // 未指定 protoc 时使用内置的 protoc，无需在系统中安装
+    if env::var_os("PROTOC").is_none() {
+        env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    // 生成的 connect 函数依赖 2021 edition 的 prelude，客户端改为手动创建 Channel
+    tonic_build::configure()
+        .build_transport(false)
+        .compile(&["proto/kvs.proto"], &["proto"])?;
+    Ok(())
+}
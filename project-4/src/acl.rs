@@ -0,0 +1,187 @@
+//! 访问控制模块
+//!
+//! ACL 文件为 JSON 格式，为每个用户指定 token 以及允许访问的 key 前缀和操作；
+//! 规则默认只适用于默认 keyspace，`keyspace` 指定其适用的 keyspace，为 `*` 时适用于所有 keyspace：
+//!
+//! ```json
+//! {
+//!     "users": [
+//!         {
+//!             "name": "admin",
+//!             "token": "secret",
+//!             "admin": true,
+//!             "rules": [{ "prefix": "", "keyspace": "*", "ops": ["Get", "Set", "Remove"] }]
+//!         },
+//!         {
+//!             "name": "reader",
+//!             "token": "reader-token",
+//!             "rules": [
+//!                 { "prefix": "app/", "ops": ["Get"] },
+//!                 { "prefix": "", "keyspace": "reports", "ops": ["Get"] }
+//!             ]
+//!         }
+//!     ]
+//! }
+//! ```
+
+use crate::{KvsErrorType, Operation, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// 可被授权的操作
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Permission {
+    /// 读取
+    Get,
+    /// 写入
+    Set,
+    /// 删除
+    Remove,
+}
+
+/// 一条授权规则，允许对 keyspace 中以 prefix 开头的 key 执行 ops 中的操作
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    /// key 前缀，为空时匹配所有 key
+    pub prefix: String,
+    /// 规则适用的 keyspace，为空时只适用于默认 keyspace，为 `*` 时适用于所有 keyspace
+    #[serde(default)]
+    pub keyspace: Option<String>,
+    /// 允许的操作
+    pub ops: Vec<Permission>,
+}
+
+impl Rule {
+    /// 规则是否适用于名为 keyspace 的 keyspace，为空时表示默认 keyspace
+    fn applies_to(&self, keyspace: Option<&str>) -> bool {
+        match self.keyspace.as_deref() {
+            Some("*") => true,
+            rule => rule == keyspace,
+        }
+    }
+}
+
+/// 一个用户（principal）及其授权规则
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Principal {
+    /// 用户名
+    pub name: String,
+    /// 用于认证的 token
+    pub token: String,
+    /// 是否为管理员，只有管理员可以执行备份等服务器管理操作
+    #[serde(default)]
+    pub admin: bool,
+    /// 授权规则
+    pub rules: Vec<Rule>,
+}
+
+impl Principal {
+    /// 判断是否允许对默认 keyspace 中的 key 执行 permission 操作
+    pub fn allows(&self, permission: Permission, key: &str) -> bool {
+        self.allows_in(None, permission, key)
+    }
+
+    /// 判断是否允许对名为 keyspace 的 keyspace 中的 key 执行 permission 操作，keyspace 为空时表示默认 keyspace
+    pub fn allows_in(&self, keyspace: Option<&str>, permission: Permission, key: &str) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.applies_to(keyspace)
+                && key.starts_with(&rule.prefix)
+                && rule.ops.contains(&permission)
+        })
+    }
+
+    /// 是否有适用于名为 keyspace 的 keyspace 的规则，keyspace 为空时表示默认 keyspace
+    fn grants_in(&self, keyspace: Option<&str>) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.applies_to(keyspace) && !rule.ops.is_empty())
+    }
+
+    /// 判断是否允许执行 op
+    ///
+    /// 默认 keyspace 中的认证与扫描操作总是被允许，扫描结果由服务器按 key 过滤；
+    /// 其他 keyspace 中的操作需要有适用于该 keyspace 的规则
+    pub fn allows_op(&self, op: &Operation) -> bool {
+        self.allows_op_in(None, op)
+    }
+
+    /// 判断是否允许在名为 keyspace 的 keyspace 中执行 op
+    fn allows_op_in(&self, keyspace: Option<&str>, op: &Operation) -> bool {
+        match op {
+            Operation::Get { key } => self.allows_in(keyspace, Permission::Get, key),
+            Operation::Set { key, .. } => self.allows_in(keyspace, Permission::Set, key),
+            Operation::Remove { key } => self.allows_in(keyspace, Permission::Remove, key),
+            Operation::Auth { .. } => true,
+            // 扫描结果中只会包含有读取权限的 key
+            Operation::Scan { .. } => true,
+            // 备份会读取所有 key 并写入服务器上的文件，只允许管理员执行
+            Operation::Backup { .. } => self.admin,
+            // 复制日志需要读取 keyspace 中所有 key 的权限
+            Operation::Replicate { .. } => self.allows_in(keyspace, Permission::Get, ""),
+            // keyspace 中不能再打开 keyspace，没有适用于该 keyspace 的规则时不能在其中执行任何操作
+            Operation::Keyspace { name, op } => {
+                keyspace.is_none()
+                    && self.grants_in(Some(name))
+                    && self.allows_op_in(Some(name), op)
+            }
+            // 删除 keyspace 需要删除其中所有 key 的权限
+            Operation::DropKeyspace { name } => {
+                keyspace.is_none() && self.allows_in(Some(name), Permission::Remove, "")
+            }
+        }
+    }
+}
+
+/// 以与内容无关的时间比较 a 与 b，避免通过响应时间逐字节猜出 token
+pub(crate) fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let diff = (0..a.len().max(b.len())).fold(a.len() ^ b.len(), |diff, i| {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff | usize::from(x ^ y)
+    });
+    diff == 0
+}
+
+/// 访问控制列表
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Acl {
+    /// 所有用户
+    pub users: Vec<Principal>,
+}
+
+impl Acl {
+    /// 从路径为 path 的 JSON 文件中读取 ACL
+    pub fn open(path: &Path) -> Result<Acl> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// 使用用户名与 token 进行认证，成功则返回对应用户
+    pub fn authenticate(&self, name: &str, token: &str) -> Result<&Principal> {
+        self.users
+            .iter()
+            .find(|user| user.name == name && constant_time_eq(&user.token, token))
+            .ok_or_else(|| {
+                info!("authentication failed: {}", name);
+                KvsErrorType::PermissionDenied.into()
+            })
+    }
+
+    /// 使用 HTTP Basic 认证头（`Basic base64(user:token)`）进行认证
+    pub fn authenticate_basic(&self, header: &str) -> Result<&Principal> {
+        let mut parts = header.trim().splitn(2, ' ');
+        let credentials = match (parts.next(), parts.next()) {
+            (Some(scheme), Some(credentials)) if scheme.eq_ignore_ascii_case("Basic") => {
+                base64::decode(credentials.trim()).ok()
+            }
+            _ => None,
+        }
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .ok_or(KvsErrorType::PermissionDenied)?;
+        let mut parts = credentials.splitn(2, ':');
+        self.authenticate(parts.next().unwrap(), parts.next().unwrap_or(""))
+    }
+}
@@ -0,0 +1,386 @@
+//! 备份模块
+//!
+//! 备份与引擎无关，按 key 从小到大的顺序保存所有键值对，每行一个 `["key","value"]`，
+//! 并附带记录引擎、key 数量与校验和的清单，可以恢复到任意引擎
+//!
+//! 默认 keyspace 的键值对在前，之后按名称顺序保存其他 keyspace，
+//! 每个 keyspace 以一行 `{"keyspace":"name"}` 开始，其名称也记录在清单中
+//!
+//! 备份可以写入文件夹（`data.jsonl` 与 `manifest.json`），也可以写入单个归档文件，
+//! 此时清单作为最后一行写在数据之后；两种形式都在写入完成后才出现在目标路径上
+
+use crate::engines::KvsEngine;
+use crate::{KvsErrorType, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 备份格式的版本
+const BACKUP_VERSION: u32 = 1;
+
+/// 文件夹形式的备份中保存数据的文件名
+const DATA_FILE: &str = "data.jsonl";
+
+/// 文件夹形式的备份中保存清单的文件名
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// 迁移时每次扫描的 key 数量
+const SCAN_BATCH: usize = 1000;
+
+/// 备份清单
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct BackupManifest {
+    /// 备份格式的版本
+    pub version: u32,
+    /// 产生备份的引擎类型
+    pub engine: String,
+    /// key 的数量
+    pub keys: u64,
+    /// 所有键值对的校验和
+    pub checksum: u64,
+    /// 备份时间（Unix 时间戳，秒）
+    pub created: u64,
+    /// 备份对应的最后一条记录的序号，只有 kvs 引擎的备份有，用于时间点恢复
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// 备份中按名称排序的 keyspace，不包括默认 keyspace
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keyspaces: Vec<String>,
+}
+
+/// 备份中的一行，为键值对、（归档文件末尾的）清单或 keyspace 的开始
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Pair(String, String),
+    Manifest(BackupManifest),
+    Keyspace { keyspace: String },
+}
+
+/// 键值对的校验和（FNV-1a）
+#[derive(Clone, Copy)]
+struct Checksum(u64);
+
+impl Checksum {
+    fn new() -> Checksum {
+        Checksum(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// 依次加入 key 与 value，以 0 分隔
+    fn update(&mut self, key: &str, value: &str) {
+        for bytes in &[key.as_bytes(), &[0], value.as_bytes(), &[0]] {
+            for byte in bytes.iter() {
+                self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+    }
+
+    /// 加入 keyspace 的名称，前后以 UTF-8 中不会出现的 0xff 分隔，与键值对区分
+    fn keyspace(&mut self, name: &str) {
+        for bytes in &[&[0xff], name.as_bytes(), &[0xff]] {
+            for byte in bytes.iter() {
+                self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+    }
+}
+
+/// 当前的 Unix 时间戳（秒）
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// 备份写入器
+///
+/// 键值对需按 key 从小到大的顺序写入，`finish` 之前目标路径上不会出现任何内容；
+/// 其他 keyspace 的键值对在默认 keyspace 之后写入，见 `keyspace`
+pub struct BackupWriter {
+    writer: BufWriter<File>,
+    /// 写入中的临时路径
+    tmp_path: PathBuf,
+    /// 目标路径
+    path: PathBuf,
+    /// 是否为文件夹形式
+    is_dir: bool,
+    keys: u64,
+    checksum: Checksum,
+    last_key: Option<String>,
+    seq: Option<u64>,
+    keyspaces: Vec<String>,
+}
+
+impl BackupWriter {
+    /// 创建写入 path 的备份
+    ///
+    /// path 为已存在的文件夹时写入文件夹形式的备份，否则写入归档文件
+    pub fn create(path: &Path) -> Result<BackupWriter> {
+        let is_dir = path.is_dir();
+        let tmp_path = if is_dir {
+            path.join(DATA_FILE).with_extension("tmp")
+        } else {
+            path.with_extension("tmp")
+        };
+        Ok(BackupWriter {
+            writer: BufWriter::new(File::create(&tmp_path)?),
+            tmp_path,
+            path: path.to_owned(),
+            is_dir,
+            keys: 0,
+            checksum: Checksum::new(),
+            last_key: None,
+            seq: None,
+            keyspaces: Vec::new(),
+        })
+    }
+
+    /// 在清单中记录备份对应的最后一条记录的序号
+    pub fn at_seq(mut self, seq: u64) -> BackupWriter {
+        self.seq = Some(seq);
+        self
+    }
+
+    /// 写入一个键值对
+    pub fn add(&mut self, key: String, value: String) -> Result<()> {
+        if matches!(&self.last_key, Some(last) if last >= &key) {
+            Err(KvsErrorType::InvalidArgument)?
+        }
+        self.checksum.update(&key, &value);
+        self.keys += 1;
+        let line = Line::Pair(key, value);
+        serde_json::to_writer(&mut self.writer, &line)?;
+        self.writer.write_all(b"\n")?;
+        if let Line::Pair(key, _) = line {
+            self.last_key = Some(key);
+        }
+        Ok(())
+    }
+
+    /// 开始写入名为 name 的 keyspace，之后写入的键值对都属于该 keyspace
+    ///
+    /// keyspace 需按名称从小到大的顺序写入，否则返回 InvalidArgument Error
+    pub fn keyspace(&mut self, name: &str) -> Result<()> {
+        if matches!(self.keyspaces.last(), Some(last) if last.as_str() >= name) {
+            Err(KvsErrorType::InvalidArgument)?
+        }
+        self.checksum.keyspace(name);
+        let line = Line::Keyspace {
+            keyspace: name.to_owned(),
+        };
+        serde_json::to_writer(&mut self.writer, &line)?;
+        self.writer.write_all(b"\n")?;
+        self.keyspaces.push(name.to_owned());
+        self.last_key = None;
+        Ok(())
+    }
+
+    /// 写入清单并完成备份，engine 为产生备份的引擎类型
+    pub fn finish(mut self, engine: &str) -> Result<BackupManifest> {
+        let manifest = BackupManifest {
+            version: BACKUP_VERSION,
+            engine: engine.to_owned(),
+            keys: self.keys,
+            checksum: self.checksum.0,
+            created: now(),
+            seq: self.seq,
+            keyspaces: self.keyspaces.clone(),
+        };
+        if self.is_dir {
+            self.sync()?;
+            fs::rename(&self.tmp_path, self.path.join(DATA_FILE))?;
+            // 清单最后写入，没有清单的文件夹不是完整的备份
+            let manifest_path = self.path.join(MANIFEST_FILE);
+            let tmp_path = manifest_path.with_extension("tmp");
+            let mut file = File::create(&tmp_path)?;
+            serde_json::to_writer_pretty(&mut file, &manifest)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &manifest_path)?;
+        } else {
+            serde_json::to_writer(&mut self.writer, &Line::Manifest(manifest.clone()))?;
+            self.writer.write_all(b"\n")?;
+            self.sync()?;
+            fs::rename(&self.tmp_path, &self.path)?;
+        }
+        Ok(manifest)
+    }
+
+    /// 将数据写入磁盘
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// 读取 path 中的备份，依次对每个键值对调用 f，最后校验 key 数量、校验和与 keyspace
+///
+/// f 的第一个参数为键值对所在的 keyspace，为空时表示默认 keyspace；
+/// 备份不完整或已损坏时返回 Corrupted Error，此时 f 可能已被调用
+pub fn read<F>(path: &Path, mut f: F) -> Result<BackupManifest>
+where
+    F: FnMut(Option<&str>, String, String) -> Result<()>,
+{
+    let is_dir = path.is_dir();
+    let (data_path, mut manifest) = if is_dir {
+        let file = File::open(path.join(MANIFEST_FILE)).map_err(|_| KvsErrorType::Corrupted)?;
+        let manifest =
+            serde_json::from_reader(BufReader::new(file)).map_err(|_| KvsErrorType::Corrupted)?;
+        (path.join(DATA_FILE), Some(manifest))
+    } else {
+        (path.to_owned(), None)
+    };
+    let reader = BufReader::new(File::open(&data_path)?);
+    let mut keys = 0;
+    let mut checksum = Checksum::new();
+    let mut last_key: Option<String> = None;
+    let mut keyspaces: Vec<String> = Vec::new();
+    for line in serde_json::Deserializer::from_reader(reader).into_iter::<Line>() {
+        match line.map_err(|_| KvsErrorType::Corrupted)? {
+            Line::Pair(key, value) => {
+                // 归档文件的清单之后不应再有数据，key 也必须严格递增
+                if manifest.is_some() && !is_dir || matches!(&last_key, Some(last) if last >= &key)
+                {
+                    Err(KvsErrorType::Corrupted)?
+                }
+                checksum.update(&key, &value);
+                keys += 1;
+                last_key = Some(key.clone());
+                f(keyspaces.last().map(String::as_str), key, value)?;
+            }
+            Line::Keyspace { keyspace } => {
+                // keyspace 的名称也必须严格递增
+                if manifest.is_some() && !is_dir
+                    || matches!(keyspaces.last(), Some(last) if last >= &keyspace)
+                {
+                    Err(KvsErrorType::Corrupted)?
+                }
+                checksum.keyspace(&keyspace);
+                keyspaces.push(keyspace);
+                last_key = None;
+            }
+            Line::Manifest(m) if manifest.is_none() => manifest = Some(m),
+            Line::Manifest(_) => Err(KvsErrorType::Corrupted)?,
+        }
+    }
+    match manifest {
+        Some(manifest)
+            if manifest.version == BACKUP_VERSION
+                && manifest.keys == keys
+                && manifest.checksum == checksum.0
+                && manifest.keyspaces == keyspaces =>
+        {
+            Ok(manifest)
+        }
+        _ => Err(KvsErrorType::Corrupted)?,
+    }
+}
+
+/// 校验 path 中的备份，返回其清单
+pub fn validate(path: &Path) -> Result<BackupManifest> {
+    read(path, |_, _, _| Ok(()))
+}
+
+/// 校验 path 中的备份，通过后将其中的键值对写入 engine 及其对应的 keyspace
+///
+/// 不会删除 engine 中已有的 key，通常应恢复到空的引擎中；
+/// 备份中有 keyspace 时先打开所有 keyspace，engine 不支持 keyspace 时在写入任何数据之前返回 Error
+pub fn restore<E: KvsEngine>(path: &Path, engine: &E) -> Result<BackupManifest> {
+    let manifest = validate(path)?;
+    let mut trees = HashMap::new();
+    for name in &manifest.keyspaces {
+        trees.insert(name.clone(), engine.open_tree(name)?);
+    }
+    read(path, |keyspace, key, value| match keyspace {
+        None => engine.set(key, value),
+        Some(name) => match trees.get(name) {
+            Some(tree) => tree.set(key, value),
+            None => Err(KvsErrorType::Corrupted)?,
+        },
+    })
+}
+
+/// engine 中按名称排序的所有 keyspace，不支持 keyspace 的引擎没有 keyspace
+pub fn keyspaces<E: KvsEngine>(engine: &E) -> Result<Vec<String>> {
+    match engine.tree_names() {
+        Err(ref e) if e.kind() == KvsErrorType::UnknownOperation => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+/// 按 key 从小到大的顺序遍历 engine 中的所有键值对并加入 checksum，返回 key 数量
+fn walk<E, F>(engine: &E, checksum: &mut Checksum, mut f: F) -> Result<u64>
+where
+    E: KvsEngine,
+    F: FnMut(String, String) -> Result<()>,
+{
+    let mut keys = 0;
+    let mut after = None;
+    loop {
+        let pairs = engine.scan(String::new(), after.take(), SCAN_BATCH)?;
+        let last = match pairs.last() {
+            Some((key, _)) => key.clone(),
+            None => break,
+        };
+        for (key, value) in pairs {
+            checksum.update(&key, &value);
+            keys += 1;
+            f(key, value)?;
+        }
+        after = Some(last);
+    }
+    Ok(keys)
+}
+
+/// 将 source 中的所有键值对复制到 target 中并加入 checksum，返回 key 数量
+///
+/// 复制完成后重新遍历 target，key 数量或校验和与 source 不一致时返回 Corrupted Error
+fn copy<S: KvsEngine, T: KvsEngine>(
+    source: &S,
+    target: &T,
+    checksum: &mut Checksum,
+) -> Result<u64> {
+    let mut copied = *checksum;
+    let keys = walk(source, checksum, |key, value| target.set(key, value))?;
+    if walk(target, &mut copied, |_, _| Ok(()))? != keys
+        || copied.0 != checksum.0
+        || target.stats()?["keys"] != keys
+    {
+        Err(KvsErrorType::Corrupted)?
+    }
+    Ok(keys)
+}
+
+/// 将 source 中的所有键值对及 keyspace 复制到空的 target 中，用于在引擎之间迁移数据
+///
+/// source 中有 keyspace 时先在 target 中创建所有 keyspace，target 不支持 keyspace 时在复制任何数据之前返回 Error；
+/// 复制完成后重新遍历 target，key 数量或校验和与 source 不一致时返回 Corrupted Error；
+/// 迁移期间 source 不应被修改
+pub fn migrate<S: KvsEngine, T: KvsEngine>(source: &S, target: &T) -> Result<BackupManifest> {
+    if target.stats()?["keys"] != 0 || !keyspaces(target)?.is_empty() {
+        Err(KvsErrorType::TargetNotEmpty)?
+    }
+    let names = keyspaces(source)?;
+    let mut trees = Vec::new();
+    for name in &names {
+        trees.push((source.open_tree(name)?, target.open_tree(name)?));
+    }
+    let mut checksum = Checksum::new();
+    let mut keys = copy(source, target, &mut checksum)?;
+    for (name, (source, target)) in names.iter().zip(trees) {
+        checksum.keyspace(name);
+        keys += copy(&source, &target, &mut checksum)?;
+    }
+    Ok(BackupManifest {
+        version: BACKUP_VERSION,
+        engine: source.get_type(),
+        keys,
+        checksum: checksum.0,
+        created: now(),
+        seq: None,
+        keyspaces: names,
+    })
+}
@@ -0,0 +1,663 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::backup::{self, BackupManifest};
+use kvs::client::KvsClient;
+use kvs::engines::{
+    self, AnyEngine, CheckReport, DumpFilter, EncryptionKey, EngineOptions, EngineRegistry,
+    Keyring, RecoveryTarget,
+};
+use kvs::export::{self, ExportWriter, Format};
+use kvs::{KvsEngine, KvsError, KvsErrorType, Result};
+use log::LevelFilter;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// 导出时每次扫描的 key 数量
+const SCAN_BATCH: usize = 1000;
+
+/// 导入时默认每批写入的 key 数量
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// dump 时 value 默认显示的字符数
+const DEFAULT_PREVIEW_WIDTH: u64 = 40;
+
+/// 打印错误信息并退出
+fn exit_with(e: KvsError) -> ! {
+    match e.kind() {
+        KvsErrorType::PermissionDenied => eprintln!("Permission denied"),
+        KvsErrorType::TargetNotEmpty => eprintln!("Target is not empty."),
+        KvsErrorType::KeyspaceNotFound => eprintln!("Keyspace not found."),
+        _ => eprintln!("{}", e),
+    }
+    std::process::exit(1);
+}
+
+/// 打印备份清单
+fn print_manifest(manifest: &BackupManifest) {
+    println!(
+        "{} keys from {} engine, checksum {:016x}",
+        manifest.keys, manifest.engine, manifest.checksum
+    );
+    if !manifest.keyspaces.is_empty() {
+        println!("keyspaces: {}", manifest.keyspaces.join(", "));
+    }
+}
+
+/// 连接到 --addr 指定的服务器，并按需认证
+fn connect(matches: &ArgMatches) -> Result<KvsClient> {
+    let addr = matches.value_of("addr").unwrap_or("127.0.0.1:4000");
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    if let (Some(user), Some(token)) = (matches.value_of("user"), matches.value_of("token")) {
+        client.auth(user.to_owned(), token.to_owned())?;
+    }
+    Ok(client)
+}
+
+/// --engine-opt 指定的引擎选项，以及 --encryption-key 与 --previous-keys 指定的密钥
+fn engine_options_of(matches: &ArgMatches) -> EngineOptions {
+    let mut options = EngineOptions::new();
+    for value in matches.values_of("engine-opt").into_iter().flatten() {
+        match value.split_once('=') {
+            Some((key, value)) => {
+                options.insert(key.trim().to_owned(), value.to_owned());
+            }
+            None => {
+                eprintln!("Invalid engine option.");
+                std::process::exit(1);
+            }
+        }
+    }
+    for name in &["encryption-key", "previous-keys"] {
+        if let Some(value) = matches.value_of(name) {
+            options.insert((*name).to_owned(), value.to_owned());
+        }
+    }
+    options
+}
+
+/// 在工作目录 dir 中打开 --engine 指定的引擎
+///
+/// 与 kvs-server 一样，工作目录中的 server.cfg 记录了所使用的引擎，未指定 --engine 时使用记录的引擎，
+/// 都不存在时使用 kvs
+fn open_engine(matches: &ArgMatches, dir: &Path) -> Result<AnyEngine> {
+    let registry = EngineRegistry::builtin();
+    let name = match (matches.value_of("engine"), EngineRegistry::marker(dir)?) {
+        (Some(name), _) => name.to_owned(),
+        (None, Some(type_id)) => match registry.name_of_type(&type_id) {
+            Some(name) => name.to_owned(),
+            None => Err(KvsErrorType::UnknownEngine)?,
+        },
+        (None, None) => "kvs".to_owned(),
+    };
+    match registry.open(&name, dir, &engine_options_of(matches)) {
+        Err(ref e) if e.kind() == KvsErrorType::WrongEngine => {
+            eprintln!("Wrong engine.");
+            std::process::exit(1);
+        }
+        Err(ref e) if e.kind() == KvsErrorType::UnknownEngine => {
+            eprintln!("Invalid engine.");
+            std::process::exit(1);
+        }
+        Err(ref e) if e.kind() == KvsErrorType::OptionsMismatch => {
+            eprintln!("Engine options do not match the data directory.");
+            std::process::exit(1);
+        }
+        result => result,
+    }
+}
+
+/// 导入导出的对象：服务器工作目录中的引擎或运行中的服务器
+enum Target {
+    Local(AnyEngine),
+    Server(KvsClient),
+}
+
+impl Target {
+    /// 指定 --addr 时连接服务器，否则打开 --dir 中的引擎
+    ///
+    /// 指定 --keyspace 时使用其中名为该名称的 keyspace，create 为 false 时 keyspace 需已存在
+    fn open(matches: &ArgMatches, create: bool) -> Result<Target> {
+        let keyspace = matches.value_of("keyspace");
+        if matches.is_present("addr") {
+            let mut client = connect(matches)?;
+            client.use_keyspace(keyspace.map(str::to_owned));
+            return Ok(Target::Server(client));
+        }
+        let dir = Path::new(matches.value_of("dir").unwrap_or("."));
+        let engine = open_engine(matches, dir)?;
+        match keyspace {
+            Some(name) => {
+                if !create && !backup::keyspaces(&engine)?.iter().any(|tree| tree == name) {
+                    Err(KvsErrorType::KeyspaceNotFound)?
+                }
+                Ok(Target::Local(engine.open_tree(name)?))
+            }
+            None => Ok(Target::Local(engine)),
+        }
+    }
+
+    /// 导出默认 keyspace 时不会被导出的其他 keyspace，连接服务器时无法列出
+    fn other_keyspaces(&self, matches: &ArgMatches) -> Result<Vec<String>> {
+        match self {
+            Target::Local(engine) if !matches.is_present("keyspace") => backup::keyspaces(engine),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// 按 key 从小到大的顺序获取大于 after 的至多 limit 个键值对
+    fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        match self {
+            Target::Local(engine) => engine.scan(String::new(), after, limit),
+            Target::Server(client) => client.scan(String::new(), after, limit),
+        }
+    }
+
+    /// 写入一批键值对
+    fn set_batch(&mut self, pairs: &[(String, String)]) -> Result<()> {
+        match self {
+            Target::Local(engine) => {
+                for (key, value) in pairs {
+                    engine.set(key.clone(), value.clone())?;
+                }
+                Ok(())
+            }
+            Target::Server(client) => client.set_batch(pairs),
+        }
+    }
+}
+
+/// --format 指定的格式，默认为 jsonl
+fn format_of(matches: &ArgMatches) -> Format {
+    match matches.value_of("format").unwrap_or("jsonl").parse() {
+        Ok(format) => format,
+        Err(_) => {
+            eprintln!("Invalid format.");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// 让服务器备份到其备份目录中的 path
+fn backup(matches: &ArgMatches) -> Result<()> {
+    let mut client = connect(matches)?;
+    let manifest = client.backup(matches.value_of("path").unwrap().to_owned())?;
+    print_manifest(&manifest);
+    Ok(())
+}
+
+/// 校验备份并恢复到 --dir 指定的服务器工作目录中的空引擎
+fn restore(matches: &ArgMatches) -> Result<()> {
+    let path = Path::new(matches.value_of("path").unwrap());
+    let dir = Path::new(matches.value_of("dir").unwrap_or("."));
+    exit_if_corrupted(backup::validate(path))?;
+    let engine = open_engine(matches, dir)?;
+    if engine.stats()?["keys"] != 0 || !backup::keyspaces(&engine)?.is_empty() {
+        eprintln!("Target is not empty.");
+        std::process::exit(1);
+    }
+    let manifest = exit_if_corrupted(backup::restore(path, &engine))?;
+    print_manifest(&manifest);
+    Ok(())
+}
+
+/// 备份校验失败时退出
+fn exit_if_corrupted<T>(result: Result<T>) -> Result<T> {
+    match result {
+        Err(ref e) if e.kind() == KvsErrorType::Corrupted => {
+            eprintln!("Backup is corrupted or incomplete");
+            std::process::exit(1);
+        }
+        result => result,
+    }
+}
+
+/// 按 key 从小到大的顺序导出默认 keyspace 或 --keyspace 中的所有键值对到 --output 指定的文件或标准输出
+///
+/// 导出格式中没有 keyspace，数据目录中还有其他 keyspace 时需逐个导出，或指定 --skip-keyspaces 只导出默认 keyspace
+fn export(matches: &ArgMatches) -> Result<()> {
+    let mut target = Target::open(matches, false)?;
+    let others = target.other_keyspaces(matches)?;
+    if !others.is_empty() && !matches.is_present("skip-keyspaces") {
+        eprintln!(
+            "The directory also has keyspaces {}, export each with --keyspace or pass --skip-keyspaces.",
+            others.join(", ")
+        );
+        std::process::exit(1);
+    }
+    let output: Box<dyn Write> = match matches.value_of("output") {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let mut writer = ExportWriter::new(BufWriter::new(output), format_of(matches))?;
+    let mut after = None;
+    let mut count = 0;
+    loop {
+        let pairs = target.scan(after.clone(), SCAN_BATCH)?;
+        let last = match pairs.last() {
+            Some((key, _)) => key.clone(),
+            None => break,
+        };
+        count += pairs.len();
+        for (key, value) in pairs {
+            writer.add(key, value)?;
+        }
+        after = Some(last);
+    }
+    writer.finish()?;
+    // 数据可能写在标准输出中，因此统计信息写入标准错误
+    eprintln!("{} keys exported", count);
+    Ok(())
+}
+
+/// 从文件（`-` 表示标准输入）中读取键值对，按批写入默认 keyspace 或 --keyspace
+fn import(matches: &ArgMatches) -> Result<()> {
+    let mut target = Target::open(matches, true)?;
+    let batch_size = match matches.value_of("batch-size").map(str::parse) {
+        Some(Ok(size)) if size > 0 => size,
+        None => DEFAULT_BATCH_SIZE,
+        _ => {
+            eprintln!("Invalid batch size.");
+            std::process::exit(1);
+        }
+    };
+    let input: Box<dyn BufRead> = match matches.value_of("file").unwrap() {
+        "-" => Box::new(BufReader::new(io::stdin())),
+        path => Box::new(BufReader::new(File::open(path)?)),
+    };
+    let mut batch = Vec::with_capacity(batch_size);
+    let result = export::read(input, format_of(matches), |key, value| {
+        batch.push((key, value));
+        if batch.len() >= batch_size {
+            target.set_batch(&batch)?;
+            batch.clear();
+        }
+        Ok(())
+    });
+    let count = match result {
+        Err(ref e) if e.kind() == KvsErrorType::Corrupted => {
+            eprintln!("Invalid input.");
+            std::process::exit(1);
+        }
+        result => result?,
+    };
+    target.set_batch(&batch)?;
+    println!("{} keys imported", count);
+    Ok(())
+}
+
+/// 打印检查结果，keyspace 的结果缩进后跟在其后
+fn print_report(report: &CheckReport, indent: &str) {
+    if let Some(error) = &report.status_error {
+        println!("{}status: {}", indent, error);
+    }
+    println!(
+        "{}log {}: {} bytes, {} records, {} keys, {} duplicate keys, {} garbage bytes",
+        indent,
+        report.file_id,
+        report.log_bytes,
+        report.records,
+        report.keys,
+        report.duplicate_keys,
+        report.garbage_bytes
+    );
+    if report.unverified > 0 {
+        println!(
+            "{}{} encrypted records not verified, pass --encryption-key to check them",
+            indent, report.unverified
+        );
+    }
+    if report.unchecksummed > 0 {
+        println!(
+            "{}{} records written by an older version have no checksum",
+            indent, report.unchecksummed
+        );
+    }
+    if let Some(offset) = report.corrupted_at {
+        println!("{}corrupted record at offset {}", indent, offset);
+    }
+    for key in &report.dangling_blobs {
+        println!("{}unreadable blob for key {}", indent, key);
+    }
+    for path in report.orphan_logs.iter().chain(&report.orphan_blobs) {
+        println!("{}orphan file {}", indent, path.display());
+    }
+    for action in &report.repaired {
+        println!("{}repaired: {}", indent, action);
+    }
+    for (name, tree) in &report.keyspaces {
+        println!("{}keyspace {}:", indent, name);
+        print_report(tree, &format!("{}  ", indent));
+    }
+}
+
+/// dir 所指的 KvStore 数据目录，dir 是服务器的工作目录时使用其中的 kvs 目录
+fn kvs_dir(dir: &Path) -> PathBuf {
+    if !dir.join("status.json").exists() && dir.join("kvs").is_dir() {
+        dir.join("kvs")
+    } else {
+        dir.to_owned()
+    }
+}
+
+/// --encryption-key 与 --previous-keys 指定的密钥
+fn keyring_of(matches: &ArgMatches) -> Result<Option<Keyring>> {
+    match matches.value_of("encryption-key") {
+        Some(source) => {
+            let mut keyring = Keyring::new(EncryptionKey::load(source)?);
+            if let Some(sources) = matches.value_of("previous-keys") {
+                for source in sources.split(',') {
+                    keyring = keyring.with_previous(EncryptionKey::load(source)?);
+                }
+            }
+            Ok(Some(keyring))
+        }
+        None => Ok(None),
+    }
+}
+
+/// 解析数值参数 name，格式错误时退出
+fn number_of(matches: &ArgMatches, name: &str) -> Option<u64> {
+    match matches.value_of(name).map(str::parse) {
+        Some(Ok(n)) => Some(n),
+        None => None,
+        Some(Err(_)) => {
+            eprintln!("Invalid {}.", name);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// 检查 kvs 引擎的数据目录，按 --repair 修复，有问题时以 1 退出
+///
+/// dir 可以是 KvStore 的数据目录，也可以是服务器的工作目录
+fn check(matches: &ArgMatches) -> Result<()> {
+    let repair = match matches.value_of("repair").map(str::parse) {
+        Some(Ok(repair)) => Some(repair),
+        None => None,
+        Some(Err(_)) => {
+            eprintln!("Invalid repair mode.");
+            std::process::exit(1);
+        }
+    };
+    let dir = kvs_dir(Path::new(matches.value_of("dir").unwrap()));
+    let report = engines::check(&dir, keyring_of(matches)?, repair)?;
+    print_report(&report, "");
+    if !report.is_clean() {
+        std::process::exit(1);
+    }
+    println!("ok");
+    Ok(())
+}
+
+/// 截取 value 的前 width 个字符，过长时以 ... 结尾
+fn preview(value: &str, width: usize) -> String {
+    match value.char_indices().nth(width) {
+        Some((end, _)) => format!("{:?}...", &value[..end]),
+        None => format!("{:?}", value),
+    }
+}
+
+/// 按文件与位置的顺序打印数据目录中满足条件的每条记录
+fn dump(matches: &ArgMatches) -> Result<()> {
+    let mut dir = kvs_dir(Path::new(matches.value_of("dir").unwrap()));
+    if let Some(name) = matches.value_of("keyspace") {
+        dir = dir.join("trees").join(name);
+    }
+    if !dir.join("status.json").exists() {
+        eprintln!("Not a kvs data directory.");
+        std::process::exit(1);
+    }
+    let filter = DumpFilter {
+        file_id: number_of(matches, "file"),
+        key: matches.value_of("key").map(str::to_owned),
+        prefix: matches.value_of("prefix").map(str::to_owned),
+        from: number_of(matches, "from"),
+        to: number_of(matches, "to"),
+    };
+    let width = number_of(matches, "width").unwrap_or(DEFAULT_PREVIEW_WIDTH) as usize;
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut count = 0;
+    engines::dump(&dir, keyring_of(matches)?, &filter, |record| {
+        write!(
+            out,
+            "{}.log {} {}",
+            record.file_id, record.offset, record.length
+        )?;
+        if let (Some(seq), Some(time)) = (record.seq, record.time) {
+            write!(out, " #{} @{}", seq, time)?;
+        }
+        write!(out, " {}", record.kind)?;
+        if let Some(key) = &record.key {
+            write!(out, " {:?}", key)?;
+        }
+        if let Some(value) = &record.value {
+            write!(out, " {}", preview(value, width))?;
+        }
+        writeln!(out)?;
+        count += 1;
+        Ok(())
+    })?;
+    out.flush()?;
+    eprintln!("{} records", count);
+    Ok(())
+}
+
+/// 由基础备份与归档的数据文件恢复到 --to-seq 或 --to-time 所指的时刻，写入 --dir 中的空引擎
+fn recover(matches: &ArgMatches) -> Result<()> {
+    let until = match (number_of(matches, "to-seq"), number_of(matches, "to-time")) {
+        (Some(seq), _) => RecoveryTarget::Seq(seq),
+        (None, Some(time)) => RecoveryTarget::Time(time),
+        (None, None) => unreachable!(),
+    };
+    let backup = matches.value_of("backup").map(Path::new);
+    if let Some(path) = backup {
+        if backup::validate(path)?.seq.is_none() {
+            eprintln!("Backup has no sequence number, take it from a kvs engine.");
+            std::process::exit(1);
+        }
+    }
+    let mut logs = vec![PathBuf::from(matches.value_of("archive").unwrap())];
+    if let Some(dir) = matches.value_of("logs") {
+        logs.push(kvs_dir(Path::new(dir)));
+    }
+    let dir = Path::new(matches.value_of("dir").unwrap_or("."));
+    let engine = open_engine(matches, dir)?;
+    if engine.stats()?["keys"] != 0 {
+        eprintln!("Target is not empty.");
+        std::process::exit(1);
+    }
+    let report = match engines::recover(backup, &logs, keyring_of(matches)?, until, &engine) {
+        Err(ref e) if e.kind() == KvsErrorType::InvalidArgument => {
+            eprintln!("Backup is newer than the target.");
+            std::process::exit(1);
+        }
+        result => result?,
+    };
+    println!(
+        "{} keys from backup at seq {}, {} records replayed, recovered to seq {}",
+        report.base_keys, report.base_seq, report.replayed, report.seq
+    );
+    if let Some(time) = report.time {
+        println!("last record written at {}", time);
+    }
+    let mut incomplete = false;
+    if let Some(seq) = report.gap {
+        eprintln!(
+            "Records from seq {} are missing from the logs, the result may be incomplete.",
+            seq
+        );
+        incomplete = true;
+    }
+    for (name, tree) in &report.keyspaces {
+        println!(
+            "keyspace {}: {} records replayed, recovered to seq {}",
+            name, tree.replayed, tree.seq
+        );
+        if let Some(seq) = tree.gap {
+            eprintln!(
+                "Records of keyspace {} from seq {} are missing from the logs, the result may be incomplete.",
+                name, seq
+            );
+            incomplete = true;
+        }
+    }
+    if incomplete {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Warn).init();
+    let addr_args = || {
+        vec![
+            Arg::from_usage("--addr [ADDR] 'IP address'"),
+            Arg::from_usage("--user [USER] 'User name for authentication'").requires("token"),
+            Arg::from_usage("--token [TOKEN] 'Token for authentication'").requires("user"),
+        ]
+    };
+    let engine_opt_arg = || {
+        Arg::from_usage(
+            "--engine-opt [OPTION]... 'Engine option as KEY=VALUE, as given to kvs-server'",
+        )
+        .number_of_values(1)
+    };
+    let dir_args = || {
+        vec![
+            Arg::from_usage(
+                "--dir [DIR] 'Working directory of the server, defaults to the current one'",
+            )
+            .conflicts_with("addr"),
+            Arg::from_usage("--engine [ENGINE] 'Engine of the directory (kvs or sled)'")
+                .conflicts_with("addr"),
+            Arg::from_usage("--format [FORMAT] 'jsonl (default) or csv'"),
+            engine_opt_arg().conflicts_with("addr"),
+        ]
+    };
+    let key_args = || {
+        vec![
+            Arg::from_usage(
+                "--encryption-key [SOURCE] 'Key file or env:NAME of an encrypted directory'",
+            ),
+            Arg::from_usage("--previous-keys [SOURCES] 'Comma-separated keys before rotation'")
+                .requires("encryption-key"),
+        ]
+    };
+    let matches = App::new("kvs-admin")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Administration tool for kvs-server")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            // kvs-admin backup <PATH>
+            SubCommand::with_name("backup")
+                .about("Take a consistent backup of a running server into PATH under its --backup-dir")
+                .arg(Arg::with_name("path").required(true))
+                .args(&addr_args()),
+        )
+        .subcommand(
+            // kvs-admin restore <PATH>
+            SubCommand::with_name("restore")
+                .about("Validate a backup and load it into an empty data directory")
+                .arg(Arg::with_name("path").required(true))
+                .arg(Arg::from_usage(
+                    "--dir [DIR] 'Working directory of the server, defaults to the current one'",
+                ))
+                .arg(Arg::from_usage(
+                    "--engine [ENGINE] 'Engine to restore into (kvs or sled)'",
+                ))
+                .arg(engine_opt_arg())
+                .args(&key_args()),
+        )
+        .subcommand(
+            // kvs-admin export
+            SubCommand::with_name("export")
+                .about("Export all pairs of a keyspace in key order from a data directory or, with --addr, a running server")
+                .arg(Arg::from_usage("--output [FILE] 'Output file, defaults to stdout'"))
+                .arg(Arg::from_usage("--keyspace [NAME] 'Export the keyspace NAME instead of the default one'"))
+                .arg(
+                    Arg::from_usage("--skip-keyspaces 'Export only the default keyspace of a directory that has other keyspaces'")
+                        .conflicts_with("keyspace"),
+                )
+                .args(&addr_args())
+                .args(&dir_args())
+                .args(&key_args()),
+        )
+        .subcommand(
+            // kvs-admin import <FILE>
+            SubCommand::with_name("import")
+                .about("Load pairs from FILE (- for stdin) into a data directory or, with --addr, a running server")
+                .arg(Arg::with_name("file").required(true))
+                .arg(Arg::from_usage("--batch-size [N] 'Number of pairs written per batch'"))
+                .arg(Arg::from_usage("--keyspace [NAME] 'Import into the keyspace NAME instead of the default one'"))
+                .args(&addr_args())
+                .args(&dir_args())
+                .args(&key_args()),
+        )
+        .subcommand(
+            // kvs-admin check <DIR>
+            SubCommand::with_name("check")
+                .about("Verify a kvs data directory offline and optionally repair it")
+                .arg(Arg::with_name("dir").required(true))
+                .args(&key_args())
+                .arg(Arg::from_usage(
+                    "--repair [MODE] 'truncate: cut off a torn tail and remove orphan files, rebuild: also rewrite the log'",
+                )),
+        )
+        .subcommand(
+            // kvs-admin dump <DIR>
+            SubCommand::with_name("dump")
+                .about("Print every record in the log files of a kvs data directory")
+                .arg(Arg::with_name("dir").required(true))
+                .args(&key_args())
+                .arg(Arg::from_usage("--keyspace [NAME] 'Dump the keyspace NAME instead'"))
+                .arg(Arg::from_usage("--file [ID] 'Only records in ID.log'"))
+                .arg(Arg::from_usage("--key [KEY] 'Only records of KEY'"))
+                .arg(Arg::from_usage("--prefix [PREFIX] 'Only records whose key starts with PREFIX'"))
+                .arg(Arg::from_usage("--from [OFFSET] 'Only records at or after OFFSET'"))
+                .arg(Arg::from_usage("--to [OFFSET] 'Only records before OFFSET'"))
+                .arg(Arg::from_usage("--width [N] 'Characters of each value to print, defaults to 40'")),
+        )
+        .subcommand(
+            // kvs-admin recover --archive <DIR>
+            SubCommand::with_name("recover")
+                .about("Rebuild a store as of a sequence number or time from a base backup and archived logs")
+                .arg(Arg::from_usage("--archive <DIR> 'Archive directory of the kvs engine'"))
+                .arg(Arg::from_usage(
+                    "--logs [DIR] 'Data directory of the kvs engine, to replay its current log too'",
+                ))
+                .arg(Arg::from_usage("--backup [PATH] 'Base backup taken from the kvs engine'"))
+                .arg(
+                    Arg::from_usage("--to-seq [SEQ] 'Replay records up to this sequence number'")
+                        .required_unless("to-time")
+                        .conflicts_with("to-time"),
+                )
+                .arg(Arg::from_usage(
+                    "--to-time [MILLIS] 'Replay records written up to this Unix time in milliseconds'",
+                ))
+                .arg(Arg::from_usage(
+                    "--dir [DIR] 'Working directory of the server to recover into, defaults to the current one'",
+                ))
+                .arg(Arg::from_usage(
+                    "--engine [ENGINE] 'Engine to recover into (kvs or sled)'",
+                ))
+                .arg(engine_opt_arg())
+                .args(&key_args()),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("backup", Some(matches)) => backup(matches),
+        ("restore", Some(matches)) => restore(matches),
+        ("export", Some(matches)) => export(matches),
+        ("import", Some(matches)) => import(matches),
+        ("check", Some(matches)) => check(matches),
+        ("dump", Some(matches)) => dump(matches),
+        ("recover", Some(matches)) => recover(matches),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        exit_with(e)
+    }
+}
@@ -1,7 +1,80 @@
 extern crate clap;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use kvs::client::KvsClient;
+use kvs::sharding::{self, ShardMap};
+use kvs::{tls, KvsError, KvsErrorType};
 use log::LevelFilter;
+use std::path::Path;
+
+/// 各子命令共用的连接参数
+fn connection_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::from_usage("--addr [ADDR] 'IP address'"),
+        Arg::from_usage("--tls-ca [FILE] 'CA bundle used to verify the server certificate (PEM)'"),
+        Arg::from_usage("--tls-cert [FILE] 'Client certificate for mutual TLS (PEM)'")
+            .requires_all(&["tls-ca", "tls-key"]),
+        Arg::from_usage("--tls-key [FILE] 'Client private key for mutual TLS (PEM)'")
+            .requires("tls-cert"),
+        Arg::from_usage(
+            "--tls-domain [DOMAIN] 'Server name in the certificate, defaults to the host in ADDR'",
+        )
+        .requires("tls-ca"),
+        Arg::from_usage("--user [USER] 'User name for authentication'").requires("token"),
+        Arg::from_usage("--token [TOKEN] 'Token for authentication'").requires("user"),
+        Arg::from_usage(
+            "--keyspace [NAME] 'Keyspace to operate in, defaults to the default keyspace'",
+        ),
+    ]
+}
+
+/// 打印错误信息并退出
+fn exit_with(e: KvsError) -> ! {
+    match e.kind() {
+        KvsErrorType::PermissionDenied => eprintln!("Permission denied"),
+        KvsErrorType::KeyNotFound => eprintln!("Key not found"),
+        _ => eprintln!("{}", e),
+    }
+    std::process::exit(1);
+}
+
+/// 根据参数连接服务器，指定 --tls-ca 时使用 TLS，指定 --user 时进行认证
+fn connect(matches: &ArgMatches) -> KvsClient {
+    let mut client = open_connection(matches);
+    if let (Some(user), Some(token)) = (matches.value_of("user"), matches.value_of("token")) {
+        if let Err(e) = client.auth(user.to_owned(), token.to_owned()) {
+            exit_with(e);
+        }
+    }
+    client.use_keyspace(matches.value_of("keyspace").map(str::to_owned));
+    client
+}
+
+/// 建立到服务器的连接
+fn open_connection(matches: &ArgMatches) -> KvsClient {
+    let mut addr = String::from("127.0.0.1:4000");
+    if let Some(v) = matches.value_of("addr") {
+        addr = v.to_string();
+    }
+    let ca = match matches.value_of("tls-ca") {
+        Some(ca) => Path::new(ca),
+        None => return KvsClient::connent(addr).unwrap(),
+    };
+    let identity = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        (Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+        _ => None,
+    };
+    let domain = match matches.value_of("tls-domain") {
+        Some(domain) => domain.to_owned(),
+        None => addr.rsplitn(2, ':').last().unwrap().to_owned(),
+    };
+    match tls::client_config(ca, identity)
+        .and_then(|config| KvsClient::connect_tls(addr, &domain, config))
+    {
+        Ok(client) => client,
+        Err(e) => exit_with(e),
+    }
+}
 
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
@@ -16,21 +89,47 @@ fn main() {
                 .about("Set the value of a string key to a string")
                 .arg(Arg::with_name("key").required(true))
                 .arg(Arg::with_name("value").required(true))
-                .arg(Arg::from_usage("--addr [ADDR] 'IP address'")),
+                .args(&connection_args()),
         )
         .subcommand(
             // kvs get <KEY>
             SubCommand::with_name("get")
                 .about("Get the string value of a given string key")
                 .arg(Arg::with_name("key").required(true))
-                .arg(Arg::from_usage("--addr [ADDR] 'IP address'")),
+                .args(&connection_args()),
         )
         .subcommand(
             // kvs rm <KEY>
             SubCommand::with_name("rm")
                 .about("Remove a given key")
                 .arg(Arg::with_name("key").required(true))
-                .arg(Arg::from_usage("--addr [ADDR] 'IP address'")),
+                .args(&connection_args()),
+        )
+        .subcommand(
+            // kvs drop-keyspace <NAME>
+            SubCommand::with_name("drop-keyspace")
+                .about("Remove a keyspace and all keys in it")
+                .arg(Arg::with_name("name").required(true))
+                .args(&connection_args()),
+        )
+        .subcommand(
+            // kvs rebalance --from <FILE> --to <FILE> --writes-stopped
+            SubCommand::with_name("rebalance")
+                .about("Move keys between servers after the shard map changed; all writes must be stopped")
+                .arg(Arg::from_usage(
+                    "--from <FILE> 'Shard map the data is laid out by'",
+                ))
+                .arg(Arg::from_usage("--to <FILE> 'New shard map'"))
+                .arg(Arg::from_usage(
+                    "--writes-stopped 'Confirm that no client writes to the servers while rebalancing'",
+                ))
+                .arg(
+                    Arg::from_usage("--user [USER] 'User name for authentication'")
+                        .requires("token"),
+                )
+                .arg(
+                    Arg::from_usage("--token [TOKEN] 'Token for authentication'").requires("user"),
+                ),
         )
         .get_matches();
 
@@ -38,40 +137,56 @@ fn main() {
         ("set", Some(matches)) => {
             let key = matches.value_of("key").expect("缺少参数 Key");
             let value = matches.value_of("value").expect("缺少参数 Value");
-            let mut addr = String::from("127.0.0.1:4000");
-            if let Some(v) = matches.value_of("addr") {
-                addr = v.to_string();
+            let mut client = connect(matches);
+            if let Err(e) = client.set(key.to_string(), value.to_string()) {
+                exit_with(e);
             }
-
-            let mut client = kvs::client::KvsClient::connent(addr).unwrap();
-            client.set(key.to_string(), value.to_string()).unwrap();
         }
         ("get", Some(matches)) => {
-            let mut addr = String::from("127.0.0.1:4000");
-            if let Some(v) = matches.value_of("addr") {
-                addr = v.to_string();
-            }
-            let mut client = kvs::client::KvsClient::connent(addr).unwrap();
+            let mut client = connect(matches);
             let key = matches.value_of("key").expect("缺少参数 Key");
-            if let Some(value) = client.get(key.to_string()).unwrap() {
-                println!("{}", value)
-            } else {
-                println!("Key not found")
+            match client.get(key.to_string()) {
+                Ok(Some(value)) => println!("{}", value),
+                Ok(None) => println!("Key not found"),
+                Err(e) => exit_with(e),
             }
         }
         ("rm", Some(matches)) => {
-            let mut addr = String::from("127.0.0.1:4000");
-            if let Some(v) = matches.value_of("addr") {
-                addr = v.to_string();
-            }
-            let mut client = kvs::client::KvsClient::connent(addr).unwrap();
+            let mut client = connect(matches);
             let key = matches.value_of("key").expect("缺少参数 Key");
-            match client.remove(key.to_string()) {
-                Ok(()) => {}
-                Err(_) => {
-                    eprintln!("Key not found");
+            if let Err(e) = client.remove(key.to_string()) {
+                exit_with(e);
+            }
+        }
+        ("drop-keyspace", Some(matches)) => {
+            let mut client = connect(matches);
+            let name = matches.value_of("name").expect("缺少参数 Name");
+            match client.drop_keyspace(name.to_string()) {
+                Ok(true) => {}
+                Ok(false) => {
+                    eprintln!("Keyspace not found");
                     std::process::exit(1);
                 }
+                Err(e) => exit_with(e),
+            }
+        }
+        ("rebalance", Some(matches)) => {
+            // 迁移期间的写入可能丢失，需要确认已停止写入
+            if !matches.is_present("writes-stopped") {
+                eprintln!("Stop all writes first and pass --writes-stopped to rebalance.");
+                std::process::exit(1);
+            }
+            let from = ShardMap::open(Path::new(matches.value_of("from").unwrap()));
+            let to = ShardMap::open(Path::new(matches.value_of("to").unwrap()));
+            let credentials = match (matches.value_of("user"), matches.value_of("token")) {
+                (Some(user), Some(token)) => Some((user.to_owned(), token.to_owned())),
+                _ => None,
+            };
+            match from
+                .and_then(|from| to.and_then(|to| sharding::rebalance(&from, &to, credentials)))
+            {
+                Ok(moved) => println!("{} keys moved", moved),
+                Err(e) => exit_with(e),
             }
         }
         _ => unreachable!(),
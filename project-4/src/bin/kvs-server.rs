@@ -1,31 +1,205 @@
 use clap::{App, Arg};
-use kvs::engines::KvStore;
-use kvs::engines::SledServer;
+use kvs::acl::Acl;
+use kvs::backup;
+use kvs::engines::{EngineOptions, EngineRegistry};
+use kvs::raft::{Member, Membership, NodeId, RaftEngine};
+use kvs::replication::{Follower, ReadOnly};
 use kvs::server::KvsServer;
 use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
-use kvs::{KvsErrorType, Result};
+use kvs::{tls, KvsEngine, KvsErrorType, Result};
 #[macro_use]
 extern crate log;
 use log::LevelFilter;
 
-use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
 
-fn check_engine(engine_type: &String) -> Result<()> {
-    let engine_cfg_path = std::env::current_dir()?.join("server.cfg");
-    if !engine_cfg_path.exists() {
-        let mut engine_cfg_file = std::fs::File::create(engine_cfg_path)?;
-        engine_cfg_file.write_all(engine_type.as_bytes())?;
-        return Ok(());
-    }
-    let mut engine_cfg_file = std::fs::File::open(engine_cfg_path)?;
-    let mut content = String::new();
-    engine_cfg_file.read_to_string(&mut content)?;
-    if &content != engine_type {
-        return Err(KvsErrorType::Other)?;
+/// 将当前目录中的数据迁移到名为 to 的引擎，校验通过后更新 server.cfg
+///
+/// 原引擎的数据保留在原目录中，不会被删除；目标引擎只将数据保存在内存中，
+/// 或原引擎中有 keyspace 而目标引擎不支持 keyspace 时拒绝迁移
+fn migrate_engine(registry: &EngineRegistry, to: &str, options: &EngineOptions) -> Result<()> {
+    if registry.is_volatile(to)? {
+        eprintln!("Cannot migrate to {}, it does not persist data.", to);
+        std::process::exit(1);
     }
+    let dir = std::env::current_dir()?;
+    let from = match EngineRegistry::marker(&dir)? {
+        Some(type_id) => match registry.name_of_type(&type_id) {
+            Some(name) => name.to_owned(),
+            None => Err(KvsErrorType::UnknownEngine)?,
+        },
+        None => {
+            eprintln!("Nothing to migrate.");
+            std::process::exit(1);
+        }
+    };
+    if from == to {
+        eprintln!("Already using {}.", to);
+        std::process::exit(1);
+    }
+    // 目标目录只可能是上次中断的迁移留下的
+    let target_dir = dir.join(to);
+    if target_dir.exists() {
+        std::fs::remove_dir_all(&target_dir)?;
+    }
+    let source = registry.create(&from, &dir, options)?;
+    let target = registry.create(to, &dir, options)?;
+    let manifest = match backup::migrate(&source, &target) {
+        // 只有目标引擎不支持 keyspace 时才会在此返回
+        Err(ref e) if e.kind() == KvsErrorType::UnknownOperation => {
+            eprintln!("Cannot migrate to {}, it does not support keyspaces.", to);
+            std::process::exit(1);
+        }
+        result => result?,
+    };
+    registry.write_marker(to, &dir)?;
+    info!(
+        "migrated {} keys in {} keyspaces from {} to {}, checksum {:016x}",
+        manifest.keys,
+        manifest.keyspaces.len() + 1,
+        from,
+        to,
+        manifest.checksum
+    );
     Ok(())
 }
 
+/// 解析 `KEY=VALUE` 形式的引擎选项
+fn parse_engine_options<'a>(values: impl Iterator<Item = &'a str>) -> Option<EngineOptions> {
+    let mut options = EngineOptions::new();
+    for value in values {
+        let (key, value) = value.split_once('=')?;
+        options.insert(key.trim().to_owned(), value.to_owned());
+    }
+    Some(options)
+}
+
+/// 与引擎无关的服务器选项
+struct ServerOptions {
+    addr: String,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    acl: Option<Arc<Acl>>,
+    backup_dir: Option<PathBuf>,
+    resp_addr: Option<String>,
+    http_addr: Option<String>,
+    grpc_addr: Option<String>,
+    max_request_size: Option<usize>,
+    replicate_from: Option<String>,
+    replicate_credentials: Option<(String, String)>,
+    replicate_tls: Option<Arc<rustls::ClientConfig>>,
+    cluster: Option<ClusterOptions>,
+}
+
+/// 集群选项
+struct ClusterOptions {
+    id: NodeId,
+    peers: Membership,
+    join: bool,
+    token: Option<String>,
+}
+
+/// 解析 `ID=CLIENT_ADDR/RAFT_ADDR,...` 形式的节点列表
+fn parse_peers(peers: &str) -> Option<Membership> {
+    let mut membership = Membership::new();
+    for peer in peers.split(',') {
+        let (id, addrs) = peer.split_once('=')?;
+        let (client_addr, raft_addr) = addrs.split_once('/')?;
+        membership.insert(
+            id.trim().parse().ok()?,
+            Member {
+                client_addr: client_addr.to_owned(),
+                raft_addr: raft_addr.to_owned(),
+            },
+        );
+    }
+    Some(membership)
+}
+
+/// 使用 engine 按 options 启动服务器
+///
+/// 指定了主节点时作为从节点运行，在后台复制主节点的日志，并只提供读取服务
+///
+/// 指定了集群时作为 Raft 集群的一个节点运行
+fn run<E: KvsEngine>(engine: E, mut options: ServerOptions) {
+    if let Some(cluster) = options.cluster.take() {
+        let raft_addr = cluster.peers[&cluster.id].raft_addr.clone();
+        let membership = if cluster.join {
+            Membership::new()
+        } else {
+            cluster.peers
+        };
+        let dir = std::env::current_dir().unwrap().join("raft");
+        info!("Cluster node {} on {}", cluster.id, raft_addr);
+        if cluster.token.is_none() {
+            warn!("Raft port {} accepts unauthenticated peers", raft_addr);
+        }
+        match RaftEngine::start_with_token(
+            engine,
+            cluster.id,
+            &raft_addr,
+            membership,
+            dir,
+            cluster.token,
+        ) {
+            Ok(engine) => return serve(engine, options),
+            Err(e) => {
+                eprintln!("Failed to start cluster node: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let leader = match options.replicate_from.take() {
+        Some(leader) => leader,
+        None => return serve(engine, options),
+    };
+    info!("Replicating from {}", leader);
+    let state_path = std::env::current_dir().unwrap().join("replica.json");
+    let mut follower = match Follower::new(engine.clone(), state_path) {
+        Ok(follower) => follower,
+        Err(_) => {
+            eprintln!("Invalid replication state.");
+            std::process::exit(1);
+        }
+    };
+    if let Some((user, token)) = options.replicate_credentials.take() {
+        follower = follower.auth(user, token);
+    }
+    if let Some(config) = options.replicate_tls.take() {
+        follower = follower.tls(config);
+    }
+    thread::spawn(move || follower.run(leader));
+    serve(ReadOnly::new(engine), options)
+}
+
+/// 使用 engine 按 options 启动服务器
+fn serve<E: KvsEngine>(engine: E, options: ServerOptions) {
+    let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(0).unwrap());
+    if let Some(config) = options.tls {
+        server = server.tls(config);
+    }
+    if let Some(acl) = options.acl {
+        server = server.acl(acl);
+    }
+    if let Some(dir) = options.backup_dir {
+        server = server.backup_dir(dir);
+    }
+    if let Some(resp_addr) = options.resp_addr {
+        server = server.resp(resp_addr);
+    }
+    if let Some(http_addr) = options.http_addr {
+        server = server.http(http_addr);
+    }
+    if let Some(grpc_addr) = options.grpc_addr {
+        server = server.grpc(grpc_addr);
+    }
+    if let Some(size) = options.max_request_size {
+        server = server.max_request_size(size);
+    }
+    server.run(options.addr).unwrap()
+}
+
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
@@ -34,8 +208,99 @@ fn main() {
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .arg(Arg::from_usage("--addr [ADDR] 'IP address'"))
-        .arg(Arg::from_usage("--engine [ENGINE] 'IP address'"))
+        .arg(Arg::from_usage("--engine [ENGINE] 'Storage engine'"))
+        .arg(
+            Arg::from_usage("--engine-opt [OPTION]... 'Engine option as KEY=VALUE'")
+                .number_of_values(1),
+        )
+        .arg(Arg::from_usage("--tls-cert [FILE] 'TLS certificate chain (PEM)'").requires("tls-key"))
+        .arg(Arg::from_usage("--tls-key [FILE] 'TLS private key (PEM)'").requires("tls-cert"))
+        .arg(
+            Arg::from_usage(
+                "--tls-client-ca [FILE] 'CA bundle used to verify client certificates (PEM)'",
+            )
+            .requires("tls-cert"),
+        )
+        .arg(Arg::from_usage(
+            "--acl [FILE] 'ACL file enabling authentication (JSON)'",
+        ))
+        .arg(Arg::from_usage(
+            "--backup-dir [DIR] 'Directory for backups requested by clients; remote backup is disabled without it'",
+        ))
+        .arg(Arg::from_usage(
+            "--resp-addr [ADDR] 'Address of the RESP (Redis protocol) listener'",
+        ))
+        .arg(Arg::from_usage(
+            "--http-addr [ADDR] 'Address of the HTTP/JSON listener'",
+        ))
+        .arg(Arg::from_usage(
+            "--grpc-addr [ADDR] 'Address of the gRPC listener'",
+        ))
+        .arg(Arg::from_usage(
+            "--max-request-size [BYTES] 'Maximum size of a RESP or HTTP request'",
+        ))
+        .arg(Arg::from_usage(
+            "--replicate-from [ADDR] 'Run as a read-only follower of the leader at ADDR'",
+        ))
+        .arg(
+            Arg::from_usage("--replicate-user [USER] 'User name for authenticating to the leader'")
+                .requires_all(&["replicate-from", "replicate-token"]),
+        )
+        .arg(
+            Arg::from_usage("--replicate-token [TOKEN] 'Token for authenticating to the leader'")
+                .requires("replicate-user"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--replicate-ca [FILE] 'CA bundle used to verify the leader certificate (PEM); defaults to --tls-client-ca'",
+            )
+            .requires_all(&["replicate-from", "tls-cert"]),
+        )
+        .arg(
+            Arg::from_usage("--cluster-id [ID] 'Id of this node in the Raft cluster'")
+                .requires("cluster-peers")
+                .conflicts_with("replicate-from"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--cluster-peers [PEERS] 'Cluster nodes including this one, as ID=CLIENT_ADDR/RAFT_ADDR,...'",
+            )
+            .requires("cluster-id"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--cluster-join 'Join a running cluster; the leader has to add this node'",
+            )
+            .requires("cluster-id"),
+        )
+        .arg(
+            Arg::from_usage(
+                "--cluster-token [TOKEN] 'Token shared by all nodes to authenticate Raft peers; Raft traffic is not encrypted and its port should stay on a private network'",
+            )
+            .requires("cluster-id"),
+        )
+        .arg(Arg::from_usage(
+            "--migrate-to [ENGINE] 'Copy all data into a persistent ENGINE (kvs, sled or lsm), switch server.cfg to it and exit'",
+        ))
         .get_matches();
+    let registry = EngineRegistry::builtin();
+    let engine_options =
+        match parse_engine_options(matches.values_of("engine-opt").into_iter().flatten()) {
+            Some(options) => options,
+            None => {
+                eprintln!("Invalid engine option.");
+                std::process::exit(1);
+            }
+        };
+    if let Some(to) = matches.value_of("migrate-to") {
+        match migrate_engine(&registry, to, &engine_options) {
+            Ok(_) => std::process::exit(0),
+            Err(e) => {
+                eprintln!("Migration failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
     let mut addr = String::from("127.0.0.1:4000");
     if let Some(v) = matches.value_of("addr") {
         addr = v.to_string();
@@ -44,40 +309,126 @@ fn main() {
     if let Some(v) = matches.value_of("engine") {
         engine = v.to_string();
     }
-    match check_engine(&engine) {
-        Ok(_) => (),
-        Err(_) => {
-            eprintln!("Wrong engine.");
+    let engine = match registry.open(&engine, &std::env::current_dir().unwrap(), &engine_options) {
+        Ok(engine) => engine,
+        Err(e) => {
+            match e.kind() {
+                KvsErrorType::WrongEngine => eprintln!("Wrong engine."),
+                KvsErrorType::UnknownEngine => eprintln!("Invalid engine."),
+                KvsErrorType::OptionsMismatch => {
+                    eprintln!("Engine options do not match the data directory.")
+                }
+                _ => eprintln!("Failed to open engine: {}", e),
+            }
             std::process::exit(1);
         }
-    }
-    info!("Server engine: {}", engine);
-    info!("Server address: {}", addr);
-    match engine.as_ref() {
-        "kvs" => KvsServer::new(
-            KvStore::open(std::env::current_dir().unwrap().join("kvs")).unwrap(),
-            SharedQueueThreadPool::new(0).unwrap(),
-        )
-        .run(addr)
-        .unwrap(),
-
-        "sled" => KvsServer::new(
-            SledServer::new(
-                match sled::Db::start_default(std::env::current_dir().unwrap().join("sled")) {
-                    Ok(db) => db,
-                    Err(_) => {
-                        error!("Sled created failed.");
-                        std::process::exit(1);
-                    }
-                },
-            ),
-            SharedQueueThreadPool::new(0).unwrap(),
-        )
-        .run(addr)
-        .unwrap(),
-        _ => {
-            eprintln!("Invalid engine.");
+    };
+    let tls = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        (Some(cert), Some(key)) => {
+            match tls::server_config(
+                Path::new(cert),
+                Path::new(key),
+                matches.value_of("tls-client-ca").map(Path::new),
+            ) {
+                Ok(config) => Some(config),
+                Err(_) => {
+                    eprintln!("Invalid TLS certificate or key.");
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
+    let acl = match matches.value_of("acl") {
+        Some(path) => match Acl::open(Path::new(path)) {
+            Ok(acl) => Some(Arc::new(acl)),
+            Err(_) => {
+                eprintln!("Invalid ACL file.");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    // 开启 TLS 时从节点也通过 TLS 连接主节点；双向认证时使用本节点的证书作为客户端证书
+    let replicate_tls = match (
+        &tls,
+        matches.value_of("replicate-from"),
+        matches
+            .value_of("replicate-ca")
+            .or_else(|| matches.value_of("tls-client-ca")),
+    ) {
+        (Some(_), Some(_), Some(ca)) => {
+            let identity = match (
+                matches.value_of("tls-client-ca"),
+                matches.value_of("tls-cert"),
+                matches.value_of("tls-key"),
+            ) {
+                (Some(_), Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+                _ => None,
+            };
+            match tls::client_config(Path::new(ca), identity) {
+                Ok(config) => Some(config),
+                Err(_) => {
+                    eprintln!("Invalid TLS CA bundle for replication.");
+                    std::process::exit(1);
+                }
+            }
+        }
+        (Some(_), Some(_), None) => {
+            eprintln!("Replicating over TLS requires --replicate-ca.");
             std::process::exit(1);
         }
-    }
+        _ => None,
+    };
+    let max_request_size = match matches.value_of("max-request-size").map(str::parse) {
+        Some(Ok(size)) => Some(size),
+        Some(Err(_)) => {
+            eprintln!("Invalid request size.");
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    let cluster = match (
+        matches.value_of("cluster-id"),
+        matches.value_of("cluster-peers"),
+    ) {
+        (Some(id), Some(peers)) => match (id.parse(), parse_peers(peers)) {
+            (Ok(id), Some(peers)) if peers.contains_key(&id) => Some(ClusterOptions {
+                id,
+                peers,
+                join: matches.is_present("cluster-join"),
+                token: matches.value_of("cluster-token").map(String::from),
+            }),
+            _ => {
+                eprintln!("Invalid cluster peers.");
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    info!("Server engine: {}", engine.get_type());
+    info!("Server address: {}", addr);
+    info!("TLS: {}", if tls.is_some() { "on" } else { "off" });
+    info!("ACL: {}", if acl.is_some() { "on" } else { "off" });
+    let options = ServerOptions {
+        addr,
+        tls,
+        acl,
+        backup_dir: matches.value_of("backup-dir").map(PathBuf::from),
+        resp_addr: matches.value_of("resp-addr").map(String::from),
+        http_addr: matches.value_of("http-addr").map(String::from),
+        grpc_addr: matches.value_of("grpc-addr").map(String::from),
+        max_request_size,
+        replicate_from: matches.value_of("replicate-from").map(String::from),
+        replicate_credentials: match (
+            matches.value_of("replicate-user"),
+            matches.value_of("replicate-token"),
+        ) {
+            (Some(user), Some(token)) => Some((user.to_owned(), token.to_owned())),
+            _ => None,
+        },
+        replicate_tls,
+        cluster,
+    };
+    run(engine, options);
 }
@@ -1,7 +1,29 @@
-use crate::{response::Response, KvsErrorType, Operation, Result};
+use crate::backup::BackupManifest;
+use crate::replication::{LogEntry, LogPosition};
+use crate::{response::Response, tls, unix_socket_path, KvsError, KvsErrorType, Operation, Result};
 
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::TcpStream;
+use rustls::{ClientConfig, ClientConnection, ServerName, StreamOwned};
+use std::convert::TryFrom;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+
+/// 客户端所使用的双向字节流（TCP 或 TLS）
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// 连接至 addr，addr 为 `unix:` 开头的路径时使用 Unix domain socket，否则使用 TCP
+fn open_stream(addr: &str) -> io::Result<Box<dyn Stream>> {
+    match unix_socket_path(addr) {
+        #[cfg(unix)]
+        Some(path) => Ok(Box::new(UnixStream::connect(path)?)),
+        _ => Ok(Box::new(TcpStream::connect(addr)?)),
+    }
+}
 
 /// 用于向服务器发送信息进行数据库操作的客户端
 ///
@@ -15,68 +37,359 @@ use std::net::TcpStream;
 /// client.remove("key".to_owned());
 /// ```
 pub struct KvsClient {
-    reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
+    stream: BufReader<Box<dyn Stream>>,
+    tls: Option<Arc<ClientConfig>>,
+    credentials: Option<(String, String)>,
+    /// 操作所在的 keyspace，为空时使用默认的 keyspace
+    keyspace: Option<String>,
+}
+
+/// 跟随集群重定向的最大次数
+const MAX_REDIRECTS: usize = 3;
+
+/// 取 addr 中的主机名，用于校验服务端证书
+///
+/// 支持 `host:port`、`[ipv6]:port` 与不带端口的 IP 地址；Unix domain socket 使用 localhost
+pub(crate) fn host_of(addr: &str) -> &str {
+    if unix_socket_path(addr).is_some() {
+        return "localhost";
+    }
+    if let Some(rest) = addr.strip_prefix('[') {
+        if let Some(end) = rest.find(']') {
+            return &rest[..end];
+        }
+    }
+    if addr.parse::<IpAddr>().is_ok() {
+        return addr;
+    }
+    match addr.rfind(':') {
+        Some(pos) => &addr[..pos],
+        None => addr,
+    }
+}
+
+/// 将服务器返回的错误信息还原为对应的错误类型，无法识别时为 Other
+fn error_of(msg: Option<String>) -> KvsError {
+    use KvsErrorType::*;
+    let msg = msg.unwrap_or_default();
+    let kinds = [
+        IOError,
+        SerdeError,
+        UnknownOperation,
+        KeyNotFound,
+        SledError,
+        PermissionDenied,
+        ReadOnly,
+        NotLeader,
+        Corrupted,
+        OptionsMismatch,
+        InvalidKeyspace,
+        KeyspaceNotFound,
+        InvalidArgument,
+    ];
+    match kinds.iter().find(|kind| kind.to_string() == msg) {
+        Some(kind) => (*kind).into(),
+        None => {
+            error!("{}", msg);
+            Other.into()
+        }
+    }
+}
+
+/// 已断开的连接，读写均返回错误
+struct Closed;
+
+impl Read for Closed {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+}
+
+impl Write for Closed {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Err(io::ErrorKind::NotConnected.into())
+    }
 }
 
 impl KvsClient {
     /// 连接至地址为 addr 的服务器
+    ///
+    /// addr 可以是 TCP 地址，也可以是 `unix:/path/kvs.sock` 形式的 Unix domain socket
     pub fn connent(addr: String) -> Result<Self> {
-        let stream = match TcpStream::connect(addr) {
-            Ok(l) => l,
+        match Self::connect(addr) {
+            Ok(client) => Ok(client),
             Err(e) => {
                 error!("{}", e);
                 std::process::exit(1);
             }
-        };
+        }
+    }
+
+    /// 连接至地址为 addr 的服务器，连接失败时返回错误而不是退出进程
+    pub fn connect(addr: String) -> Result<Self> {
+        Ok(KvsClient {
+            stream: BufReader::new(open_stream(&addr)?),
+            tls: None,
+            credentials: None,
+            keyspace: None,
+        })
+    }
 
+    /// 通过 TLS 连接至地址为 addr 的服务器
+    ///
+    /// domain 为服务端证书中的域名（或 IP），config 可由 `tls::client_config` 构建
+    pub fn connect_tls(addr: String, domain: &str, config: Arc<ClientConfig>) -> Result<Self> {
+        let server_name = ServerName::try_from(domain).map_err(|_| KvsErrorType::TlsError)?;
+        let conn = ClientConnection::new(Arc::clone(&config), server_name)?;
+        let stream = open_stream(&addr)?;
         Ok(KvsClient {
-            reader: BufReader::new(stream.try_clone()?),
-            writer: BufWriter::new(stream),
+            stream: BufReader::new(Box::new(StreamOwned::new(conn, stream))),
+            tls: Some(config),
+            credentials: None,
+            keyspace: None,
         })
     }
 
+    /// 通过 TLS 连接至地址为 addr 的服务器，并使用 ca 中的 CA 证书包校验服务端证书
+    ///
+    /// 服务端证书需包含 addr 中的主机名
+    pub fn connect_with_ca(addr: String, ca: &Path) -> Result<Self> {
+        let domain = host_of(&addr).to_owned();
+        Self::connect_tls(addr, &domain, tls::client_config(ca, None)?)
+    }
+
+    /// 以 user 的身份向服务器进行认证
+    ///
+    /// 服务器开启访问控制时，需在连接后首先调用
+    pub fn auth(&mut self, user: String, token: String) -> Result<()> {
+        self.send(&Operation::auth(&user, &token))?;
+        self.recv()?;
+        self.credentials = Some((user, token));
+        Ok(())
+    }
+
+    /// 之后的 get、set、remove 与 scan 操作在名为 name 的 keyspace 中进行，为空时使用默认的 keyspace
+    pub fn use_keyspace(&mut self, name: Option<String>) {
+        self.keyspace = name;
+    }
+
+    /// 删除服务器中名为 name 的 keyspace 及其中的所有数据，返回其是否存在
+    pub fn drop_keyspace(&mut self, name: String) -> Result<bool> {
+        let response = self.call(&Operation::DropKeyspace { name })?;
+        match (response.status, response.msg) {
+            (0, Some(msg)) => Ok(msg == "true"),
+            (_, msg) => {
+                error!("{}", msg.unwrap_or_default());
+                Err(KvsErrorType::UnknownOperation)?
+            }
+        }
+    }
+
+    /// 在当前 keyspace 中执行 op 的操作
+    fn in_keyspace(&self, op: Operation) -> Operation {
+        match &self.keyspace {
+            Some(name) => Operation::keyspace(name, op),
+            None => op,
+        }
+    }
+
     /// 向服务器请求 key 所对应的 value
+    ///
+    /// key 不存在时返回 None
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.send(&Operation::get(&key));
-        let response = self.recv().unwrap();
-        return Ok(response.msg);
+        let response = self.call(&self.in_keyspace(Operation::get(&key)))?;
+        match (response.status, response.msg) {
+            (0, msg) => Ok(msg),
+            (_, Some(ref msg)) if msg == "Key not found" => Ok(None),
+            (_, msg) => Err(error_of(msg)),
+        }
+    }
+
+    /// 按 key 从小到大的顺序获取以 prefix 开头的键值对
+    ///
+    /// after 不为空时只返回大于 after 的 key，最多返回 limit 个
+    pub fn scan(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let response = self.call(&self.in_keyspace(Operation::Scan {
+            prefix,
+            after,
+            limit,
+        }))?;
+        match (response.status, response.msg) {
+            (0, Some(msg)) => Ok(serde_json::from_str(&msg)?),
+            (0, None) => Err(KvsErrorType::UnknownOperation)?,
+            (_, msg) => Err(error_of(msg)),
+        }
+    }
+
+    /// 发送操作并接收响应
+    ///
+    /// 连接的是集群中的非 leader 节点时，重新连接到 leader（使用相同的 TLS 配置与认证信息）后重试
+    fn call(&mut self, op: &Operation) -> Result<Response> {
+        for _ in 0..MAX_REDIRECTS {
+            self.send(op)?;
+            let response = self.recv()?;
+            if response.status != -3 {
+                return Ok(response);
+            }
+            match response.msg {
+                Some(leader) => self.reconnect(leader)?,
+                None => Err(KvsErrorType::NotLeader)?,
+            }
+        }
+        Err(KvsErrorType::NotLeader)?
+    }
+
+    /// 重新连接至地址为 addr 的服务器
+    fn reconnect(&mut self, addr: String) -> Result<()> {
+        info!("redirected to {}", addr);
+        let mut client = match self.tls.clone() {
+            Some(config) => {
+                let domain = host_of(&addr).to_owned();
+                Self::connect_tls(addr, &domain, config)?
+            }
+            None => Self::connect(addr)?,
+        };
+        if let Some((user, token)) = self.credentials.clone() {
+            client.auth(user, token)?;
+        }
+        client.keyspace = self.keyspace.take();
+        *self = client;
+        Ok(())
+    }
+
+    /// 将服务器某一时刻的完整数据备份到服务器备份目录中的 path，返回备份清单
+    ///
+    /// path 为备份目录中的相对路径；服务器未设置备份目录或用户不是管理员时返回 PermissionDenied Error
+    pub fn backup(&mut self, path: String) -> Result<BackupManifest> {
+        let response = self.call(&Operation::Backup { path })?;
+        match (response.status, response.msg) {
+            (0, Some(msg)) => Ok(serde_json::from_str(&msg)?),
+            (_, msg) => {
+                error!("{}", msg.unwrap_or_default());
+                Err(KvsErrorType::UnknownOperation)?
+            }
+        }
+    }
+
+    /// 请求从 position 开始复制服务器当前 keyspace 的日志，返回日志条目的迭代器
+    ///
+    /// 之后该连接只用于接收日志
+    pub fn replicate(
+        mut self,
+        position: Option<LogPosition>,
+    ) -> Result<impl Iterator<Item = Result<LogEntry>>> {
+        let op = self.in_keyspace(Operation::Replicate { position });
+        self.send(&op)?;
+        let response = self.recv()?;
+        if response.status != 0 {
+            error!("{}", response.msg.unwrap_or_default());
+            Err(KvsErrorType::UnknownOperation)?
+        }
+        Ok(serde_json::Deserializer::from_reader(self.stream)
+            .into_iter::<LogEntry>()
+            .map(|entry| entry.map_err(KvsError::from)))
+    }
+
+    /// 断开连接，之后的操作均返回 IOError
+    fn disconnect(&mut self) {
+        self.stream = BufReader::new(Box::new(Closed));
     }
 
     /// 向服务器发送操作 op
-    fn send(&mut self, op: &Operation) {
-        serde_json::to_writer(&mut self.writer, op).unwrap();
-        self.writer.flush().unwrap();
+    fn send(&mut self, op: &Operation) -> Result<()> {
+        let buf = serde_json::to_vec(op)?;
+        let stream = self.stream.get_mut();
+        stream.write_all(&buf)?;
+        stream.flush()?;
+        Ok(())
     }
 
     /// 接收信息
     fn recv(&mut self) -> Result<Response> {
         let mut stream =
-            serde_json::Deserializer::from_reader(self.reader.by_ref()).into_iter::<Response>();
+            serde_json::Deserializer::from_reader(self.stream.by_ref()).into_iter::<Response>();
         while let Some(op) = stream.next() {
-            let op = op.unwrap();
-            return Ok(op);
+            let response: Response = op?;
+            if response.status == -2 {
+                Err(KvsErrorType::PermissionDenied)?
+            }
+            return Ok(response);
         }
         Err(KvsErrorType::UnknownOperation)?
     }
 
     /// 向服务器发送 (key, value) 用于设置键值对
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.send(&Operation::set(&key, value));
-        let response = self.recv().unwrap();
+        let response = self.call(&self.in_keyspace(Operation::set(&key, value)))?;
         match response.status {
             0 => Ok(()),
             _ => Err(KvsErrorType::UnknownOperation)?,
         }
     }
 
+    /// 批量设置键值对
+    ///
+    /// 一次发送所有请求后再依次接收响应，减少往返次数；
+    /// 被重定向时连接到 leader 后重新发送整批请求
+    pub fn set_batch(&mut self, pairs: &[(String, String)]) -> Result<()> {
+        for _ in 0..MAX_REDIRECTS {
+            let mut buf = Vec::new();
+            for (key, value) in pairs {
+                let op = self.in_keyspace(Operation::set(key, value.clone()));
+                serde_json::to_writer(&mut buf, &op)?;
+            }
+            let stream = self.stream.get_mut();
+            stream.write_all(&buf)?;
+            stream.flush()?;
+
+            // 出错后仍需读完所有回复，否则剩余的回复会被之后的操作误读
+            let mut leader = None;
+            let mut failed = None;
+            for _ in pairs {
+                let response = match self.recv() {
+                    Ok(response) => response,
+                    Err(ref e) if e.kind() == KvsErrorType::PermissionDenied => {
+                        failed = Some(KvsErrorType::PermissionDenied.into());
+                        continue;
+                    }
+                    Err(e) => {
+                        self.disconnect();
+                        return Err(e);
+                    }
+                };
+                match (response.status, response.msg) {
+                    (0, _) => {}
+                    (-3, Some(msg)) => leader = Some(msg),
+                    (-3, None) => failed = Some(KvsErrorType::NotLeader.into()),
+                    (_, msg) => failed = Some(error_of(msg)),
+                }
+            }
+            if let Some(e) = failed {
+                return Err(e);
+            }
+            match leader {
+                Some(leader) => self.reconnect(leader)?,
+                None => return Ok(()),
+            }
+        }
+        Err(KvsErrorType::NotLeader)?
+    }
+
     /// 在服务器中移除 key 所对应的元素
     pub fn remove(&mut self, key: String) -> Result<()> {
-        self.send(&Operation::remove(&key));
-        let response = self.recv().unwrap();
-        match response.status {
-            0 => Ok(()),
-            _ => Err(KvsErrorType::KeyNotFound)?,
+        let response = self.call(&self.in_keyspace(Operation::remove(&key)))?;
+        match (response.status, response.msg) {
+            (0, _) => Ok(()),
+            (_, msg) => Err(error_of(msg)),
         }
     }
 }
@@ -0,0 +1,224 @@
+use super::archive_file;
+use crate::{KvsErrorType, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// 单个 blob 文件的大小上限，超出后写入新文件
+const BLOB_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// 垃圾超过该大小且超过文件的一半时回收该文件
+const GC_THRESHOLD: u64 = 1024 * 1024;
+
+/// value 在 blob 文件中的位置
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BlobPointer {
+    /// 文件 id
+    pub file: u64,
+    /// 文件中的偏移量
+    pub offset: u64,
+    /// 长度
+    pub len: u64,
+}
+
+/// blob 文件的大小与其中仍被引用的数据大小
+#[derive(Default)]
+struct BlobFile {
+    size: u64,
+    live: u64,
+}
+
+/// 根据文件夹路径和 id 获取 blob 文件路径
+fn blob_filename(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.blob", id))
+}
+
+/// 从 file 中读取 pointer 指向的数据
+fn read_at(mut file: &File, pointer: BlobPointer) -> Result<Vec<u8>> {
+    let mut buf = vec![0; pointer.len as usize];
+    file.seek(SeekFrom::Start(pointer.offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// 值分离存储：较大的 value 追加写入 blob 文件，日志中只保存其位置
+///
+/// 只在持有写锁时修改
+pub struct BlobStore {
+    dir: PathBuf,
+    /// 当前写入的文件 id
+    active: u64,
+    writer: Option<File>,
+    files: BTreeMap<u64, BlobFile>,
+}
+
+impl BlobStore {
+    /// 打开 dir 中的 blob 文件，之后需通过 `mark_live` 登记仍被引用的数据
+    pub fn open(dir: PathBuf) -> Result<BlobStore> {
+        let mut files = BTreeMap::new();
+        if dir.exists() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                if path.extension() != Some("blob".as_ref()) {
+                    continue;
+                }
+                if let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse().ok())
+                {
+                    let size = entry.metadata()?.len();
+                    files.insert(id, BlobFile { size, live: 0 });
+                }
+            }
+        }
+        let active = files.keys().next_back().map_or(0, |id| id + 1);
+        Ok(BlobStore {
+            dir,
+            active,
+            writer: None,
+            files,
+        })
+    }
+
+    /// 登记 pointer 指向的数据仍被引用
+    pub fn mark_live(&mut self, pointer: BlobPointer) {
+        self.files.entry(pointer.file).or_default().live += pointer.len;
+    }
+
+    /// 数据文件中出现过 id 为 file 的文件，之后不再使用该 id，避免旧记录指向新文件
+    pub fn reserve(&mut self, file: u64) {
+        if file >= self.active {
+            self.active = file + 1;
+        }
+    }
+
+    /// pointer 指向的数据不再被引用
+    pub fn release(&mut self, pointer: BlobPointer) {
+        if let Some(file) = self.files.get_mut(&pointer.file) {
+            file.live = file.live.saturating_sub(pointer.len);
+        }
+    }
+
+    /// 追加写入 data，当前文件超出大小上限时写入新文件
+    pub fn append(&mut self, data: &[u8]) -> Result<BlobPointer> {
+        if matches!(self.files.get(&self.active), Some(file) if file.size >= BLOB_FILE_SIZE) {
+            self.switch();
+        }
+        if self.writer.is_none() {
+            fs::create_dir_all(&self.dir)?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(blob_filename(&self.dir, self.active))?;
+            self.writer = Some(file);
+        }
+        let file = self.files.entry(self.active).or_default();
+        let pointer = BlobPointer {
+            file: self.active,
+            offset: file.size,
+            len: data.len() as u64,
+        };
+        self.writer.as_mut().unwrap().write_all(data)?;
+        file.size += pointer.len;
+        file.live += pointer.len;
+        Ok(pointer)
+    }
+
+    /// 读取 pointer 指向的数据
+    pub fn read(&self, pointer: BlobPointer) -> Result<Vec<u8>> {
+        read_at(
+            &File::open(blob_filename(&self.dir, pointer.file))?,
+            pointer,
+        )
+    }
+
+    /// 之后的数据写入新文件
+    fn switch(&mut self) {
+        self.active += 1;
+        self.writer = None;
+    }
+
+    /// 需要回收的文件：没有被引用的数据，或垃圾超过阈值且超过文件的一半
+    ///
+    /// 当前文件需要回收时，之后的数据写入新文件
+    pub fn collectable(&mut self) -> Vec<u64> {
+        let ids: Vec<u64> = self
+            .files
+            .iter()
+            .filter(|(_, file)| {
+                file.live == 0
+                    || (file.size.saturating_sub(file.live) >= GC_THRESHOLD
+                        && file.live * 2 < file.size)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        if ids.contains(&self.active) {
+            self.switch();
+        }
+        ids
+    }
+
+    /// 删除已回收的文件，archive 不为空时移入其中的 blobs 目录
+    pub fn remove(&mut self, id: u64, archive: Option<&Path>) -> Result<()> {
+        debug!("remove blob file {}", id);
+        self.files.remove(&id);
+        match archive {
+            Some(archive) => archive_file(&blob_filename(&self.dir, id), &archive.join("blobs")),
+            None => Ok(fs::remove_file(blob_filename(&self.dir, id))?),
+        }
+    }
+
+    /// 打开所有文件，得到之后被回收的文件仍可读取的快照
+    pub fn snapshot(&self) -> Result<BlobFiles> {
+        let mut files = HashMap::new();
+        for id in self.files.keys() {
+            files.insert(*id, File::open(blob_filename(&self.dir, *id))?);
+        }
+        Ok(BlobFiles::Snapshot(files))
+    }
+
+    /// 文件数量、总大小与其中的垃圾大小
+    pub fn usage(&self) -> (u64, u64, u64) {
+        let size = self.files.values().map(|file| file.size).sum::<u64>();
+        let live = self.files.values().map(|file| file.live).sum::<u64>();
+        (self.files.len() as u64, size, size.saturating_sub(live))
+    }
+
+    /// 按需打开文件的读取方式
+    pub fn reader(&self) -> BlobFiles {
+        BlobFiles::Dir(self.dir.clone())
+    }
+}
+
+/// 读取 blob 的方式
+pub enum BlobFiles {
+    /// 按需打开文件夹中的文件
+    Dir(PathBuf),
+    /// 某一时刻打开的所有文件
+    Snapshot(HashMap<u64, File>),
+}
+
+impl BlobFiles {
+    /// 文件是否仍可读取
+    pub fn contains(&self, file: u64) -> bool {
+        match self {
+            BlobFiles::Dir(dir) => blob_filename(dir, file).exists(),
+            BlobFiles::Snapshot(files) => files.contains_key(&file),
+        }
+    }
+
+    /// 读取 pointer 指向的数据
+    pub fn read(&self, pointer: BlobPointer) -> Result<Vec<u8>> {
+        match self {
+            BlobFiles::Dir(dir) => read_at(&File::open(blob_filename(dir, pointer.file))?, pointer),
+            BlobFiles::Snapshot(files) => match files.get(&pointer.file) {
+                Some(file) => read_at(file, pointer),
+                None => Err(KvsErrorType::Corrupted)?,
+            },
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::Result;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// 分片数量，不同分片的访问互不阻塞
+const SHARDS: usize = 16;
+
+/// 缓存项占用的内存，只计算 key 与 value 的长度
+fn size_of(key: &str, value: &str) -> u64 {
+    (key.len() + value.len()) as u64
+}
+
+/// 缓存的一个分片，按 LRU 淘汰
+#[derive(Default)]
+struct CacheShard {
+    capacity: u64,
+    used: u64,
+    /// 逻辑时钟，每次访问加一
+    clock: u64,
+    /// 每次失效加一，读取期间发生变化时不写入读到的 value
+    version: u64,
+    /// value 与最后一次访问时的逻辑时钟
+    entries: HashMap<String, (String, u64)>,
+    /// 淘汰顺序
+    order: BTreeMap<u64, String>,
+}
+
+impl CacheShard {
+    /// 读取 key 并记录一次访问
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.clock += 1;
+        let clock = self.clock;
+        let (value, tick) = self.entries.get_mut(key)?;
+        let key = self.order.remove(tick).unwrap();
+        *tick = clock;
+        self.order.insert(clock, key);
+        Some(value.clone())
+    }
+
+    /// 写入 key，超出容量时淘汰最久未被访问的项
+    fn insert(&mut self, key: &str, value: String) {
+        let size = size_of(key, &value);
+        if size > self.capacity {
+            return;
+        }
+        self.remove(key);
+        self.clock += 1;
+        self.used += size;
+        self.entries.insert(key.to_owned(), (value, self.clock));
+        self.order.insert(self.clock, key.to_owned());
+        while self.used > self.capacity {
+            let (_, victim) = self.order.pop_first().unwrap();
+            let (value, _) = self.entries.remove(&victim).unwrap();
+            self.used -= size_of(&victim, &value);
+        }
+    }
+
+    /// 删除 key
+    fn remove(&mut self, key: &str) {
+        if let Some((value, tick)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+            self.used -= size_of(key, &value);
+        }
+    }
+}
+
+/// 读缓存，保存最近读取的 value
+///
+/// 按 key 的哈希分为多个分片，每个分片有独立的锁与容量；
+/// 读取数据文件期间 key 被修改时不会缓存读到的旧 value
+pub struct ValueCache {
+    shards: Vec<Mutex<CacheShard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+    /// 创建容量为 capacity 字节的缓存，容量为 0 时不缓存
+    pub fn new(capacity: u64) -> ValueCache {
+        let per_shard = capacity / SHARDS as u64;
+        ValueCache {
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(CacheShard {
+                        capacity: per_shard,
+                        ..CacheShard::default()
+                    })
+                })
+                .collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<CacheShard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    /// 获取 key 对应的 value，未缓存时通过 load 读取并缓存
+    pub fn get_or_load(
+        &self,
+        key: &str,
+        load: impl FnOnce() -> Result<Option<String>>,
+    ) -> Result<Option<String>> {
+        let shard = self.shard(key);
+        let version = {
+            let mut shard = shard.lock().unwrap();
+            if shard.capacity == 0 {
+                drop(shard);
+                return load();
+            }
+            if let Some(value) = shard.get(key) {
+                self.hits.fetch_add(1, Ordering::SeqCst);
+                return Ok(Some(value));
+            }
+            shard.version
+        };
+        self.misses.fetch_add(1, Ordering::SeqCst);
+        let value = load()?;
+        if let Some(value) = &value {
+            let mut shard = shard.lock().unwrap();
+            if shard.version == version {
+                shard.insert(key, value.clone());
+            }
+        }
+        Ok(value)
+    }
+
+    /// 使 key 的缓存失效，需在修改 index 之后调用
+    pub fn invalidate(&self, key: &str) {
+        let mut shard = self.shard(key).lock().unwrap();
+        shard.version += 1;
+        shard.remove(key);
+    }
+
+    /// 使所有缓存失效
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.version += 1;
+            shard.entries.clear();
+            shard.order.clear();
+            shard.used = 0;
+        }
+    }
+
+    /// 命中次数、未命中次数与缓存占用的内存
+    pub fn usage(&self) -> (u64, u64, u64) {
+        let used = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().used)
+            .sum();
+        (
+            self.hits.load(Ordering::SeqCst),
+            self.misses.load(Ordering::SeqCst),
+            used,
+        )
+    }
+}
@@ -0,0 +1,312 @@
+use super::blob::{BlobFiles, BlobPointer};
+use super::kvs::{
+    log_filename, rebuild, status_filename, tree_dir, LogRecord, LogStatus, RecordCodec,
+    RecordStream,
+};
+use super::{Keyring, KvStoreOptions};
+use crate::{KvsError, KvsErrorType, Operation, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// 检查发现问题时的修复方式
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Repair {
+    /// 在最后一条完整的记录之后截断数据文件，并删除多余的数据文件与未被引用的 blob 文件
+    Truncate,
+    /// 截断后重写数据文件，只保留有效的 key，value 无法读取的 key 会被删除
+    Rebuild,
+}
+
+impl FromStr for Repair {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Repair> {
+        match s {
+            "truncate" => Ok(Repair::Truncate),
+            "rebuild" => Ok(Repair::Rebuild),
+            _ => Err(KvsErrorType::InvalidArgument)?,
+        }
+    }
+}
+
+/// KvStore 数据目录的检查结果
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// status.json 或密钥的问题，不为空时不会检查记录的内容，也不会进行修复
+    pub status_error: Option<String>,
+    /// 当前数据文件的 id
+    pub file_id: u64,
+    /// 当前数据文件的大小
+    pub log_bytes: u64,
+    /// 完整读取的记录数
+    pub records: u64,
+    /// 因未提供密钥而只检查了格式与校验和的加密记录数
+    pub unverified: u64,
+    /// 旧版本写入的、没有校验和的记录数
+    pub unchecksummed: u64,
+    /// 无法解析、解密或解压的第一条记录的位置，之后的内容都无法读取
+    pub corrupted_at: Option<u64>,
+    /// 有效的 key 数量
+    pub keys: u64,
+    /// 在数据文件中出现了不止一次的 key 的数量
+    pub duplicate_keys: u64,
+    /// 压缩可以回收的大小
+    pub garbage_bytes: u64,
+    /// value 所在的 blob 无法读取的有效 key
+    pub dangling_blobs: Vec<String>,
+    /// 不是当前数据文件的数据文件，通常是压缩中断后的遗留
+    pub orphan_logs: Vec<PathBuf>,
+    /// 没有被有效 key 引用的 blob 文件
+    pub orphan_blobs: Vec<PathBuf>,
+    /// 已进行的修复
+    pub repaired: Vec<String>,
+    /// 各 keyspace 的检查结果
+    pub keyspaces: BTreeMap<String, CheckReport>,
+}
+
+impl CheckReport {
+    /// 数据目录及其中的 keyspace 是否没有问题
+    pub fn is_clean(&self) -> bool {
+        self.status_error.is_none()
+            && self.corrupted_at.is_none()
+            && self.dangling_blobs.is_empty()
+            && self.orphan_logs.is_empty()
+            && self.orphan_blobs.is_empty()
+            && self.keyspaces.values().all(CheckReport::is_clean)
+    }
+}
+
+/// 在不打开数据库的情况下检查 dir 中的 KvStore 数据
+///
+/// 依次检查 status.json、当前数据文件中的每条记录（格式、校验和、加密记录的认证与压缩数据）、
+/// 有效 key 的 blob，以及多余的数据文件与 blob 文件；数据库加密时需要提供 keyring 才能检查记录的内容
+///
+/// repair 不为空且发现问题时按其修复，返回修复后的检查结果
+pub fn check(dir: &Path, keyring: Option<Keyring>, repair: Option<Repair>) -> Result<CheckReport> {
+    let mut report = inspect(dir, keyring.as_ref())?;
+    if let Some(repair) = repair {
+        if !report.is_clean() && report.status_error.is_none() && report.unverified == 0 {
+            let repaired = fix(dir, &report, keyring.clone(), repair)?;
+            report = inspect(dir, keyring.as_ref())?;
+            report.repaired = repaired;
+        }
+    }
+    if dir.join("trees").exists() {
+        for entry in fs::read_dir(dir.join("trees"))? {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                let tree = check(&tree_dir(dir, name), keyring.clone(), repair)?;
+                report.keyspaces.insert(name.to_owned(), tree);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// 检查 dir 中的数据，不包括 keyspace
+fn inspect(dir: &Path, keyring: Option<&Keyring>) -> Result<CheckReport> {
+    let mut report = CheckReport::default();
+    let status: LogStatus = match fs::read(status_filename(dir)) {
+        Ok(content) => match serde_json::from_slice(&content) {
+            Ok(status) => status,
+            Err(e) => {
+                report.status_error = Some(format!("status.json is invalid: {}", e));
+                return Ok(report);
+            }
+        },
+        Err(e) => {
+            report.status_error = Some(format!("status.json is unreadable: {}", e));
+            return Ok(report);
+        }
+    };
+    report.file_id = status.cur_file_id;
+    let log_path = log_filename(dir, status.cur_file_id);
+    if !log_path.exists() {
+        report.status_error = Some(format!("{} is missing", log_path.display()));
+        return Ok(report);
+    }
+    // 没有数据库所用的密钥时只检查加密记录的格式
+    let keyring = match (&status.key_id, keyring) {
+        (Some(key_id), Some(keyring)) if keyring.get(key_id).is_none() => {
+            report.status_error = Some(format!("key {} is not in the keyring", key_id));
+            None
+        }
+        (Some(_), keyring) => keyring.cloned(),
+        (None, _) => None,
+    };
+    let codec = RecordCodec {
+        compression: status.compression,
+        keyring,
+        legacy: !status.bound,
+        checksummed: status.checksummed,
+    };
+    let blobs = BlobFiles::Dir(dir.join("blobs"));
+
+    let file = File::open(&log_path)?;
+    report.log_bytes = file.metadata()?.len();
+    let mut stream = RecordStream::new(BufReader::new(file));
+    // 有效 key 的最后一条记录的长度与 blob 位置
+    let mut live: HashMap<String, (u64, Option<BlobPointer>)> = HashMap::new();
+    let mut seen = HashMap::new();
+    let mut offset = 0;
+    while let Some(data) = stream.next() {
+        let end = stream.byte_offset();
+        let length = end - offset;
+        // 没有密钥时仍可以校验加密记录的校验和
+        let record = data.and_then(|data| codec.parse(&data));
+        if let Ok(LogRecord::Stamped { crc: None, .. }) = &record {
+            report.unchecksummed += 1;
+        }
+        let record = match record {
+            Ok(LogRecord::Sealed { .. }) | Ok(LogRecord::Stamped { .. })
+                if status.key_id.is_some() && codec.keyring.is_none() =>
+            {
+                report.unverified += 1;
+                offset = end;
+                continue;
+            }
+            Ok(record) => codec.open(record),
+            Err(e) => Err(e),
+        };
+        let key = match record {
+            Ok(LogRecord::Remove { key }) => {
+                report.garbage_bytes += length;
+                if let Some((old, _)) = live.remove(&key) {
+                    report.garbage_bytes += old;
+                }
+                key
+            }
+            Ok(LogRecord::Blob { key, blob }) => {
+                live_insert(&mut live, &mut report, key.clone(), length, Some(blob));
+                key
+            }
+            // 检查 value 能否解压
+            Ok(record) => match codec.resolve(record, &blobs) {
+                Ok(Operation::Set { key, .. }) => {
+                    live_insert(&mut live, &mut report, key.clone(), length, None);
+                    key
+                }
+                _ => {
+                    report.corrupted_at = Some(offset);
+                    break;
+                }
+            },
+            Err(_) => {
+                report.corrupted_at = Some(offset);
+                break;
+            }
+        };
+        *seen.entry(key).or_insert(0u64) += 1;
+        report.records += 1;
+        offset = end;
+    }
+    report.keys = live.len() as u64;
+    report.duplicate_keys = seen.values().filter(|count| **count > 1).count() as u64;
+
+    let mut referenced = BTreeSet::new();
+    for (key, (_, blob)) in &live {
+        if let Some(blob) = blob {
+            referenced.insert(blob.file);
+            let record = LogRecord::Blob {
+                key: key.clone(),
+                blob: *blob,
+            };
+            if codec.keyring.is_some() || status.key_id.is_none() {
+                if codec.resolve(record, &blobs).is_err() {
+                    report.dangling_blobs.push(key.clone());
+                }
+            } else if !blobs.contains(blob.file) {
+                report.dangling_blobs.push(key.clone());
+            }
+        }
+    }
+    report.dangling_blobs.sort();
+    report.orphan_logs = numbered_files(dir, "log")?
+        .into_iter()
+        .filter(|(id, _)| *id != status.cur_file_id)
+        .map(|(_, path)| path)
+        .collect();
+    report.orphan_blobs = numbered_files(&dir.join("blobs"), "blob")?
+        .into_iter()
+        .filter(|(id, _)| !referenced.contains(id))
+        .map(|(_, path)| path)
+        .collect();
+    Ok(report)
+}
+
+/// 记录 key 的最新记录，之前的记录计入垃圾
+fn live_insert(
+    live: &mut HashMap<String, (u64, Option<BlobPointer>)>,
+    report: &mut CheckReport,
+    key: String,
+    length: u64,
+    blob: Option<BlobPointer>,
+) {
+    if let Some((old, _)) = live.insert(key, (length, blob)) {
+        report.garbage_bytes += old;
+    }
+}
+
+/// dir 中以数字为文件名、扩展名为 extension 的文件，按 id 排序
+pub(super) fn numbered_files(dir: &Path, extension: &str) -> Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    if dir.exists() {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension() != Some(extension.as_ref()) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                files.push((id, path));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// 按 repair 修复 report 中的问题，返回所做的修复
+fn fix(
+    dir: &Path,
+    report: &CheckReport,
+    keyring: Option<Keyring>,
+    repair: Repair,
+) -> Result<Vec<String>> {
+    let mut repaired = Vec::new();
+    if let Some(offset) = report.corrupted_at {
+        let log_path = log_filename(dir, report.file_id);
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)?
+            .set_len(offset)?;
+        repaired.push(format!(
+            "truncated {} from {} to {} bytes",
+            log_path.display(),
+            report.log_bytes,
+            offset
+        ));
+    }
+    for path in report.orphan_logs.iter().chain(&report.orphan_blobs) {
+        fs::remove_file(path)?;
+        repaired.push(format!("removed {}", path.display()));
+    }
+    if repair == Repair::Rebuild {
+        let options = KvStoreOptions {
+            keyring,
+            ..KvStoreOptions::default()
+        };
+        rebuild(dir.to_owned(), options, &report.dangling_blobs)?;
+        for key in &report.dangling_blobs {
+            repaired.push(format!("dropped key {} with an unreadable value", key));
+        }
+        repaired.push("rewrote the log".to_owned());
+    }
+    Ok(repaired)
+}
@@ -0,0 +1,68 @@
+use crate::{KvsErrorType, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// zstd 的压缩级别
+const ZSTD_LEVEL: i32 = 3;
+
+/// value 的压缩算法
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// 不压缩
+    #[default]
+    None,
+    /// LZ4，速度快
+    Lz4,
+    /// zstd，压缩率高
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = crate::KvsError;
+
+    fn from_str(s: &str) -> Result<Compression> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(KvsErrorType::InvalidArgument)?,
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+impl Compression {
+    /// 压缩 data
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            Compression::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?),
+        }
+    }
+
+    /// 解压 data，数据无法解压时返回 Corrupted Error
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => {
+                Ok(lz4_flex::decompress_size_prepended(data)
+                    .map_err(|_| KvsErrorType::Corrupted)?)
+            }
+            Compression::Zstd => {
+                Ok(zstd::stream::decode_all(data).map_err(|_| KvsErrorType::Corrupted)?)
+            }
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use super::blob::BlobFiles;
+use super::check::numbered_files;
+use super::kvs::{status_filename, LogRecord, LogStatus, RecordCodec, RecordStream};
+use super::{Compression, Keyring};
+use crate::{Operation, Result};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+/// 数据文件中的一条记录
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DumpRecord {
+    /// 所在数据文件的 id
+    pub file_id: u64,
+    /// 在数据文件中的位置
+    pub offset: u64,
+    /// 序列化后的长度
+    pub length: u64,
+    /// 记录的序号，压缩复制与回收 blob 文件时写入的记录保留原序号，旧版本写入的记录可能没有序号
+    pub seq: Option<u64>,
+    /// 记录的写入时间（Unix 时间戳，毫秒）
+    pub time: Option<u64>,
+    /// 记录的类型：set、compressed、blob、remove，
+    /// 无法解密的加密记录为 sealed，value 无法读取的 blob 记录为 dangling，无法解析或校验和不符的记录为 corrupted
+    pub kind: &'static str,
+    /// 记录的 key，sealed 与 corrupted 记录为空
+    pub key: Option<String>,
+    /// 解压或从 blob 文件中读取后的 value，remove、sealed、dangling 与 corrupted 记录为空
+    pub value: Option<String>,
+}
+
+/// dump 时的过滤条件，为空的条件不过滤
+#[derive(Debug, Clone, Default)]
+pub struct DumpFilter {
+    /// 只输出该数据文件中的记录
+    pub file_id: Option<u64>,
+    /// 只输出该 key 的记录
+    pub key: Option<String>,
+    /// 只输出 key 以此开头的记录
+    pub prefix: Option<String>,
+    /// 只输出位置不小于此值的记录
+    pub from: Option<u64>,
+    /// 只输出位置小于此值的记录
+    pub to: Option<u64>,
+}
+
+impl DumpFilter {
+    /// record 是否满足条件，corrupted 记录只按位置过滤
+    fn matches(&self, record: &DumpRecord) -> bool {
+        if matches!(self.from, Some(from) if record.offset < from) {
+            return false;
+        }
+        if record.kind == "corrupted" {
+            return true;
+        }
+        match &record.key {
+            Some(key) => {
+                self.key.iter().all(|expected| key == expected)
+                    && self.prefix.iter().all(|prefix| key.starts_with(prefix))
+            }
+            None => self.key.is_none() && self.prefix.is_none(),
+        }
+    }
+}
+
+/// 按文件 id 与位置的顺序读取 dir 中所有数据文件（包括压缩遗留的旧文件）的记录，
+/// 将满足 filter 的记录交给 f
+///
+/// 提供 keyring 时解密加密的记录；一个数据文件中出现无法解析的记录时，输出一条 corrupted 记录后跳过该文件的剩余部分
+pub fn dump(
+    dir: &Path,
+    keyring: Option<Keyring>,
+    filter: &DumpFilter,
+    mut f: impl FnMut(DumpRecord) -> Result<()>,
+) -> Result<()> {
+    // 未加密的数据库中的记录不需要解密
+    let status = fs::read(status_filename(dir))
+        .ok()
+        .and_then(|content| serde_json::from_slice::<LogStatus>(&content).ok());
+    let codec = RecordCodec {
+        compression: Compression::None,
+        legacy: !matches!(status, Some(LogStatus { bound: true, .. })),
+        checksummed: matches!(
+            status,
+            Some(LogStatus {
+                checksummed: true,
+                ..
+            })
+        ),
+        keyring: match status {
+            Some(LogStatus { key_id: None, .. }) => None,
+            _ => keyring,
+        },
+    };
+    let blobs = BlobFiles::Dir(dir.join("blobs"));
+    for (file_id, path) in numbered_files(dir, "log")? {
+        if matches!(filter.file_id, Some(id) if id != file_id) {
+            continue;
+        }
+        let mut stream = RecordStream::new(BufReader::new(File::open(path)?));
+        let mut offset = 0;
+        while let Some(data) = stream.next() {
+            if matches!(filter.to, Some(to) if offset >= to) {
+                break;
+            }
+            let end = stream.byte_offset();
+            let mut dumped = DumpRecord {
+                file_id,
+                offset,
+                length: end - offset,
+                seq: None,
+                time: None,
+                kind: "corrupted",
+                key: None,
+                value: None,
+            };
+            let record = data.and_then(|data| Ok((serde_json::from_slice(&data)?, data)));
+            let corrupted = record.is_err();
+            if let Ok((record, data)) = record {
+                describe(&codec, &blobs, record, &data, &mut dumped);
+            }
+            if filter.matches(&dumped) {
+                f(dumped)?;
+            }
+            if corrupted {
+                break;
+            }
+            offset = end;
+        }
+    }
+    Ok(())
+}
+
+/// 按 record 的内容填写 dumped 的类型、key 与 value，data 为 record 的原始字节
+fn describe(
+    codec: &RecordCodec,
+    blobs: &BlobFiles,
+    record: LogRecord,
+    data: &[u8],
+    dumped: &mut DumpRecord,
+) {
+    if codec.verify(&record, data).is_err() {
+        return;
+    }
+    let mut checksum = None;
+    let record = match record {
+        LogRecord::Stamped {
+            seq,
+            time,
+            crc,
+            record,
+        } => {
+            dumped.seq = Some(seq);
+            dumped.time = Some(time);
+            checksum = crc;
+            *record
+        }
+        record => record,
+    };
+    let record = match record {
+        LogRecord::Sealed { .. } if codec.keyring.is_some() => {
+            // 加密时绑定了序号与写入时间，解密时需要一并传入
+            let record = match (dumped.seq, dumped.time) {
+                (Some(seq), Some(time)) => LogRecord::Stamped {
+                    seq,
+                    time,
+                    crc: checksum,
+                    record: Box::new(record),
+                },
+                _ => record,
+            };
+            match codec.open(record) {
+                Ok(record) => record,
+                Err(_) => {
+                    dumped.kind = "sealed";
+                    return;
+                }
+            }
+        }
+        record => record,
+    };
+    let (kind, key) = match &record {
+        LogRecord::Set { key, .. } => ("set", key.clone()),
+        LogRecord::Compressed { key, .. } => ("compressed", key.clone()),
+        LogRecord::Blob { key, .. } => ("blob", key.clone()),
+        LogRecord::Remove { key } => ("remove", key.clone()),
+        LogRecord::Sealed { .. } => {
+            dumped.kind = "sealed";
+            return;
+        }
+        LogRecord::Stamped { .. } => return,
+    };
+    dumped.kind = kind;
+    dumped.key = Some(key);
+    match codec.resolve(record, blobs) {
+        Ok(Operation::Set { value, .. }) => dumped.value = Some(value),
+        Ok(_) => {}
+        Err(_) if kind == "blob" => dumped.kind = "dangling",
+        Err(_) => dumped.kind = "corrupted",
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{KvsErrorType, Result};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use std::fs;
+use std::path::Path;
+
+/// 密钥的长度（AES-256）
+const KEY_LEN: usize = 32;
+
+/// 数据文件的加密密钥
+///
+/// 以 base64 编码的 32 字节密钥保存在密钥文件或环境变量中
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl EncryptionKey {
+    /// 由 32 字节的密钥创建，长度不符时返回 InvalidArgument Error
+    pub fn new(key: &[u8]) -> Result<EncryptionKey> {
+        if key.len() != KEY_LEN {
+            Err(KvsErrorType::InvalidArgument)?
+        }
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| KvsErrorType::InvalidArgument)?;
+        // 以空消息在全零 nonce 下的认证标签作为密钥标识，不泄露密钥本身
+        let tag = cipher
+            .encrypt(Nonce::from_slice(&[0; 12]), &[][..])
+            .map_err(|_| KvsErrorType::Other)?;
+        let id = tag[..4]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Ok(EncryptionKey { id, cipher })
+    }
+
+    /// 生成随机密钥，返回密钥及其 base64 编码
+    pub fn generate() -> (EncryptionKey, String) {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        (EncryptionKey::new(&key).unwrap(), base64::encode(&key))
+    }
+
+    /// 解析 base64 编码的密钥，编码或长度不正确时返回 InvalidArgument Error
+    pub fn from_base64(encoded: &str) -> Result<EncryptionKey> {
+        let key = base64::decode(encoded.trim()).map_err(|_| KvsErrorType::InvalidArgument)?;
+        EncryptionKey::new(&key)
+    }
+
+    /// 从密钥文件读取
+    pub fn from_file(path: &Path) -> Result<EncryptionKey> {
+        EncryptionKey::from_base64(&fs::read_to_string(path)?)
+    }
+
+    /// 按 source 读取密钥：`env:NAME` 表示环境变量 NAME，其他值为密钥文件的路径
+    pub fn load(source: &str) -> Result<EncryptionKey> {
+        if let Some(name) = source.strip_prefix("env:") {
+            match std::env::var(name) {
+                Ok(encoded) => EncryptionKey::from_base64(&encoded),
+                Err(_) => Err(KvsErrorType::InvalidArgument)?,
+            }
+        } else {
+            EncryptionKey::from_file(Path::new(source))
+        }
+    }
+
+    /// 密钥标识，用于在记录中标明加密所用的密钥
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// 当前密钥与轮换前的旧密钥
+///
+/// 新数据总是使用当前密钥加密，旧密钥只用于解密轮换完成前写入的数据
+#[derive(Clone)]
+pub struct Keyring {
+    current: EncryptionKey,
+    previous: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    /// 创建只有当前密钥的 Keyring
+    pub fn new(current: EncryptionKey) -> Keyring {
+        Keyring {
+            current,
+            previous: Vec::new(),
+        }
+    }
+
+    /// 添加一个轮换前的旧密钥
+    pub fn with_previous(mut self, key: EncryptionKey) -> Keyring {
+        self.previous.push(key);
+        self
+    }
+
+    /// 当前密钥
+    pub fn current(&self) -> &EncryptionKey {
+        &self.current
+    }
+
+    /// 标识为 id 的密钥
+    pub fn get(&self, id: &str) -> Option<&EncryptionKey> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|key| key.id == id)
+    }
+
+    /// 使用当前密钥加密，返回随机的 nonce 与密文
+    ///
+    /// aad 为附加数据，不会被加密，但解密时必须提供相同的 aad 才能通过认证
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let data = self
+            .current
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| KvsErrorType::Other)?;
+        Ok((nonce.to_vec(), data))
+    }
+
+    /// 使用标识为 key_id 的密钥解密，aad 为加密时的附加数据
+    ///
+    /// 密钥不存在、数据被篡改或 aad 不符时返回 Corrupted Error
+    pub fn open(&self, key_id: &str, nonce: &[u8], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let key = self.get(key_id).ok_or(KvsErrorType::Corrupted)?;
+        if nonce.len() != 12 {
+            Err(KvsErrorType::Corrupted)?
+        }
+        Ok(key
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+            .map_err(|_| KvsErrorType::Corrupted)?)
+    }
+}
@@ -1,17 +1,43 @@
-use super::KvsEngine;
+use super::blob::{BlobFiles, BlobPointer, BlobStore};
+use super::cache::ValueCache;
+use super::check::numbered_files;
+use super::mmap::MappedLog;
+use super::{archive_file, check_tree_name, Compression, Keyring, KvsEngine, Stats};
+use crate::backup::{BackupManifest, BackupWriter};
 use crate::error::{KvsErrorType, Result};
+use crate::replication::{LogEntry, LogPosition, Replication};
 use crate::Operation;
+use crossbeam::channel::{self, Sender};
+use crossbeam_skiplist::map::Entry;
 use crossbeam_skiplist::SkipMap;
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
 use std::io::SeekFrom;
-use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// 数据库开始压缩的阈值
 const COMPACT_THERASHOLD: u64 = 1024 * 1024;
 
+/// 读缓存的默认容量
+const DEFAULT_CACHE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// 数据文件中未映射的部分超过该大小时重新映射
+const REMAP_THRESHOLD: u64 = 256 * 1024;
+
+/// 每个订阅者最多积压的日志条目数，超过时断开该订阅者
+const SUBSCRIBER_BACKLOG: usize = 16 * 1024;
+
+/// 当前数据文件的内存映射，映射失败或不使用映射时为空
+type SharedMap = Arc<RwLock<Option<Arc<MappedLog>>>>;
+
 /// 数据库中数据在文件中的位置
 struct Offset {
     /// 文件名
@@ -20,46 +46,624 @@ struct Offset {
     offset: u64,
     /// 条目长度
     length: u64,
+    /// value 保存在 blob 文件中时的位置
+    blob: Option<BlobPointer>,
+}
+
+/// 一次写操作
+///
+/// record 为序列化后的记录，在进入写入队列前序列化并压缩，因此并发的写入可以并行地进行；
+/// 加密需要绑定写入时分配的序号，在写入时进行
+enum WriteOp {
+    Set {
+        key: String,
+        value: String,
+        record: String,
+    },
+    Remove {
+        key: String,
+        record: String,
+    },
+}
+
+impl WriteOp {
+    /// 设置 key 的操作
+    fn set(codec: &RecordCodec, key: String, value: String) -> Result<WriteOp> {
+        let record = codec.set(&key, &value)?;
+        Ok(WriteOp::Set { key, value, record })
+    }
+
+    /// 删除 key 的操作
+    fn remove(codec: &RecordCodec, key: String) -> Result<WriteOp> {
+        let record = codec.remove(&key)?;
+        Ok(WriteOp::Remove { key, record })
+    }
+
+    /// 操作的 key
+    fn key(&self) -> &String {
+        match self {
+            WriteOp::Set { key, .. } | WriteOp::Remove { key, .. } => key,
+        }
+    }
+
+    /// 编码后的记录
+    fn record(&self) -> &str {
+        match self {
+            WriteOp::Set { record, .. } | WriteOp::Remove { record, .. } => record,
+        }
+    }
+}
+
+/// 已写入数据文件但尚未更新 index 的写操作
+struct Written {
+    op: WriteOp,
+    /// 记录在数据文件中的偏移量
+    offset: u64,
+    /// 记录长度
+    length: u64,
+    /// value 保存在 blob 文件中时的位置
+    blob: Option<BlobPointer>,
+}
+
+/// 等待写入的操作与接收其结果的 channel
+type WriteQueue = Arc<Mutex<Vec<(WriteOp, Sender<Result<()>>)>>>;
+
+/// KvStore 的参数
+#[derive(Clone, Default)]
+pub struct KvStoreOptions {
+    /// value 的压缩算法，只能在创建数据库时指定
+    ///
+    /// 为空时新建的数据库不压缩，已有的数据库沿用创建时的算法
+    pub compression: Option<Compression>,
+    /// 加密数据文件使用的密钥，为空时不加密
+    ///
+    /// 当前密钥与数据库使用的不同时，需要在旧密钥中提供后者，打开时会进行一次压缩，用当前密钥重新加密所有数据
+    pub keyring: Option<Keyring>,
+    /// value 不小于该长度时单独保存在 blob 文件中，数据文件中只记录其位置，为空时不分离
+    ///
+    /// 压缩数据文件时不需要复制这些 value，blob 文件中的垃圾超过一半时单独回收
+    pub blob_threshold: Option<u64>,
+    /// 读缓存的容量（字节），为空时使用默认容量 16MB，为 0 时不缓存
+    pub cache_size: Option<u64>,
+    /// 为 true 时不使用内存映射，总是通过缓冲读取数据文件
+    pub buffered_reads: bool,
+    /// 归档目录，为空时不归档
+    ///
+    /// 压缩后的旧数据文件与回收的 blob 文件会移入该目录而不是删除，配合备份用于时间点恢复（见 `engines::recover`）；
+    /// keyspace 归档在其中的 `trees/名称` 目录。归档目录只能属于一个数据库，其中已有不早于当前数据文件的数据文件时，
+    /// 打开返回 OptionsMismatch Error；归档时已有同名文件则不会覆盖，而是返回 IOError
+    pub archive: Option<PathBuf>,
 }
 
 /// 数据库状态
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-struct LogStatus {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct LogStatus {
     /// 对应的文件 id，随压缩次数递增
-    cur_file_id: u64,
+    pub(super) cur_file_id: u64,
+    /// 写入 value 时使用的压缩算法，创建数据库时确定
+    #[serde(default)]
+    pub(super) compression: Compression,
+    /// 加密当前数据文件所用密钥的标识，为空表示不加密
+    #[serde(default)]
+    pub(super) key_id: Option<String>,
+    /// 加密记录是否都绑定了附加数据（见 `Placement`），旧版本创建的数据库为 false，
+    /// 此时接受旧格式的加密记录，并在下次压缩时重新加密所有记录
+    #[serde(default)]
+    pub(super) bound: bool,
+    /// 带序号的记录是否都有校验和，旧版本创建的数据库为 false，
+    /// 此时接受没有校验和的记录，并在下次压缩时重写所有记录
+    #[serde(default)]
+    pub(super) checksummed: bool,
+    /// 压缩时最后一条记录的序号，压缩会丢弃删除记录，因此单独保存
+    #[serde(default)]
+    pub(super) last_seq: u64,
 }
 
 impl LogStatus {
     /// 创建一个初始的 LogStatus
-    fn new() -> LogStatus {
-        LogStatus { cur_file_id: 0 }
+    fn new(options: &KvStoreOptions) -> LogStatus {
+        LogStatus {
+            cur_file_id: 0,
+            compression: options.compression.unwrap_or_default(),
+            key_id: options
+                .keyring
+                .as_ref()
+                .map(|keyring| keyring.current().id().to_owned()),
+            bound: true,
+            checksummed: true,
+            last_seq: 0,
+        }
+    }
+}
+
+/// 数据文件中的一条记录
+///
+/// 未压缩的记录与对应的 Operation 格式相同，因此未开启压缩的数据文件仍按原格式读写；
+/// 压缩后的 value 以 base64 编码，codec 为压缩算法；
+/// 加密的记录为其他记录序列化后经 AES-GCM 加密的结果，nonce 与密文以 base64 编码，
+/// bound 为 true 时记录所在的位置作为附加数据参与认证（见 `Placement`）；
+/// blob 记录只保存 value 在 blob 文件中的位置，blob 文件中保存的是对应的设置记录；
+/// 写入的记录（可能已加密）外层带有序号与写入时间，见 `stamp`
+#[derive(Serialize, Deserialize)]
+pub(super) enum LogRecord {
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    Compressed {
+        key: String,
+        codec: Compression,
+        value: String,
+    },
+    Sealed {
+        key_id: String,
+        nonce: String,
+        data: String,
+        #[serde(default)]
+        bound: bool,
+    },
+    Blob {
+        key: String,
+        blob: BlobPointer,
+    },
+    Stamped {
+        seq: u64,
+        time: u64,
+        /// record 序列化结果的 CRC-32，旧版本写入的记录没有
+        #[serde(default)]
+        crc: Option<u32>,
+        record: Box<LogRecord>,
+    },
+}
+
+/// 记录的序号与写入时间
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Stamp {
+    /// 在数据库中从 1 开始递增，压缩复制的记录保留原序号
+    pub(super) seq: u64,
+    /// Unix 时间戳（毫秒）
+    pub(super) time: u64,
+}
+
+/// 加密记录所在的位置，作为附加数据（AAD）参与认证
+///
+/// 数据文件中的记录绑定其序号与写入时间，blob 文件中的记录只能出现在 blob 文件中，
+/// 因此记录无法被移动到其他位置，序号与写入时间也无法被修改；
+/// 序号在数据库中唯一且压缩时保留，因此压缩复制记录时不需要重新加密
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Placement {
+    /// 数据文件中的记录及其序号与写入时间
+    Log(Option<Stamp>),
+    /// blob 文件中的记录
+    Blob,
+}
+
+impl Placement {
+    /// 附加数据
+    fn aad(self) -> String {
+        match self {
+            Placement::Log(Some(stamp)) => format!("kvs-log:{}:{}", stamp.seq, stamp.time),
+            Placement::Log(None) => "kvs-log".to_owned(),
+            Placement::Blob => "kvs-blob".to_owned(),
+        }
+    }
+}
+
+/// 为编码后的记录加上序号、写入时间与校验和
+///
+/// 结果与 `LogRecord::Stamped` 序列化的结果相同，但不需要重新序列化或加密 record，
+/// 因此可以在持有写锁时进行
+fn stamp(stamp: Stamp, record: &str) -> String {
+    format!(
+        "{{\"Stamped\":{{\"seq\":{},\"time\":{},\"crc\":{},\"record\":{}}}}}",
+        stamp.seq,
+        stamp.time,
+        crc32(record.as_bytes()),
+        record
+    )
+}
+
+/// CRC-32（IEEE）的查找表
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// data 的 CRC-32（IEEE）
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        CRC_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// 当前的 Unix 时间戳（毫秒）
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+impl LogRecord {
+    /// 使用 compression 编码设置操作，压缩后没有变小时不压缩
+    fn set(key: &str, value: &str, compression: Compression) -> Result<LogRecord> {
+        if compression != Compression::None {
+            let compressed = base64::encode(&compression.compress(value.as_bytes())?);
+            if compressed.len() < value.len() {
+                return Ok(LogRecord::Compressed {
+                    key: key.to_owned(),
+                    codec: compression,
+                    value: compressed,
+                });
+            }
+        }
+        Ok(LogRecord::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        })
+    }
+
+    /// 按记录中的压缩算法解压，得到对应的 Operation
+    fn into_operation(self) -> Result<Operation> {
+        match self {
+            LogRecord::Set { key, value } => Ok(Operation::Set { key, value }),
+            LogRecord::Remove { key } => Ok(Operation::Remove { key }),
+            LogRecord::Compressed { key, codec, value } => {
+                let data = base64::decode(&value).map_err(|_| KvsErrorType::Corrupted)?;
+                let value = String::from_utf8(codec.decompress(&data)?)
+                    .map_err(|_| KvsErrorType::Corrupted)?;
+                Ok(Operation::Set { key, value })
+            }
+            LogRecord::Sealed { .. } | LogRecord::Blob { .. } | LogRecord::Stamped { .. } => {
+                Err(KvsErrorType::Corrupted)?
+            }
+        }
+    }
+}
+
+/// 数据文件中记录的编码方式：先按 compression 压缩 value，再按 keyring 加密整条记录
+pub(super) struct RecordCodec {
+    pub(super) compression: Compression,
+    pub(super) keyring: Option<Keyring>,
+    /// 是否接受没有绑定附加数据的旧格式加密记录
+    pub(super) legacy: bool,
+    /// 是否要求带序号的记录有校验和
+    pub(super) checksummed: bool,
+}
+
+impl RecordCodec {
+    /// 序列化设置操作，按 compression 压缩 value
+    fn set(&self, key: &str, value: &str) -> Result<String> {
+        Ok(serde_json::to_string(&LogRecord::set(
+            key,
+            value,
+            self.compression,
+        )?)?)
+    }
+
+    /// 序列化删除操作
+    fn remove(&self, key: &str) -> Result<String> {
+        Ok(serde_json::to_string(&LogRecord::Remove {
+            key: key.to_owned(),
+        })?)
+    }
+
+    /// 有密钥时使用当前密钥加密序列化后的记录，并绑定记录所在的位置 placement
+    fn seal(&self, serialized: &str, placement: Placement) -> Result<String> {
+        match &self.keyring {
+            Some(keyring) => {
+                let aad = placement.aad();
+                let (nonce, data) = keyring.seal(serialized.as_bytes(), aad.as_bytes())?;
+                Ok(serde_json::to_string(&LogRecord::Sealed {
+                    key_id: keyring.current().id().to_owned(),
+                    nonce: base64::encode(&nonce),
+                    data: base64::encode(&data),
+                    bound: true,
+                })?)
+            }
+            None => Ok(serialized.to_owned()),
+        }
+    }
+
+    /// 序列化并加密 record，见 `seal`
+    fn seal_record(&self, record: &LogRecord, placement: Placement) -> Result<String> {
+        self.seal(&serde_json::to_string(record)?, placement)
+    }
+
+    /// 加密序列化后的数据文件记录，并加上序号与写入时间
+    fn seal_stamped(&self, serialized: &str, stamped: Stamp) -> Result<String> {
+        let sealed = self.seal(serialized, Placement::Log(Some(stamped)))?;
+        Ok(stamp(stamped, &sealed))
+    }
+
+    /// 去掉序号并解密记录
+    pub(super) fn open(&self, record: LogRecord) -> Result<LogRecord> {
+        Ok(self.open_stamped(record)?.1)
+    }
+
+    /// 解密记录，同时返回记录的序号与写入时间，没有时为空
+    ///
+    /// record 需已通过 `parse` 校验；序号只能出现在最外层，否则返回 Corrupted Error
+    pub(super) fn open_stamped(&self, record: LogRecord) -> Result<(Option<Stamp>, LogRecord)> {
+        let (stamp, record) = match record {
+            LogRecord::Stamped {
+                seq, time, record, ..
+            } => (Some(Stamp { seq, time }), *record),
+            record => (None, record),
+        };
+        match self.unseal(record, Placement::Log(stamp))? {
+            LogRecord::Stamped { .. } => Err(KvsErrorType::Corrupted)?,
+            record => Ok((stamp, record)),
+        }
+    }
+
+    /// 解析 data 中的一条数据文件记录并校验其校验和，见 `verify`
+    pub(super) fn parse(&self, data: &[u8]) -> Result<LogRecord> {
+        let record = serde_json::from_slice(data)?;
+        self.verify(&record, data)?;
+        Ok(record)
+    }
+
+    /// 校验 record 的校验和，data 为其原始字节，不符或缺少校验和时返回 Corrupted Error
+    ///
+    /// 校验和覆盖内层记录的原始字节，因此没有密钥时也可以校验；
+    /// 只有 checksummed 为 false 时才接受没有序号或没有校验和的记录
+    pub(super) fn verify(&self, record: &LogRecord, data: &[u8]) -> Result<()> {
+        let valid = match record {
+            LogRecord::Stamped { crc: Some(crc), .. } => {
+                stamped_record(data).is_some_and(|inner| crc32(inner) == *crc)
+            }
+            _ => !self.checksummed,
+        };
+        if !valid {
+            Err(KvsErrorType::Corrupted)?
+        }
+        Ok(())
+    }
+
+    /// 解密 blob 文件中的记录
+    fn open_blob(&self, record: LogRecord) -> Result<LogRecord> {
+        match self.unseal(record, Placement::Blob)? {
+            LogRecord::Stamped { .. } => Err(KvsErrorType::Corrupted)?,
+            record => Ok(record),
+        }
+    }
+
+    /// 解密位于 placement 的记录
+    ///
+    /// 加密的数据库中只允许出现能通过认证的加密记录，未加密的数据库中不允许出现加密记录，
+    /// 否则视为数据被篡改，返回 Corrupted Error；只有 legacy 为 true 时才接受没有绑定附加数据的记录
+    fn unseal(&self, record: LogRecord, placement: Placement) -> Result<LogRecord> {
+        match (record, &self.keyring) {
+            (
+                LogRecord::Sealed {
+                    key_id,
+                    nonce,
+                    data,
+                    bound,
+                },
+                Some(keyring),
+            ) => {
+                let aad = match (bound, self.legacy) {
+                    (true, _) => placement.aad(),
+                    (false, true) => String::new(),
+                    (false, false) => Err(KvsErrorType::Corrupted)?,
+                };
+                let nonce = base64::decode(&nonce).map_err(|_| KvsErrorType::Corrupted)?;
+                let data = base64::decode(&data).map_err(|_| KvsErrorType::Corrupted)?;
+                let plaintext = keyring.open(&key_id, &nonce, &data, aad.as_bytes())?;
+                match serde_json::from_slice(&plaintext).map_err(|_| KvsErrorType::Corrupted)? {
+                    LogRecord::Sealed { .. } => Err(KvsErrorType::Corrupted)?,
+                    record => Ok(record),
+                }
+            }
+            (LogRecord::Sealed { .. }, None) | (_, Some(_)) => Err(KvsErrorType::Corrupted)?,
+            (record, None) => Ok(record),
+        }
+    }
+
+    /// 将解密后的记录解码为 Operation，value 保存在 blob 文件中时从 blobs 读取
+    ///
+    /// blob 文件中的记录不是对应 key 的设置记录时返回 Corrupted Error
+    pub(super) fn resolve(&self, record: LogRecord, blobs: &BlobFiles) -> Result<Operation> {
+        match record {
+            LogRecord::Blob { key, blob } => {
+                let entry = serde_json::from_slice(&blobs.read(blob)?)
+                    .map_err(|_| KvsErrorType::Corrupted)?;
+                match self.open_blob(entry)?.into_operation()? {
+                    Operation::Set { key: stored, value } if stored == key => {
+                        Ok(Operation::Set { key, value })
+                    }
+                    _ => Err(KvsErrorType::Corrupted)?,
+                }
+            }
+            record => record.into_operation(),
+        }
+    }
+
+    /// 解码 data 中的一条记录
+    fn decode(&self, data: &[u8], blobs: &BlobFiles) -> Result<Operation> {
+        self.resolve(self.open(self.parse(data)?)?, blobs)
+    }
+
+    /// 读取 reader 中的一条完整记录并校验
+    fn read_record(&self, mut reader: impl Read) -> Result<LogRecord> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        self.parse(&data)
+    }
+
+    /// 从 reader 中读取一条记录并解码为 Operation
+    fn read(&self, reader: impl Read, blobs: &BlobFiles) -> Result<Operation> {
+        self.resolve(self.open(self.read_record(reader)?)?, blobs)
+    }
+}
+
+/// data 中带序号的记录的内层记录的原始字节，即 `stamp` 写入的 `"record":` 之后到最外层结尾之前的部分
+///
+/// 序号、写入时间与校验和都是数字，因此第一个 `"record":` 即为该字段
+fn stamped_record(data: &[u8]) -> Option<&[u8]> {
+    const FIELD: &[u8] = b"\"record\":";
+    let start = data.windows(FIELD.len()).position(|w| w == FIELD)? + FIELD.len();
+    let end = data.len().checked_sub(2)?;
+    if start > end || !data.ends_with(b"}}") {
+        return None;
+    }
+    Some(&data[start..end])
+}
+
+/// 逐条读取数据文件中的记录，得到每条记录的原始字节
+///
+/// 记录之间没有分隔符，按 JSON 的括号与字符串边界找到每条记录的结尾，记录之前的空白计入该记录；
+/// 遇到不完整或不是对象的记录时返回 SerdeError Error，之后不再读取
+pub(super) struct RecordStream<R> {
+    reader: R,
+    offset: u64,
+    failed: bool,
+}
+
+impl<R: BufRead> RecordStream<R> {
+    pub(super) fn new(reader: R) -> RecordStream<R> {
+        RecordStream {
+            reader,
+            offset: 0,
+            failed: false,
+        }
+    }
+
+    /// 已读取的字节数，即上一条记录的结尾
+    pub(super) fn byte_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// 读取下一条记录，没有更多记录时返回 None
+    fn read_next(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut data = Vec::new();
+        let (mut depth, mut in_string, mut escaped) = (0u32, false, false);
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                if data.iter().all(u8::is_ascii_whitespace) {
+                    self.offset += data.len() as u64;
+                    return Ok(None);
+                }
+                Err(KvsErrorType::SerdeError)?
+            }
+            let mut used = buf.len();
+            let mut end = false;
+            for (i, &byte) in buf.iter().enumerate() {
+                if in_string {
+                    match byte {
+                        _ if escaped => escaped = false,
+                        b'\\' => escaped = true,
+                        b'"' => in_string = false,
+                        _ => {}
+                    }
+                    continue;
+                }
+                match byte {
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' if depth > 0 => depth -= 1,
+                    b'"' if depth > 0 => in_string = true,
+                    _ if depth == 0 && byte.is_ascii_whitespace() => continue,
+                    _ if depth == 0 => Err(KvsErrorType::SerdeError)?,
+                    _ => {}
+                }
+                if depth == 0 {
+                    used = i + 1;
+                    end = true;
+                    break;
+                }
+            }
+            data.extend_from_slice(&buf[..used]);
+            self.reader.consume(used);
+            if end {
+                self.offset += data.len() as u64;
+                return Ok(Some(data));
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for RecordStream<R> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>>> {
+        if self.failed {
+            return None;
+        }
+        let result = self.read_next();
+        self.failed = result.is_err();
+        result.transpose()
     }
 }
 
 /// 用于向数据库中写内容的对象
 struct KvStoreWriter {
     map: Arc<SkipMap<String, Offset>>,
+    /// 替换 map 中已有项的计数，见 `lookup`
+    replacing: Arc<Replacing>,
+    codec: Arc<RecordCodec>,
     path: Arc<PathBuf>,
     reader: BufReader<File>,
     writer: BufWriter<File>,
     file_len: u64,
     log_status: LogStatus,
     could_be_compacted: u64,
+    subscribers: Vec<Sender<LogEntry>>,
+    blobs: BlobStore,
+    blob_threshold: Option<u64>,
+    cache: Arc<ValueCache>,
+    mapped: SharedMap,
+    /// 上次映射时数据文件的长度
+    remapped_at: u64,
+    buffered_reads: bool,
+    /// 最后一条记录的序号
+    seq: u64,
+    archive: Option<PathBuf>,
+    /// 所属的 keyspace 是否已被删除，见 `KvStore::invalidate`
+    dropped: Arc<AtomicBool>,
 }
 
 /// 根据文件夹路径获取存有数据库状态的文件路径
-fn status_filename(path: &Path) -> PathBuf {
+pub(super) fn status_filename(path: &Path) -> PathBuf {
     path.join(format!("status.json"))
 }
 
+/// keyspace 的数据目录
+pub(super) fn tree_dir(path: &Path, name: &str) -> PathBuf {
+    path.join("trees").join(name)
+}
+
 /// 根据文件夹路径和id获取当前数据文件路径
-fn log_filename(path: &Path, id: u64) -> PathBuf {
+pub(super) fn log_filename(path: &Path, id: u64) -> PathBuf {
     path.join(format!("{}.log", id))
 }
 
 impl KvStoreWriter {
     /// 根据数据文件路径构建一个 writer
-    fn new(path: PathBuf) -> Self {
+    ///
+    /// 已有数据库的压缩算法或密钥与 options 不符时返回 OptionsMismatch Error
+    fn new(path: PathBuf, options: KvStoreOptions) -> Result<Self> {
         let status_file_name = status_filename(&path);
         let mut f: File;
         let status: LogStatus;
@@ -67,7 +671,7 @@ impl KvStoreWriter {
         if !status_file_name.exists() {
             // 不存在则新建
             f = File::create(&status_file_name).unwrap();
-            status = LogStatus::new();
+            status = LogStatus::new(&options);
             let serialized = serde_json::to_string(&status).unwrap();
             f.write_all(serialized.as_bytes()).unwrap();
         } else {
@@ -77,6 +681,39 @@ impl KvStoreWriter {
             f.read_to_string(&mut content).unwrap();
             status = serde_json::from_str(&content).unwrap();
         }
+        match options.compression {
+            Some(compression) if compression != status.compression => {
+                Err(KvsErrorType::OptionsMismatch)?
+            }
+            _ => {}
+        }
+        let known_key = match (&status.key_id, &options.keyring) {
+            (Some(key_id), Some(keyring)) => keyring.get(key_id).is_some(),
+            (None, None) => true,
+            _ => false,
+        };
+        if !known_key {
+            Err(KvsErrorType::OptionsMismatch)?
+        }
+        let codec = Arc::new(RecordCodec {
+            compression: status.compression,
+            keyring: options.keyring,
+            legacy: !status.bound,
+            checksummed: status.checksummed,
+        });
+        let mut blobs = BlobStore::open(path.join("blobs"))?;
+        // 已归档的 blob 文件 id 不能再次使用，否则归档的数据文件会指向新的文件；
+        // 归档中已有不早于当前数据文件的数据文件时，归档目录属于其他数据库，之后的压缩无法归档
+        if let Some(archive) = &options.archive {
+            for (id, _) in numbered_files(&archive.join("blobs"), "blob")? {
+                blobs.reserve(id);
+            }
+            let logs = numbered_files(archive, "log")?;
+            if logs.iter().any(|(id, _)| *id >= status.cur_file_id) {
+                error!("{} belongs to another database", archive.display());
+                Err(KvsErrorType::OptionsMismatch)?
+            }
+        }
         let path = log_filename(&path, status.cur_file_id);
         let log_file = fs::OpenOptions::new()
             .read(true)
@@ -87,42 +724,88 @@ impl KvStoreWriter {
             .unwrap();
         info!("server log path: {}", path.to_str().unwrap());
         // 从文件中读入信息构建 index map
-        let (map, could_be_compacted) = build_map(&path);
+        let (map, could_be_compacted, seq) = build_map(&path, &codec, &mut blobs)?;
+        let last_seq = status.last_seq;
 
-        KvStoreWriter {
+        let mut writer = KvStoreWriter {
             map: Arc::new(map),
+            replacing: Arc::new(Replacing::new()),
+            codec,
             path: Arc::new(path),
             reader: BufReader::new(log_file.try_clone().unwrap()),
             writer: BufWriter::new(log_file.try_clone().unwrap()),
             log_status: status,
             file_len: log_file.metadata().unwrap().len(),
             could_be_compacted,
+            subscribers: Vec::new(),
+            blobs,
+            blob_threshold: options.blob_threshold,
+            cache: Arc::new(ValueCache::new(
+                options.cache_size.unwrap_or(DEFAULT_CACHE_SIZE),
+            )),
+            mapped: Arc::new(RwLock::new(None)),
+            remapped_at: 0,
+            buffered_reads: options.buffered_reads,
+            seq: seq.max(last_seq),
+            archive: options.archive,
+            dropped: Arc::new(AtomicBool::new(false)),
+        };
+        // 更换了密钥或有旧格式的记录，通过压缩用当前密钥重新编码所有数据
+        if writer.rotating() || writer.unbound() || !writer.log_status.checksummed {
+            info!("re-encode data with the current format and key");
+            writer.compact()?;
+        }
+        // 回收上次关闭前未完成回收的 blob 文件
+        writer.collect_blobs()?;
+        writer.remap();
+        Ok(writer)
+    }
+
+    /// 映射当前数据文件已写入的部分，映射失败时之后的读取退回缓冲读取
+    fn remap(&mut self) {
+        self.remapped_at = self.file_len;
+        if self.buffered_reads {
+            return;
+        }
+        let mapped = MappedLog::map(&self.path, self.file_len);
+        *self.mapped.write().unwrap() = mapped.map(Arc::new);
+    }
+
+    /// 当前数据文件是否使用旧密钥加密
+    fn rotating(&self) -> bool {
+        match &self.codec.keyring {
+            Some(keyring) => self.log_status.key_id.as_deref() != Some(keyring.current().id()),
+            None => false,
+        }
+    }
+
+    /// 数据库中是否可能有旧版本写入的、没有绑定附加数据的加密记录
+    fn unbound(&self) -> bool {
+        self.codec.keyring.is_some() && !self.log_status.bound
+    }
+
+    /// 当前日志的末尾位置
+    fn position(&self) -> LogPosition {
+        LogPosition {
+            file_id: self.log_status.cur_file_id,
+            offset: self.file_len,
         }
     }
 
+    /// 将日志条目发送给所有订阅者，并移除已断开或积压过多的订阅者
+    ///
+    /// 被移除的订阅者收完已积压的条目后日志流结束，从节点会从已应用的位置重新请求
+    fn publish(&mut self, entry: LogEntry) {
+        self.subscribers
+            .retain(|subscriber| subscriber.try_send(entry.clone()).is_ok());
+    }
+
     /// 删除 key 及其对应的 value
     ///
     /// 不存在会返回 KeyNotFound Error
     fn remove(&mut self, key: String) -> Result<()> {
-        let op = Operation::remove(&key);
-        let serialized = serde_json::to_string(&op).unwrap();
-        if let Some(old_val) = self.map.get(&key) {
-            self.could_be_compacted += old_val.value().length;
-        }
-        if let Some(_) = self.map.remove(&key) {
-            self.writer.write(serialized.as_bytes())?;
-            self.file_len += serialized.len() as u64;
-            self.writer.flush()?;
-            self.could_be_compacted += serialized.len() as u64;
-        } else {
-            info!("rm failed: key {} doesn't exist.", &key);
-            Err(KvsErrorType::KeyNotFound)?
-        }
-        // 超过阈值则进行压缩
-        if self.could_be_compacted > COMPACT_THERASHOLD {
-            self.compact()?;
-        }
-        Ok(())
+        let op = WriteOp::remove(&self.codec, key)?;
+        self.apply(vec![op]).pop().unwrap()
     }
 
     /// 用于设置一个键值对
@@ -131,27 +814,251 @@ impl KvStoreWriter {
     ///
     /// key 不存在则会创建一个新的键值对
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let op = Operation::set(&key, value);
-        let serialized = serde_json::to_string(&op).unwrap();
+        let op = WriteOp::set(&self.codec, key, value)?;
+        self.apply(vec![op]).pop().unwrap()
+    }
 
-        let len = serialized.len() as u64;
-        self.writer.write(serialized.as_bytes())?;
-        self.writer.flush()?;
+    /// 按顺序执行一批写操作，返回每个操作的结果
+    ///
+    /// 所有记录写入后只 flush 一次，之后再依次更新 index，因此读者不会看到未写入文件的记录；
+    /// 写入文件失败时整批操作都返回该错误，数据文件与序号保持不变
+    fn apply(&mut self, ops: Vec<WriteOp>) -> Vec<Result<()>> {
+        let count = ops.len();
+        let written = match self.write_batch(ops) {
+            Ok(written) => written,
+            Err(e) => return (0..count).map(|_| Err(e.kind().into())).collect(),
+        };
+        let mut results: Vec<Result<()>> = written
+            .into_iter()
+            .map(|written| match written {
+                Some(written) => {
+                    self.index(written);
+                    Ok(())
+                }
+                None => Err(KvsErrorType::KeyNotFound.into()),
+            })
+            .collect();
+        if let Err(e) = self.maintain() {
+            for result in results.iter_mut().filter(|result| result.is_ok()) {
+                *result = Err(e.kind().into());
+            }
+        }
+        results
+    }
+
+    /// 将一批操作的记录写入数据文件，删除不存在的 key 的操作不写入，对应位置为 None
+    fn write_batch(&mut self, ops: Vec<WriteOp>) -> Result<Vec<Option<Written>>> {
+        if self.dropped.load(Ordering::SeqCst) {
+            Err(KvsErrorType::KeyspaceNotFound)?
+        }
+        // 本批中已写入的操作对 key 是否存在的影响
+        let mut exists = HashMap::new();
+        let time = now_millis();
+        let mut seq = self.seq;
+        let mut offset = self.file_len;
+        let mut data = Vec::new();
+        let mut batch = Vec::with_capacity(ops.len());
+        for mut op in ops {
+            let blob = match &mut op {
+                WriteOp::Set { key, value, record } => {
+                    exists.insert(key.clone(), true);
+                    self.separate(key, value, record)?
+                }
+                WriteOp::Remove { key, .. } => {
+                    let found = match exists.get(key) {
+                        Some(found) => *found,
+                        None => self.map.contains_key(key),
+                    };
+                    if !found {
+                        info!("rm failed: key {} doesn't exist.", key);
+                        batch.push(None);
+                        continue;
+                    }
+                    exists.insert(key.clone(), false);
+                    None
+                }
+            };
+            seq += 1;
+            let record = self.codec.seal_stamped(op.record(), Stamp { seq, time })?;
+            data.extend_from_slice(record.as_bytes());
+            let length = record.len() as u64;
+            batch.push(Some(Written {
+                op,
+                offset,
+                length,
+                blob,
+            }));
+            offset += length;
+        }
+        self.write_log(&data)?;
+        self.seq = seq;
+        Ok(batch)
+    }
+
+    /// 将 data 写入当前数据文件的末尾并 flush
+    ///
+    /// 失败时丢弃缓冲区中未写入的数据，并将文件截断回写入前的长度，之后写入的记录位置仍然正确
+    fn write_log(&mut self, data: &[u8]) -> Result<()> {
+        if let Err(e) = self
+            .writer
+            .write_all(data)
+            .and_then(|_| self.writer.flush())
+        {
+            let file = self.writer.get_ref().try_clone()?;
+            let (file, _) = std::mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+            file.set_len(self.file_len)?;
+            Err(e)?
+        }
+        Ok(())
+    }
+
+    /// value 不小于 blob_threshold 时将设置记录写入 blob 文件，并替换为记录其位置的 blob 记录
+    fn separate(
+        &mut self,
+        key: &str,
+        value: &str,
+        record: &mut String,
+    ) -> Result<Option<BlobPointer>> {
+        match self.blob_threshold {
+            Some(threshold) if value.len() as u64 >= threshold => {
+                let entry = self.codec.seal(record, Placement::Blob)?;
+                let blob = self.blobs.append(entry.as_bytes())?;
+                *record = serde_json::to_string(&LogRecord::Blob {
+                    key: key.to_owned(),
+                    blob,
+                })?;
+                Ok(Some(blob))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 根据已写入的记录更新 index 与读缓存，并发送给订阅者
+    fn index(&mut self, written: Written) {
+        let Written {
+            op,
+            offset,
+            length,
+            blob,
+        } = written;
+        let key = op.key().clone();
         if let Some(old_val) = self.map.get(&key) {
             self.could_be_compacted += old_val.value().length;
+            if let Some(blob) = old_val.value().blob {
+                self.blobs.release(blob);
+            }
+        }
+        let op = match op {
+            WriteOp::Set { key, value, .. } => {
+                let offset = Offset {
+                    path: (*self.path).clone(),
+                    offset,
+                    length,
+                    blob,
+                };
+                self.replace(key.clone(), offset);
+                Operation::set(&key, value)
+            }
+            WriteOp::Remove { key, .. } => {
+                self.map.remove(&key);
+                self.could_be_compacted += length;
+                Operation::remove(&key)
+            }
+        };
+        self.cache.invalidate(&key);
+        self.file_len = offset + length;
+        if !self.subscribers.is_empty() {
+            let position = self.position();
+            self.publish(LogEntry::Record { position, op });
+        }
+    }
+
+    /// 写入后的维护：重新映射数据文件、超过阈值时压缩，并回收 blob 文件
+    fn maintain(&mut self) -> Result<()> {
+        if self.file_len - self.remapped_at >= REMAP_THRESHOLD {
+            self.remap();
         }
-        self.map.insert(
-            key,
-            Offset {
-                path: (*self.path).clone(),
-                offset: self.file_len,
-                length: len,
-            },
-        );
-        self.file_len += len;
         if self.could_be_compacted > COMPACT_THERASHOLD {
             self.compact()?;
         }
+        self.collect_blobs()
+    }
+
+    /// 写入一条设置 key 的记录并更新 index
+    fn append(&mut self, key: String, serialized: &str, blob: Option<BlobPointer>) -> Result<()> {
+        let len = serialized.len() as u64;
+        self.write_log(serialized.as_bytes())?;
+        if let Some(old_val) = self.map.get(&key) {
+            self.could_be_compacted += old_val.value().length;
+            if let Some(blob) = old_val.value().blob {
+                self.blobs.release(blob);
+            }
+        }
+        let offset = Offset {
+            path: (*self.path).clone(),
+            offset: self.file_len,
+            length: len,
+            blob,
+        };
+        self.replace(key, offset);
+        self.file_len += len;
+        Ok(())
+    }
+
+    /// 写入或替换 map 中的项，替换前后各将 key 所在的计数加一
+    fn replace(&self, key: String, offset: Offset) {
+        let _guard = self.replacing.begin(&key);
+        self.map.insert(key, offset);
+    }
+
+    /// 回收 blob 文件
+    ///
+    /// 将文件中仍被引用的 value 复制到当前 blob 文件，在数据文件中记录新的位置后删除该文件；
+    /// value 没有变化，因此不发送给订阅者。新记录与压缩复制的记录一样保留原记录的序号与写入时间，
+    /// 原记录没有序号时分配新的序号
+    fn collect_blobs(&mut self) -> Result<()> {
+        for file in self.blobs.collectable() {
+            let moved: Vec<_> = self
+                .map
+                .iter()
+                .filter_map(|entry| match entry.value().blob {
+                    Some(blob) if blob.file == file => Some((
+                        entry.key().clone(),
+                        blob,
+                        entry.value().offset,
+                        entry.value().length,
+                    )),
+                    _ => None,
+                })
+                .collect();
+            for (key, old, offset, length) in moved {
+                // 读取原记录的序号与写入时间
+                self.reader.seek(SeekFrom::Start(offset))?;
+                let content = self.reader.by_ref().take(length);
+                let (stamp, _) = self.codec.open_stamped(self.codec.read_record(content)?)?;
+                let stamp = match stamp {
+                    Some(stamp) => stamp,
+                    None => {
+                        self.seq += 1;
+                        Stamp {
+                            seq: self.seq,
+                            time: now_millis(),
+                        }
+                    }
+                };
+                let data = self.blobs.read(old)?;
+                let blob = self.blobs.append(&data)?;
+                let record = LogRecord::Blob {
+                    key: key.clone(),
+                    blob,
+                };
+                let serialized = self
+                    .codec
+                    .seal_stamped(&serde_json::to_string(&record)?, stamp)?;
+                self.append(key, &serialized, Some(blob))?;
+            }
+            self.blobs.remove(file, self.archive.as_deref())?;
+        }
         Ok(())
     }
 
@@ -162,7 +1069,14 @@ impl KvStoreWriter {
     /// 为识别不同版本 数据文件，会使得数据文件 id 增加
     ///
     /// 压缩主要工作是如果最后一次是 set 操作则删除之前所有该 key 的操作，如果是 remove 则删除所有该 key 的操作
+    ///
+    /// 更换了密钥或数据库中还有旧格式的加密记录时，解密每条记录并用当前密钥重新加密
+    ///
+    /// 保存在 blob 文件中的 value 不会被复制，只在更换密钥时用当前密钥重新写入
+    ///
+    /// 压缩后读缓存全部失效，开启归档时旧文件移入归档目录
     fn compact(&mut self) -> Result<()> {
+        let reseal = self.rotating() || self.unbound() || !self.log_status.checksummed;
         let mut parent_path = (*self.path).clone();
         parent_path.pop();
         // 创建新文件
@@ -180,48 +1094,129 @@ impl KvStoreWriter {
         for v in (*self.map).iter() {
             self.reader.seek(SeekFrom::Start(v.value().offset))?;
             let mut content = self.reader.by_ref().take(v.value().length);
-            let length = std::io::copy(&mut content, &mut writer)?;
+            let (length, blob) = if reseal {
+                let (stamped, record) = self
+                    .codec
+                    .open_stamped(self.codec.read_record(&mut content)?)?;
+                let record = match record {
+                    LogRecord::Blob { key, blob } => {
+                        let entry = serde_json::from_slice(&self.blobs.read(blob)?)?;
+                        let entry = self
+                            .codec
+                            .seal_record(&self.codec.open_blob(entry)?, Placement::Blob)?;
+                        self.blobs.release(blob);
+                        let blob = self.blobs.append(entry.as_bytes())?;
+                        LogRecord::Blob { key, blob }
+                    }
+                    record => record,
+                };
+                let blob = match record {
+                    LogRecord::Blob { blob, .. } => Some(blob),
+                    _ => None,
+                };
+                // 没有序号的旧记录分配新的序号，压缩后所有记录都带有校验和
+                let stamped = match stamped {
+                    Some(stamped) => stamped,
+                    None => {
+                        self.seq += 1;
+                        Stamp {
+                            seq: self.seq,
+                            time: now_millis(),
+                        }
+                    }
+                };
+                let serialized = self
+                    .codec
+                    .seal_stamped(&serde_json::to_string(&record)?, stamped)?;
+                writer.write_all(serialized.as_bytes())?;
+                (serialized.len() as u64, blob)
+            } else {
+                (std::io::copy(&mut content, &mut writer)?, v.value().blob)
+            };
 
-            self.map.insert(
-                v.key().clone(),
-                Offset {
-                    path: new_log_file_path.clone(),
-                    offset,
-                    length,
-                },
-            );
+            let new_offset = Offset {
+                path: new_log_file_path.clone(),
+                offset,
+                length,
+                blob,
+            };
+            self.replace(v.key().clone(), new_offset);
             offset += length;
         }
         writer.flush()?;
+        self.cache.clear();
 
         // 更新 status
         let mut status_f = File::create(&status_filename(&parent_path))?;
         self.log_status.cur_file_id += 1;
+        self.log_status.last_seq = self.seq;
+        self.log_status.bound = true;
+        self.log_status.checksummed = true;
+        if let Some(keyring) = &self.codec.keyring {
+            self.log_status.key_id = Some(keyring.current().id().to_owned());
+        }
         let serialized = serde_json::to_string(&self.log_status).unwrap();
         status_f.write_all(serialized.as_bytes())?;
         self.writer = writer;
         let reader = BufReader::new(new_log_file.try_clone()?);
         self.reader = reader;
+        self.path = Arc::new(new_log_file_path);
+        self.file_len = offset;
         self.could_be_compacted = 0;
-        // 删除旧文件
-        fs::remove_file(log_filename(&parent_path, self.log_status.cur_file_id - 1))?;
+        self.remap();
+        // 删除或归档旧文件
+        let old_path = log_filename(&parent_path, self.log_status.cur_file_id - 1);
+        match &self.archive {
+            Some(archive) => archive_file(&old_path, archive)?,
+            None => fs::remove_file(old_path)?,
+        }
+        let position = self.position();
+        self.publish(LogEntry::Compacted { position });
         Ok(())
     }
 }
 
 /// 以 KvStore 为核心的引擎
+///
+/// 每个 keyspace 是数据目录下 trees 目录中的一个独立的 KvStore
 pub struct KvStore {
     path: Arc<PathBuf>,
+    /// 数据目录
+    dir: Arc<PathBuf>,
+    options: Arc<KvStoreOptions>,
+    /// 已打开的 keyspace，删除 keyspace 时通过它使已打开的实例失效
+    trees: Arc<Mutex<BTreeMap<String, KvStore>>>,
+    /// 是否为 keyspace
+    keyspace: bool,
+    /// 是否已作为 keyspace 被删除
+    dropped: Arc<AtomicBool>,
     writer: Arc<Mutex<KvStoreWriter>>,
+    queue: WriteQueue,
     map: Arc<SkipMap<String, Offset>>,
+    replacing: Arc<Replacing>,
+    codec: Arc<RecordCodec>,
+    blobs: Arc<BlobFiles>,
+    cache: Arc<ValueCache>,
+    mapped: SharedMap,
 }
 
 impl Clone for KvStore {
     fn clone(&self) -> KvStore {
         KvStore {
             path: Arc::clone(&self.path),
+            dir: Arc::clone(&self.dir),
+            options: Arc::clone(&self.options),
+            trees: Arc::clone(&self.trees),
+            keyspace: self.keyspace,
+            dropped: Arc::clone(&self.dropped),
             writer: Arc::clone(&self.writer),
+            queue: Arc::clone(&self.queue),
             map: Arc::clone(&self.map),
+            replacing: Arc::clone(&self.replacing),
+            codec: Arc::clone(&self.codec),
+            blobs: Arc::clone(&self.blobs),
+            cache: Arc::clone(&self.cache),
+            mapped: Arc::clone(&self.mapped),
         }
     }
 }
@@ -233,35 +1228,15 @@ impl KvsEngine for KvStore {
     ///
     /// key 不存在则会创建一个新的键值对
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)?;
-        Ok(())
+        self.write(WriteOp::set(&self.codec, key, value)?)
     }
 
     /// 获取 key 所对应的 value
     ///
     /// 不存在会返回 None
     fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(offset) = self.map.get(&key) {
-            let file = match File::open(&offset.value().path) {
-                Ok(file) => file,
-                Err(_) => {
-                    if let Some(offset) = self.map.get(&key) {
-                        File::open(&offset.value().path)?
-                    } else {
-                        return Ok(None);
-                    }
-                }
-            };
-            let mut reader = BufReader::new(file);
-            reader.seek(SeekFrom::Start(offset.value().offset))?;
-            let op_reader = reader.by_ref().take(offset.value().length);
-            if let Operation::Set { key: _, value } = serde_json::from_reader(op_reader).unwrap() {
-                return Ok(Some(value));
-            } else {
-                Err(KvsErrorType::SerdeError)?
-            }
-        }
-        Ok(None)
+        self.check_dropped()?;
+        self.read(&key)
     }
 
     /// 删除 key 及其对应的 value
@@ -269,64 +1244,598 @@ impl KvsEngine for KvStore {
     /// 不存在会返回 KeyNotFound Error
     fn remove(&self, key: String) -> Result<()> {
         info!("rm key {}", &key);
-        self.writer.lock().unwrap().remove(key)?;
-        Ok(())
+        self.write(WriteOp::remove(&self.codec, key)?)
+    }
+
+    /// 比较并交换
+    ///
+    /// 比较与写入都在持有写锁时进行
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.check_dropped()?;
+        let mut writer = self.writer.lock().unwrap();
+        if self.read(&key)? != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => writer.set(key, value)?,
+            None if expected.is_some() => writer.remove(key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// 按 key 从小到大的顺序获取以 prefix 开头的键值对
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.check_dropped()?;
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix.clone()),
+        };
+        let mut pairs = Vec::new();
+        for entry in self.map.range((start, Bound::Unbounded)) {
+            if pairs.len() >= limit || !entry.key().starts_with(&prefix) {
+                break;
+            }
+            // 读取期间 key 可能已被删除
+            if let Some(value) = self.read(entry.key())? {
+                pairs.push((entry.key().clone(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// 获取统计信息
+    ///
+    /// 包括 key 的数量、当前数据文件的 id 与大小、可被压缩的大小、最后一条记录的序号、blob 文件的数量、大小与其中的垃圾大小，
+    /// 读缓存的命中次数、未命中次数与占用的内存，数据文件中已映射的大小，以及 keyspace 的数量
+    ///
+    /// 以上都不包括 keyspace 中的数据，keyspace 的统计信息需通过其实例获取
+    fn stats(&self) -> Result<Stats> {
+        self.check_dropped()?;
+        let writer = self.writer.lock().unwrap();
+        let mut stats = Stats::new();
+        stats.insert("keys".to_owned(), self.map.len() as u64);
+        stats.insert("file_id".to_owned(), writer.log_status.cur_file_id);
+        stats.insert("log_bytes".to_owned(), writer.file_len);
+        stats.insert("garbage_bytes".to_owned(), writer.could_be_compacted);
+        stats.insert("seq".to_owned(), writer.seq);
+        let (files, bytes, garbage) = writer.blobs.usage();
+        stats.insert("blob_files".to_owned(), files);
+        stats.insert("blob_bytes".to_owned(), bytes);
+        stats.insert("blob_garbage_bytes".to_owned(), garbage);
+        let (hits, misses, bytes) = self.cache.usage();
+        stats.insert("cache_hits".to_owned(), hits);
+        stats.insert("cache_misses".to_owned(), misses);
+        stats.insert("cache_bytes".to_owned(), bytes);
+        let mapped = self.mapped.read().unwrap();
+        stats.insert(
+            "mapped_bytes".to_owned(),
+            mapped.as_ref().map_or(0, |mapped| mapped.end()),
+        );
+        stats.insert("keyspaces".to_owned(), self.tree_names()?.len() as u64);
+        Ok(stats)
     }
 
     /// 获取 engine 的类型 (kvs)
     fn get_type(&self) -> String {
         String::from("kvs")
     }
+
+    /// 备份当前数据及所有 keyspace
+    ///
+    /// 每个 keyspace 各自是一致的快照，见 `backup_pairs`；
+    /// 清单中记录默认 keyspace 快照的最后一个序号，用于时间点恢复
+    fn backup(&self, path: &Path) -> Result<BackupManifest> {
+        self.check_dropped()?;
+        let mut backup = BackupWriter::create(path)?;
+        let seq = self.backup_pairs(&mut backup)?;
+        for name in self.tree_names()? {
+            // 列出后被删除的 keyspace 不再备份
+            if let Some(tree) = self.existing_tree(&name)? {
+                backup.keyspace(&name)?;
+                tree.backup_pairs(&mut backup)?;
+            }
+        }
+        backup.at_seq(seq).finish(&self.get_type())
+    }
+
+    /// 订阅从 position 开始的日志
+    ///
+    /// 只在记录当前日志位置、打开数据文件与 blob 文件并注册订阅时持有写锁，保证不会遗漏或重复记录；
+    /// 已有的日志在之后发送时才从已打开的文件中逐条读取，不会阻塞写入，也不会整体读入内存
+    ///
+    /// 默认 keyspace 的日志流以所有 keyspace 的名称开始，keyspace 被创建或删除时再次发送；
+    /// 各 keyspace 的日志需通过其实例单独订阅
+    fn replicate(&self, position: Option<LogPosition>) -> Result<Replication> {
+        self.check_dropped()?;
+        let (current, mut file, blobs, receiver, names) = {
+            let mut writer = self.writer.lock().unwrap();
+            let (sender, receiver) = channel::bounded(SUBSCRIBER_BACKLOG);
+            writer.subscribers.push(sender);
+            (
+                writer.position(),
+                File::open(&*writer.path)?,
+                writer.blobs.snapshot()?,
+                receiver,
+                // 持有写锁时读取，之后创建或删除的 keyspace 都会发送给该订阅者
+                match self.keyspace {
+                    false => Some(self.tree_names()?),
+                    true => None,
+                },
+            )
+        };
+        // 位置属于已被压缩的文件时发送快照
+        let (snapshot, start) = match position {
+            Some(p) if p.file_id == current.file_id && p.offset <= current.offset => {
+                (false, p.offset)
+            }
+            _ => (true, 0),
+        };
+        file.seek(SeekFrom::Start(start))?;
+        let reader = BufReader::new(file).take(current.offset - start);
+        let mut stream = RecordStream::new(reader);
+        let codec = Arc::clone(&self.codec);
+        let records = std::iter::from_fn(move || loop {
+            let data = stream.next()?;
+            let position = LogPosition {
+                file_id: current.file_id,
+                offset: start + stream.byte_offset(),
+            };
+            let record = match data.and_then(|data| codec.open(codec.parse(&data)?)) {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            };
+            // blob 文件已被回收的记录一定会被之后的记录覆盖
+            if matches!(&record, LogRecord::Blob { blob, .. } if !blobs.contains(blob.file)) {
+                continue;
+            }
+            let entry = codec
+                .resolve(record, &blobs)
+                .map(|op| LogEntry::Record { position, op });
+            return Some(entry);
+        });
+        let keyspaces = names.map(|names| Ok(LogEntry::Keyspaces { names }));
+        let begin = snapshot.then(|| Ok(LogEntry::Snapshot));
+        let end = snapshot.then(|| Ok(LogEntry::SnapshotEnd { position: current }));
+        let backlog = keyspaces.into_iter().chain(begin).chain(records).chain(end);
+        Ok(Replication::new(backlog, receiver))
+    }
+
+    /// 打开名为 name 的 keyspace，不存在时创建
+    ///
+    /// keyspace 是 trees 目录下的独立 KvStore，使用相同的参数，有各自的数据文件、index、压缩与归档目录；
+    /// 同一名称只会打开一次，之后返回同一个实例
+    fn open_tree(&self, name: &str) -> Result<KvStore> {
+        self.check_dropped()?;
+        check_tree_name(name)?;
+        if self.keyspace {
+            Err(KvsErrorType::InvalidKeyspace)?
+        }
+        let mut trees = self.trees.lock().unwrap();
+        self.open_tree_locked(&mut trees, name)
+    }
+
+    /// 删除名为 name 的 keyspace 及其数据目录
+    ///
+    /// 已打开的实例会先失效，之后通过它进行的操作返回 KeyspaceNotFound Error；
+    /// 开启归档时，keyspace 的归档目录重命名为 `<name>.<删除时间>`，同名的新 keyspace 使用新的归档目录
+    fn drop_tree(&self, name: &str) -> Result<bool> {
+        self.check_dropped()?;
+        check_tree_name(name)?;
+        if self.keyspace {
+            Err(KvsErrorType::InvalidKeyspace)?
+        }
+        let mut trees = self.trees.lock().unwrap();
+        if let Some(tree) = trees.remove(name) {
+            tree.invalidate();
+        }
+        let dir = tree_dir(&self.dir, name);
+        if !dir.exists() {
+            return Ok(false);
+        }
+        info!("drop keyspace {}", name);
+        fs::remove_dir_all(dir)?;
+        if let Some(archive) = &self.options.archive {
+            let archived = tree_dir(archive, name);
+            if archived.exists() {
+                let name = format!("{}.{}", name, now_millis());
+                fs::rename(&archived, archived.with_file_name(name))?;
+            }
+        }
+        self.publish_trees()?;
+        Ok(true)
+    }
+
+    /// 按名称排序的所有 keyspace
+    fn tree_names(&self) -> Result<Vec<String>> {
+        self.check_dropped()?;
+        let dir = self.dir.join("trees");
+        let mut names = Vec::new();
+        if dir.exists() {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    names.extend(entry.file_name().to_str().map(str::to_owned));
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
 }
 
 impl KvStore {
     /// 构造函数，用来创建一个存储位置为 path 的 KvStore
+    ///
+    /// 新建的数据库不压缩 value，已有的数据库沿用创建时的压缩算法
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// 以 options 创建一个存储位置为 path 的 KvStore
+    ///
+    /// 已有数据库的压缩算法与 options 不符、数据库已加密而未提供其密钥、
+    /// 或数据库未加密而提供了密钥时返回 OptionsMismatch Error
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path = path.into();
         std::fs::create_dir_all(&path).unwrap();
-        let writer = KvStoreWriter::new(path);
+        let writer = KvStoreWriter::new(path.clone(), options.clone())?;
         Ok(KvStore {
             path: Arc::clone(&writer.path),
+            dir: Arc::new(path),
+            options: Arc::new(options),
+            trees: Arc::new(Mutex::new(BTreeMap::new())),
+            keyspace: false,
+            dropped: Arc::clone(&writer.dropped),
             map: Arc::clone(&writer.map),
+            replacing: Arc::clone(&writer.replacing),
+            codec: Arc::clone(&writer.codec),
+            blobs: Arc::new(writer.blobs.reader()),
+            cache: Arc::clone(&writer.cache),
+            mapped: Arc::clone(&writer.mapped),
             writer: Arc::new(Mutex::new(writer)),
+            queue: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// 作为 keyspace 被删除后返回 KeyspaceNotFound Error
+    fn check_dropped(&self) -> Result<()> {
+        if self.dropped.load(Ordering::SeqCst) {
+            Err(KvsErrorType::KeyspaceNotFound)?
+        }
+        Ok(())
+    }
+
+    /// 使作为 keyspace 打开的实例失效
+    ///
+    /// 持有写锁时设置标记，正在进行的写入完成后不再接受新的写入；同时断开所有订阅者，使日志流结束
+    fn invalidate(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        self.dropped.store(true, Ordering::SeqCst);
+        writer.subscribers.clear();
+    }
+
+    /// 将当前所有 keyspace 的名称发送给订阅者
+    fn publish_trees(&self) -> Result<()> {
+        let names = self.tree_names()?;
+        self.writer
+            .lock()
+            .unwrap()
+            .publish(LogEntry::Keyspaces { names });
+        Ok(())
+    }
+
+    /// 将当前数据写入 backup，返回快照的最后一个序号
+    ///
+    /// 只在打开当前数据文件并记录其长度时持有写锁；数据文件只会追加，
+    /// 压缩产生新文件后旧文件仍可通过已打开的句柄读取，因此该长度之前的内容即为一致的快照；
+    /// blob 文件同样在持有写锁时打开
+    fn backup_pairs(&self, backup: &mut BackupWriter) -> Result<u64> {
+        self.check_dropped()?;
+        let (file, len, blobs, seq) = {
+            let writer = self.writer.lock().unwrap();
+            (
+                File::open(&*writer.path)?,
+                writer.file_len,
+                writer.blobs.snapshot()?,
+                writer.seq,
+            )
+        };
+        // 重放快照，得到每个 key 最后一次写入在文件中的位置
+        let mut index = BTreeMap::new();
+        let reader = BufReader::new(file.try_clone()?.take(len));
+        let mut stream = RecordStream::new(reader);
+        let mut offset = 0;
+        while let Some(data) = stream.next() {
+            let end_offset = stream.byte_offset();
+            match self.codec.open(self.codec.parse(&data?)?)? {
+                LogRecord::Set { key, .. }
+                | LogRecord::Compressed { key, .. }
+                | LogRecord::Blob { key, .. } => {
+                    index.insert(key, (offset, end_offset - offset));
+                }
+                LogRecord::Remove { key } => {
+                    index.remove(&key);
+                }
+                LogRecord::Sealed { .. } | LogRecord::Stamped { .. } => {
+                    Err(KvsErrorType::Corrupted)?
+                }
+            }
+            offset = end_offset;
+        }
+
+        let mut reader = BufReader::new(file);
+        for (key, (offset, length)) in index {
+            reader.seek(SeekFrom::Start(offset))?;
+            match self.codec.read(reader.by_ref().take(length), &blobs)? {
+                Operation::Set { value, .. } => backup.add(key, value)?,
+                _ => Err(KvsErrorType::SerdeError)?,
+            }
+        }
+        Ok(seq)
+    }
+
+    /// 打开已存在的名为 name 的 keyspace，不存在时返回 None
+    fn existing_tree(&self, name: &str) -> Result<Option<KvStore>> {
+        let mut trees = self.trees.lock().unwrap();
+        if !trees.contains_key(name) && !tree_dir(&self.dir, name).exists() {
+            return Ok(None);
+        }
+        self.open_tree_locked(&mut trees, name).map(Some)
+    }
+
+    /// 持有 keyspace 表的锁时打开名为 name 的 keyspace，不存在时创建
+    fn open_tree_locked(
+        &self,
+        trees: &mut BTreeMap<String, KvStore>,
+        name: &str,
+    ) -> Result<KvStore> {
+        if let Some(tree) = trees.get(name) {
+            return Ok(tree.clone());
+        }
+        let dir = tree_dir(&self.dir, name);
+        let created = !dir.exists();
+        let mut options = (*self.options).clone();
+        options.archive = options.archive.map(|archive| tree_dir(&archive, name));
+        let mut tree = KvStore::open_with(dir, options)?;
+        tree.keyspace = true;
+        trees.insert(name.to_owned(), tree.clone());
+        if created {
+            self.publish_trees()?;
+        }
+        Ok(tree)
+    }
+
+    /// 提交一次写操作并等待其结果
+    ///
+    /// 操作先放入队列，放入时队列为空的线程成为 leader，取得写锁后将队列中的所有操作作为一批写入，
+    /// 只需 flush 一次；其余线程不争抢写锁，只等待 leader 发回结果，
+    /// 因此 leader 持有写锁期间提交的操作都会合并到下一批
+    fn write(&self, op: WriteOp) -> Result<()> {
+        let (sender, receiver) = channel::bounded(1);
+        let leader = {
+            let mut queue = self.queue.lock().unwrap();
+            queue.push((op, sender));
+            queue.len() == 1
+        };
+        if leader {
+            let mut writer = self.writer.lock().unwrap();
+            // 之前的 leader 可能已经取走了该操作，此时队列可能为空
+            let (ops, senders): (Vec<_>, Vec<_>) = std::mem::take(&mut *self.queue.lock().unwrap())
+                .into_iter()
+                .unzip();
+            if !ops.is_empty() {
+                for (sender, result) in senders.into_iter().zip(writer.apply(ops)) {
+                    sender.send(result).ok();
+                }
+            }
+        }
+        receiver.recv().unwrap()
+    }
+
+    /// 读取 key 对应的 value，优先从读缓存中获取
+    fn read(&self, key: &String) -> Result<Option<String>> {
+        self.cache.get_or_load(key, || {
+            read_value(
+                &self.map,
+                &self.replacing,
+                &self.codec,
+                &self.blobs,
+                &self.mapped,
+                key,
+            )
         })
     }
 }
 
-/// 从路径为 path 的数据文件中构造 index map，同时计算可压缩的大小
-fn build_map(path: &PathBuf) -> (SkipMap<String, Offset>, u64) {
+/// 替换计数的分段数
+const REPLACING_STRIPES: usize = 64;
+
+/// 按 key 分段的替换计数，写入方替换前后各将 key 所在分段加一，奇数表示替换正在进行
+struct Replacing {
+    stripes: Vec<AtomicU64>,
+}
+
+/// 替换结束时将计数加一，写入方 panic 时也会执行，计数不会停留在奇数
+struct ReplaceGuard<'a>(&'a AtomicU64);
+
+impl Drop for ReplaceGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Replacing {
+    fn new() -> Replacing {
+        Replacing {
+            stripes: (0..REPLACING_STRIPES).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// key 所在分段的计数
+    fn stripe(&self, key: &str) -> &AtomicU64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.stripes[hasher.finish() as usize % REPLACING_STRIPES]
+    }
+
+    /// 开始替换 key，返回的 guard 被丢弃时替换结束
+    fn begin(&self, key: &str) -> ReplaceGuard<'_> {
+        let stripe = self.stripe(key);
+        stripe.fetch_add(1, Ordering::SeqCst);
+        ReplaceGuard(stripe)
+    }
+}
+
+/// 读不到 key 时的最大重试次数
+const MAX_LOOKUP_RETRIES: usize = 1000;
+
+/// 获取 map 中 key 对应的项
+///
+/// SkipMap 替换已有的项时先标记删除旧项再插入新项，期间并发的读取可能读不到该 key；
+/// 读不到 key 时若 key 所在分段的替换正在进行或已发生则重试，
+/// 只有同一分段被持续替换时才会一直重试，超过次数后按不存在处理
+fn lookup<'a>(
+    map: &'a SkipMap<String, Offset>,
+    replacing: &Replacing,
+    key: &String,
+) -> Option<Entry<'a, String, Offset>> {
+    let stripe = replacing.stripe(key);
+    for _ in 0..MAX_LOOKUP_RETRIES {
+        let before = stripe.load(Ordering::SeqCst);
+        if let Some(entry) = map.get(key) {
+            return Some(entry);
+        }
+        if before.is_multiple_of(2) && stripe.load(Ordering::SeqCst) == before {
+            return None;
+        }
+        std::thread::yield_now();
+    }
+    map.get(key)
+}
+
+/// 从数据文件中读取 key 对应的 value
+///
+/// 读取期间数据文件或 blob 文件可能因压缩或回收被删除，此时 map 中已是新的位置，重试一次
+fn read_value(
+    map: &SkipMap<String, Offset>,
+    replacing: &Replacing,
+    codec: &RecordCodec,
+    blobs: &BlobFiles,
+    mapped: &RwLock<Option<Arc<MappedLog>>>,
+    key: &String,
+) -> Result<Option<String>> {
+    let mut retried = false;
+    loop {
+        let offset = match lookup(map, replacing, key) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let mapped = mapped.read().unwrap().clone();
+        match read_offset(offset.value(), codec, blobs, mapped.as_deref()) {
+            Err(ref e) if e.kind() == KvsErrorType::IOError && !retried => retried = true,
+            result => return result.map(Some),
+        }
+    }
+}
+
+/// 读取 offset 处记录的 value，记录在映射范围内时直接从映射中解码，否则通过缓冲读取
+fn read_offset(
+    offset: &Offset,
+    codec: &RecordCodec,
+    blobs: &BlobFiles,
+    mapped: Option<&MappedLog>,
+) -> Result<String> {
+    let data = mapped.and_then(|mapped| mapped.slice(&offset.path, offset.offset, offset.length));
+    let op = match data {
+        Some(data) => codec.decode(data, blobs)?,
+        None => {
+            let mut reader = BufReader::new(File::open(&offset.path)?);
+            reader.seek(SeekFrom::Start(offset.offset))?;
+            codec.read(reader.take(offset.length), blobs)?
+        }
+    };
+    match op {
+        Operation::Set { value, .. } => Ok(value),
+        _ => Err(KvsErrorType::SerdeError)?,
+    }
+}
+
+/// 打开 path 中的数据库，从 index 中去掉 dropped 中的 key 后压缩，用于重写损坏的数据目录
+pub(super) fn rebuild(path: PathBuf, options: KvStoreOptions, dropped: &[String]) -> Result<()> {
+    let mut writer = KvStoreWriter::new(path, options)?;
+    for key in dropped {
+        writer.map.remove(key);
+    }
+    writer.compact()
+}
+
+/// 从路径为 path 的数据文件中构造 index map，同时计算可压缩的大小与最大的序号，并向 blobs 登记仍被引用的 blob
+///
+/// 记录无法解密或未通过认证时返回 Corrupted Error
+fn build_map(
+    path: &PathBuf,
+    codec: &RecordCodec,
+    blobs: &mut BlobStore,
+) -> Result<(SkipMap<String, Offset>, u64, u64)> {
     let file = File::open(path).unwrap();
     let reader = BufReader::new(file.try_clone().unwrap());
     let mut offset = 0;
-    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Operation>();
-    let map = SkipMap::new();
+    let mut stream = RecordStream::new(reader);
+    let map: SkipMap<String, Offset> = SkipMap::new();
     let mut uncompacted = 0;
-    while let Some(op) = stream.next() {
-        let end_offset = stream.byte_offset() as u64;
+    let mut seq = 0;
+    while let Some(data) = stream.next() {
+        let end_offset = stream.byte_offset();
         let length = end_offset - offset;
-        match op.unwrap() {
-            Operation::Set { key, .. } => {
-                if map.contains_key(&key) {
-                    uncompacted += length;
-                }
-                map.insert(
-                    key,
-                    Offset {
-                        path: path.clone(),
-                        offset,
-                        length,
-                    },
-                );
-            }
-            Operation::Remove { key } => {
+        let (stamp, record) = codec.open_stamped(codec.parse(&data?)?)?;
+        if let Some(stamp) = stamp {
+            seq = seq.max(stamp.seq);
+        }
+        let (key, blob) = match record {
+            LogRecord::Set { key, .. } | LogRecord::Compressed { key, .. } => (key, None),
+            LogRecord::Blob { key, blob } => {
+                blobs.reserve(blob.file);
+                (key, Some(blob))
+            }
+            LogRecord::Remove { key } => {
                 if let Some(last_offset) = map.remove(&key) {
                     uncompacted += last_offset.value().length;
                 }
                 uncompacted += length;
+                offset = end_offset;
+                continue;
             }
-            _ => unreachable!(),
+            LogRecord::Sealed { .. } | LogRecord::Stamped { .. } => Err(KvsErrorType::Corrupted)?,
+        };
+        if map.contains_key(&key) {
+            uncompacted += length;
         }
+        map.insert(
+            key,
+            Offset {
+                path: path.clone(),
+                offset,
+                length,
+                blob,
+            },
+        );
         offset = end_offset
     }
-    (map, uncompacted)
+    for entry in map.iter() {
+        if let Some(blob) = entry.value().blob {
+            blobs.mark_live(blob);
+        }
+    }
+    Ok((map, uncompacted, seq))
 }
@@ -0,0 +1,66 @@
+/// 每个 key 使用的位数，误判率约为 1%
+const BITS_PER_KEY: usize = 10;
+
+/// 哈希函数的数量
+const HASHES: u32 = 7;
+
+/// key 的 64 位哈希（FNV-1a 后经 fmix64 混合，使相近的 key 分布均匀）
+pub fn key_hash(key: &str) -> u64 {
+    let mut hash = key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// 布隆过滤器，用于在读取 SSTable 前判断 key 是否可能存在
+///
+/// 使用双重哈希由一个 64 位哈希值得到所有位置
+pub struct BloomFilter {
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// 由所有 key 的哈希构建过滤器
+    pub fn build(hashes: &[u64]) -> BloomFilter {
+        let bytes = hashes.len() * BITS_PER_KEY / 8 + 1;
+        let mut filter = BloomFilter {
+            bits: vec![0; bytes.max(8)],
+        };
+        for hash in hashes {
+            for bit in filter.positions(*hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// 由 `as_bytes` 的结果恢复过滤器
+    pub fn from_bytes(bits: Vec<u8>) -> BloomFilter {
+        BloomFilter { bits }
+    }
+
+    /// 过滤器的内容
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /// key 是否可能存在，返回 false 时一定不存在
+    pub fn may_contain(&self, key: &str) -> bool {
+        if self.bits.is_empty() {
+            return true;
+        }
+        self.positions(key_hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// 哈希值在过滤器中对应的位置
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let (h1, h2) = (hash as u32, (hash >> 32) as u32);
+        let len = self.bits.len() as u64 * 8;
+        (0..HASHES).map(move |i| (u64::from(h1.wrapping_add(i.wrapping_mul(h2))) % len) as usize)
+    }
+}
@@ -0,0 +1,625 @@
+//! 基于 LSM-tree 的存储引擎
+//!
+//! 写入先追加到预写日志（WAL），再写入内存中的有序表（memtable）；
+//! memtable 超过上限后写入 L0 的 SSTable，L0 的表之间 key 范围可以重叠，新的在前；
+//! L1 及以下各层的表按 key 排序且互不重叠，每层的容量为上一层的 10 倍，超出时与下一层合并
+
+mod bloom;
+mod table;
+
+use self::table::{Table, TableBuilder};
+use super::{KvsEngine, Stats};
+use crate::backup::{BackupManifest, BackupWriter};
+use crate::{KvsErrorType, Operation, Result};
+use crossbeam_skiplist::SkipMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// 一条记录，value 为 None 表示删除
+type Record = (String, Option<String>);
+
+/// 按 key 从小到大输出记录的数据源
+type Source = Box<dyn Iterator<Item = Result<Record>> + Send>;
+
+/// 最大层数
+const MAX_LEVELS: usize = 7;
+
+/// 记录当前所有 SSTable 的文件
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// 预写日志文件
+const WAL_FILE: &str = "wal.log";
+
+/// LsmEngine 的参数
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// memtable 的大小上限（字节），超出时写入 SSTable
+    pub memtable_size: u64,
+    /// 压缩时输出的单个 SSTable 的大小
+    pub table_size: u64,
+    /// SSTable 中数据块的大小
+    pub block_size: usize,
+    /// L0 的表达到该数量时与 L1 合并
+    pub level0_tables: usize,
+    /// L1 的容量（字节），之后每层为上一层的 10 倍
+    pub level_size: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 * 1024 * 1024,
+            table_size: 2 * 1024 * 1024,
+            block_size: 4096,
+            level0_tables: 4,
+            level_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// MANIFEST 的内容
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    /// 下一个 SSTable 的编号
+    next_id: u64,
+    /// 每层的 SSTable 编号，顺序与 Version 中一致
+    levels: Vec<Vec<u64>>,
+}
+
+/// 某一时刻的数据视图，读操作持有其引用，因此不会受到写入 SSTable 与压缩的影响
+struct Version {
+    memtable: Arc<SkipMap<String, Option<String>>>,
+    /// L0 新的在前，其余各层按 first_key 排序
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl Version {
+    /// 在所有 SSTable 中查找 key，含义同 `Table::get`
+    fn get_from_tables(&self, key: &str) -> Result<Option<Option<String>>> {
+        for table in &self.levels[0] {
+            if let Some(value) = table.get(key)? {
+                return Ok(Some(value));
+            }
+        }
+        for level in &self.levels[1..] {
+            let i = level.partition_point(|table| table.last_key() < key);
+            if let Some(table) = level.get(i) {
+                if let Some(value) = table.get(key)? {
+                    return Ok(Some(value));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// 按新旧顺序合并 memtable（已按 start 截取）与所有 SSTable 中从 start 开始的记录
+    fn merged(&self, memtable: Vec<Record>, start: Bound<String>) -> MergeIter {
+        let mut sources: Vec<Source> = vec![Box::new(memtable.into_iter().map(Ok))];
+        for table in &self.levels[0] {
+            sources.push(Box::new(table.iter_from(start.clone())));
+        }
+        for level in &self.levels[1..] {
+            let start = start.clone();
+            let tables = level
+                .iter()
+                .filter(|table| match &start {
+                    Bound::Included(key) | Bound::Excluded(key) => table.last_key() >= key.as_str(),
+                    Bound::Unbounded => true,
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            sources.push(Box::new(
+                tables
+                    .into_iter()
+                    .flat_map(move |table| table.iter_from(start.clone())),
+            ));
+        }
+        MergeIter::new(sources)
+    }
+}
+
+/// 多路归并，同一个 key 只输出最新的数据源中的记录
+///
+/// 数据源按从新到旧的顺序排列
+struct MergeIter {
+    sources: Vec<Peekable<Source>>,
+}
+
+impl MergeIter {
+    fn new(sources: Vec<Source>) -> MergeIter {
+        MergeIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Result<Record>> {
+        let mut best: Option<(usize, String)> = None;
+        for i in 0..self.sources.len() {
+            match self.sources[i].peek() {
+                Some(Ok((key, _))) if !matches!(&best, Some((_, best)) if best <= key) => {
+                    best = Some((i, key.clone()));
+                }
+                Some(Err(_)) => return self.sources[i].next(),
+                Some(Ok(_)) | None => {}
+            }
+        }
+        let (i, key) = best?;
+        let record = self.sources[i].next();
+        for source in &mut self.sources {
+            while matches!(source.peek(), Some(Ok((k, _))) if *k == key) {
+                source.next();
+            }
+        }
+        record
+    }
+}
+
+/// 写操作共享的状态
+struct LsmWriter {
+    path: PathBuf,
+    options: LsmOptions,
+    wal: BufWriter<File>,
+    /// memtable 中数据的大小
+    memtable_bytes: u64,
+    next_id: u64,
+    /// 每层下一次压缩开始的 key，使各层的表轮流参与压缩
+    compact_pointers: Vec<String>,
+}
+
+impl LsmWriter {
+    fn table_path(&self, id: u64) -> PathBuf {
+        self.path.join(format!("{}.sst", id))
+    }
+
+    /// 先写入临时文件再替换 MANIFEST
+    fn save_manifest(&self, levels: &[Vec<Arc<Table>>]) -> Result<()> {
+        let manifest = Manifest {
+            next_id: self.next_id,
+            levels: levels
+                .iter()
+                .map(|level| level.iter().map(|table| table.id()).collect())
+                .collect(),
+        };
+        let path = self.path.join(MANIFEST_FILE);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(&manifest)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// 将 records 写入若干个不超过 table_size 的 SSTable
+    ///
+    /// drop_deleted 为 true 时丢弃删除记录
+    fn write_tables<I>(
+        &mut self,
+        records: I,
+        table_size: u64,
+        drop_deleted: bool,
+    ) -> Result<Vec<Arc<Table>>>
+    where
+        I: Iterator<Item = Result<Record>>,
+    {
+        let mut tables = Vec::new();
+        let mut builder: Option<(u64, TableBuilder)> = None;
+        for record in records {
+            let (key, value) = record?;
+            if drop_deleted && value.is_none() {
+                continue;
+            }
+            if builder.is_none() {
+                let id = self.next_id;
+                self.next_id += 1;
+                builder = Some((
+                    id,
+                    TableBuilder::create(&self.table_path(id), self.options.block_size)?,
+                ));
+            }
+            let (_, b) = builder.as_mut().unwrap();
+            b.add(&key, value.as_deref())?;
+            if b.size() >= table_size {
+                let (id, b) = builder.take().unwrap();
+                tables.push(Arc::new(b.finish(id)?));
+            }
+        }
+        if let Some((id, b)) = builder {
+            tables.push(Arc::new(b.finish(id)?));
+        }
+        Ok(tables)
+    }
+
+    /// 第 level 层的容量
+    fn max_bytes(&self, level: usize) -> u64 {
+        self.options.level_size * 10u64.pow(level as u32 - 1)
+    }
+
+    /// 选出需要压缩的层及其参与压缩的表，不需要压缩时返回 None
+    fn pick_compaction(&self, version: &Version) -> Option<(usize, Vec<Arc<Table>>)> {
+        if version.levels[0].len() >= self.options.level0_tables {
+            return Some((0, version.levels[0].clone()));
+        }
+        for level in 1..MAX_LEVELS - 1 {
+            let tables = &version.levels[level];
+            let size: u64 = tables.iter().map(|table| table.size()).sum();
+            if size > self.max_bytes(level) {
+                let pointer = &self.compact_pointers[level];
+                let table = tables
+                    .iter()
+                    .find(|table| table.first_key() > pointer.as_str())
+                    .unwrap_or(&tables[0]);
+                return Some((level, vec![Arc::clone(table)]));
+            }
+        }
+        None
+    }
+}
+
+/// 基于 LSM-tree 的存储引擎，数据量不受内存大小限制
+///
+/// 读操作持有某一时刻的 Version，不需要获取写锁；
+/// 写入 SSTable 与压缩在写操作中同步进行
+#[derive(Clone)]
+pub struct LsmEngine {
+    version: Arc<RwLock<Arc<Version>>>,
+    writer: Arc<Mutex<LsmWriter>>,
+}
+
+impl LsmEngine {
+    /// 以默认参数打开存储位置为 path 的 LsmEngine
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmEngine> {
+        LsmEngine::open_with(path, LsmOptions::default())
+    }
+
+    /// 以 options 打开存储位置为 path 的 LsmEngine
+    ///
+    /// 删除未被 MANIFEST 引用的文件（上次写入或压缩中断时留下），并重放预写日志
+    pub fn open_with(path: impl Into<PathBuf>, options: LsmOptions) -> Result<LsmEngine> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let manifest_path = path.join(MANIFEST_FILE);
+        let manifest: Manifest = if manifest_path.exists() {
+            serde_json::from_slice(&fs::read(&manifest_path)?)
+                .map_err(|_| KvsErrorType::Corrupted)?
+        } else {
+            Manifest::default()
+        };
+
+        let mut levels = vec![Vec::new(); MAX_LEVELS];
+        let mut live = HashSet::new();
+        for (level, ids) in manifest.levels.iter().enumerate() {
+            for &id in ids {
+                let table = Table::open(&path.join(format!("{}.sst", id)), id)?;
+                levels[level].push(Arc::new(table));
+                live.insert(format!("{}.sst", id));
+            }
+        }
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if (name.ends_with(".sst") && !live.contains(&name)) || name.ends_with(".tmp") {
+                debug!("remove stale file {}", name);
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        let memtable = Arc::new(SkipMap::new());
+        let memtable_bytes = replay_wal(&path.join(WAL_FILE), &memtable)?;
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.join(WAL_FILE))?;
+        let writer = LsmWriter {
+            path,
+            options,
+            wal: BufWriter::new(wal),
+            memtable_bytes,
+            next_id: manifest.next_id,
+            compact_pointers: vec![String::new(); MAX_LEVELS],
+        };
+        Ok(LsmEngine {
+            version: Arc::new(RwLock::new(Arc::new(Version { memtable, levels }))),
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    fn current(&self) -> Arc<Version> {
+        Arc::clone(&self.version.read().unwrap())
+    }
+
+    /// 持有写锁时写入一条记录，memtable 超出上限时写入 SSTable 并按需压缩
+    fn write_locked(
+        &self,
+        writer: &mut LsmWriter,
+        key: String,
+        value: Option<String>,
+    ) -> Result<()> {
+        let op = match &value {
+            Some(value) => Operation::Set {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            None => Operation::Remove { key: key.clone() },
+        };
+        serde_json::to_writer(&mut writer.wal, &op)?;
+        writer.wal.flush()?;
+        writer.memtable_bytes += (key.len() + value.as_ref().map_or(0, String::len)) as u64;
+        self.current().memtable.insert(key, value);
+        if writer.memtable_bytes >= writer.options.memtable_size {
+            self.flush(writer)?;
+            self.compact(writer)?;
+        }
+        Ok(())
+    }
+
+    /// 将 memtable 写入 L0 的 SSTable 并清空预写日志
+    fn flush(&self, writer: &mut LsmWriter) -> Result<()> {
+        let version = self.current();
+        let records = version
+            .memtable
+            .iter()
+            .map(|entry| Ok((entry.key().clone(), entry.value().clone())));
+        // L0 的每张表对应一个完整的 memtable，不切分
+        let tables = writer.write_tables(records, u64::MAX, false)?;
+        let mut levels = version.levels.clone();
+        for table in tables {
+            levels[0].insert(0, table);
+        }
+        writer.save_manifest(&levels)?;
+        *self.version.write().unwrap() = Arc::new(Version {
+            memtable: Arc::new(SkipMap::new()),
+            levels,
+        });
+        writer.wal = BufWriter::new(File::create(writer.path.join(WAL_FILE))?);
+        writer.memtable_bytes = 0;
+        Ok(())
+    }
+
+    /// 压缩直到各层都不超出容量
+    fn compact(&self, writer: &mut LsmWriter) -> Result<()> {
+        loop {
+            let version = self.current();
+            let (level, mut inputs) = match writer.pick_compaction(&version) {
+                Some(compaction) => compaction,
+                None => return Ok(()),
+            };
+            let first = inputs
+                .iter()
+                .map(|t| t.first_key())
+                .min()
+                .unwrap()
+                .to_owned();
+            let last = inputs
+                .iter()
+                .map(|t| t.last_key())
+                .max()
+                .unwrap()
+                .to_owned();
+            let overlapping = version.levels[level + 1]
+                .iter()
+                .filter(|table| table.overlaps(&first, &last))
+                .cloned()
+                .collect::<Vec<_>>();
+            debug!(
+                "compact {} tables in L{} with {} tables in L{}",
+                inputs.len(),
+                level,
+                overlapping.len(),
+                level + 1
+            );
+
+            // 输入按从新到旧排列：L0 本身新的在前，下一层的表互不重叠，作为一个数据源
+            let mut sources: Vec<Source> = inputs
+                .iter()
+                .map(|table| Box::new(table.iter_from(Bound::Unbounded)) as Source)
+                .collect();
+            let next = overlapping.clone();
+            sources.push(Box::new(
+                next.into_iter()
+                    .flat_map(|table| table.iter_from(Bound::Unbounded)),
+            ));
+            // 更深的层中没有数据时，删除记录不再需要保留
+            let drop_deleted = version.levels[level + 2..].iter().all(Vec::is_empty);
+            let table_size = writer.options.table_size;
+            let outputs = writer.write_tables(MergeIter::new(sources), table_size, drop_deleted)?;
+
+            inputs.extend(overlapping);
+            let removed = inputs
+                .iter()
+                .map(|table| table.id())
+                .collect::<HashSet<_>>();
+            let mut levels = version.levels.clone();
+            levels[level].retain(|table| !removed.contains(&table.id()));
+            levels[level + 1].retain(|table| !removed.contains(&table.id()));
+            levels[level + 1].extend(outputs);
+            levels[level + 1].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+            writer.save_manifest(&levels)?;
+            writer.compact_pointers[level] = last;
+            *self.version.write().unwrap() = Arc::new(Version {
+                memtable: Arc::clone(&version.memtable),
+                levels,
+            });
+            for table in inputs {
+                table.mark_obsolete();
+            }
+        }
+    }
+
+    /// 持有写锁时复制 memtable 中从 start 开始、以 prefix 开头的记录，得到一致的数据视图
+    fn snapshot(&self, start: Bound<String>, prefix: &str) -> (Arc<Version>, Vec<Record>) {
+        let _writer = self.writer.lock().unwrap();
+        let version = self.current();
+        let memtable = version
+            .memtable
+            .range((start, Bound::Unbounded))
+            .take_while(|entry| entry.key().starts_with(prefix))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        (version, memtable)
+    }
+}
+
+/// 将预写日志重放到 memtable 中，返回写入的数据大小
+///
+/// 遇到不完整的记录（写入时崩溃）时停止，并截断日志使之后的追加仍然有效
+fn replay_wal(path: &Path, memtable: &SkipMap<String, Option<String>>) -> Result<u64> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    let reader = BufReader::new(File::open(path)?);
+    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Operation>();
+    let mut bytes = 0;
+    let mut valid = 0;
+    while let Some(Ok(op)) = stream.next() {
+        match op {
+            Operation::Set { key, value } => {
+                bytes += (key.len() + value.len()) as u64;
+                memtable.insert(key, Some(value));
+            }
+            Operation::Remove { key } => {
+                bytes += key.len() as u64;
+                memtable.insert(key, None);
+            }
+            _ => break,
+        }
+        valid = stream.byte_offset() as u64;
+    }
+    let file = OpenOptions::new().write(true).open(path)?;
+    if file.metadata()?.len() > valid {
+        info!("truncate torn write-ahead log at {}", valid);
+        file.set_len(valid)?;
+    }
+    Ok(bytes)
+}
+
+impl KvsEngine for LsmEngine {
+    /// 用于设置一个键值对
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        self.write_locked(&mut writer, key, Some(value))
+    }
+
+    /// 获取 key 所对应的 value
+    ///
+    /// 依次查找 memtable、L0 中从新到旧的表与之后的各层
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let version = self.current();
+        if let Some(entry) = version.memtable.get(&key) {
+            return Ok(entry.value().clone());
+        }
+        Ok(version.get_from_tables(&key)?.unwrap_or(None))
+    }
+
+    /// 删除 key 及其对应的 value
+    ///
+    /// 不存在会返回 KeyNotFound Error
+    fn remove(&self, key: String) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if self.get(key.clone())?.is_none() {
+            Err(KvsErrorType::KeyNotFound)?
+        }
+        self.write_locked(&mut writer, key, None)
+    }
+
+    /// 比较并交换
+    ///
+    /// 比较与写入都在持有写锁时进行
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+        let current = self.get(key.clone())?;
+        if current != expected {
+            return Ok(false);
+        }
+        if current.is_some() || new.is_some() {
+            self.write_locked(&mut writer, key, new)?;
+        }
+        Ok(true)
+    }
+
+    /// 按 key 从小到大的顺序获取以 prefix 开头的键值对
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix.clone()),
+        };
+        let (version, memtable) = self.snapshot(start.clone(), &prefix);
+        let mut pairs = Vec::new();
+        for record in version.merged(memtable, start) {
+            let (key, value) = record?;
+            if pairs.len() >= limit || !key.starts_with(&prefix) {
+                break;
+            }
+            if let Some(value) = value {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// 获取统计信息
+    ///
+    /// 包括 key 的数量（需要遍历所有数据）、SSTable 的数量与大小、memtable 的大小
+    fn stats(&self) -> Result<Stats> {
+        let (version, memtable) = self.snapshot(Bound::Unbounded, "");
+        let mut keys = 0;
+        for record in version.merged(memtable, Bound::Unbounded) {
+            if record?.1.is_some() {
+                keys += 1;
+            }
+        }
+        let tables = version.levels.iter().flatten();
+        let mut stats = Stats::new();
+        stats.insert("keys".to_owned(), keys);
+        stats.insert("tables".to_owned(), tables.clone().count() as u64);
+        stats.insert("sst_bytes".to_owned(), tables.map(|t| t.size()).sum());
+        stats.insert(
+            "memtable_bytes".to_owned(),
+            self.writer.lock().unwrap().memtable_bytes,
+        );
+        for (level, tables) in version.levels.iter().enumerate() {
+            if !tables.is_empty() {
+                stats.insert(format!("level{}_tables", level), tables.len() as u64);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// 获取 engine 的类型 (lsm)
+    fn get_type(&self) -> String {
+        String::from("lsm")
+    }
+
+    /// 备份当前数据
+    ///
+    /// 只在复制 memtable 时持有写锁，SSTable 在被引用期间不会被删除，因此之后的写入与压缩不影响备份
+    fn backup(&self, path: &Path) -> Result<BackupManifest> {
+        let (version, memtable) = self.snapshot(Bound::Unbounded, "");
+        let mut backup = BackupWriter::create(path)?;
+        for record in version.merged(memtable, Bound::Unbounded) {
+            if let (key, Some(value)) = record? {
+                backup.add(key, value)?;
+            }
+        }
+        backup.finish(&self.get_type())
+    }
+}
@@ -0,0 +1,372 @@
+//! SSTable 文件
+//!
+//! 文件由按 key 排序的数据块、JSON 格式的块索引、布隆过滤器与定长的文件尾组成：
+//!
+//! ```text
+//! | 数据块 ... | 块索引 | 布隆过滤器 | 块索引偏移 (u64) | 过滤器偏移 (u64) | MAGIC (u64) |
+//! ```
+//!
+//! 数据块中每条记录为 `key 长度 (u32) | key | 标记 (u8) | value 长度 (u32) | value`，
+//! 标记为 0 表示删除，此时没有 value 部分；所有整数均为小端序
+
+use super::bloom::{self, BloomFilter};
+use super::Record;
+use crate::{KvsErrorType, Result};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::vec;
+
+/// 文件尾的魔数
+const MAGIC: u64 = 0x6b76_735f_6c73_6d31;
+
+/// 文件尾的长度
+const FOOTER_LEN: u64 = 24;
+
+/// 数据块在文件中的位置
+#[derive(Serialize, Deserialize)]
+struct BlockHandle {
+    /// 块中最后一个 key
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// 块索引
+#[derive(Serialize, Deserialize)]
+struct TableIndex {
+    /// 表中第一个 key
+    first_key: String,
+    /// 记录数量
+    records: u64,
+    blocks: Vec<BlockHandle>,
+}
+
+/// SSTable 写入器，记录需按 key 从小到大的顺序写入
+pub struct TableBuilder {
+    writer: BufWriter<File>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    block_size: usize,
+    /// 正在写入的数据块
+    block: Vec<u8>,
+    offset: u64,
+    first_key: Option<String>,
+    last_key: Option<String>,
+    records: u64,
+    blocks: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    /// 创建写入 path 的 SSTable，数据块的大小约为 block_size
+    pub fn create(path: &Path, block_size: usize) -> Result<TableBuilder> {
+        let tmp_path = path.with_extension("tmp");
+        Ok(TableBuilder {
+            writer: BufWriter::new(File::create(&tmp_path)?),
+            tmp_path,
+            path: path.to_owned(),
+            block_size,
+            block: Vec::new(),
+            offset: 0,
+            first_key: None,
+            last_key: None,
+            records: 0,
+            blocks: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    /// 写入一条记录
+    pub fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        self.block
+            .extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.block.extend_from_slice(key.as_bytes());
+        match value {
+            Some(value) => {
+                self.block.push(1);
+                self.block
+                    .extend_from_slice(&(value.len() as u32).to_le_bytes());
+                self.block.extend_from_slice(value.as_bytes());
+            }
+            None => self.block.push(0),
+        }
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_owned());
+        }
+        self.last_key = Some(key.to_owned());
+        self.records += 1;
+        self.hashes.push(bloom::key_hash(key));
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// 已写入的字节数（估计值，用于切分文件）
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    /// 写入当前数据块
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&self.block)?;
+        self.blocks.push(BlockHandle {
+            last_key: self.last_key.clone().unwrap(),
+            offset: self.offset,
+            len: self.block.len() as u64,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// 写入索引、过滤器与文件尾，同步到磁盘后打开该表
+    pub fn finish(mut self, id: u64) -> Result<Table> {
+        self.finish_block()?;
+        let index = TableIndex {
+            first_key: self.first_key.take().unwrap_or_default(),
+            records: self.records,
+            blocks: std::mem::take(&mut self.blocks),
+        };
+        let index_offset = self.offset;
+        let index = serde_json::to_vec(&index)?;
+        self.writer.write_all(&index)?;
+        let bloom_offset = index_offset + index.len() as u64;
+        self.writer
+            .write_all(BloomFilter::build(&self.hashes).as_bytes())?;
+        self.writer.write_all(&index_offset.to_le_bytes())?;
+        self.writer.write_all(&bloom_offset.to_le_bytes())?;
+        self.writer.write_all(&MAGIC.to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        Table::open(&self.path, id)
+    }
+}
+
+/// 从 buf 的 pos 处读取一个小端序的 u32
+fn read_u32(buf: &[u8], pos: &mut usize) -> Result<u32> {
+    let bytes = buf.get(*pos..*pos + 4).ok_or(KvsErrorType::Corrupted)?;
+    *pos += 4;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// 从 buf 的 pos 处读取长度为 len 的字符串
+fn read_string(buf: &[u8], pos: &mut usize, len: usize) -> Result<String> {
+    let bytes = buf.get(*pos..*pos + len).ok_or(KvsErrorType::Corrupted)?;
+    *pos += len;
+    Ok(String::from_utf8(bytes.to_vec()).map_err(|_| KvsErrorType::Corrupted)?)
+}
+
+/// 解析数据块中的所有记录
+fn decode_block(buf: &[u8]) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let len = read_u32(buf, &mut pos)? as usize;
+        let key = read_string(buf, &mut pos, len)?;
+        let tag = *buf.get(pos).ok_or(KvsErrorType::Corrupted)?;
+        pos += 1;
+        let value = match tag {
+            0 => None,
+            1 => {
+                let len = read_u32(buf, &mut pos)? as usize;
+                Some(read_string(buf, &mut pos, len)?)
+            }
+            _ => Err(KvsErrorType::Corrupted)?,
+        };
+        records.push((key, value));
+    }
+    Ok(records)
+}
+
+/// 只读的 SSTable
+///
+/// 被压缩合并后标记为过期，最后一个引用释放时删除文件，使正在进行的读取不受影响
+pub struct Table {
+    id: u64,
+    path: PathBuf,
+    file: Mutex<File>,
+    index: TableIndex,
+    bloom: BloomFilter,
+    size: u64,
+    obsolete: AtomicBool,
+}
+
+impl Table {
+    /// 打开路径为 path、编号为 id 的 SSTable，读取其索引与过滤器
+    pub fn open(path: &Path, id: u64) -> Result<Table> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN {
+            Err(KvsErrorType::Corrupted)?
+        }
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.seek(SeekFrom::Start(size - FOOTER_LEN))?;
+        file.read_exact(&mut footer)?;
+        let index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+        let bloom_offset = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+        let magic = u64::from_le_bytes(footer[16..24].try_into().unwrap());
+        if magic != MAGIC || index_offset > bloom_offset || bloom_offset > size - FOOTER_LEN {
+            Err(KvsErrorType::Corrupted)?
+        }
+        let mut buf = vec![0; (size - FOOTER_LEN - index_offset) as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut buf)?;
+        let bloom = buf.split_off((bloom_offset - index_offset) as usize);
+        let index = serde_json::from_slice(&buf).map_err(|_| KvsErrorType::Corrupted)?;
+        Ok(Table {
+            id,
+            path: path.to_owned(),
+            file: Mutex::new(file),
+            index,
+            bloom: BloomFilter::from_bytes(bloom),
+            size,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    /// 表的编号
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// 文件大小
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// 表中最小的 key
+    pub fn first_key(&self) -> &str {
+        &self.index.first_key
+    }
+
+    /// 表中最大的 key
+    pub fn last_key(&self) -> &str {
+        self.index
+            .blocks
+            .last()
+            .map_or("", |block| block.last_key.as_str())
+    }
+
+    /// key 的范围是否与 [first, last] 有交集
+    pub fn overlaps(&self, first: &str, last: &str) -> bool {
+        self.first_key() <= last && self.last_key() >= first
+    }
+
+    /// 标记为过期，最后一个引用释放时删除文件
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+
+    /// 读取第 i 个数据块
+    fn read_block(&self, i: usize) -> Result<Vec<Record>> {
+        let handle = &self.index.blocks[i];
+        let mut buf = vec![0; handle.len as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut buf)?;
+        }
+        decode_block(&buf)
+    }
+
+    /// 查找 key
+    ///
+    /// 表中没有该 key 时返回 None，key 已被删除时返回 `Some(None)`
+    pub fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.first_key() || key > self.last_key() || !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let i = self
+            .index
+            .blocks
+            .partition_point(|block| block.last_key.as_str() < key);
+        if i >= self.index.blocks.len() {
+            return Ok(None);
+        }
+        for (k, value) in self.read_block(i)? {
+            if k == key {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// 从 start 开始按 key 顺序遍历表中的记录，按需逐块读取
+    pub fn iter_from(self: &Arc<Self>, start: Bound<String>) -> TableIter {
+        let block = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => self
+                .index
+                .blocks
+                .partition_point(|block| &block.last_key < key),
+            Bound::Unbounded => 0,
+        };
+        TableIter {
+            table: Arc::clone(self),
+            block,
+            records: Vec::new().into_iter(),
+            start,
+        }
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                error!("failed to remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// SSTable 的迭代器
+pub struct TableIter {
+    table: Arc<Table>,
+    /// 下一个要读取的数据块
+    block: usize,
+    records: vec::IntoIter<Record>,
+    /// 跳过小于起点的记录
+    start: Bound<String>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Result<Record>> {
+        loop {
+            if let Some(record) = self.records.next() {
+                let before_start = match &self.start {
+                    Bound::Included(start) => &record.0 < start,
+                    Bound::Excluded(start) => &record.0 <= start,
+                    Bound::Unbounded => false,
+                };
+                if !before_start {
+                    return Some(Ok(record));
+                }
+                continue;
+            }
+            if self.block >= self.table.index.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.block) {
+                Ok(records) => self.records = records.into_iter(),
+                Err(e) => {
+                    self.block = self.table.index.blocks.len();
+                    return Some(Err(e));
+                }
+            }
+            self.block += 1;
+        }
+    }
+}
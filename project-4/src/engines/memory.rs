@@ -0,0 +1,387 @@
+use super::{check_tree_name, KvsEngine, Stats};
+use crate::backup::{BackupManifest, BackupWriter};
+use crate::{KvsErrorType, Result};
+use crossbeam_skiplist::SkipMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// 超出内存上限时的淘汰策略
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EvictionPolicy {
+    /// 淘汰最久未被访问的 key
+    Lru,
+    /// 淘汰访问次数最少的 key，次数相同时淘汰最久未被访问的
+    Lfu,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = crate::KvsError;
+
+    fn from_str(s: &str) -> Result<EvictionPolicy> {
+        match s {
+            "lru" => Ok(EvictionPolicy::Lru),
+            "lfu" => Ok(EvictionPolicy::Lfu),
+            _ => Err(KvsErrorType::InvalidArgument)?,
+        }
+    }
+}
+
+/// 内存中的一个键值对及其访问记录
+///
+/// 访问记录只在持有状态锁时修改
+struct Entry {
+    value: String,
+    /// 最后一次访问时的逻辑时钟
+    tick: AtomicU64,
+    /// 访问次数
+    hits: AtomicU64,
+}
+
+impl Entry {
+    /// 在淘汰顺序中的位置，越小越先被淘汰
+    fn rank(&self, policy: EvictionPolicy) -> (u64, u64) {
+        let tick = self.tick.load(Ordering::SeqCst);
+        match policy {
+            EvictionPolicy::Lru => (0, tick),
+            EvictionPolicy::Lfu => (self.hits.load(Ordering::SeqCst), tick),
+        }
+    }
+}
+
+/// 键值对占用的内存，只计算 key 与 value 的长度
+fn size_of(key: &str, value: &str) -> u64 {
+    (key.len() + value.len()) as u64
+}
+
+/// 写操作与有内存上限时的读操作共享的状态
+struct MemoryState {
+    /// 内存上限与淘汰策略
+    limit: Option<(u64, EvictionPolicy)>,
+    /// 当前占用的内存
+    used: u64,
+    /// 逻辑时钟，每次访问加一
+    clock: u64,
+    /// 被淘汰的 key 数量
+    evicted: u64,
+    /// 淘汰顺序，有内存上限时才维护
+    order: BTreeSet<((u64, u64), String)>,
+}
+
+impl MemoryState {
+    /// 记录对 entry 的一次访问
+    fn touch(&mut self, key: &str, entry: &Entry) {
+        self.clock += 1;
+        match self.limit {
+            Some((_, policy)) => {
+                self.order.remove(&(entry.rank(policy), key.to_owned()));
+                entry.tick.store(self.clock, Ordering::SeqCst);
+                entry.hits.fetch_add(1, Ordering::SeqCst);
+                self.order.insert((entry.rank(policy), key.to_owned()));
+            }
+            None => entry.tick.store(self.clock, Ordering::SeqCst),
+        }
+    }
+}
+
+/// 数据只保存在内存中的引擎，适用于测试与缓存
+///
+/// 数据保存在并发的有序表中，写操作持有状态锁；
+/// 设置内存上限后，写入使占用超出上限时按淘汰策略删除其他 key；
+/// 每个 keyspace 是一个独立的 MemoryEngine，有各自的内存上限
+#[derive(Clone)]
+pub struct MemoryEngine {
+    map: Arc<SkipMap<String, Arc<Entry>>>,
+    state: Arc<Mutex<MemoryState>>,
+    /// 是否有内存上限
+    limited: bool,
+    /// 所有 keyspace
+    trees: Arc<Mutex<BTreeMap<String, MemoryEngine>>>,
+    /// 作为 keyspace 时，其是否已被删除；默认 keyspace 为空
+    dropped: Option<Arc<AtomicBool>>,
+}
+
+impl Default for MemoryEngine {
+    fn default() -> Self {
+        MemoryEngine::new()
+    }
+}
+
+impl MemoryEngine {
+    /// 创建一个没有内存上限的引擎
+    pub fn new() -> MemoryEngine {
+        MemoryEngine::with_state(None)
+    }
+
+    /// 创建一个内存上限为 max_memory 字节的引擎，超出时按 policy 淘汰
+    pub fn with_limit(max_memory: u64, policy: EvictionPolicy) -> MemoryEngine {
+        MemoryEngine::with_state(Some((max_memory, policy)))
+    }
+
+    fn with_state(limit: Option<(u64, EvictionPolicy)>) -> MemoryEngine {
+        MemoryEngine {
+            map: Arc::new(SkipMap::new()),
+            state: Arc::new(Mutex::new(MemoryState {
+                limit,
+                used: 0,
+                clock: 0,
+                evicted: 0,
+                order: BTreeSet::new(),
+            })),
+            limited: limit.is_some(),
+            trees: Arc::new(Mutex::new(BTreeMap::new())),
+            dropped: None,
+        }
+    }
+
+    /// keyspace 已被删除时返回 KeyspaceNotFound Error
+    fn check_dropped(&self) -> Result<()> {
+        if matches!(&self.dropped, Some(dropped) if dropped.load(Ordering::SeqCst)) {
+            Err(KvsErrorType::KeyspaceNotFound)?
+        }
+        Ok(())
+    }
+
+    /// 将当前数据写入 backup，期间持有状态锁
+    fn backup_pairs(&self, backup: &mut BackupWriter) -> Result<()> {
+        self.check_dropped()?;
+        let _state = self.state.lock().unwrap();
+        for entry in self.map.iter() {
+            backup.add(entry.key().clone(), entry.value().value.clone())?;
+        }
+        Ok(())
+    }
+
+    /// 检查 keyspace 名称，keyspace 中不能再打开或删除 keyspace
+    fn check_tree(&self, name: &str) -> Result<()> {
+        check_tree_name(name)?;
+        if self.dropped.is_some() {
+            Err(KvsErrorType::InvalidKeyspace)?
+        }
+        Ok(())
+    }
+
+    /// 持有状态锁时写入键值对，之后按需淘汰其他 key
+    ///
+    /// SkipMap 替换已有的 key 时先删除旧项再插入新项，期间不加锁的读操作可能读不到该 key，
+    /// 因此读操作未命中时需持有状态锁再读取一次
+    fn set_locked(&self, state: &mut MemoryState, key: String, value: String) {
+        let mut hits = 0;
+        if let Some(old) = self.map.get(&key) {
+            let old = old.value();
+            hits = old.hits.load(Ordering::SeqCst);
+            state.used -= size_of(&key, &old.value);
+            if let Some((_, policy)) = state.limit {
+                state.order.remove(&(old.rank(policy), key.clone()));
+            }
+        }
+        state.used += size_of(&key, &value);
+        let entry = Arc::new(Entry {
+            value,
+            tick: AtomicU64::new(0),
+            hits: AtomicU64::new(hits),
+        });
+        state.touch(&key, &entry);
+        self.map.insert(key.clone(), entry);
+        self.evict(state, &key);
+    }
+
+    /// 持有状态锁时删除 key，返回是否存在
+    fn remove_locked(&self, state: &mut MemoryState, key: &str) -> bool {
+        match self.map.remove(key) {
+            Some(entry) => {
+                let entry = entry.value();
+                state.used -= size_of(key, &entry.value);
+                if let Some((_, policy)) = state.limit {
+                    state.order.remove(&(entry.rank(policy), key.to_owned()));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 占用超出上限时按淘汰顺序删除 key，刚写入的 keep 不会被删除
+    fn evict(&self, state: &mut MemoryState, keep: &str) {
+        let max_memory = match state.limit {
+            Some((max_memory, _)) => max_memory,
+            None => return,
+        };
+        while state.used > max_memory {
+            let victim = match state.order.iter().find(|(_, key)| key != keep) {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            debug!("evict key {}", victim);
+            self.remove_locked(state, &victim);
+            state.evicted += 1;
+        }
+    }
+}
+
+impl KvsEngine for MemoryEngine {
+    /// 用于设置一个键值对
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.check_dropped()?;
+        let mut state = self.state.lock().unwrap();
+        self.set_locked(&mut state, key, value);
+        Ok(())
+    }
+
+    /// 获取 key 所对应的 value
+    ///
+    /// 有内存上限时需要更新淘汰顺序，因此会获取状态锁，否则只在未命中时加锁
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.check_dropped()?;
+        if !self.limited {
+            if let Some(entry) = self.map.get(&key) {
+                return Ok(Some(entry.value().value.clone()));
+            }
+        }
+        // 未命中时可能正有写操作在替换该 key，持有状态锁时不存在中间状态
+        let mut state = self.state.lock().unwrap();
+        Ok(match self.map.get(&key) {
+            Some(entry) => {
+                state.touch(&key, entry.value());
+                Some(entry.value().value.clone())
+            }
+            None => None,
+        })
+    }
+
+    /// 删除 key 及其对应的 value
+    ///
+    /// 不存在会返回 KeyNotFound Error
+    fn remove(&self, key: String) -> Result<()> {
+        self.check_dropped()?;
+        let mut state = self.state.lock().unwrap();
+        if !self.remove_locked(&mut state, &key) {
+            Err(KvsErrorType::KeyNotFound)?
+        }
+        Ok(())
+    }
+
+    /// 比较并交换
+    ///
+    /// 比较与写入都在持有状态锁时进行
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.check_dropped()?;
+        let mut state = self.state.lock().unwrap();
+        let current = self.map.get(&key).map(|entry| entry.value().value.clone());
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set_locked(&mut state, key, value),
+            None => {
+                self.remove_locked(&mut state, &key);
+            }
+        }
+        Ok(true)
+    }
+
+    /// 按 key 从小到大的顺序获取以 prefix 开头的键值对
+    ///
+    /// 扫描不算作访问，不影响淘汰顺序；扫描不持有状态锁，正被并发替换的 key 可能不在结果中
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.check_dropped()?;
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix.clone()),
+        };
+        let mut pairs = Vec::new();
+        for entry in self.map.range((start, Bound::Unbounded)) {
+            if pairs.len() >= limit || !entry.key().starts_with(&prefix) {
+                break;
+            }
+            pairs.push((entry.key().clone(), entry.value().value.clone()));
+        }
+        Ok(pairs)
+    }
+
+    /// 获取统计信息
+    ///
+    /// 包括 key 的数量、占用的内存、内存上限与被淘汰的 key 数量
+    fn stats(&self) -> Result<Stats> {
+        self.check_dropped()?;
+        let state = self.state.lock().unwrap();
+        let mut stats = Stats::new();
+        stats.insert("keys".to_owned(), self.map.len() as u64);
+        stats.insert("memory_bytes".to_owned(), state.used);
+        stats.insert("evicted".to_owned(), state.evicted);
+        if let Some((max_memory, _)) = state.limit {
+            stats.insert("max_memory".to_owned(), max_memory);
+        }
+        Ok(stats)
+    }
+
+    /// 获取 engine 的类型 (memory)
+    fn get_type(&self) -> String {
+        String::from("memory")
+    }
+
+    /// 备份当前数据及所有 keyspace
+    ///
+    /// 备份每个 keyspace 期间持有其状态锁，对其的写操作会被阻塞
+    fn backup(&self, path: &Path) -> Result<BackupManifest> {
+        self.check_dropped()?;
+        let mut backup = BackupWriter::create(path)?;
+        self.backup_pairs(&mut backup)?;
+        let trees: Vec<_> = self
+            .trees
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, tree)| (name.clone(), tree.clone()))
+            .collect();
+        for (name, tree) in trees {
+            backup.keyspace(&name)?;
+            tree.backup_pairs(&mut backup)?;
+        }
+        backup.finish(&self.get_type())
+    }
+
+    /// 打开名为 name 的 keyspace，不存在时创建，内存上限与淘汰策略与当前引擎相同
+    fn open_tree(&self, name: &str) -> Result<MemoryEngine> {
+        self.check_tree(name)?;
+        let limit = self.state.lock().unwrap().limit;
+        let mut trees = self.trees.lock().unwrap();
+        Ok(trees
+            .entry(name.to_owned())
+            .or_insert_with(|| MemoryEngine {
+                dropped: Some(Arc::new(AtomicBool::new(false))),
+                ..MemoryEngine::with_state(limit)
+            })
+            .clone())
+    }
+
+    /// 删除名为 name 的 keyspace，返回其是否存在
+    ///
+    /// 之后通过该 keyspace 已打开的实例进行的操作返回 KeyspaceNotFound Error
+    fn drop_tree(&self, name: &str) -> Result<bool> {
+        self.check_tree(name)?;
+        let tree = self.trees.lock().unwrap().remove(name);
+        if let Some(dropped) = tree.as_ref().and_then(|tree| tree.dropped.as_ref()) {
+            dropped.store(true, Ordering::SeqCst);
+        }
+        Ok(tree.is_some())
+    }
+
+    /// 按名称排序的所有 keyspace
+    fn tree_names(&self) -> Result<Vec<String>> {
+        Ok(self.trees.lock().unwrap().keys().cloned().collect())
+    }
+}
@@ -0,0 +1,46 @@
+use memmap2::{Mmap, MmapOptions};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// 数据文件中已写入部分的内存映射
+///
+/// 数据文件只会追加，压缩后旧文件被删除但不会被修改或截断，因此已写入的部分可以安全地映射；
+/// 映射之后追加的记录不在映射范围内，需要通过缓冲读取
+pub struct MappedLog {
+    path: PathBuf,
+    map: Mmap,
+}
+
+impl MappedLog {
+    /// 映射 path 的前 len 字节，失败时返回 None，之后的读取退回缓冲读取
+    pub fn map(path: &Path, len: u64) -> Option<MappedLog> {
+        if len == 0 {
+            return None;
+        }
+        let file = File::open(path).ok()?;
+        // 映射范围内的内容不会再被修改，见类型说明
+        match unsafe { MmapOptions::new().len(len as usize).map(&file) } {
+            Ok(map) => Some(MappedLog {
+                path: path.to_owned(),
+                map,
+            }),
+            Err(e) => {
+                error!("failed to map {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// 映射范围的末尾，之前的内容都已映射
+    pub fn end(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    /// path 中从 offset 开始长度为 length 的内容，不在映射范围内时返回 None
+    pub fn slice(&self, path: &Path, offset: u64, length: u64) -> Option<&[u8]> {
+        if path != self.path || offset + length > self.end() {
+            return None;
+        }
+        Some(&self.map[offset as usize..(offset + length) as usize])
+    }
+}
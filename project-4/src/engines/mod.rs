@@ -1,6 +1,15 @@
 //! KvsServer Engine 模块
 
-use super::Result;
+use super::{KvsErrorType, Result};
+use crate::backup::BackupManifest;
+use crate::replication::{LogPosition, Replication};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// 引擎的统计信息，为 名称 -> 数值 的映射
+pub type Stats = BTreeMap<String, u64>;
 
 /// 定义了可作为 KvsServer 的 Engine Trait
 ///
@@ -12,7 +21,7 @@ use super::Result;
 /// let v = kv.get("key".to_owned());               // 获取 value
 /// kv.remove("key".to_owned());                    // 删除
 /// ```
-pub trait KvsEngine: Clone + Send + 'static {
+pub trait KvsEngine: Clone + Send + Sync + 'static {
     /// 用于设置一个键值对
     ///
     /// key 存在则会更新 value
@@ -30,12 +39,136 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// 不存在会返回 KeyNotFound Error
     fn remove(&self, key: String) -> Result<()>;
 
-    /// 获取 engine 的类型 (kvs || sled)
+    /// 比较并交换
+    ///
+    /// 仅当 key 当前的 value 等于 expected 时（None 表示不存在）才将其替换为 new（None 表示删除）
+    ///
+    /// 交换成功返回 true，value 不符返回 false
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool>;
+
+    /// 按 key 从小到大的顺序获取以 prefix 开头的键值对
+    ///
+    /// after 不为空时只返回大于 after 的 key，最多返回 limit 个
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>>;
+
+    /// 获取引擎的统计信息，如 key 的数量
+    fn stats(&self) -> Result<Stats>;
+
+    /// 获取 engine 的类型，如 kvs、sled
     fn get_type(&self) -> String;
+
+    /// 订阅从 position 开始的日志，用于主从复制
+    ///
+    /// position 为空或已被压缩时，日志流以完整的快照开始
+    ///
+    /// 仅 KvStore 支持，其他引擎返回 UnknownOperation Error
+    fn replicate(&self, _position: Option<LogPosition>) -> Result<Replication> {
+        Err(KvsErrorType::UnknownOperation)?
+    }
+
+    /// 在不停止服务的情况下，将某一时刻的完整数据备份到 path
+    ///
+    /// path 为已存在的文件夹时写入文件夹形式的备份，否则写入归档文件，格式见 `backup` 模块
+    fn backup(&self, _path: &Path) -> Result<BackupManifest> {
+        Err(KvsErrorType::UnknownOperation)?
+    }
+
+    /// 集群中 leader 的客户端地址，用于将客户端重定向到 leader
+    ///
+    /// 非集群引擎或 leader 未知时返回 None
+    fn leader(&self) -> Option<String> {
+        None
+    }
+
+    /// 打开名为 name 的 keyspace，不存在时创建
+    ///
+    /// 返回的引擎只包含该 keyspace 中的键值对，与默认的 keyspace 及其他 keyspace 互不影响；
+    /// 名称只能包含字母、数字、`-` 与 `_`，keyspace 中也不能再打开 keyspace，否则返回 InvalidKeyspace Error
+    ///
+    /// 仅 KvStore、MemoryEngine 与 SledServer 支持，其他引擎返回 UnknownOperation Error
+    fn open_tree(&self, _name: &str) -> Result<Self> {
+        Err(KvsErrorType::UnknownOperation)?
+    }
+
+    /// 删除名为 name 的 keyspace 及其中的所有数据，返回其是否存在
+    ///
+    /// 之后通过该 keyspace 已打开的实例进行的操作返回 KeyspaceNotFound Error
+    fn drop_tree(&self, _name: &str) -> Result<bool> {
+        Err(KvsErrorType::UnknownOperation)?
+    }
+
+    /// 所有 keyspace 的名称，不包括默认的 keyspace
+    fn tree_names(&self) -> Result<Vec<String>> {
+        Err(KvsErrorType::UnknownOperation)?
+    }
+}
+
+/// 检查 keyspace 名称是否合法
+///
+/// 名称会用作目录名，因此只能包含字母、数字、`-` 与 `_`
+pub(crate) fn check_tree_name(name: &str) -> Result<()> {
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if name.is_empty() || !valid {
+        Err(KvsErrorType::InvalidKeyspace)?
+    }
+    Ok(())
+}
+
+/// 将文件 path 移入 dir，不在同一文件系统中时复制后删除
+///
+/// dir 中已有同名文件时不覆盖，返回 IOError，path 保留在原处
+pub(crate) fn archive_file(path: &Path, dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let target = dir.join(path.file_name().unwrap());
+    // 目标已存在时硬链接与 create_new 都会失败，不会覆盖已归档的文件
+    if let Err(e) = fs::hard_link(path, &target) {
+        if e.kind() == io::ErrorKind::AlreadyExists {
+            error!("{} is already archived", target.display());
+            Err(e)?
+        }
+        let mut archived = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&target)?;
+        io::copy(&mut File::open(path)?, &mut archived)?;
+    }
+    fs::remove_file(path)?;
+    Ok(())
 }
 
+mod blob;
+mod cache;
+mod check;
+mod compression;
+mod dump;
+mod encryption;
 mod kvs;
+mod lsm;
+mod memory;
+mod mmap;
+mod recovery;
+mod registry;
 mod sled;
 
-pub use self::kvs::KvStore;
+pub use self::check::{check, CheckReport, Repair};
+pub use self::compression::Compression;
+pub use self::dump::{dump, DumpFilter, DumpRecord};
+pub use self::encryption::{EncryptionKey, Keyring};
+pub use self::kvs::{KvStore, KvStoreOptions};
+pub use self::lsm::{LsmEngine, LsmOptions};
+pub use self::memory::{EvictionPolicy, MemoryEngine};
+pub use self::recovery::{recover, RecoveryReport, RecoveryTarget};
+pub use self::registry::{AnyEngine, EngineFactory, EngineOptions, EngineRegistry};
 pub use self::sled::SledServer;
@@ -0,0 +1,219 @@
+use super::blob::BlobFiles;
+use super::check::numbered_files;
+use super::kvs::{tree_dir, LogRecord, RecordCodec, RecordStream};
+use super::{check_tree_name, Compression, Keyring, KvsEngine};
+use crate::backup;
+use crate::{KvsErrorType, Operation, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// 时间点恢复的目标
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RecoveryTarget {
+    /// 恢复序号不大于该值的所有记录
+    Seq(u64),
+    /// 恢复写入时间不晚于该值（Unix 时间戳，毫秒）的所有记录
+    Time(u64),
+}
+
+impl RecoveryTarget {
+    /// 带有 seq 与 time 的记录是否在目标之后
+    fn passed(self, seq: u64, time: u64) -> bool {
+        match self {
+            RecoveryTarget::Seq(target) => seq > target,
+            RecoveryTarget::Time(target) => time > target,
+        }
+    }
+}
+
+/// 时间点恢复的结果
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// 基础备份对应的序号，没有基础备份时为 0
+    pub base_seq: u64,
+    /// 从基础备份中恢复的 key 数量
+    pub base_keys: u64,
+    /// 重放的记录数
+    pub replayed: u64,
+    /// 恢复到的序号
+    pub seq: u64,
+    /// 最后重放的记录的写入时间，没有重放记录时为空
+    pub time: Option<u64>,
+    /// 重放的记录的序号不连续时，第一个缺失的序号；此时缺失的修改（特别是删除）没有被恢复
+    pub gap: Option<u64>,
+    /// 各 keyspace 的恢复结果，keyspace 不从基础备份中恢复，总是从其第一条记录开始重放
+    pub keyspaces: BTreeMap<String, RecoveryReport>,
+}
+
+/// 将基础备份 backup 与 logs 中各目录下的数据文件重放到空的 engine 中，恢复到 until 所指的时刻
+///
+/// logs 通常为 KvStore 的归档目录，以及（若仍可用）其数据目录；所有目录中的数据文件按 id 的顺序读取，
+/// 只重放序号大于备份序号的记录，压缩复制的记录与没有序号的记录会被跳过。
+/// 没有基础备份时从第一条记录开始重放，因此归档需从创建数据库时开启
+///
+/// 各目录下 trees 目录中的 keyspace 同样被重放到 engine 中同名的 keyspace，只恢复在目标时刻已存在的 keyspace，
+/// 已删除的 keyspace 不会被恢复；keyspace 的序号与默认 keyspace 无关，按序号恢复时，
+/// keyspace 恢复到默认 keyspace 中最后重放的记录的写入时间，没有重放记录时不恢复 keyspace
+///
+/// 数据库加密时需要提供 keyring；engine 中已有数据或 keyspace 时返回 TargetNotEmpty Error，
+/// 备份不是来自 KvStore（没有序号）或已晚于目标序号时返回 InvalidArgument Error
+pub fn recover<E: KvsEngine>(
+    backup: Option<&Path>,
+    logs: &[PathBuf],
+    keyring: Option<Keyring>,
+    until: RecoveryTarget,
+    engine: &E,
+) -> Result<RecoveryReport> {
+    if engine.stats()?["keys"] != 0 || matches!(engine.tree_names(), Ok(names) if !names.is_empty())
+    {
+        Err(KvsErrorType::TargetNotEmpty)?
+    }
+    let mut report = RecoveryReport::default();
+    if let Some(path) = backup {
+        let manifest = backup::validate(path)?;
+        match (manifest.seq, until) {
+            (None, _) => Err(KvsErrorType::InvalidArgument)?,
+            (Some(seq), RecoveryTarget::Seq(target)) if seq > target => {
+                Err(KvsErrorType::InvalidArgument)?
+            }
+            (Some(seq), _) => report.base_seq = seq,
+        }
+        // keyspace 从其归档中完整重放，不从基础备份中恢复，否则之后删除的 keyspace 会被重新创建
+        backup::read(path, |keyspace, key, value| {
+            if keyspace.is_none() {
+                engine.set(key, value)?;
+                report.base_keys += 1;
+            }
+            Ok(())
+        })?;
+    }
+    report.seq = report.base_seq;
+
+    // 归档中可能有旧版本写入的数据文件，接受没有绑定附加数据的加密记录
+    let codec = RecordCodec {
+        compression: Compression::None,
+        keyring,
+        legacy: true,
+        checksummed: false,
+    };
+    replay(&codec, logs, until, &mut || Ok(engine.clone()), &mut report)?;
+
+    let until = match until {
+        RecoveryTarget::Time(_) => until,
+        RecoveryTarget::Seq(_) => match report.time {
+            Some(time) => RecoveryTarget::Time(time),
+            None => return Ok(report),
+        },
+    };
+    for name in keyspace_names(logs)? {
+        let tree_logs: Vec<_> = logs.iter().map(|dir| tree_dir(dir, &name)).collect();
+        let mut tree_report = RecoveryReport::default();
+        replay(
+            &codec,
+            &tree_logs,
+            until,
+            &mut || engine.open_tree(&name),
+            &mut tree_report,
+        )?;
+        if tree_report.replayed > 0 {
+            report.keyspaces.insert(name, tree_report);
+        }
+    }
+    Ok(report)
+}
+
+/// logs 中各目录下的 keyspace 名称，不包括已删除的 keyspace 的归档
+fn keyspace_names(logs: &[PathBuf]) -> Result<BTreeSet<String>> {
+    let mut names = BTreeSet::new();
+    for dir in logs {
+        let dir = dir.join("trees");
+        if !dir.exists() {
+            continue;
+        }
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            match name.to_str() {
+                Some(name) if check_tree_name(name).is_ok() => names.insert(name.to_owned()),
+                _ => false,
+            };
+        }
+    }
+    Ok(names)
+}
+
+/// 将 logs 中各目录下序号大于 report.seq 的记录按顺序重放，直到 until，并更新 report
+///
+/// 第一次需要写入时才通过 open 得到目标引擎
+fn replay<E: KvsEngine>(
+    codec: &RecordCodec,
+    logs: &[PathBuf],
+    until: RecoveryTarget,
+    open: &mut dyn FnMut() -> Result<E>,
+    report: &mut RecoveryReport,
+) -> Result<()> {
+    let mut target = None;
+    let blobs: Vec<_> = logs
+        .iter()
+        .map(|dir| BlobFiles::Dir(dir.join("blobs")))
+        .collect();
+    let mut files = Vec::new();
+    for dir in logs {
+        files.extend(numbered_files(dir, "log")?);
+    }
+    // 同一个数据文件可能同时出现在多个目录中
+    files.sort_by_key(|(id, _)| *id);
+    files.dedup_by_key(|(id, _)| *id);
+
+    for (_, path) in files {
+        let reader = BufReader::new(File::open(path)?);
+        for data in RecordStream::new(reader) {
+            let (stamp, record) = match codec.open_stamped(codec.parse(&data?)?)? {
+                (Some(stamp), record) if stamp.seq > report.seq => (stamp, record),
+                _ => continue,
+            };
+            if until.passed(stamp.seq, stamp.time) {
+                return Ok(());
+            }
+            if stamp.seq != report.seq + 1 && report.gap.is_none() {
+                report.gap = Some(report.seq + 1);
+            }
+            let engine = match &target {
+                Some(engine) => engine,
+                None => target.insert(open()?),
+            };
+            match resolve(codec, record, &blobs)? {
+                Operation::Set { key, value } => engine.set(key, value)?,
+                Operation::Remove { key } => match engine.remove(key) {
+                    Err(ref e) if e.kind() == KvsErrorType::KeyNotFound => {}
+                    result => result?,
+                },
+                _ => Err(KvsErrorType::Corrupted)?,
+            }
+            report.replayed += 1;
+            report.seq = stamp.seq;
+            report.time = Some(stamp.time);
+        }
+    }
+    Ok(())
+}
+
+/// 解码记录，blob 记录的 value 依次在各目录的 blob 文件中查找
+fn resolve(codec: &RecordCodec, record: LogRecord, blobs: &[BlobFiles]) -> Result<Operation> {
+    match record {
+        LogRecord::Blob { key, blob } => {
+            for files in blobs.iter().filter(|files| files.contains(blob.file)) {
+                let record = LogRecord::Blob {
+                    key: key.clone(),
+                    blob,
+                };
+                if let Ok(op) = codec.resolve(record, files) {
+                    return Ok(op);
+                }
+            }
+            Err(KvsErrorType::Corrupted)?
+        }
+        record => codec.resolve(record, &BlobFiles::Dir(PathBuf::new())),
+    }
+}
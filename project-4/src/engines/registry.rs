@@ -0,0 +1,417 @@
+use super::{
+    EncryptionKey, EvictionPolicy, Keyring, KvStore, KvStoreOptions, KvsEngine, LsmEngine,
+    LsmOptions, MemoryEngine, SledServer, Stats,
+};
+use crate::backup::BackupManifest;
+use crate::replication::{LogPosition, Replication};
+use crate::{KvsErrorType, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// 服务器工作目录中记录引擎类型的文件名
+const MARKER_FILE: &str = "server.cfg";
+
+/// 传给引擎工厂的选项，为 名称 -> 值 的映射，含义由各引擎决定
+pub type EngineOptions = BTreeMap<String, String>;
+
+/// 由服务器工作目录与选项创建引擎的工厂
+pub type EngineFactory = Box<dyn Fn(&Path, &EngineOptions) -> Result<AnyEngine> + Send + Sync>;
+
+/// KvsEngine 中可以通过 trait object 调用的部分
+trait DynEngine: Send + Sync {
+    fn set(&self, key: String, value: String) -> Result<()>;
+    fn get(&self, key: String) -> Result<Option<String>>;
+    fn remove(&self, key: String) -> Result<()>;
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool>;
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>>;
+    fn stats(&self) -> Result<Stats>;
+    fn get_type(&self) -> String;
+    fn replicate(&self, position: Option<LogPosition>) -> Result<Replication>;
+    fn backup(&self, path: &Path) -> Result<BackupManifest>;
+    fn leader(&self) -> Option<String>;
+    fn open_tree(&self, name: &str) -> Result<AnyEngine>;
+    fn drop_tree(&self, name: &str) -> Result<bool>;
+    fn tree_names(&self) -> Result<Vec<String>>;
+}
+
+impl<E: KvsEngine> DynEngine for E {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvsEngine::set(self, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvsEngine::get(self, key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvsEngine::remove(self, key)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        KvsEngine::compare_and_swap(self, key, expected, new)
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        KvsEngine::scan(self, prefix, after, limit)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        KvsEngine::stats(self)
+    }
+
+    fn get_type(&self) -> String {
+        KvsEngine::get_type(self)
+    }
+
+    fn replicate(&self, position: Option<LogPosition>) -> Result<Replication> {
+        KvsEngine::replicate(self, position)
+    }
+
+    fn backup(&self, path: &Path) -> Result<BackupManifest> {
+        KvsEngine::backup(self, path)
+    }
+
+    fn leader(&self) -> Option<String> {
+        KvsEngine::leader(self)
+    }
+
+    fn open_tree(&self, name: &str) -> Result<AnyEngine> {
+        Ok(AnyEngine::new(KvsEngine::open_tree(self, name)?))
+    }
+
+    fn drop_tree(&self, name: &str) -> Result<bool> {
+        KvsEngine::drop_tree(self, name)
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        KvsEngine::tree_names(self)
+    }
+}
+
+/// 类型擦除后的引擎，使运行时选择的引擎可以用于泛型的 KvsServer
+#[derive(Clone)]
+pub struct AnyEngine {
+    inner: Arc<dyn DynEngine>,
+}
+
+impl AnyEngine {
+    /// 包装 engine
+    pub fn new<E: KvsEngine>(engine: E) -> AnyEngine {
+        AnyEngine {
+            inner: Arc::new(engine),
+        }
+    }
+}
+
+impl KvsEngine for AnyEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.inner.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.inner.remove(key)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.inner.compare_and_swap(key, expected, new)
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.inner.scan(prefix, after, limit)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        self.inner.stats()
+    }
+
+    fn get_type(&self) -> String {
+        self.inner.get_type()
+    }
+
+    fn replicate(&self, position: Option<LogPosition>) -> Result<Replication> {
+        self.inner.replicate(position)
+    }
+
+    fn backup(&self, path: &Path) -> Result<BackupManifest> {
+        self.inner.backup(path)
+    }
+
+    fn leader(&self) -> Option<String> {
+        self.inner.leader()
+    }
+
+    fn open_tree(&self, name: &str) -> Result<AnyEngine> {
+        self.inner.open_tree(name)
+    }
+
+    fn drop_tree(&self, name: &str) -> Result<bool> {
+        self.inner.drop_tree(name)
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        self.inner.tree_names()
+    }
+}
+
+/// 由选项得到 KvStore 的参数
+fn kvs_options(options: &EngineOptions) -> Result<KvStoreOptions> {
+    let compression = match options.get("compression") {
+        Some(compression) => Some(compression.parse()?),
+        None => None,
+    };
+    let keyring = match options.get("encryption-key") {
+        Some(source) => {
+            let mut keyring = Keyring::new(EncryptionKey::load(source)?);
+            if let Some(sources) = options.get("previous-keys") {
+                for source in sources.split(',') {
+                    keyring = keyring.with_previous(EncryptionKey::load(source)?);
+                }
+            }
+            Some(keyring)
+        }
+        None => None,
+    };
+    let blob_threshold = match options.get("blob-threshold") {
+        Some(threshold) => Some(
+            threshold
+                .parse()
+                .map_err(|_| KvsErrorType::InvalidArgument)?,
+        ),
+        None => None,
+    };
+    let cache_size = match options.get("cache-size") {
+        Some(size) => Some(size.parse().map_err(|_| KvsErrorType::InvalidArgument)?),
+        None => None,
+    };
+    let buffered_reads = match options.get("buffered-reads") {
+        Some(buffered) => buffered
+            .parse()
+            .map_err(|_| KvsErrorType::InvalidArgument)?,
+        None => false,
+    };
+    Ok(KvStoreOptions {
+        compression,
+        keyring,
+        blob_threshold,
+        cache_size,
+        buffered_reads,
+        archive: options.get("archive").map(PathBuf::from),
+    })
+}
+
+/// 注册的引擎
+struct Registration {
+    type_id: String,
+    factory: EngineFactory,
+    /// 数据是否只保存在内存中
+    volatile: bool,
+}
+
+/// 引擎注册表，按名称创建引擎
+///
+/// 每个引擎注册一个名称、一个写入服务器工作目录中 `server.cfg` 的类型标识，
+/// 以及由工作目录与选项创建引擎的工厂，引擎应将数据保存在工作目录中以自身名称命名的子目录中
+///
+/// 使用方法：
+///
+/// ```no_run
+/// # use kvs::engines::{AnyEngine, EngineOptions, EngineRegistry, KvStore};
+/// # use std::path::Path;
+/// let mut registry = EngineRegistry::builtin();
+/// registry.register("my-kvs", "my-kvs", |dir, _| Ok(AnyEngine::new(KvStore::open(dir.join("my-kvs"))?)));
+/// let engine = registry.open("my-kvs", Path::new("."), &EngineOptions::new()).unwrap();
+/// ```
+#[derive(Default)]
+pub struct EngineRegistry {
+    engines: BTreeMap<String, Registration>,
+}
+
+impl EngineRegistry {
+    /// 创建空的注册表
+    pub fn new() -> EngineRegistry {
+        EngineRegistry::default()
+    }
+
+    /// 创建注册了内置引擎的注册表
+    ///
+    /// - `kvs`、`sled`：数据保存在工作目录中同名的子目录中，
+    ///   `kvs` 的选项 `compression` 为 value 的压缩算法（`none`、`lz4` 或 `zstd`），只能在创建时指定；
+    ///   `encryption-key` 为加密数据文件的密钥（密钥文件路径或 `env:环境变量名`），
+    ///   `previous-keys` 为以逗号分隔的轮换前的旧密钥，
+    ///   `blob-threshold` 为单独保存在 blob 文件中的 value 的最小长度（字节），
+    ///   `cache-size` 为读缓存的容量（字节，0 表示不缓存），
+    ///   `buffered-reads` 为 `true` 时不使用内存映射读取数据文件，
+    ///   `archive` 为归档旧数据文件的目录
+    /// - `lsm`：数据保存在工作目录中的 `lsm` 子目录中，选项 `memtable-size` 为 memtable 的大小上限（字节）
+    /// - `memory`：数据只保存在内存中，选项 `max-memory` 为内存上限（字节），
+    ///   `eviction` 为淘汰策略（`lru` 或 `lfu`，默认为 `lru`）
+    pub fn builtin() -> EngineRegistry {
+        let mut registry = EngineRegistry::new();
+        registry.register("kvs", "kvs", |dir, options| {
+            Ok(AnyEngine::new(KvStore::open_with(
+                dir.join("kvs"),
+                kvs_options(options)?,
+            )?))
+        });
+        registry.register("sled", "sled", |dir, _| {
+            Ok(AnyEngine::new(SledServer::new(sled::Db::start_default(
+                dir.join("sled"),
+            )?)))
+        });
+        registry.register("lsm", "lsm", |dir, options| {
+            let mut lsm_options = LsmOptions::default();
+            if let Some(size) = options.get("memtable-size") {
+                lsm_options.memtable_size =
+                    size.parse().map_err(|_| KvsErrorType::InvalidArgument)?;
+            }
+            Ok(AnyEngine::new(LsmEngine::open_with(
+                dir.join("lsm"),
+                lsm_options,
+            )?))
+        });
+        registry.register_volatile("memory", "memory", |_, options| {
+            let policy = match options.get("eviction") {
+                Some(policy) => policy.parse()?,
+                None => EvictionPolicy::Lru,
+            };
+            match options.get("max-memory") {
+                Some(max_memory) => {
+                    let max_memory = max_memory
+                        .parse()
+                        .map_err(|_| KvsErrorType::InvalidArgument)?;
+                    Ok(AnyEngine::new(MemoryEngine::with_limit(max_memory, policy)))
+                }
+                None => Ok(AnyEngine::new(MemoryEngine::new())),
+            }
+        });
+        registry
+    }
+
+    /// 注册名为 name 的引擎，已存在时替换
+    pub fn register<F>(&mut self, name: &str, type_id: &str, factory: F)
+    where
+        F: Fn(&Path, &EngineOptions) -> Result<AnyEngine> + Send + Sync + 'static,
+    {
+        self.insert(name, type_id, Box::new(factory), false);
+    }
+
+    /// 注册名为 name 的引擎，其数据只保存在内存中，进程退出后丢失
+    pub fn register_volatile<F>(&mut self, name: &str, type_id: &str, factory: F)
+    where
+        F: Fn(&Path, &EngineOptions) -> Result<AnyEngine> + Send + Sync + 'static,
+    {
+        self.insert(name, type_id, Box::new(factory), true);
+    }
+
+    fn insert(&mut self, name: &str, type_id: &str, factory: EngineFactory, volatile: bool) {
+        self.engines.insert(
+            name.to_owned(),
+            Registration {
+                type_id: type_id.to_owned(),
+                factory,
+                volatile,
+            },
+        );
+    }
+
+    /// 名为 name 的引擎是否只将数据保存在内存中，引擎未注册时返回 UnknownEngine Error
+    pub fn is_volatile(&self, name: &str) -> Result<bool> {
+        Ok(self.registration(name)?.volatile)
+    }
+
+    /// 所有已注册引擎的名称
+    pub fn names(&self) -> Vec<&str> {
+        self.engines.keys().map(String::as_str).collect()
+    }
+
+    /// 类型标识为 type_id 的引擎的名称
+    pub fn name_of_type(&self, type_id: &str) -> Option<&str> {
+        self.engines
+            .iter()
+            .find(|(_, registration)| registration.type_id == type_id)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// 工作目录 dir 中 `server.cfg` 记录的引擎类型标识，不存在时返回 None
+    pub fn marker(dir: &Path) -> Result<Option<String>> {
+        let path = dir.join(MARKER_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read_to_string(path)?))
+    }
+
+    /// 将 name 对应的类型标识写入工作目录 dir 中的 `server.cfg`，先写入临时文件再替换
+    pub fn write_marker(&self, name: &str, dir: &Path) -> Result<()> {
+        let type_id = &self.registration(name)?.type_id;
+        let path = dir.join(MARKER_FILE);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, type_id)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// 在工作目录 dir 中创建名为 name 的引擎，不检查 `server.cfg`
+    ///
+    /// 引擎未注册时返回 UnknownEngine Error
+    pub fn create(&self, name: &str, dir: &Path, options: &EngineOptions) -> Result<AnyEngine> {
+        (self.registration(name)?.factory)(dir, options)
+    }
+
+    /// 在工作目录 dir 中打开名为 name 的引擎
+    ///
+    /// `server.cfg` 不存在时写入该引擎的类型标识，
+    /// 记录的是其他引擎时返回 WrongEngine Error，引擎未注册时返回 UnknownEngine Error
+    pub fn open(&self, name: &str, dir: &Path, options: &EngineOptions) -> Result<AnyEngine> {
+        let type_id = &self.registration(name)?.type_id;
+        match EngineRegistry::marker(dir)? {
+            Some(ref marker) if marker != type_id => Err(KvsErrorType::WrongEngine)?,
+            Some(_) => {}
+            None => self.write_marker(name, dir)?,
+        }
+        self.create(name, dir, options)
+    }
+
+    fn registration(&self, name: &str) -> Result<&Registration> {
+        match self.engines.get(name) {
+            Some(registration) => Ok(registration),
+            None => Err(KvsErrorType::UnknownEngine)?,
+        }
+    }
+}
@@ -1,17 +1,62 @@
-use super::KvsEngine;
+use super::{check_tree_name, KvsEngine, Stats};
+use crate::backup::{BackupManifest, BackupWriter};
 use crate::{KvsError, KvsErrorType, Result};
-use sled::Db;
+use sled::{Db, Tree};
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// keyspace 对应的 sled tree 名称的前缀，与 sled 内部使用的 tree 区分
+const TREE_PREFIX: &str = "keyspace/";
 
 /// 以 sled 为核心的引擎
+///
+/// 每个 keyspace 是同一个 sled db 中的一个 tree
 #[derive(Clone)]
 pub struct SledServer {
     db: Db,
+    /// 所在的 keyspace，为空时为默认 keyspace
+    tree: Option<Arc<Tree>>,
+    /// 作为 keyspace 时，其是否已被删除
+    dropped: Option<Arc<AtomicBool>>,
+    /// 已打开的 keyspace 的删除标记
+    trees: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+    /// 写操作持有读锁，备份持有写锁，使备份期间的数据保持不变
+    backup_lock: Arc<RwLock<()>>,
 }
 
 impl SledServer {
     /// 由 sled db 创建一个对象
     pub fn new(db: Db) -> Self {
-        SledServer { db }
+        SledServer {
+            db,
+            tree: None,
+            dropped: None,
+            trees: Arc::default(),
+            backup_lock: Arc::new(RwLock::new(())),
+        }
+    }
+
+    /// 当前 keyspace 对应的 tree，keyspace 已被删除时返回 KeyspaceNotFound Error
+    fn tree(&self) -> Result<&Tree> {
+        if matches!(&self.dropped, Some(dropped) if dropped.load(Ordering::SeqCst)) {
+            Err(KvsErrorType::KeyspaceNotFound)?
+        }
+        Ok(match &self.tree {
+            Some(tree) => tree,
+            None => &self.db,
+        })
+    }
+
+    /// 检查 keyspace 名称，keyspace 中不能再打开 keyspace
+    fn check_tree(&self, name: &str) -> Result<()> {
+        check_tree_name(name)?;
+        if self.tree.is_some() {
+            Err(KvsErrorType::InvalidKeyspace)?
+        }
+        Ok(())
     }
 }
 
@@ -22,8 +67,9 @@ impl KvsEngine for SledServer {
     ///
     /// key 不存在则会创建一个新的键值对
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.set(key, value.into_bytes()).unwrap();
-        self.db.flush().unwrap();
+        let _guard = self.backup_lock.read().unwrap();
+        self.tree()?.set(key, value.into_bytes()).unwrap();
+        self.tree()?.flush().unwrap();
         Ok(())
     }
 
@@ -31,7 +77,7 @@ impl KvsEngine for SledServer {
     ///
     /// 不存在会返回 None
     fn get(&self, key: String) -> Result<Option<String>> {
-        let buf = self.db.get(key)?;
+        let buf = self.tree()?.get(key)?;
         match buf {
             Some(vec) => Ok(Some(String::from_utf8(vec.to_vec()).unwrap())),
             None => return Ok(None),
@@ -42,16 +88,146 @@ impl KvsEngine for SledServer {
     ///
     /// 不存在会返回 KeyNotFound Error
     fn remove(&self, key: String) -> Result<()> {
-        self.db
+        let _guard = self.backup_lock.read().unwrap();
+        self.tree()?
             .del(key)
             .unwrap()
             .ok_or(KvsError::from(KvsErrorType::KeyNotFound))?;
-        self.db.flush().unwrap();
+        self.tree()?.flush().unwrap();
         Ok(())
     }
 
+    /// 比较并交换
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let _guard = self.backup_lock.read().unwrap();
+        let swapped = self
+            .tree()?
+            .cas(key, expected, new.map(String::into_bytes))?
+            .is_ok();
+        if swapped {
+            self.tree()?.flush()?;
+        }
+        Ok(swapped)
+    }
+
+    /// 按 key 从小到大的顺序获取以 prefix 开头的键值对
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let start = match &after {
+            Some(after) if after >= &prefix => Bound::Excluded(after.as_bytes()),
+            _ => Bound::Included(prefix.as_bytes()),
+        };
+        let mut pairs = Vec::new();
+        for item in self.tree()?.range::<&[u8], _>((start, Bound::Unbounded)) {
+            let (key, value) = item?;
+            let key = String::from_utf8(key.to_vec()).map_err(|_| KvsErrorType::SerdeError)?;
+            if pairs.len() >= limit || !key.starts_with(&prefix) {
+                break;
+            }
+            let value = String::from_utf8(value.to_vec()).map_err(|_| KvsErrorType::SerdeError)?;
+            pairs.push((key, value));
+        }
+        Ok(pairs)
+    }
+
+    /// 获取统计信息
+    fn stats(&self) -> Result<Stats> {
+        let mut stats = Stats::new();
+        stats.insert("keys".to_owned(), self.tree()?.len() as u64);
+        Ok(stats)
+    }
+
+    /// 备份当前数据及所有 keyspace
+    ///
+    /// sled 的迭代器不是快照，因此备份期间会阻塞所有 keyspace 的写操作与删除，读操作不受影响
+    fn backup(&self, path: &Path) -> Result<BackupManifest> {
+        let _guard = self.backup_lock.write().unwrap();
+        let mut backup = BackupWriter::create(path)?;
+        backup_tree(self.tree()?, &mut backup)?;
+        for name in self.tree_names()? {
+            let tree = self
+                .db
+                .open_tree(format!("{}{}", TREE_PREFIX, name).into_bytes())?;
+            backup.keyspace(&name)?;
+            backup_tree(&tree, &mut backup)?;
+        }
+        backup.finish(&self.get_type())
+    }
+
     /// 获取 engine 的类型 (sled)
     fn get_type(&self) -> String {
         String::from("sled")
     }
+
+    /// 打开名为 name 的 keyspace，不存在时创建
+    fn open_tree(&self, name: &str) -> Result<SledServer> {
+        self.check_tree(name)?;
+        let tree = self
+            .db
+            .open_tree(format!("{}{}", TREE_PREFIX, name).into_bytes())?;
+        let dropped = self
+            .trees
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .clone();
+        Ok(SledServer {
+            db: self.db.clone(),
+            tree: Some(tree),
+            dropped: Some(dropped),
+            trees: Arc::default(),
+            backup_lock: Arc::clone(&self.backup_lock),
+        })
+    }
+
+    /// 删除名为 name 的 keyspace 及其中的所有数据，返回其是否存在
+    ///
+    /// 之后通过该 keyspace 已打开的实例进行的操作返回 KeyspaceNotFound Error
+    fn drop_tree(&self, name: &str) -> Result<bool> {
+        self.check_tree(name)?;
+        let _guard = self.backup_lock.read().unwrap();
+        if let Some(dropped) = self.trees.lock().unwrap().remove(name) {
+            dropped.store(true, Ordering::SeqCst);
+        }
+        Ok(self
+            .db
+            .drop_tree(format!("{}{}", TREE_PREFIX, name).as_bytes())?)
+    }
+
+    /// 按名称排序的所有 keyspace
+    fn tree_names(&self) -> Result<Vec<String>> {
+        if self.tree.is_some() {
+            return Ok(Vec::new());
+        }
+        let mut names: Vec<_> = self
+            .db
+            .tree_names()
+            .into_iter()
+            .filter_map(|name| String::from_utf8(name.to_vec()).ok())
+            .filter_map(|name| name.strip_prefix(TREE_PREFIX).map(str::to_owned))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// 将 tree 中的键值对写入 backup
+fn backup_tree(tree: &Tree, backup: &mut BackupWriter) -> Result<()> {
+    for item in tree.iter() {
+        let (key, value) = item?;
+        let key = String::from_utf8(key.to_vec()).map_err(|_| KvsErrorType::SerdeError)?;
+        let value = String::from_utf8(value.to_vec()).map_err(|_| KvsErrorType::SerdeError)?;
+        backup.add(key, value)?;
+    }
+    Ok(())
 }
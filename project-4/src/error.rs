@@ -26,6 +26,45 @@ pub enum KvsErrorType {
     /// sled 错误
     #[fail(display = "SledError")]
     SledError,
+    /// TLS 错误（证书、握手）
+    #[fail(display = "TlsError")]
+    TlsError,
+    /// 认证失败或无权执行该操作
+    #[fail(display = "PermissionDenied")]
+    PermissionDenied,
+    /// 只读节点（如从节点）拒绝写操作
+    #[fail(display = "ReadOnly")]
+    ReadOnly,
+    /// 集群中的非 leader 节点无法处理该请求
+    #[fail(display = "NotLeader")]
+    NotLeader,
+    /// 数据损坏或不完整（如校验和不符）
+    #[fail(display = "Corrupted")]
+    Corrupted,
+    /// 引擎未注册
+    #[fail(display = "UnknownEngine")]
+    UnknownEngine,
+    /// 数据目录中的引擎与所选引擎不符
+    #[fail(display = "WrongEngine")]
+    WrongEngine,
+    /// 打开时指定的设置（如压缩算法）与数据目录中记录的不符
+    #[fail(display = "OptionsMismatch")]
+    OptionsMismatch,
+    /// keyspace 名称不合法
+    #[fail(display = "InvalidKeyspace")]
+    InvalidKeyspace,
+    /// keyspace 不存在或已被删除
+    #[fail(display = "KeyspaceNotFound")]
+    KeyspaceNotFound,
+    /// 参数不合法（如无法解析的选项、顺序不正确的输入）
+    #[fail(display = "InvalidArgument")]
+    InvalidArgument,
+    /// 要写入的目标引擎中已有数据
+    #[fail(display = "TargetNotEmpty")]
+    TargetNotEmpty,
+    /// gRPC 请求失败
+    #[fail(display = "GrpcError")]
+    GrpcError,
     /// 其他错误
     #[fail(display = "Other")]
     Other,
@@ -74,6 +113,12 @@ impl From<std::io::Error> for KvsError {
     }
 }
 
+impl From<serde_json::Error> for KvsError {
+    fn from(_: serde_json::Error) -> KvsError {
+        KvsErrorType::SerdeError.into()
+    }
+}
+
 impl From<sled::Error> for KvsError {
     fn from(_: sled::Error) -> KvsError {
         KvsErrorType::SledError.into()
@@ -81,5 +126,34 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<rustls::Error> for KvsError {
+    fn from(e: rustls::Error) -> KvsError {
+        error!("{}", e);
+        KvsErrorType::TlsError.into()
+    }
+}
+
+impl From<tonic::transport::Error> for KvsError {
+    fn from(e: tonic::transport::Error) -> KvsError {
+        error!("{}", e);
+        KvsErrorType::GrpcError.into()
+    }
+}
+
+impl From<tonic::Status> for KvsError {
+    fn from(status: tonic::Status) -> KvsError {
+        match status.code() {
+            tonic::Code::NotFound => KvsErrorType::KeyNotFound.into(),
+            tonic::Code::Unauthenticated | tonic::Code::PermissionDenied => {
+                KvsErrorType::PermissionDenied.into()
+            }
+            _ => {
+                error!("{}", status);
+                KvsErrorType::GrpcError.into()
+            }
+        }
+    }
+}
+
 /// 别名，用于简化使用 KvsError 的 Result
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -0,0 +1,162 @@
+//! 导入导出模块
+//!
+//! 以通用的文本格式导出与导入键值对，便于在引擎之间或与其他系统之间迁移数据：
+//!
+//! - `jsonl`：每行一个 `{"key":"...","value":"..."}`
+//! - `csv`：首行为 `key,value` 表头，字段按 RFC 4180 的规则加引号
+
+use crate::{KvsErrorType, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+/// 导入导出的格式
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    /// JSON Lines
+    Jsonl,
+    /// CSV
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = crate::KvsError;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(KvsErrorType::InvalidArgument)?,
+        }
+    }
+}
+
+/// jsonl 格式中的一行
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+}
+
+/// 导出写入器
+pub struct ExportWriter<W: Write> {
+    writer: W,
+    format: Format,
+}
+
+impl<W: Write> ExportWriter<W> {
+    /// 创建以 format 格式写入 writer 的导出写入器，csv 格式会先写入表头
+    pub fn new(mut writer: W, format: Format) -> Result<ExportWriter<W>> {
+        if format == Format::Csv {
+            writer.write_all(b"key,value\r\n")?;
+        }
+        Ok(ExportWriter { writer, format })
+    }
+
+    /// 写入一个键值对
+    pub fn add(&mut self, key: String, value: String) -> Result<()> {
+        match self.format {
+            Format::Jsonl => {
+                serde_json::to_writer(&mut self.writer, &Record { key, value })?;
+                self.writer.write_all(b"\n")?;
+            }
+            Format::Csv => {
+                write!(self.writer, "{},{}\r\n", csv_field(&key), csv_field(&value))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 完成导出，返回 writer
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// 按需为 csv 字段加引号
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// 读取 csv 中的一条记录，带引号的字段中可以包含换行
+///
+/// 读到末尾时返回 None，格式错误时返回 Corrupted Error
+fn read_csv_record<R: BufRead>(reader: &mut R) -> Result<Option<Vec<String>>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    loop {
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            let field = fields.last_mut().unwrap();
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' if quoted => quoted = false,
+                '"' if field.is_empty() => quoted = true,
+                ',' if !quoted => fields.push(String::new()),
+                '\r' | '\n' if !quoted => break,
+                c => field.push(c),
+            }
+        }
+        if !quoted {
+            return Ok(Some(fields));
+        }
+        // 引号内的换行属于字段内容，继续读取下一行
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            Err(KvsErrorType::Corrupted)?
+        }
+    }
+}
+
+/// 以 format 格式从 reader 中读取键值对，依次对每个键值对调用 f，返回键值对的数量
+///
+/// 格式错误时返回 Corrupted Error，此时 f 可能已被调用
+pub fn read<R, F>(mut reader: R, format: Format, mut f: F) -> Result<u64>
+where
+    R: BufRead,
+    F: FnMut(String, String) -> Result<()>,
+{
+    let mut count = 0;
+    match format {
+        Format::Jsonl => {
+            for record in serde_json::Deserializer::from_reader(reader).into_iter::<Record>() {
+                let record = record.map_err(|_| KvsErrorType::Corrupted)?;
+                f(record.key, record.value)?;
+                count += 1;
+            }
+        }
+        Format::Csv => {
+            match read_csv_record(&mut reader)? {
+                Some(ref header) if header == &["key", "value"] => {}
+                None => return Ok(0),
+                Some(_) => Err(KvsErrorType::Corrupted)?,
+            }
+            while let Some(mut fields) = read_csv_record(&mut reader)? {
+                // 忽略空行
+                if fields.len() == 1 && fields[0].is_empty() {
+                    continue;
+                }
+                if fields.len() != 2 {
+                    Err(KvsErrorType::Corrupted)?
+                }
+                let value = fields.pop().unwrap();
+                let key = fields.pop().unwrap();
+                f(key, value)?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
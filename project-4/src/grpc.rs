@@ -0,0 +1,386 @@
+//! gRPC 接口
+//!
+//! 接口定义位于 `proto/kvs.proto`，服务端由 `KvsServer::grpc` 开启，
+//! 与主协议共用引擎、线程池与访问控制
+//!
+//! 开启访问控制时，请求需带有 `authorization` 元数据，格式与 HTTP Basic 认证相同
+//!
+//! 使用方法：
+//!
+//! ```no_run
+//! # use kvs::grpc::KvsGrpcClient;
+//! let mut client = KvsGrpcClient::connect("127.0.0.1:4002".to_owned()).unwrap();
+//! client.set("key".to_owned(), "value".to_owned()).unwrap();
+//! client.get("key".to_owned()).unwrap();
+//! client.scan("k".to_owned(), None, 10).unwrap();
+//! client.remove("key".to_owned()).unwrap();
+//! ```
+
+use self::proto::batch_operation::Op;
+use self::proto::batch_result::Result as BatchResult;
+use self::proto::kvs_client::KvsClient as Client;
+use self::proto::kvs_server::{Kvs, KvsServer as Server};
+use self::proto::*;
+use crate::acl::{Acl, Permission};
+use crate::engines::KvsEngine;
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, KvsErrorType, Operation, Result};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Response, Status};
+
+/// 由 `proto/kvs.proto` 生成的消息与服务定义
+#[allow(missing_docs, clippy::all)]
+pub mod proto {
+    tonic::include_proto!("kvs");
+}
+
+/// Scan 未指定 limit 时最多返回的键值对数量
+const DEFAULT_SCAN_LIMIT: usize = 1000;
+
+/// gRPC 服务，引擎操作交由线程池执行
+struct KvsService<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    acl: Option<Arc<Acl>>,
+    thread_pool: Arc<P>,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> KvsService<E, P> {
+    /// 认证请求，并检查是否允许对每个 key 执行对应的操作
+    #[allow(clippy::result_large_err)]
+    fn check<T>(
+        &self,
+        request: &Request<T>,
+        ops: &[(Permission, &str)],
+    ) -> std::result::Result<(), Status> {
+        let acl = match &self.acl {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+        let principal = request
+            .metadata()
+            .get("authorization")
+            .and_then(|auth| auth.to_str().ok())
+            .and_then(|auth| acl.authenticate_basic(auth).ok())
+            .ok_or_else(|| Status::unauthenticated("Unauthorized"))?;
+        if ops
+            .iter()
+            .all(|(permission, key)| principal.allows(*permission, key))
+        {
+            Ok(())
+        } else {
+            warn!("permission denied");
+            Err(Status::permission_denied("PermissionDenied"))
+        }
+    }
+
+    /// 在线程池中使用引擎执行 f，并等待其结果
+    async fn call<T, F>(&self, f: F) -> std::result::Result<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let engine = self.engine.clone();
+        self.thread_pool.spawn(move || {
+            let _ = tx.send(f(engine));
+        });
+        match rx.await {
+            Ok(result) => result.map_err(to_status),
+            Err(_) => Err(Status::internal("request aborted")),
+        }
+    }
+}
+
+/// 将引擎的错误转换为 gRPC 状态
+fn to_status(e: KvsError) -> Status {
+    match e.kind() {
+        KvsErrorType::KeyNotFound => Status::not_found("Key not found"),
+        _ => Status::internal(format!("{}", e)),
+    }
+}
+
+/// 在引擎上执行一个批量操作
+fn apply<E: KvsEngine>(engine: &E, op: Op) -> Result<BatchResult> {
+    Ok(match op {
+        Op::Get(GetRequest { key }) => BatchResult::Get(match engine.get(key)? {
+            Some(value) => GetReply { found: true, value },
+            None => GetReply::default(),
+        }),
+        Op::Set(SetRequest { key, value }) => {
+            engine.set(key, value)?;
+            BatchResult::Set(SetReply {})
+        }
+        Op::Remove(RemoveRequest { key }) => {
+            engine.remove(key)?;
+            BatchResult::Remove(RemoveReply {})
+        }
+    })
+}
+
+#[tonic::async_trait]
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> Kvs for KvsService<E, P> {
+    async fn get(
+        &self,
+        request: Request<GetRequest>,
+    ) -> std::result::Result<Response<GetReply>, Status> {
+        self.check(&request, &[(Permission::Get, &request.get_ref().key)])?;
+        let GetRequest { key } = request.into_inner();
+        let value = self.call(move |engine| engine.get(key)).await?;
+        Ok(Response::new(match value {
+            Some(value) => GetReply { found: true, value },
+            None => GetReply::default(),
+        }))
+    }
+
+    async fn set(
+        &self,
+        request: Request<SetRequest>,
+    ) -> std::result::Result<Response<SetReply>, Status> {
+        self.check(&request, &[(Permission::Set, &request.get_ref().key)])?;
+        let SetRequest { key, value } = request.into_inner();
+        self.call(move |engine| engine.set(key, value)).await?;
+        Ok(Response::new(SetReply {}))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<RemoveRequest>,
+    ) -> std::result::Result<Response<RemoveReply>, Status> {
+        self.check(&request, &[(Permission::Remove, &request.get_ref().key)])?;
+        let RemoveRequest { key } = request.into_inner();
+        self.call(move |engine| engine.remove(key)).await?;
+        Ok(Response::new(RemoveReply {}))
+    }
+
+    async fn scan(
+        &self,
+        request: Request<ScanRequest>,
+    ) -> std::result::Result<Response<ScanReply>, Status> {
+        self.check(&request, &[(Permission::Get, &request.get_ref().prefix)])?;
+        let ScanRequest {
+            prefix,
+            after,
+            limit,
+        } = request.into_inner();
+        let after = if after.is_empty() { None } else { Some(after) };
+        let limit = match limit as usize {
+            0 => DEFAULT_SCAN_LIMIT,
+            limit => limit,
+        };
+        let pairs = self
+            .call(move |engine| engine.scan(prefix, after, limit))
+            .await?;
+        // 返回数量达到 limit 时，可能还有更多的 key
+        let next = match pairs.last() {
+            Some((key, _)) if pairs.len() == limit => key.clone(),
+            _ => String::new(),
+        };
+        Ok(Response::new(ScanReply {
+            pairs: pairs
+                .into_iter()
+                .map(|(key, value)| KeyValue { key, value })
+                .collect(),
+            next,
+        }))
+    }
+
+    async fn batch(
+        &self,
+        request: Request<BatchRequest>,
+    ) -> std::result::Result<Response<BatchReply>, Status> {
+        let mut ops = Vec::new();
+        for op in &request.get_ref().ops {
+            ops.push(match &op.op {
+                Some(Op::Get(GetRequest { key })) => (Permission::Get, key.as_str()),
+                Some(Op::Set(SetRequest { key, .. })) => (Permission::Set, key.as_str()),
+                Some(Op::Remove(RemoveRequest { key })) => (Permission::Remove, key.as_str()),
+                None => return Err(Status::invalid_argument("empty operation")),
+            });
+        }
+        self.check(&request, &ops)?;
+        let ops = request.into_inner().ops;
+        // 操作依次执行，遇到错误时停止，之前的操作不会被撤销
+        let results = self
+            .call(move |engine| {
+                ops.into_iter()
+                    .filter_map(|op| op.op)
+                    .map(|op| apply(&engine, op))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .await?;
+        Ok(Response::new(BatchReply {
+            results: results
+                .into_iter()
+                .map(|result| proto::BatchResult {
+                    result: Some(result),
+                })
+                .collect(),
+        }))
+    }
+}
+
+/// 在新线程中启动 gRPC 服务，接收 listener 上的连接
+pub(crate) fn serve<E: KvsEngine, P: ThreadPool + Send + Sync + 'static>(
+    engine: E,
+    acl: Option<Arc<Acl>>,
+    thread_pool: Arc<P>,
+    listener: TcpListener,
+) -> Result<()> {
+    let runtime = Runtime::new()?;
+    listener.set_nonblocking(true)?;
+    let service = KvsService {
+        engine,
+        acl,
+        thread_pool,
+    };
+    thread::spawn(move || {
+        let result = runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            tonic::transport::Server::builder()
+                .add_service(Server::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+                .map_err(KvsError::from)
+        });
+        if let Err(e) = result {
+            error!("{}", e);
+        }
+    });
+    Ok(())
+}
+
+/// 通过 gRPC 进行数据库操作的客户端
+///
+/// 接口是同步的，内部使用单线程的 tokio 运行时
+pub struct KvsGrpcClient {
+    runtime: Runtime,
+    client: Client<Channel>,
+    authorization: Option<MetadataValue<Ascii>>,
+}
+
+impl KvsGrpcClient {
+    /// 连接至地址为 addr 的服务器
+    pub fn connect(addr: String) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let endpoint = Endpoint::from_shared(format!("http://{}", addr))?;
+        let client = Client::new(runtime.block_on(endpoint.connect())?);
+        Ok(KvsGrpcClient {
+            runtime,
+            client,
+            authorization: None,
+        })
+    }
+
+    /// 之后的请求均以 user 的身份进行
+    ///
+    /// 认证在每个请求中进行，token 错误时请求会返回 PermissionDenied
+    pub fn auth(&mut self, user: String, token: String) -> Result<()> {
+        let credentials = base64::encode(&format!("{}:{}", user, token));
+        let value = format!("Basic {}", credentials)
+            .parse()
+            .map_err(|_| KvsErrorType::PermissionDenied)?;
+        self.authorization = Some(value);
+        Ok(())
+    }
+
+    /// 向服务器请求 key 所对应的 value
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let request = self.request(GetRequest { key });
+        let reply = self.runtime.block_on(self.client.get(request))?;
+        let GetReply { found, value } = reply.into_inner();
+        Ok(if found { Some(value) } else { None })
+    }
+
+    /// 向服务器请求设置键值对
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let request = self.request(SetRequest { key, value });
+        self.runtime.block_on(self.client.set(request))?;
+        Ok(())
+    }
+
+    /// 向服务器请求删除 key
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let request = self.request(RemoveRequest { key });
+        self.runtime.block_on(self.client.remove(request))?;
+        Ok(())
+    }
+
+    /// 按 key 的顺序获取以 prefix 开头且大于 after 的键值对，最多 limit 个
+    pub fn scan(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let request = self.request(ScanRequest {
+            prefix,
+            after: after.unwrap_or_default(),
+            limit: limit as u32,
+        });
+        let reply = self.runtime.block_on(self.client.scan(request))?;
+        Ok(reply
+            .into_inner()
+            .pairs
+            .into_iter()
+            .map(|pair| (pair.key, pair.value))
+            .collect())
+    }
+
+    /// 在一个请求中依次执行 ops 中的操作
+    ///
+    /// 返回每个操作的结果，Get 操作为对应的 value，其余为 None
+    ///
+    /// 某个操作失败时返回该错误，之前的操作已经生效
+    pub fn batch(&mut self, ops: Vec<Operation>) -> Result<Vec<Option<String>>> {
+        let ops = ops
+            .into_iter()
+            .map(|op| {
+                let op = match op {
+                    Operation::Get { key } => Op::Get(GetRequest { key }),
+                    Operation::Set { key, value } => Op::Set(SetRequest { key, value }),
+                    Operation::Remove { key } => Op::Remove(RemoveRequest { key }),
+                    Operation::Auth { .. }
+                    | Operation::Scan { .. }
+                    | Operation::Backup { .. }
+                    | Operation::Replicate { .. }
+                    | Operation::Keyspace { .. }
+                    | Operation::DropKeyspace { .. } => {
+                        return Err(KvsErrorType::UnknownOperation.into())
+                    }
+                };
+                Ok(BatchOperation { op: Some(op) })
+            })
+            .collect::<Result<_>>()?;
+        let request = self.request(BatchRequest { ops });
+        let reply = self.runtime.block_on(self.client.batch(request))?;
+        Ok(reply
+            .into_inner()
+            .results
+            .into_iter()
+            .map(|result| match result.result {
+                Some(BatchResult::Get(GetReply { found: true, value })) => Some(value),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// 构造请求，并附带认证信息
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        request
+    }
+}
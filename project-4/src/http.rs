@@ -0,0 +1,433 @@
+//! HTTP/JSON 网关模块
+//!
+//! 提供以下接口：
+//!
+//! - `GET /v1/keys/{key}`：获取 value，不存在返回 404
+//! - `PUT /v1/keys/{key}`：设置 value，请求体为 `{"value": "..."}`，
+//!   带有 `"expected"` 字段（字符串或 null）时进行比较并交换，不符返回 409
+//! - `DELETE /v1/keys/{key}`：删除，不存在返回 404
+//! - `GET /v1/keys?prefix=&after=&limit=`：按顺序列出 key
+//! - `GET /v1/stats`：引擎统计信息
+//!
+//! 开启访问控制时使用 HTTP Basic 认证，用户名与 token 分别作为用户名与密码
+
+use crate::acl::{Acl, Permission, Principal};
+use crate::engines::KvsEngine;
+use crate::{KvsErrorType, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+
+/// 列出 key 时默认的最大数量
+const DEFAULT_LIST_LIMIT: usize = 1000;
+
+/// 请求行与每个请求头的最大长度
+const MAX_LINE_LEN: usize = 8 * 1024;
+
+/// 请求头的最大数量
+const MAX_HEADERS: usize = 100;
+
+/// 一个 HTTP 请求
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// 获取名为 name 的请求头（不区分大小写）
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// 获取名为 name 的查询参数
+    fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// 请求结束后是否保持连接
+    fn keep_alive(&self) -> bool {
+        !self
+            .header("Connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close"))
+    }
+}
+
+/// 一个 HTTP 响应
+struct Response {
+    status: u16,
+    body: Option<Value>,
+}
+
+impl Response {
+    /// 带有 JSON 响应体的响应
+    fn new(status: u16, body: Value) -> Response {
+        Response {
+            status,
+            body: Some(body),
+        }
+    }
+
+    /// 不带响应体的响应
+    fn empty(status: u16) -> Response {
+        Response { status, body: None }
+    }
+
+    /// 出错时的响应，响应体为 `{"error": msg}`
+    fn error(status: u16, msg: &str) -> Response {
+        Response::new(status, json!({ "error": msg }))
+    }
+
+    /// 序列化后写入 writer
+    fn write_to(&self, writer: &mut impl Write, keep_alive: bool) -> std::io::Result<()> {
+        let body = match &self.body {
+            Some(body) => body.to_string(),
+            None => String::new(),
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: {}\r\n",
+            self.status,
+            reason(self.status),
+            body.len(),
+            if keep_alive { "keep-alive" } else { "close" }
+        )?;
+        if self.body.is_some() {
+            write!(writer, "Content-Type: application/json\r\n")?;
+        }
+        if self.status == 401 {
+            write!(writer, "WWW-Authenticate: Basic realm=\"kvs\"\r\n")?;
+        }
+        write!(writer, "\r\n{}", body)
+    }
+}
+
+/// 状态码对应的描述
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// URL 解码（`%XX`）
+fn percent_decode(s: &str) -> Result<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex =
+                std::str::from_utf8(&bytes[i + 1..i + 3]).map_err(|_| KvsErrorType::SerdeError)?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| KvsErrorType::SerdeError)?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(String::from_utf8(decoded).map_err(|_| KvsErrorType::SerdeError)?)
+}
+
+/// 解析查询字符串 `a=1&b=2`
+fn parse_query(query: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let key = parts.next().unwrap().replace('+', " ");
+        let value = parts.next().unwrap_or("").replace('+', " ");
+        pairs.push((percent_decode(&key)?, percent_decode(&value)?));
+    }
+    Ok(pairs)
+}
+
+/// 读取一行（不含结尾的 `\r\n`），超过 MAX_LINE_LEN 字节仍未读到行尾时返回 UnknownOperation Error
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = String::new();
+    let limit = MAX_LINE_LEN as u64 + 2;
+    if reader.by_ref().take(limit).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') && line.len() as u64 == limit {
+        Err(KvsErrorType::UnknownOperation)?
+    }
+    while line.ends_with('\n') || line.ends_with('\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// 读取一个请求，连接关闭时返回 None
+///
+/// 请求体超过 max_body_size 字节时返回 InvalidArgument Error，请求格式不正确时返回 UnknownOperation Error
+fn read_request(reader: &mut impl BufRead, max_body_size: usize) -> Result<Option<Request>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(_version)) => (method.to_owned(), target),
+        _ => Err(KvsErrorType::UnknownOperation)?,
+    };
+    let (path, query) = match target.find('?') {
+        Some(pos) => (&target[..pos], parse_query(&target[pos + 1..])?),
+        None => (target, Vec::new()),
+    };
+    let path = percent_decode(path)?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?.ok_or(KvsErrorType::UnknownOperation)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            Err(KvsErrorType::UnknownOperation)?
+        }
+        if let Some(pos) = line.find(':') {
+            headers.push((
+                line[..pos].trim().to_owned(),
+                line[pos + 1..].trim().to_owned(),
+            ));
+        }
+    }
+    let mut request = Request {
+        method,
+        path,
+        query,
+        headers,
+        body: Vec::new(),
+    };
+    let length = match request.header("Content-Length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| KvsErrorType::UnknownOperation)?,
+        None => 0,
+    };
+    if length > max_body_size {
+        Err(KvsErrorType::InvalidArgument)?
+    }
+    request.body = vec![0; length];
+    reader.read_exact(&mut request.body)?;
+    Ok(Some(request))
+}
+
+/// PUT 请求的请求体
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+    /// 期望的旧值，字段存在时进行比较并交换，null 表示 key 不存在
+    #[serde(default, deserialize_with = "deserialize_expected")]
+    expected: Option<Option<String>>,
+}
+
+/// 区分 `"expected"` 字段缺失与值为 null
+fn deserialize_expected<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<Option<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Some(Option::deserialize(deserializer)?))
+}
+
+/// 单个 HTTP 连接的状态
+struct Session<'a, E: KvsEngine> {
+    engine: &'a E,
+    acl: Option<&'a Acl>,
+}
+
+impl<'a, E: KvsEngine> Session<'a, E> {
+    /// 根据 Basic 认证请求头认证用户
+    ///
+    /// 未开启访问控制时返回 Ok(None)，认证失败返回 Err
+    fn authenticate(
+        &self,
+        request: &Request,
+    ) -> std::result::Result<Option<&'a Principal>, Response> {
+        let acl = match self.acl {
+            Some(acl) => acl,
+            None => return Ok(None),
+        };
+        request
+            .header("Authorization")
+            .and_then(|auth| acl.authenticate_basic(auth).ok())
+            .map(Some)
+            .ok_or_else(|| Response::error(401, "Unauthorized"))
+    }
+
+    /// 处理一个请求
+    fn handle(&self, request: &Request) -> Response {
+        let principal = match self.authenticate(request) {
+            Ok(principal) => principal,
+            Err(response) => return response,
+        };
+        let allows = |permission: Permission, key: &str| match principal {
+            Some(principal) => principal.allows(permission, key),
+            None => true,
+        };
+        let result = if request.path == "/v1/stats" {
+            match request.method.as_str() {
+                "GET" => self.stats(),
+                _ => Ok(Response::error(405, "Method not allowed")),
+            }
+        } else if request.path == "/v1/keys" {
+            match request.method.as_str() {
+                "GET" => self.list(request, &allows),
+                _ => Ok(Response::error(405, "Method not allowed")),
+            }
+        } else if request.path.starts_with("/v1/keys/") && request.path.len() > "/v1/keys/".len() {
+            let key = request.path["/v1/keys/".len()..].to_owned();
+            let permission = match request.method.as_str() {
+                "GET" => Permission::Get,
+                "PUT" => Permission::Set,
+                "DELETE" => Permission::Remove,
+                _ => return Response::error(405, "Method not allowed"),
+            };
+            if !allows(permission, &key) {
+                return Response::error(403, "Permission denied");
+            }
+            match permission {
+                Permission::Get => self.get(key),
+                Permission::Set => self.put(key, &request.body),
+                Permission::Remove => self.delete(key),
+            }
+        } else {
+            Ok(Response::error(404, "Not found"))
+        };
+        result.unwrap_or_else(|e| Response::error(500, &e.to_string()))
+    }
+
+    /// GET /v1/keys/{key}
+    fn get(&self, key: String) -> Result<Response> {
+        Ok(match self.engine.get(key.clone())? {
+            Some(value) => Response::new(200, json!({ "key": key, "value": value })),
+            None => Response::error(404, "Key not found"),
+        })
+    }
+
+    /// PUT /v1/keys/{key}
+    fn put(&self, key: String, body: &[u8]) -> Result<Response> {
+        let body: PutBody = match serde_json::from_slice(body) {
+            Ok(body) => body,
+            Err(_) => return Ok(Response::error(400, "Invalid body")),
+        };
+        match body.expected {
+            Some(expected) => {
+                if !self
+                    .engine
+                    .compare_and_swap(key, expected, Some(body.value))?
+                {
+                    return Ok(Response::error(409, "Value does not match expected"));
+                }
+            }
+            None => self.engine.set(key, body.value)?,
+        }
+        Ok(Response::empty(204))
+    }
+
+    /// DELETE /v1/keys/{key}
+    fn delete(&self, key: String) -> Result<Response> {
+        match self.engine.remove(key) {
+            Ok(()) => Ok(Response::empty(204)),
+            Err(ref e) if e.kind() == KvsErrorType::KeyNotFound => {
+                Ok(Response::error(404, "Key not found"))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// GET /v1/keys?prefix=&after=&limit=
+    ///
+    /// 结果中的 next 不为 null 时，可作为下一次请求的 after 继续列出
+    fn list(
+        &self,
+        request: &Request,
+        allows: &dyn Fn(Permission, &str) -> bool,
+    ) -> Result<Response> {
+        let prefix = request.query("prefix").unwrap_or("").to_owned();
+        let after = request.query("after").map(String::from);
+        let limit = match request.query("limit").map(str::parse::<usize>) {
+            Some(Ok(limit)) if limit > 0 => limit,
+            Some(_) => return Ok(Response::error(400, "Invalid limit")),
+            None => DEFAULT_LIST_LIMIT,
+        };
+        let pairs = self.engine.scan(prefix, after, limit)?;
+        let next = if pairs.len() == limit {
+            pairs.last().map(|(key, _)| key.clone())
+        } else {
+            None
+        };
+        let keys: Vec<String> = pairs
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| allows(Permission::Get, key))
+            .collect();
+        Ok(Response::new(200, json!({ "keys": keys, "next": next })))
+    }
+
+    /// GET /v1/stats
+    fn stats(&self) -> Result<Response> {
+        Ok(Response::new(
+            200,
+            json!({ "engine": self.engine.get_type(), "stats": self.engine.stats()? }),
+        ))
+    }
+}
+
+/// 处理一个 HTTP 连接
+///
+/// 请求体超过 max_request_size 字节时返回 413 并关闭连接，请求格式不正确时返回 400
+pub(crate) fn handle_connection<E: KvsEngine, S: Read + Write>(
+    engine: E,
+    acl: Option<Arc<Acl>>,
+    max_request_size: usize,
+    stream: S,
+) {
+    let mut reader = BufReader::new(stream);
+    let session = Session {
+        engine: &engine,
+        acl: acl.as_ref().map(|acl| acl.as_ref()),
+    };
+    loop {
+        let (response, keep_alive) = match read_request(&mut reader, max_request_size) {
+            Ok(Some(request)) => (session.handle(&request), request.keep_alive()),
+            Ok(None) => break,
+            Err(ref e) if e.kind() == KvsErrorType::InvalidArgument => {
+                (Response::error(413, "Payload too large"), false)
+            }
+            Err(e) => {
+                error!("{}", e);
+                (Response::error(400, "Bad request"), false)
+            }
+        };
+        let mut buf = Vec::new();
+        response.write_to(&mut buf, keep_alive).unwrap();
+        let stream = reader.get_mut();
+        if let Err(e) = stream.write_all(&buf).and_then(|_| stream.flush()) {
+            error!("{}", e);
+            break;
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+}
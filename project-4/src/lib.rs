@@ -6,21 +6,33 @@
 #[macro_use]
 extern crate log;
 pub use crate::error::{KvsError, KvsErrorType, Result};
+use crate::replication::LogPosition;
 pub use engines::{KvStore, KvsEngine, SledServer};
 use serde::{Deserialize, Serialize};
 pub use server::KvsServer;
+use std::path::Path;
+pub mod acl;
+pub mod backup;
 /// 数据库客户端
 pub mod client;
 pub mod engines;
 /// 错误处理模块
 mod error;
+pub mod export;
+pub mod grpc;
+mod http;
+pub mod raft;
+pub mod replication;
+mod resp;
 mod response;
 /// 数据库服务端
 pub mod server;
+pub mod sharding;
 pub mod thread_pool;
+pub mod tls;
 
 /// 数据库操作
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Operation {
     /// 设置元素
     Set {
@@ -39,6 +51,46 @@ pub enum Operation {
         /// 键
         key: String,
     },
+    /// 认证，需作为连接上的第一条消息
+    Auth {
+        /// 用户名
+        user: String,
+        /// token
+        token: String,
+    },
+    /// 按 key 从小到大的顺序获取以 prefix 开头的键值对，结果以 JSON 数组的形式放在响应消息中
+    Scan {
+        /// key 前缀
+        prefix: String,
+        /// 只返回大于 after 的 key
+        after: Option<String>,
+        /// 最多返回的数量
+        limit: usize,
+    },
+    /// 将服务器某一时刻的完整数据备份到服务器备份目录中的 path，响应消息为 JSON 格式的备份清单
+    ///
+    /// 服务器未设置备份目录时拒绝该操作
+    Backup {
+        /// 备份目录中的相对路径，不能包含 `..`
+        path: String,
+    },
+    /// 从节点请求复制日志，之后连接上只会收到 `LogEntry`
+    Replicate {
+        /// 已应用的日志位置，为空时从快照开始
+        position: Option<LogPosition>,
+    },
+    /// 在名为 name 的 keyspace 中执行 op，keyspace 不存在时创建（复制日志时除外），op 不能再是 Keyspace
+    Keyspace {
+        /// keyspace 名称
+        name: String,
+        /// 要执行的操作
+        op: Box<Operation>,
+    },
+    /// 删除名为 name 的 keyspace 及其中的所有数据，响应消息为其是否存在
+    DropKeyspace {
+        /// keyspace 名称
+        name: String,
+    },
 }
 
 impl Operation {
@@ -59,4 +111,28 @@ impl Operation {
     pub fn get(key: &String) -> Operation {
         Operation::Get { key: key.clone() }
     }
+
+    /// 在名为 name 的 keyspace 中执行 op
+    pub fn keyspace(name: &str, op: Operation) -> Operation {
+        Operation::Keyspace {
+            name: name.to_owned(),
+            op: Box::new(op),
+        }
+    }
+
+    /// 以 user 的身份进行认证
+    pub fn auth(user: &str, token: &str) -> Operation {
+        Operation::Auth {
+            user: user.to_owned(),
+            token: token.to_owned(),
+        }
+    }
+}
+
+/// Unix domain socket 地址的前缀，如 `unix:/tmp/kvs.sock`
+const UNIX_ADDR_PREFIX: &str = "unix:";
+
+/// addr 为 Unix domain socket 地址时，返回 socket 文件的路径
+pub(crate) fn unix_socket_path(addr: &str) -> Option<&Path> {
+    addr.strip_prefix(UNIX_ADDR_PREFIX).map(Path::new)
 }
@@ -0,0 +1,508 @@
+use super::{Command, EntryData, FileStorage, Member, Membership, Message, NodeId, Raft, Role};
+use crate::acl::constant_time_eq;
+use crate::backup::BackupManifest;
+use crate::engines::{KvsEngine, Stats};
+use crate::{KvsError, KvsErrorType, Result};
+use crossbeam::channel::{self, Receiver, Sender};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// 每个 tick 的时长
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// 等待提议被提交的最长时间
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 连接其他节点的超时时间
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// 提议被应用后的结果，compare_and_swap 时为是否交换成功，其他命令为 true
+type Reply = Sender<Result<bool>>;
+
+/// 驱动线程处理的事件
+enum Event {
+    Tick,
+    Message(Message),
+    Propose(EntryData, Reply),
+    ChangeMember(NodeId, Option<Member>, Reply),
+    Read(Reply),
+}
+
+/// 供其他线程读取的节点状态
+#[derive(Clone)]
+struct Status {
+    term: u64,
+    role: Role,
+    leader: Option<NodeId>,
+    membership: Membership,
+    commit: u64,
+    applied: u64,
+}
+
+/// 以 Raft 复制写操作的引擎
+///
+/// 写操作作为日志提交到集群的多数节点后，才会应用到每个节点的 engine 上；
+/// 读操作只由 leader 处理，其他节点返回 NotLeader Error，
+/// 服务器会将客户端重定向到 `leader()` 返回的地址
+///
+/// 读操作以 ReadIndex 的方式执行：leader 先向多数节点确认自己仍是 leader，
+/// 再等待状态机应用到确认时的提交序号，因此不会读到已被新 leader 覆盖的旧数据
+///
+/// 节点之间的消息不加密；指定 token 时每个连接需先发送相同的 token，
+/// 但仍应将 raft 地址限制在集群内部的网络中
+///
+/// 使用方法：
+///
+/// ```no_run
+/// # use kvs::raft::{Member, Membership, RaftEngine};
+/// # use kvs::KvStore;
+/// let mut membership = Membership::new();
+/// for id in 1..=3 {
+///     membership.insert(id, Member {
+///         client_addr: format!("127.0.0.1:400{}", id),
+///         raft_addr: format!("127.0.0.1:500{}", id),
+///     });
+/// }
+/// let engine = KvStore::open("node1/kvs").unwrap();
+/// let engine = RaftEngine::start(engine, 1, "127.0.0.1:5001", membership, "node1/raft".into()).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct RaftEngine<E: KvsEngine> {
+    engine: E,
+    events: Sender<Event>,
+    status: Arc<Mutex<Status>>,
+}
+
+impl<E: KvsEngine> RaftEngine<E> {
+    /// 启动 id 为 id 的节点，在 raft_addr 上接收其他节点的消息
+    ///
+    /// membership 为集群的初始成员配置，加入已有集群的节点应传入空的配置，
+    /// 再由 leader 通过 `add_member` 将其加入；日志与已应用的序号保存在文件夹 dir 中
+    pub fn start(
+        engine: E,
+        id: NodeId,
+        raft_addr: &str,
+        membership: Membership,
+        dir: PathBuf,
+    ) -> Result<Self> {
+        Self::start_with_token(engine, id, raft_addr, membership, dir, None)
+    }
+
+    /// 与 `start` 相同，但节点之间的连接需先发送 token 认证
+    ///
+    /// token 不符的连接会被关闭，集群中所有节点应使用相同的 token
+    pub fn start_with_token(
+        engine: E,
+        id: NodeId,
+        raft_addr: &str,
+        membership: Membership,
+        dir: PathBuf,
+        token: Option<String>,
+    ) -> Result<Self> {
+        let storage = FileStorage::open(&dir)?;
+        let applied_path = dir.join("applied");
+        let applied = if applied_path.exists() {
+            serde_json::from_reader(File::open(&applied_path)?)?
+        } else {
+            0
+        };
+        let raft = Raft::new(id, membership, storage, applied)?;
+        let status = Arc::new(Mutex::new(Status {
+            term: raft.term(),
+            role: raft.role(),
+            leader: raft.leader(),
+            membership: raft.membership().clone(),
+            commit: raft.commit_index(),
+            applied,
+        }));
+        let (events, receiver) = channel::unbounded();
+
+        let listener = TcpListener::bind(raft_addr)?;
+        info!("raft node {} listening on {}", id, raft_addr);
+        let sender = events.clone();
+        let listen_token = token.clone();
+        thread::spawn(move || listen(listener, sender, listen_token));
+
+        let sender = events.clone();
+        thread::spawn(move || {
+            while sender.send(Event::Tick).is_ok() {
+                thread::sleep(TICK_INTERVAL);
+            }
+        });
+
+        let driver = Driver {
+            raft,
+            engine: engine.clone(),
+            applied_path,
+            pending: BTreeMap::new(),
+            next_read: 0,
+            reads: HashMap::new(),
+            waiting: Vec::new(),
+            peers: HashMap::new(),
+            token,
+            status: Arc::clone(&status),
+        };
+        thread::spawn(move || driver.run(receiver));
+
+        Ok(RaftEngine {
+            engine,
+            events,
+            status,
+        })
+    }
+
+    /// 当前成员配置
+    pub fn membership(&self) -> Membership {
+        self.status.lock().unwrap().membership.clone()
+    }
+
+    /// 向集群中增加一个节点，只能在 leader 上调用
+    pub fn add_member(&self, id: NodeId, member: Member) -> Result<()> {
+        self.call(|reply| Event::ChangeMember(id, Some(member), reply))
+            .map(|_| ())
+    }
+
+    /// 从集群中删除一个节点，只能在 leader 上调用
+    pub fn remove_member(&self, id: NodeId) -> Result<()> {
+        self.call(|reply| Event::ChangeMember(id, None, reply))
+            .map(|_| ())
+    }
+
+    /// 提议一条命令，并等待其被应用
+    fn propose(&self, command: Command) -> Result<bool> {
+        self.call(|reply| Event::Propose(EntryData::Command(command), reply))
+    }
+
+    /// 向驱动线程发送事件，并等待结果
+    fn call<F: FnOnce(Reply) -> Event>(&self, event: F) -> Result<bool> {
+        let (reply, receiver) = channel::bounded(1);
+        self.events
+            .send(event(reply))
+            .map_err(|_| KvsErrorType::Other)?;
+        match receiver.recv_timeout(PROPOSE_TIMEOUT) {
+            Ok(result) => result,
+            Err(_) => Err(KvsErrorType::Other)?,
+        }
+    }
+
+    /// 等待 leader 确认读请求可以执行，不是 leader 时返回 NotLeader Error
+    fn read_barrier(&self) -> Result<()> {
+        if self.status.lock().unwrap().role != Role::Leader {
+            Err(KvsErrorType::NotLeader)?
+        }
+        self.call(Event::Read).map(|_| ())
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for RaftEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.propose(Command::Set { key, value }).map(|_| ())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.read_barrier()?;
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.propose(Command::Remove { key }).map(|_| ())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.propose(Command::CompareAndSwap { key, expected, new })
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.read_barrier()?;
+        self.engine.scan(prefix, after, limit)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let mut stats = self.engine.stats()?;
+        let status = self.status.lock().unwrap();
+        stats.insert("raft_term".to_owned(), status.term);
+        stats.insert("raft_leader".to_owned(), status.leader.unwrap_or(0));
+        stats.insert("raft_members".to_owned(), status.membership.len() as u64);
+        stats.insert("raft_commit".to_owned(), status.commit);
+        stats.insert("raft_applied".to_owned(), status.applied);
+        Ok(stats)
+    }
+
+    fn get_type(&self) -> String {
+        self.engine.get_type()
+    }
+
+    /// 备份本节点已应用的数据
+    fn backup(&self, path: &Path) -> Result<BackupManifest> {
+        self.engine.backup(path)
+    }
+
+    fn leader(&self) -> Option<String> {
+        let status = self.status.lock().unwrap();
+        status
+            .leader
+            .and_then(|id| status.membership.get(&id))
+            .map(|member| member.client_addr.clone())
+    }
+}
+
+/// 驱动线程，独占 Raft 状态机并按顺序处理事件
+struct Driver<E: KvsEngine> {
+    raft: Raft<FileStorage>,
+    engine: E,
+    applied_path: PathBuf,
+    /// 等待应用的提议，为 日志序号 -> (任期, 结果的发送端)
+    pending: BTreeMap<u64, (u64, Reply)>,
+    /// 下一个读请求的 id
+    next_read: u64,
+    /// 等待 leader 确认的读请求，为 读请求 id -> 结果的发送端
+    reads: HashMap<u64, Reply>,
+    /// 已确认、等待状态机应用到对应序号的读请求，为 (日志序号, 结果的发送端)
+    waiting: Vec<(u64, Reply)>,
+    /// 发送到各节点的消息队列，以 raft 地址区分
+    peers: HashMap<String, Sender<Message>>,
+    /// 连接其他节点时发送的 token
+    token: Option<String>,
+    status: Arc<Mutex<Status>>,
+}
+
+impl<E: KvsEngine> Driver<E> {
+    /// 处理事件，直到所有发送端都被关闭
+    fn run(mut self, receiver: Receiver<Event>) {
+        for event in receiver {
+            if let Err(e) = self.handle(event) {
+                // 无法持久化日志时不能继续参与集群
+                error!("raft node {} stopped: {}", self.raft.id(), e);
+                return;
+            }
+        }
+    }
+
+    /// 处理一个事件，并发送消息、应用已提交的日志
+    fn handle(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Tick => self.raft.tick()?,
+            Event::Message(msg) => self.raft.step(msg)?,
+            Event::Propose(data, reply) => self.propose(reply, |raft| raft.propose(data)),
+            Event::ChangeMember(id, member, reply) => self.propose(reply, |raft| match member {
+                Some(member) => raft.add_member(id, member),
+                None => raft.remove_member(id),
+            }),
+            Event::Read(reply) => self.read(reply),
+        }
+        for msg in self.raft.take_messages() {
+            self.send(msg);
+        }
+        self.apply()?;
+        self.finish_reads();
+        let mut status = self.status.lock().unwrap();
+        status.term = self.raft.term();
+        status.role = self.raft.role();
+        status.leader = self.raft.leader();
+        status.commit = self.raft.commit_index();
+        if &status.membership != self.raft.membership() {
+            status.membership = self.raft.membership().clone();
+        }
+        Ok(())
+    }
+
+    /// 提议一条日志，成功后等待其被应用再回复
+    fn propose<F>(&mut self, reply: Reply, propose: F)
+    where
+        F: FnOnce(&mut Raft<FileStorage>) -> Result<u64>,
+    {
+        match propose(&mut self.raft) {
+            Ok(index) => {
+                self.pending.insert(index, (self.raft.term(), reply));
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e));
+            }
+        }
+    }
+
+    /// 发起一个读请求，leader 确认后在 `finish_reads` 中回复
+    fn read(&mut self, reply: Reply) {
+        let id = self.next_read;
+        self.next_read += 1;
+        match self.raft.read_index(id) {
+            Ok(()) => {
+                self.reads.insert(id, reply);
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e));
+            }
+        }
+    }
+
+    /// 回复已确认且状态机已应用到对应序号的读请求
+    fn finish_reads(&mut self) {
+        for (id, index) in self.raft.take_reads() {
+            if let Some(reply) = self.reads.remove(&id) {
+                match index {
+                    Some(index) => self.waiting.push((index, reply)),
+                    None => {
+                        let _ = reply.send(Err(KvsError::from(KvsErrorType::NotLeader)));
+                    }
+                }
+            }
+        }
+        let applied = self.status.lock().unwrap().applied;
+        self.waiting.retain(|(index, reply)| {
+            let ready = *index <= applied;
+            if ready {
+                let _ = reply.send(Ok(true));
+            }
+            !ready
+        });
+    }
+
+    /// 将消息交给对应节点的发送线程
+    fn send(&mut self, msg: Message) {
+        let addr = match self.raft.membership().get(&msg.to) {
+            Some(member) => member.raft_addr.clone(),
+            None => return,
+        };
+        let token = &self.token;
+        let sender = self.peers.entry(addr.clone()).or_insert_with(|| {
+            let (sender, receiver) = channel::unbounded();
+            let token = token.clone();
+            thread::spawn(move || send_to_peer(addr, token, receiver));
+            sender
+        });
+        let _ = sender.send(msg);
+    }
+
+    /// 将已提交的日志应用到引擎，并回复对应的提议
+    ///
+    /// 每应用一条日志就保存已应用的序号，崩溃后最多重新应用最后一条日志；
+    /// 该日志的效果已在引擎中，重新应用不会改变状态（比较并交换会失败，其他命令是幂等的）
+    fn apply(&mut self) -> Result<()> {
+        for entry in self.raft.take_committed() {
+            let result = match entry.data {
+                EntryData::Command(command) => apply_command(&self.engine, command),
+                _ => Ok(true),
+            };
+            if let Err(e) = &result {
+                if e.kind() != KvsErrorType::KeyNotFound {
+                    error!("failed to apply entry {}: {}", entry.index, e);
+                }
+            }
+            // 提议所在的日志被其他 leader 覆盖时，告知客户端重试
+            if let Some((term, reply)) = self.pending.remove(&entry.index) {
+                let result = if term == entry.term {
+                    result
+                } else {
+                    Err(KvsError::from(KvsErrorType::NotLeader))
+                };
+                let _ = reply.send(result);
+            }
+            let tmp_path = self.applied_path.with_extension("tmp");
+            serde_json::to_writer(File::create(&tmp_path)?, &entry.index)?;
+            fs::rename(&tmp_path, &self.applied_path)?;
+            self.status.lock().unwrap().applied = entry.index;
+        }
+        Ok(())
+    }
+}
+
+/// 将命令应用到引擎
+fn apply_command<E: KvsEngine>(engine: &E, command: Command) -> Result<bool> {
+    match command {
+        Command::Set { key, value } => engine.set(key, value).map(|_| true),
+        Command::Remove { key } => engine.remove(key).map(|_| true),
+        Command::CompareAndSwap { key, expected, new } => {
+            engine.compare_and_swap(key, expected, new)
+        }
+    }
+}
+
+/// 接收其他节点的连接，并将收到的消息交给驱动线程
+///
+/// token 不为空时，连接发送的第一个值必须是相同的 token，否则关闭连接
+fn listen(listener: TcpListener, events: Sender<Event>, token: Option<String>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            }
+        };
+        let events = events.clone();
+        let token = token.clone();
+        thread::spawn(move || {
+            let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(stream));
+            if let Some(token) = token {
+                match String::deserialize(&mut deserializer) {
+                    Ok(received) if constant_time_eq(&received, &token) => {}
+                    _ => {
+                        info!("raft peer authentication failed");
+                        return;
+                    }
+                }
+            }
+            for msg in deserializer.into_iter::<Message>() {
+                match msg {
+                    Ok(msg) => {
+                        if events.send(Event::Message(msg)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(_) => return,
+                }
+            }
+        });
+    }
+}
+
+/// 将消息发送到地址为 addr 的节点，token 不为空时每次连接后先发送 token
+///
+/// 连接失败或断开时丢弃消息，由 Raft 的重传机制保证最终送达
+fn send_to_peer(addr: String, token: Option<String>, receiver: Receiver<Message>) {
+    let mut stream: Option<TcpStream> = None;
+    for msg in receiver.iter() {
+        if stream.is_none() {
+            stream = addr
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .and_then(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).ok())
+                .and_then(|mut s| match &token {
+                    Some(token) => {
+                        let buf = serde_json::to_vec(token).unwrap();
+                        s.write_all(&buf).ok().map(|_| s)
+                    }
+                    None => Some(s),
+                });
+            if stream.is_none() {
+                // 节点不可达时丢弃积压的消息，避免队列无限增长
+                receiver.try_iter().count();
+                continue;
+            }
+        }
+        if let Some(s) = &mut stream {
+            let buf = serde_json::to_vec(&msg).unwrap();
+            if s.write_all(&buf).and_then(|_| s.flush()).is_err() {
+                stream = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Raft 共识模块
+//!
+//! `Raft` 是不依赖时钟与网络的 Raft 状态机：外部通过 `tick` 推进时间、通过 `step` 传入消息，
+//! 再取出需要发送的消息与已提交的日志，因此可以在进程内确定性地模拟整个集群
+//!
+//! `RaftEngine` 在其之上实现了网络传输与定时器，并将已提交的日志应用到任意 `KvsEngine`，
+//! 使多个 kvs-server 组成一个复制的集群
+//!
+//! 成员变更每次只增加或删除一个节点，新的成员配置在追加到日志时即生效
+
+use crate::Operation;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+mod engine;
+mod node;
+mod storage;
+
+pub use self::engine::RaftEngine;
+pub use self::node::Raft;
+pub use self::storage::{FileStorage, MemStorage, Storage};
+
+/// 节点 id
+pub type NodeId = u64;
+
+/// 集群中的一个节点
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Member {
+    /// 客户端连接的地址，用于将客户端重定向到 leader
+    pub client_addr: String,
+    /// 节点之间发送 Raft 消息的地址
+    pub raft_addr: String,
+}
+
+/// 集群成员配置，为 节点 id -> 节点 的映射
+pub type Membership = BTreeMap<NodeId, Member>;
+
+/// 复制到所有节点并应用到引擎的命令
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum Command {
+    /// 设置键值对
+    Set {
+        /// 键
+        key: String,
+        /// 值
+        value: String,
+    },
+    /// 删除 key
+    Remove {
+        /// 键
+        key: String,
+    },
+    /// 比较并交换
+    CompareAndSwap {
+        /// 键
+        key: String,
+        /// 期望的值，None 表示不存在
+        expected: Option<String>,
+        /// 新的值，None 表示删除
+        new: Option<String>,
+    },
+}
+
+impl Command {
+    /// 由数据库操作构建命令，只有写操作可以转换
+    pub fn from_operation(op: Operation) -> Option<Command> {
+        match op {
+            Operation::Set { key, value } => Some(Command::Set { key, value }),
+            Operation::Remove { key } => Some(Command::Remove { key }),
+            _ => None,
+        }
+    }
+}
+
+/// 日志条目的内容
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum EntryData {
+    /// 空条目，leader 当选后追加，用于提交之前任期的日志
+    Noop,
+    /// 命令
+    Command(Command),
+    /// 新的成员配置
+    Membership(Membership),
+}
+
+/// 日志条目
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Entry {
+    /// 追加该条目时 leader 的任期
+    pub term: u64,
+    /// 在日志中的序号，从 1 开始
+    pub index: u64,
+    /// 内容
+    pub data: EntryData,
+}
+
+/// 需要持久化的节点状态
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct HardState {
+    /// 当前任期
+    pub term: u64,
+    /// 当前任期内投票给的节点
+    pub voted_for: Option<NodeId>,
+}
+
+/// 节点之间的消息
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Message {
+    /// 发送者
+    pub from: NodeId,
+    /// 接收者
+    pub to: NodeId,
+    /// 发送者的任期
+    pub term: u64,
+    /// 消息内容
+    pub body: MessageBody,
+}
+
+/// 消息内容
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum MessageBody {
+    /// 候选者请求投票
+    RequestVote {
+        /// 候选者最后一条日志的序号
+        last_log_index: u64,
+        /// 候选者最后一条日志的任期
+        last_log_term: u64,
+    },
+    /// 投票结果
+    RequestVoteResponse {
+        /// 是否投票给候选者
+        granted: bool,
+    },
+    /// leader 追加日志，entries 为空时即为心跳
+    AppendEntries {
+        /// entries 之前一条日志的序号
+        prev_log_index: u64,
+        /// entries 之前一条日志的任期
+        prev_log_term: u64,
+        /// 需要追加的日志
+        entries: Vec<Entry>,
+        /// leader 的提交序号
+        leader_commit: u64,
+        /// leader 发送时最新的读请求轮次，用于确认读请求到达后自身仍是 leader
+        #[serde(default)]
+        read: u64,
+    },
+    /// 追加日志的结果
+    AppendEntriesResponse {
+        /// 是否成功
+        success: bool,
+        /// 成功时为已与 leader 一致的最后一条日志序号，失败时为接收者最后一条日志的序号
+        match_index: u64,
+        /// 对应的 AppendEntries 中的读请求轮次
+        #[serde(default)]
+        read: u64,
+    },
+}
+
+/// 节点角色
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Role {
+    /// 跟随者
+    Follower,
+    /// 候选者
+    Candidate,
+    /// 领导者
+    Leader,
+}
@@ -0,0 +1,682 @@
+use super::{
+    Entry, EntryData, HardState, Member, Membership, Message, MessageBody, NodeId, Role, Storage,
+};
+use crate::{KvsErrorType, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// 选举超时的最小 tick 数，实际超时在 [ELECTION_TICKS, 2 * ELECTION_TICKS) 之间随机
+pub const ELECTION_TICKS: u32 = 10;
+
+/// leader 发送心跳的间隔 tick 数
+pub const HEARTBEAT_TICKS: u32 = 3;
+
+/// 一条 AppendEntries 消息中最多携带的日志数量
+const MAX_ENTRIES_PER_MESSAGE: usize = 64;
+
+/// leader 记录的每个节点的复制进度
+#[derive(Debug, Clone, Copy)]
+struct Progress {
+    /// 下一条要发送的日志序号
+    next: u64,
+    /// 已知与 leader 一致的最后一条日志序号
+    matched: u64,
+}
+
+/// Raft 状态机
+///
+/// 所有输入都通过 `tick`、`step` 与 `propose` 传入，输出通过 `take_messages` 与
+/// `take_committed` 取出，自身不进行任何网络操作；随机数由节点 id 决定，因此行为是确定的
+pub struct Raft<S: Storage> {
+    id: NodeId,
+    storage: S,
+    state: HardState,
+    log: Vec<Entry>,
+    initial_membership: Membership,
+    membership: Membership,
+    role: Role,
+    leader: Option<NodeId>,
+    commit: u64,
+    applied: u64,
+    election_elapsed: u32,
+    election_timeout: u32,
+    heartbeat_elapsed: u32,
+    votes: BTreeSet<NodeId>,
+    progress: BTreeMap<NodeId, Progress>,
+    messages: Vec<Message>,
+    rng: u64,
+    /// 当前任期第一条日志（leader 当选时追加的空日志）的序号
+    term_start: u64,
+    /// 最新的读请求轮次
+    read_round: u64,
+    /// 每个节点已确认的最大读请求轮次
+    read_acks: BTreeMap<NodeId, u64>,
+    /// 等待多数节点确认的读请求，为 (轮次, 请求 id, 读取序号)
+    reads: Vec<(u64, u64, u64)>,
+    /// 已有结果的读请求，为 (请求 id, 读取序号)，失去 leader 身份时读取序号为 None
+    read_results: Vec<(u64, Option<u64>)>,
+}
+
+impl<S: Storage> Raft<S> {
+    /// 创建一个 id 为 id 的节点
+    ///
+    /// membership 为集群的初始成员配置，日志中的成员配置优先；
+    /// applied 为状态机已应用的日志序号，之后的已提交日志会通过 `take_committed` 取出
+    pub fn new(id: NodeId, membership: Membership, mut storage: S, applied: u64) -> Result<Self> {
+        let (state, log) = storage.load()?;
+        let mut raft = Raft {
+            id,
+            storage,
+            state,
+            log,
+            initial_membership: membership.clone(),
+            membership,
+            role: Role::Follower,
+            leader: None,
+            commit: applied,
+            applied,
+            election_elapsed: 0,
+            election_timeout: ELECTION_TICKS,
+            heartbeat_elapsed: 0,
+            votes: BTreeSet::new(),
+            progress: BTreeMap::new(),
+            messages: Vec::new(),
+            rng: id.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            term_start: 0,
+            read_round: 0,
+            read_acks: BTreeMap::new(),
+            reads: Vec::new(),
+            read_results: Vec::new(),
+        };
+        raft.update_membership();
+        raft.reset_election_timer();
+        Ok(raft)
+    }
+
+    /// 节点 id
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// 当前任期
+    pub fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    /// 当前角色
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// 已知的 leader
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    /// 当前生效的成员配置
+    pub fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    /// 已提交的日志序号
+    pub fn commit_index(&self) -> u64 {
+        self.commit
+    }
+
+    /// 最后一条日志的序号
+    pub fn last_index(&self) -> u64 {
+        self.log.last().map_or(0, |entry| entry.index)
+    }
+
+    /// 序号为 index 的日志，不存在时返回 None
+    pub fn entry(&self, index: u64) -> Option<&Entry> {
+        if index == 0 {
+            return None;
+        }
+        self.log.get(index as usize - 1)
+    }
+
+    /// 推进一个时间单位
+    ///
+    /// leader 定期发送心跳，其他节点在超时后发起选举
+    pub fn tick(&mut self) -> Result<()> {
+        if self.role == Role::Leader {
+            self.heartbeat_elapsed += 1;
+            if self.heartbeat_elapsed >= HEARTBEAT_TICKS {
+                self.heartbeat_elapsed = 0;
+                self.broadcast_append();
+            }
+        } else {
+            self.election_elapsed += 1;
+            if self.election_elapsed >= self.election_timeout
+                && self.membership.contains_key(&self.id)
+            {
+                self.campaign()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 由 leader 追加一条日志，返回其序号
+    ///
+    /// 不是 leader 时返回 NotLeader Error；
+    /// 上一次成员变更尚未提交时，新的成员变更会返回 UnknownOperation Error
+    pub fn propose(&mut self, data: EntryData) -> Result<u64> {
+        if self.role != Role::Leader {
+            Err(KvsErrorType::NotLeader)?
+        }
+        if let EntryData::Membership(membership) = &data {
+            let pending = self
+                .log
+                .iter()
+                .skip(self.commit as usize)
+                .any(|entry| matches!(entry.data, EntryData::Membership(_)));
+            let changed = membership
+                .keys()
+                .chain(self.membership.keys())
+                .filter(|id| membership.get(id) != self.membership.get(id))
+                .collect::<BTreeSet<_>>()
+                .len();
+            if pending || changed > 1 {
+                Err(KvsErrorType::UnknownOperation)?
+            }
+        }
+        self.append_entry(data)
+    }
+
+    /// 增加一个节点
+    pub fn add_member(&mut self, id: NodeId, member: Member) -> Result<u64> {
+        let mut membership = self.membership.clone();
+        membership.insert(id, member);
+        self.propose(EntryData::Membership(membership))
+    }
+
+    /// 删除一个节点
+    pub fn remove_member(&mut self, id: NodeId) -> Result<u64> {
+        let mut membership = self.membership.clone();
+        membership.remove(&id);
+        self.propose(EntryData::Membership(membership))
+    }
+
+    /// 以 ReadIndex 的方式处理 id 为 id 的读请求
+    ///
+    /// leader 记录当前的提交序号，并向其他节点发送心跳；多数节点确认后，
+    /// 通过 `take_reads` 取出该序号，状态机应用到该序号后即可读取，读到的数据不会早于请求到达时已提交的写入
+    ///
+    /// 不是 leader 时返回 NotLeader Error
+    pub fn read_index(&mut self, id: u64) -> Result<()> {
+        if self.role != Role::Leader {
+            Err(KvsErrorType::NotLeader)?
+        }
+        // 当前任期的空日志提交之前，提交序号可能落后于之前任期已提交的日志
+        let index = self.commit.max(self.term_start);
+        self.read_round += 1;
+        self.reads.push((self.read_round, id, index));
+        self.read_acks.insert(self.id, self.read_round);
+        self.confirm_reads();
+        if !self.reads.is_empty() {
+            self.broadcast_append();
+        }
+        Ok(())
+    }
+
+    /// 取出已有结果的读请求，为 (请求 id, 读取序号)
+    ///
+    /// 读取序号为 None 表示确认前已失去 leader 身份，需要重试
+    pub fn take_reads(&mut self) -> Vec<(u64, Option<u64>)> {
+        std::mem::take(&mut self.read_results)
+    }
+
+    /// 处理一条来自其他节点的消息
+    pub fn step(&mut self, msg: Message) -> Result<()> {
+        if msg.term > self.state.term {
+            // 跟随者刚收到过 leader 心跳时忽略投票请求，避免已被移除的节点干扰集群；
+            // leader 收到更高任期的投票请求时总会退位
+            if let MessageBody::RequestVote { .. } = msg.body {
+                if self.role == Role::Follower
+                    && self.leader.is_some()
+                    && self.election_elapsed < ELECTION_TICKS
+                {
+                    return Ok(());
+                }
+            }
+            let leader = match msg.body {
+                MessageBody::AppendEntries { .. } => Some(msg.from),
+                _ => None,
+            };
+            self.become_follower(msg.term, leader)?;
+        } else if msg.term < self.state.term {
+            // 让过期的 leader 或候选者得知新的任期
+            match msg.body {
+                MessageBody::RequestVote { .. } => self.send(
+                    msg.from,
+                    MessageBody::RequestVoteResponse { granted: false },
+                ),
+                MessageBody::AppendEntries { .. } => self.send(
+                    msg.from,
+                    MessageBody::AppendEntriesResponse {
+                        success: false,
+                        match_index: self.last_index(),
+                        read: 0,
+                    },
+                ),
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        match msg.body {
+            MessageBody::RequestVote {
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = up_to_date
+                    && self.leader.is_none()
+                    && self.state.voted_for.unwrap_or(msg.from) == msg.from;
+                if granted {
+                    self.state.voted_for = Some(msg.from);
+                    self.storage.save_hard_state(&self.state)?;
+                    self.election_elapsed = 0;
+                }
+                self.send(msg.from, MessageBody::RequestVoteResponse { granted });
+            }
+            MessageBody::RequestVoteResponse { granted } => {
+                if self.role == Role::Candidate && granted {
+                    self.votes.insert(msg.from);
+                    if self.has_quorum(&self.votes) {
+                        self.become_leader()?;
+                    }
+                }
+            }
+            MessageBody::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                read,
+            } => {
+                if self.role != Role::Follower {
+                    self.become_follower(msg.term, Some(msg.from))?;
+                }
+                self.leader = Some(msg.from);
+                self.election_elapsed = 0;
+                let (success, match_index) =
+                    self.handle_append(prev_log_index, prev_log_term, entries, leader_commit)?;
+                self.send(
+                    msg.from,
+                    MessageBody::AppendEntriesResponse {
+                        success,
+                        match_index,
+                        read,
+                    },
+                );
+            }
+            MessageBody::AppendEntriesResponse {
+                success,
+                match_index,
+                read,
+            } => {
+                if self.role == Role::Leader {
+                    // 同一任期内的响应，无论是否成功都说明对方仍认可该 leader
+                    let acked = self.read_acks.entry(msg.from).or_insert(0);
+                    *acked = (*acked).max(read);
+                    self.confirm_reads();
+                    self.handle_append_response(msg.from, success, match_index)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 取出需要发送的消息
+    pub fn take_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// 取出已提交但尚未应用的日志
+    ///
+    /// 状态机已应用的序号可能大于本地日志的长度（如日志被截断或丢失），此时只取出之后新提交的日志
+    pub fn take_committed(&mut self) -> Vec<Entry> {
+        let end = self.commit.min(self.last_index());
+        let start = self.applied.min(end);
+        self.applied = self.applied.max(end);
+        self.log[start as usize..end as usize].to_vec()
+    }
+
+    /// 最后一条日志的任期
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(0, |entry| entry.term)
+    }
+
+    /// 序号为 index 的日志的任期，index 为 0 时返回 0
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == 0 {
+            Some(0)
+        } else {
+            self.entry(index).map(|entry| entry.term)
+        }
+    }
+
+    /// 生成下一个伪随机数（xorshift）
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// 重置选举计时，并重新随机选举超时
+    fn reset_election_timer(&mut self) {
+        self.election_elapsed = 0;
+        self.election_timeout = ELECTION_TICKS + (self.random() % ELECTION_TICKS as u64) as u32;
+    }
+
+    /// 多数节点确认过的读请求可以读取
+    fn confirm_reads(&mut self) {
+        let mut acked: Vec<u64> = self
+            .membership
+            .keys()
+            .map(|id| self.read_acks.get(id).cloned().unwrap_or(0))
+            .collect();
+        if acked.is_empty() {
+            return;
+        }
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        let round = acked[self.membership.len() / 2];
+        let results = &mut self.read_results;
+        self.reads.retain(|&(read_round, id, index)| {
+            let confirmed = read_round <= round;
+            if confirmed {
+                results.push((id, Some(index)));
+            }
+            !confirmed
+        });
+    }
+
+    /// 失去 leader 身份时，所有未确认的读请求都需要重试
+    fn abandon_reads(&mut self) {
+        let results = &mut self.read_results;
+        results.extend(self.reads.drain(..).map(|(_, id, _)| (id, None)));
+        self.read_acks.clear();
+    }
+
+    /// 判断 nodes 中的节点是否占当前成员的多数
+    fn has_quorum(&self, nodes: &BTreeSet<NodeId>) -> bool {
+        let count = self
+            .membership
+            .keys()
+            .filter(|id| nodes.contains(id))
+            .count();
+        count > self.membership.len() / 2
+    }
+
+    /// 发送消息
+    fn send(&mut self, to: NodeId, body: MessageBody) {
+        self.messages.push(Message {
+            from: self.id,
+            to,
+            term: self.state.term,
+            body,
+        });
+    }
+
+    /// 成为 term 任期的跟随者
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.state.term {
+            self.state = HardState {
+                term,
+                voted_for: None,
+            };
+            self.storage.save_hard_state(&self.state)?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reset_election_timer();
+        self.abandon_reads();
+        Ok(())
+    }
+
+    /// 发起选举
+    fn campaign(&mut self) -> Result<()> {
+        self.state = HardState {
+            term: self.state.term + 1,
+            voted_for: Some(self.id),
+        };
+        self.storage.save_hard_state(&self.state)?;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.reset_election_timer();
+        self.abandon_reads();
+        self.votes.clear();
+        self.votes.insert(self.id);
+        info!(
+            "node {} starts election for term {}",
+            self.id, self.state.term
+        );
+        if self.has_quorum(&self.votes) {
+            return self.become_leader();
+        }
+        let (last_log_index, last_log_term) = (self.last_index(), self.last_term());
+        let peers: Vec<NodeId> = self.peers().collect();
+        for peer in peers {
+            self.send(
+                peer,
+                MessageBody::RequestVote {
+                    last_log_index,
+                    last_log_term,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// 当选 leader，并追加一条空日志以提交之前任期的日志
+    fn become_leader(&mut self) -> Result<()> {
+        info!(
+            "node {} becomes leader of term {}",
+            self.id, self.state.term
+        );
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.heartbeat_elapsed = 0;
+        self.progress.clear();
+        self.update_progress();
+        self.term_start = self.append_entry(EntryData::Noop)?;
+        Ok(())
+    }
+
+    /// 除自身外的所有成员
+    fn peers<'a>(&'a self) -> impl Iterator<Item = NodeId> + 'a {
+        self.membership
+            .keys()
+            .cloned()
+            .filter(move |id| *id != self.id)
+    }
+
+    /// 使 progress 与成员配置一致
+    fn update_progress(&mut self) {
+        let next = self.last_index() + 1;
+        let membership = &self.membership;
+        self.progress.retain(|id, _| membership.contains_key(id));
+        for id in membership.keys() {
+            self.progress
+                .entry(*id)
+                .or_insert(Progress { next, matched: 0 });
+        }
+    }
+
+    /// 根据日志重新计算生效的成员配置
+    fn update_membership(&mut self) {
+        self.membership = self
+            .log
+            .iter()
+            .rev()
+            .filter_map(|entry| match &entry.data {
+                EntryData::Membership(membership) => Some(membership.clone()),
+                _ => None,
+            })
+            .next()
+            .unwrap_or_else(|| self.initial_membership.clone());
+        if self.role == Role::Leader {
+            self.update_progress();
+        }
+    }
+
+    /// leader 追加一条日志并发送给其他节点
+    fn append_entry(&mut self, data: EntryData) -> Result<u64> {
+        let entry = Entry {
+            term: self.state.term,
+            index: self.last_index() + 1,
+            data,
+        };
+        let index = entry.index;
+        let is_membership = matches!(entry.data, EntryData::Membership(_));
+        self.storage.append(std::slice::from_ref(&entry))?;
+        self.log.push(entry);
+        if is_membership {
+            self.update_membership();
+        }
+        if let Some(progress) = self.progress.get_mut(&self.id) {
+            progress.matched = index;
+            progress.next = index + 1;
+        }
+        self.maybe_commit()?;
+        self.broadcast_append();
+        Ok(index)
+    }
+
+    /// 向所有其他成员发送日志（或心跳）
+    fn broadcast_append(&mut self) {
+        let peers: Vec<NodeId> = self.peers().collect();
+        for peer in peers {
+            self.send_append(peer);
+        }
+    }
+
+    /// 向 to 发送其缺少的日志
+    fn send_append(&mut self, to: NodeId) {
+        let next = match self.progress.get(&to) {
+            Some(progress) => progress.next,
+            None => return,
+        };
+        let prev_log_index = next - 1;
+        let prev_log_term = self.term_at(prev_log_index).unwrap_or(0);
+        let end =
+            (self.last_index() as usize).min(prev_log_index as usize + MAX_ENTRIES_PER_MESSAGE);
+        let entries = self.log[prev_log_index as usize..end].to_vec();
+        let leader_commit = self.commit;
+        let read = self.read_round;
+        self.send(
+            to,
+            MessageBody::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                read,
+            },
+        );
+    }
+
+    /// 跟随者处理 AppendEntries，返回是否成功与响应中的 match_index
+    fn handle_append(
+        &mut self,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<(bool, u64)> {
+        if self.term_at(prev_log_index) != Some(prev_log_term) {
+            let match_index = self.last_index().min(prev_log_index.saturating_sub(1));
+            return Ok((false, match_index));
+        }
+        let match_index = prev_log_index + entries.len() as u64;
+        let mut membership_changed = false;
+        for entry in entries {
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    // 与 leader 冲突的日志及其之后的日志都需要删除
+                    self.storage.truncate(entry.index)?;
+                    self.log.truncate(entry.index as usize - 1);
+                    membership_changed = true;
+                }
+                None => {}
+            }
+            if let EntryData::Membership(_) = entry.data {
+                membership_changed = true;
+            }
+            self.storage.append(std::slice::from_ref(&entry))?;
+            self.log.push(entry);
+        }
+        if membership_changed {
+            self.update_membership();
+        }
+        let commit = leader_commit.min(match_index);
+        if commit > self.commit {
+            self.commit = commit;
+        }
+        Ok((true, match_index))
+    }
+
+    /// leader 处理 AppendEntries 的结果
+    fn handle_append_response(
+        &mut self,
+        from: NodeId,
+        success: bool,
+        match_index: u64,
+    ) -> Result<()> {
+        let last_index = self.last_index();
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return Ok(()),
+        };
+        if success {
+            if match_index > progress.matched {
+                progress.matched = match_index;
+            }
+            progress.next = progress.matched + 1;
+        } else {
+            // 根据跟随者的日志长度回退
+            progress.next = progress.next.saturating_sub(1).min(match_index + 1).max(1);
+        }
+        let next = progress.next;
+        if success {
+            self.maybe_commit()?;
+        }
+        if next <= last_index || !success {
+            self.send_append(from);
+        }
+        Ok(())
+    }
+
+    /// 多数节点都已复制的日志可以提交，但只直接提交当前任期的日志
+    fn maybe_commit(&mut self) -> Result<()> {
+        if self.role != Role::Leader {
+            return Ok(());
+        }
+        let mut matched: Vec<u64> = self
+            .membership
+            .keys()
+            .filter_map(|id| self.progress.get(id))
+            .map(|progress| progress.matched)
+            .collect();
+        if matched.is_empty() {
+            return Ok(());
+        }
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.membership.len() / 2];
+        if index > self.commit && self.term_at(index) == Some(self.state.term) {
+            self.commit = index;
+            // 已将自身移除的 leader 在配置提交后退出
+            if !self.membership.contains_key(&self.id) {
+                info!("node {} removed from the cluster", self.id);
+                self.broadcast_append();
+                let term = self.state.term;
+                self.become_follower(term, None)?;
+            }
+        }
+        Ok(())
+    }
+}
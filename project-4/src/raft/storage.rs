@@ -0,0 +1,145 @@
+use super::{Entry, HardState};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Raft 节点的持久化存储
+///
+/// 每个方法返回时数据都应已写入存储，节点在此之后才会发送消息
+pub trait Storage {
+    /// 读取已持久化的状态与日志
+    fn load(&mut self) -> Result<(HardState, Vec<Entry>)>;
+
+    /// 保存任期与投票
+    fn save_hard_state(&mut self, state: &HardState) -> Result<()>;
+
+    /// 在日志末尾追加条目
+    fn append(&mut self, entries: &[Entry]) -> Result<()>;
+
+    /// 删除序号大于等于 index 的日志
+    fn truncate(&mut self, index: u64) -> Result<()>;
+}
+
+/// 内存中的存储
+///
+/// 克隆得到的存储共享同一份数据，可用于在测试中模拟节点崩溃后重启
+#[derive(Clone, Default)]
+pub struct MemStorage {
+    inner: Arc<Mutex<(HardState, Vec<Entry>)>>,
+}
+
+impl MemStorage {
+    /// 创建一个空的存储
+    pub fn new() -> MemStorage {
+        MemStorage::default()
+    }
+}
+
+impl Storage for MemStorage {
+    fn load(&mut self) -> Result<(HardState, Vec<Entry>)> {
+        Ok(self.inner.lock().unwrap().clone())
+    }
+
+    fn save_hard_state(&mut self, state: &HardState) -> Result<()> {
+        self.inner.lock().unwrap().0 = *state;
+        Ok(())
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        self.inner.lock().unwrap().1.extend_from_slice(entries);
+        Ok(())
+    }
+
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let len = inner.1.len().min(index.saturating_sub(1) as usize);
+        inner.1.truncate(len);
+        Ok(())
+    }
+}
+
+/// Raft 日志文件中的一条记录
+#[derive(Serialize, Deserialize)]
+enum Record {
+    HardState(HardState),
+    Entry(Entry),
+    Truncate(u64),
+}
+
+/// 基于文件的存储
+///
+/// 所有修改以记录的形式追加到 `raft.log` 中并同步到磁盘，启动时重放得到状态与日志
+pub struct FileStorage {
+    writer: BufWriter<File>,
+    state: HardState,
+    entries: Vec<Entry>,
+}
+
+impl FileStorage {
+    /// 打开文件夹 dir 中的存储，不存在则创建
+    pub fn open(dir: &Path) -> Result<FileStorage> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join("raft.log");
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut state = HardState::default();
+        let mut entries = Vec::new();
+        let reader = BufReader::new(File::open(&path)?);
+        for record in serde_json::Deserializer::from_reader(reader).into_iter::<Record>() {
+            match record? {
+                Record::HardState(s) => state = s,
+                Record::Entry(entry) => entries.push(entry),
+                Record::Truncate(index) => {
+                    entries.truncate(index.saturating_sub(1) as usize);
+                }
+            }
+        }
+        Ok(FileStorage {
+            writer: BufWriter::new(file),
+            state,
+            entries,
+        })
+    }
+
+    /// 追加一条记录
+    fn write(&mut self, record: &Record) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.sync()
+    }
+
+    /// 将缓冲区中的记录写入文件并同步到磁盘
+    ///
+    /// 投票与日志必须在回复 RequestVote 或 AppendEntries 之前落盘，否则崩溃后可能重复投票或丢失已提交的日志
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&mut self) -> Result<(HardState, Vec<Entry>)> {
+        Ok((self.state, std::mem::take(&mut self.entries)))
+    }
+
+    fn save_hard_state(&mut self, state: &HardState) -> Result<()> {
+        self.write(&Record::HardState(*state))
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        for entry in entries {
+            serde_json::to_writer(&mut self.writer, &Record::Entry(entry.clone()))?;
+        }
+        self.sync()
+    }
+
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        self.write(&Record::Truncate(index))
+    }
+}
@@ -0,0 +1,494 @@
+//! 主从复制模块
+//!
+//! 从节点通过 `Operation::Replicate` 向主节点（使用 KvStore 引擎的 kvs-server）请求日志，
+//! 主节点先发送从节点缺少的日志，之后持续发送新写入的记录
+//!
+//! 从节点将记录应用到本地引擎，并在状态文件中保存已应用的日志位置，重启后从该位置继续；
+//! 若该位置已被主节点压缩，则主节点会发送一份完整的快照
+//!
+//! 每个 keyspace 有独立的日志，从节点按默认 keyspace 的日志流中的 keyspace 列表，
+//! 为每个 keyspace 单独建立连接复制，并删除主节点上已不存在的 keyspace
+
+use crate::backup::BackupManifest;
+use crate::client::{host_of, KvsClient};
+use crate::engines::KvsEngine;
+use crate::{KvsErrorType, Operation, Result};
+use crossbeam::channel::Receiver;
+use rustls::ClientConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// 连接断开后重新连接主节点前等待的时间
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 快照结束后清理多余 key 时，每次扫描的 key 数量
+const SCAN_BATCH: usize = 1000;
+
+/// 日志中的位置
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LogPosition {
+    /// 数据文件 id，每次压缩后递增
+    pub file_id: u64,
+    /// 数据文件中的偏移量
+    pub offset: u64,
+}
+
+/// 主节点发送给从节点的日志条目
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LogEntry {
+    /// 快照开始，直到 SnapshotEnd 之间的记录组成主节点的完整数据
+    Snapshot,
+    /// 快照结束，position 为快照对应的日志位置
+    SnapshotEnd {
+        /// 日志位置
+        position: LogPosition,
+    },
+    /// 日志中的一条记录，position 为该记录结束处的位置
+    Record {
+        /// 日志位置
+        position: LogPosition,
+        /// 记录的操作
+        op: Operation,
+    },
+    /// 主节点完成了一次压缩，此前已应用的数据对应压缩后日志中的 position
+    Compacted {
+        /// 日志位置
+        position: LogPosition,
+    },
+    /// 主节点上所有 keyspace 的名称，在默认 keyspace 的日志流开始时以及 keyspace 被创建或删除时发送
+    Keyspaces {
+        /// keyspace 名称
+        names: Vec<String>,
+    },
+}
+
+/// 主节点一侧的日志流
+///
+/// 先返回订阅时已存在的日志，之后阻塞等待新写入的记录；读取已有日志出错或积压过多被主节点断开时结束
+pub struct Replication {
+    backlog: Box<dyn Iterator<Item = Result<LogEntry>> + Send>,
+    receiver: Receiver<LogEntry>,
+    failed: bool,
+}
+
+impl Replication {
+    /// 由已存在的日志与新记录的接收端构建日志流，已存在的日志在取出时才读取
+    pub(crate) fn new(
+        backlog: impl Iterator<Item = Result<LogEntry>> + Send + 'static,
+        receiver: Receiver<LogEntry>,
+    ) -> Replication {
+        Replication {
+            backlog: Box::new(backlog),
+            receiver,
+            failed: false,
+        }
+    }
+
+    /// 取出下一条已存在的日志，出错时之后的日志都不再发送
+    fn next_backlog(&mut self) -> Option<LogEntry> {
+        if self.failed {
+            return None;
+        }
+        match self.backlog.next() {
+            Some(Ok(entry)) => Some(entry),
+            Some(Err(e)) => {
+                error!("failed to read log: {}", e);
+                self.failed = true;
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// 取出当前所有可用的日志条目，不会阻塞
+    pub fn pending(&mut self) -> Vec<LogEntry> {
+        let mut entries = Vec::new();
+        while let Some(entry) = self.next_backlog() {
+            entries.push(entry);
+        }
+        if !self.failed {
+            entries.extend(self.receiver.try_iter());
+        }
+        entries
+    }
+}
+
+impl Iterator for Replication {
+    type Item = LogEntry;
+
+    fn next(&mut self) -> Option<LogEntry> {
+        match self.next_backlog() {
+            Some(entry) => Some(entry),
+            None if self.failed => None,
+            None => self.receiver.recv().ok(),
+        }
+    }
+}
+
+/// 从节点，将主节点的日志应用到本地引擎
+///
+/// 使用方法：
+///
+/// ```no_run
+/// # use kvs::replication::Follower;
+/// # use kvs::KvStore;
+/// let engine = KvStore::open("follower").unwrap();
+/// let follower = Follower::new(engine, "follower/replica.json".into()).unwrap();
+/// follower.run("127.0.0.1:4000".to_owned());
+/// ```
+pub struct Follower<E: KvsEngine> {
+    engine: E,
+    state_path: PathBuf,
+    position: Option<LogPosition>,
+    credentials: Option<(String, String)>,
+    tls: Option<Arc<ClientConfig>>,
+    snapshot_keys: Option<HashSet<String>>,
+    /// 复制的 keyspace，为空时复制默认 keyspace
+    keyspace: Option<String>,
+    keyspaces: Arc<Mutex<Keyspaces>>,
+}
+
+/// 主节点上的 keyspace，以及正在复制的 keyspace
+#[derive(Default)]
+struct Keyspaces {
+    names: BTreeSet<String>,
+    running: HashSet<String>,
+}
+
+impl<E: KvsEngine> Follower<E> {
+    /// 创建一个从节点，已应用的日志位置保存在 state_path 中
+    ///
+    /// 各 keyspace 已应用的日志位置保存在 state_path 去掉扩展名后加上 `.trees` 的目录中
+    pub fn new(engine: E, state_path: PathBuf) -> Result<Self> {
+        let position = if state_path.exists() {
+            Some(serde_json::from_reader(File::open(&state_path)?)?)
+        } else {
+            None
+        };
+        Ok(Follower {
+            engine,
+            state_path,
+            position,
+            credentials: None,
+            tls: None,
+            snapshot_keys: None,
+            keyspace: None,
+            keyspaces: Arc::default(),
+        })
+    }
+
+    /// 复制名为 name 的 keyspace 的从节点，使用相同的 TLS 配置与认证信息
+    ///
+    /// 本地的 keyspace 不存在时创建；name 不在主节点发来的 keyspace 列表中时，
+    /// 应用日志后保存位置会返回 KeyspaceNotFound Error
+    pub fn keyspace(&self, name: &str) -> Result<Follower<E>> {
+        let state_path = self.keyspace_state_path(name);
+        fs::create_dir_all(state_path.parent().unwrap())?;
+        let mut follower = Follower::new(self.engine.open_tree(name)?, state_path)?;
+        follower.credentials = self.credentials.clone();
+        follower.tls = self.tls.clone();
+        follower.keyspace = Some(name.to_owned());
+        follower.keyspaces = Arc::clone(&self.keyspaces);
+        Ok(follower)
+    }
+
+    /// 名为 name 的 keyspace 的状态文件
+    fn keyspace_state_path(&self, name: &str) -> PathBuf {
+        self.state_path
+            .with_extension("trees")
+            .join(format!("{}.json", name))
+    }
+
+    /// 主节点开启访问控制时，以 user 的身份进行认证
+    ///
+    /// 该用户需要有读取所有 key 的权限
+    pub fn auth(mut self, user: String, token: String) -> Self {
+        self.credentials = Some((user, token));
+        self
+    }
+
+    /// 主节点开启 TLS 时，通过 TLS 连接主节点
+    ///
+    /// config 可由 `tls::client_config` 构建
+    pub fn tls(mut self, config: Arc<ClientConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// 已应用的日志位置，从未同步过时为 None
+    pub fn position(&self) -> Option<LogPosition> {
+        self.position
+    }
+
+    /// 持续从地址为 leader 的主节点复制日志，连接断开后自动重连
+    ///
+    /// 每个 keyspace 在单独的线程中复制，直到其在主节点上被删除
+    pub fn run(mut self, leader: String) {
+        loop {
+            if let Err(e) = self.sync(&leader) {
+                error!("replication from {} failed: {}", leader, e);
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+
+    /// 连接主节点并应用日志，直到连接断开
+    fn sync(&mut self, leader: &str) -> Result<()> {
+        let mut client = match &self.tls {
+            Some(config) => {
+                KvsClient::connect_tls(leader.to_owned(), host_of(leader), Arc::clone(config))?
+            }
+            None => KvsClient::connect(leader.to_owned())?,
+        };
+        if let Some((user, token)) = &self.credentials {
+            client.auth(user.clone(), token.clone())?;
+        }
+        client.use_keyspace(self.keyspace.clone());
+        info!("replicating from {} at {:?}", leader, self.position);
+        for entry in client.replicate(self.position)? {
+            let entry = entry?;
+            let keyspaces = matches!(entry, LogEntry::Keyspaces { .. });
+            self.apply(entry)?;
+            if keyspaces {
+                self.spawn_keyspaces(leader);
+            }
+        }
+        Ok(())
+    }
+
+    /// 为每个还没有在复制的 keyspace 启动一个复制线程
+    fn spawn_keyspaces(&self, leader: &str) {
+        let mut keyspaces = self.keyspaces.lock().unwrap();
+        let names: Vec<_> = keyspaces
+            .names
+            .iter()
+            .filter(|name| !keyspaces.running.contains(*name))
+            .cloned()
+            .collect();
+        for name in names {
+            keyspaces.running.insert(name.clone());
+            // 只用于在复制线程中打开 keyspace 的从节点
+            let parent = Follower {
+                engine: self.engine.clone(),
+                state_path: self.state_path.clone(),
+                position: None,
+                credentials: self.credentials.clone(),
+                tls: self.tls.clone(),
+                snapshot_keys: None,
+                keyspace: None,
+                keyspaces: Arc::clone(&self.keyspaces),
+            };
+            let leader = leader.to_owned();
+            thread::spawn(move || parent.run_keyspace(&name, &leader));
+        }
+    }
+
+    /// 持续复制名为 name 的 keyspace，直到其在主节点上被删除
+    ///
+    /// 每次重连时重新打开本地的 keyspace，因此 keyspace 被删除后重新创建时从快照开始复制
+    fn run_keyspace(&self, name: &str, leader: &str) {
+        loop {
+            {
+                let mut keyspaces = self.keyspaces.lock().unwrap();
+                if !keyspaces.names.contains(name) {
+                    keyspaces.running.remove(name);
+                    return;
+                }
+            }
+            if let Err(e) = self.keyspace(name).and_then(|mut f| f.sync(leader)) {
+                error!("replication of keyspace {} failed: {}", name, e);
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+
+    /// 应用一个日志条目
+    ///
+    /// 快照期间不保存日志位置，中途中断后会重新请求快照
+    pub fn apply(&mut self, entry: LogEntry) -> Result<()> {
+        match entry {
+            LogEntry::Snapshot => {
+                info!("receiving snapshot");
+                self.snapshot_keys = Some(HashSet::new());
+            }
+            LogEntry::SnapshotEnd { position } => {
+                if let Some(keys) = self.snapshot_keys.take() {
+                    self.remove_stale_keys(&keys)?;
+                }
+                self.save(position)?;
+            }
+            LogEntry::Record { position, op } => {
+                if let Some(keys) = &mut self.snapshot_keys {
+                    match &op {
+                        Operation::Set { key, .. } => keys.insert(key.clone()),
+                        Operation::Remove { key } => keys.remove(key),
+                        _ => false,
+                    };
+                }
+                self.apply_op(op)?;
+                if self.snapshot_keys.is_none() {
+                    self.save(position)?;
+                }
+            }
+            LogEntry::Compacted { position } => self.save(position)?,
+            LogEntry::Keyspaces { names } => self.apply_keyspaces(names)?,
+        }
+        Ok(())
+    }
+
+    /// 创建主节点上新增的 keyspace，删除主节点上已不存在的 keyspace 及其状态文件
+    ///
+    /// 各 keyspace 的数据需通过 `keyspace` 得到的从节点复制
+    fn apply_keyspaces(&mut self, names: Vec<String>) -> Result<()> {
+        let local = match self.engine.tree_names() {
+            // 不支持 keyspace 的引擎可以复制没有 keyspace 的主节点
+            Err(ref e) if e.kind() == KvsErrorType::UnknownOperation && names.is_empty() => {
+                return Ok(())
+            }
+            result => result?,
+        };
+        let mut keyspaces = self.keyspaces.lock().unwrap();
+        for name in local.iter().filter(|name| !names.contains(name)) {
+            info!("dropping keyspace {}", name);
+            self.engine.drop_tree(name)?;
+            let state_path = self.keyspace_state_path(name);
+            if state_path.exists() {
+                fs::remove_file(state_path)?;
+            }
+        }
+        for name in &names {
+            self.engine.open_tree(name)?;
+        }
+        keyspaces.names = names.into_iter().collect();
+        Ok(())
+    }
+
+    /// 将操作应用到本地引擎
+    ///
+    /// 重复应用同一条记录不会改变结果，因此崩溃后可以安全地重放
+    fn apply_op(&self, op: Operation) -> Result<()> {
+        match op {
+            Operation::Set { key, value } => self.engine.set(key, value),
+            Operation::Remove { key } => match self.engine.remove(key) {
+                Err(ref e) if e.kind() == KvsErrorType::KeyNotFound => Ok(()),
+                result => result,
+            },
+            _ => Err(KvsErrorType::UnknownOperation)?,
+        }
+    }
+
+    /// 删除本地存在而快照中不存在的 key
+    fn remove_stale_keys(&self, keys: &HashSet<String>) -> Result<()> {
+        let mut after = None;
+        loop {
+            let pairs = self.engine.scan(String::new(), after, SCAN_BATCH)?;
+            for (key, _) in &pairs {
+                if !keys.contains(key) {
+                    self.apply_op(Operation::remove(key))?;
+                }
+            }
+            match pairs.last() {
+                Some((key, _)) => after = Some(key.clone()),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// 保存已应用的日志位置
+    ///
+    /// 持有 keyspace 列表的锁，keyspace 被删除后不再保存，避免删除时留下过期的位置
+    fn save(&mut self, position: LogPosition) -> Result<()> {
+        let keyspaces = Arc::clone(&self.keyspaces);
+        let keyspaces = keyspaces.lock().unwrap();
+        if matches!(&self.keyspace, Some(name) if !keyspaces.names.contains(name)) {
+            Err(KvsErrorType::KeyspaceNotFound)?
+        }
+        let tmp_path = self.state_path.with_extension("tmp");
+        serde_json::to_writer(File::create(&tmp_path)?, &position)?;
+        fs::rename(&tmp_path, &self.state_path)?;
+        self.position = Some(position);
+        Ok(())
+    }
+}
+
+/// 只读引擎，拒绝所有写操作，用于从节点对外提供服务
+#[derive(Clone)]
+pub struct ReadOnly<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> ReadOnly<E> {
+    /// 包装 engine，使其只能读取
+    pub fn new(engine: E) -> Self {
+        ReadOnly { engine }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ReadOnly<E> {
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Err(KvsErrorType::ReadOnly)?
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn remove(&self, _key: String) -> Result<()> {
+        Err(KvsErrorType::ReadOnly)?
+    }
+
+    fn compare_and_swap(
+        &self,
+        _key: String,
+        _expected: Option<String>,
+        _new: Option<String>,
+    ) -> Result<bool> {
+        Err(KvsErrorType::ReadOnly)?
+    }
+
+    fn scan(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        self.engine.scan(prefix, after, limit)
+    }
+
+    fn stats(&self) -> Result<crate::engines::Stats> {
+        self.engine.stats()
+    }
+
+    fn get_type(&self) -> String {
+        self.engine.get_type()
+    }
+
+    fn backup(&self, path: &Path) -> Result<BackupManifest> {
+        self.engine.backup(path)
+    }
+
+    fn replicate(&self, position: Option<LogPosition>) -> Result<Replication> {
+        self.engine.replicate(position)
+    }
+
+    fn open_tree(&self, name: &str) -> Result<Self> {
+        // 只读取已复制的 keyspace，不在从节点上创建
+        if !self.engine.tree_names()?.iter().any(|tree| tree == name) {
+            Err(KvsErrorType::KeyspaceNotFound)?
+        }
+        Ok(ReadOnly::new(self.engine.open_tree(name)?))
+    }
+
+    fn drop_tree(&self, _name: &str) -> Result<bool> {
+        Err(KvsErrorType::ReadOnly)?
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        self.engine.tree_names()
+    }
+}
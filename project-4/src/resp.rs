@@ -0,0 +1,433 @@
+//! RESP (Redis 协议) 模块
+//!
+//! 将 RESP2 的 GET/SET/DEL/EXISTS/MGET/INCR/SCAN/PING/INFO 等命令映射到 KvsEngine 上，
+//! 从而可以使用 redis-cli 或 Redis 客户端库访问数据库
+
+use crate::acl::{Acl, Permission, Principal};
+use crate::engines::KvsEngine;
+use crate::{KvsErrorType, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+
+/// SCAN 未指定 COUNT 时每次返回的 key 数量
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// RESP 中的值
+#[derive(Debug)]
+enum Value {
+    /// 简单字符串 `+OK`
+    Simple(String),
+    /// 错误 `-ERR ...`
+    Error(String),
+    /// 整数 `:1`
+    Integer(i64),
+    /// 批量字符串，None 表示 nil
+    Bulk(Option<String>),
+    /// 数组
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// 序列化后写入 writer
+    fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        match self {
+            Value::Simple(s) => write!(writer, "+{}\r\n", s),
+            Value::Error(s) => write!(writer, "-{}\r\n", s),
+            Value::Integer(i) => write!(writer, ":{}\r\n", i),
+            Value::Bulk(None) => write!(writer, "$-1\r\n"),
+            Value::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s),
+            Value::Array(values) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                for value in values {
+                    value.write_to(writer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// 一条命令中参数数量的上限
+const MAX_ARGS: i64 = 1024 * 1024;
+
+/// 读取一行（不含结尾的 `\r\n`），连接关闭时返回 None
+///
+/// 超过 max_len 字节仍未读到行尾时返回 InvalidArgument Error
+fn read_line(reader: &mut impl BufRead, max_len: usize) -> Result<Option<String>> {
+    let mut line = String::new();
+    let limit = max_len as u64 + 2;
+    if reader.by_ref().take(limit).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') && line.len() as u64 == limit {
+        Err(KvsErrorType::InvalidArgument)?
+    }
+    while line.ends_with('\n') || line.ends_with('\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// 解析 line 中的整数
+fn parse_int(line: &str) -> Result<i64> {
+    Ok(line.parse().map_err(|_| KvsErrorType::UnknownOperation)?)
+}
+
+/// 读取一条命令
+///
+/// 支持 RESP 数组形式与 telnet 使用的 inline 形式，连接关闭时返回 None
+///
+/// 参数总长度超过 max_size 字节、参数数量过多或长度为负时返回 InvalidArgument Error
+fn read_command(reader: &mut impl BufRead, max_size: usize) -> Result<Option<Vec<String>>> {
+    let line = match read_line(reader, max_size)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if !line.starts_with('*') {
+        return Ok(Some(line.split_whitespace().map(String::from).collect()));
+    }
+    let count = parse_int(&line[1..])?;
+    if count < 0 || count > MAX_ARGS {
+        Err(KvsErrorType::InvalidArgument)?
+    }
+    let mut args = Vec::new();
+    let mut size = 0;
+    for _ in 0..count {
+        let header = read_line(reader, max_size)?.ok_or(KvsErrorType::UnknownOperation)?;
+        if !header.starts_with('$') {
+            Err(KvsErrorType::UnknownOperation)?
+        }
+        let len = parse_int(&header[1..])?;
+        if len < 0 || len as u64 > (max_size - size) as u64 {
+            Err(KvsErrorType::InvalidArgument)?
+        }
+        let len = len as usize;
+        size += len;
+        // 读取内容以及结尾的 \r\n
+        let mut buf = vec![0; len + 2];
+        reader.read_exact(&mut buf)?;
+        if !buf.ends_with(b"\r\n") {
+            Err(KvsErrorType::UnknownOperation)?
+        }
+        buf.truncate(len);
+        args.push(String::from_utf8(buf).map_err(|_| KvsErrorType::SerdeError)?);
+    }
+    Ok(Some(args))
+}
+
+/// 判断 key 是否匹配 glob 风格的 pattern（支持 `*` 与 `?`）
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match (pattern.first(), key.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], key) || (!key.is_empty() && glob_match(pattern, &key[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &key[1..]),
+        (Some(p), Some(k)) if p == k => glob_match(&pattern[1..], &key[1..]),
+        _ => false,
+    }
+}
+
+/// 单个 RESP 连接的状态
+struct Session<'a, E: KvsEngine> {
+    engine: &'a E,
+    acl: Option<&'a Acl>,
+    principal: Option<&'a Principal>,
+}
+
+impl<'a, E: KvsEngine> Session<'a, E> {
+    /// 检查是否可以对 key 执行 permission 操作
+    fn allows(&self, permission: Permission, key: &str) -> bool {
+        match (self.acl, self.principal) {
+            (None, _) => true,
+            (Some(_), Some(principal)) => principal.allows(permission, key),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// 执行一条命令
+    fn execute(&mut self, args: &[String]) -> Result<Value> {
+        let name = args[0].to_uppercase();
+        let args = &args[1..];
+        let wrong_args = || {
+            Value::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            ))
+        };
+        if self.acl.is_some()
+            && self.principal.is_none()
+            && !["AUTH", "PING", "QUIT"].contains(&name.as_str())
+        {
+            return Ok(Value::Error("NOAUTH Authentication required.".to_owned()));
+        }
+        let denied =
+            Value::Error("NOPERM this user has no permissions to access the key".to_owned());
+        let value = match name.as_str() {
+            "PING" => match args.len() {
+                0 => Value::Simple("PONG".to_owned()),
+                1 => Value::Bulk(Some(args[0].clone())),
+                _ => wrong_args(),
+            },
+            "AUTH" => {
+                let (user, token) = match args.len() {
+                    1 => ("default", &args[0]),
+                    2 => (args[0].as_str(), &args[1]),
+                    _ => return Ok(wrong_args()),
+                };
+                match self.acl {
+                    None => Value::Simple("OK".to_owned()),
+                    Some(acl) => match acl.authenticate(user, token) {
+                        Ok(principal) => {
+                            self.principal = Some(principal);
+                            Value::Simple("OK".to_owned())
+                        }
+                        Err(_) => Value::Error(
+                            "WRONGPASS invalid username-password pair or user is disabled."
+                                .to_owned(),
+                        ),
+                    },
+                }
+            }
+            "GET" if args.len() == 1 => {
+                if !self.allows(Permission::Get, &args[0]) {
+                    return Ok(denied);
+                }
+                Value::Bulk(self.engine.get(args[0].clone())?)
+            }
+            "SET" if args.len() >= 2 => {
+                if !self.allows(Permission::Set, &args[0]) {
+                    return Ok(denied);
+                }
+                let key = args[0].clone();
+                let value = args[1].clone();
+                match args.get(2).map(|s| s.to_uppercase()) {
+                    None => {
+                        self.engine.set(key, value)?;
+                        Value::Simple("OK".to_owned())
+                    }
+                    // 仅在 key 不存在时设置
+                    Some(ref opt) if opt == "NX" && args.len() == 3 => {
+                        match self.engine.compare_and_swap(key, None, Some(value))? {
+                            true => Value::Simple("OK".to_owned()),
+                            false => Value::Bulk(None),
+                        }
+                    }
+                    // 仅在 key 存在时设置
+                    Some(ref opt) if opt == "XX" && args.len() == 3 => loop {
+                        let old = match self.engine.get(key.clone())? {
+                            Some(old) => old,
+                            None => break Value::Bulk(None),
+                        };
+                        if self.engine.compare_and_swap(
+                            key.clone(),
+                            Some(old),
+                            Some(value.clone()),
+                        )? {
+                            break Value::Simple("OK".to_owned());
+                        }
+                    },
+                    _ => Value::Error("ERR syntax error".to_owned()),
+                }
+            }
+            "DEL" if !args.is_empty() => {
+                if !args.iter().all(|key| self.allows(Permission::Remove, key)) {
+                    return Ok(denied);
+                }
+                let mut removed = 0;
+                for key in args {
+                    match self.engine.remove(key.clone()) {
+                        Ok(()) => removed += 1,
+                        Err(ref e) if e.kind() == KvsErrorType::KeyNotFound => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Value::Integer(removed)
+            }
+            "EXISTS" if !args.is_empty() => {
+                if !args.iter().all(|key| self.allows(Permission::Get, key)) {
+                    return Ok(denied);
+                }
+                let mut count = 0;
+                for key in args {
+                    if self.engine.get(key.clone())?.is_some() {
+                        count += 1;
+                    }
+                }
+                Value::Integer(count)
+            }
+            "MGET" if !args.is_empty() => {
+                if !args.iter().all(|key| self.allows(Permission::Get, key)) {
+                    return Ok(denied);
+                }
+                let mut values = Vec::new();
+                for key in args {
+                    values.push(Value::Bulk(self.engine.get(key.clone())?));
+                }
+                Value::Array(values)
+            }
+            "INCR" if args.len() == 1 => {
+                let key = &args[0];
+                if !self.allows(Permission::Get, key) || !self.allows(Permission::Set, key) {
+                    return Ok(denied);
+                }
+                // 使用比较并交换保证并发时的原子性
+                loop {
+                    let old = self.engine.get(key.clone())?;
+                    let num = match &old {
+                        Some(old) => match old.parse::<i64>().ok().and_then(|n| n.checked_add(1)) {
+                            Some(num) => num,
+                            None => {
+                                break Value::Error(
+                                    "ERR value is not an integer or out of range".to_owned(),
+                                )
+                            }
+                        },
+                        None => 1,
+                    };
+                    if self
+                        .engine
+                        .compare_and_swap(key.clone(), old, Some(num.to_string()))?
+                    {
+                        break Value::Integer(num);
+                    }
+                }
+            }
+            "SCAN" if !args.is_empty() => self.scan(args)?,
+            "INFO" => Value::Bulk(Some(format!(
+                "# Server\r\nkvs_version:{}\r\nengine:{}\r\nauth_enabled:{}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                self.engine.get_type(),
+                if self.acl.is_some() { 1 } else { 0 }
+            ))),
+            // redis-cli 启动时会发送 COMMAND DOCS
+            "COMMAND" => Value::Array(Vec::new()),
+            "SELECT" if args.len() == 1 && args[0] == "0" => Value::Simple("OK".to_owned()),
+            "QUIT" => Value::Simple("OK".to_owned()),
+            "GET" | "SET" | "DEL" | "EXISTS" | "MGET" | "INCR" | "SCAN" | "SELECT" => wrong_args(),
+            _ => Value::Error(format!("ERR unknown command '{}'", name.to_lowercase())),
+        };
+        Ok(value)
+    }
+
+    /// SCAN cursor [MATCH pattern] [COUNT count]
+    ///
+    /// cursor 为上一次遍历到的最后一个 key 的十六进制编码，从头遍历与遍历结束时为 0，
+    /// 因此两次调用之间的写入不会导致 key 被跳过或重复返回
+    ///
+    /// 与 Redis 相同，MATCH 与权限过滤在遍历之后进行，返回的 key 可能少于 COUNT 甚至为空，
+    /// 只有 cursor 为 0 时遍历才结束
+    fn scan(&self, args: &[String]) -> Result<Value> {
+        let after = match args[0].as_str() {
+            "0" => None,
+            cursor => match decode_cursor(cursor) {
+                Some(key) => Some(key),
+                None => return Ok(Value::Error("ERR invalid cursor".to_owned())),
+            },
+        };
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        for option in args[1..].chunks(2) {
+            match (option[0].to_uppercase().as_str(), option.get(1)) {
+                ("MATCH", Some(p)) => pattern = Some(p.clone()),
+                ("COUNT", Some(c)) => match c.parse::<usize>() {
+                    Ok(c) if c > 0 => count = c,
+                    _ => return Ok(Value::Error("ERR syntax error".to_owned())),
+                },
+                _ => return Ok(Value::Error("ERR syntax error".to_owned())),
+            }
+        }
+        // 以 pattern 中第一个通配符之前的部分作为前缀缩小遍历范围
+        let prefix = match &pattern {
+            Some(p) => p.split(['*', '?']).next().unwrap().to_owned(),
+            None => String::new(),
+        };
+        let pairs = self.engine.scan(prefix, after, count)?;
+        let next = match pairs.last() {
+            Some((key, _)) if pairs.len() == count => encode_cursor(key),
+            _ => "0".to_owned(),
+        };
+        let keys = pairs
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| match &pattern {
+                Some(p) => glob_match(p.as_bytes(), key.as_bytes()),
+                None => true,
+            })
+            .filter(|key| self.allows(Permission::Get, key))
+            .map(|key| Value::Bulk(Some(key)))
+            .collect();
+        Ok(Value::Array(vec![
+            Value::Bulk(Some(next)),
+            Value::Array(keys),
+        ]))
+    }
+}
+
+/// 将 key 编码为 SCAN 的 cursor
+///
+/// 编码结果不会是 0，空 key 编码为空字符串
+fn encode_cursor(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 解码 encode_cursor 生成的 cursor，格式不正确时返回 None
+fn decode_cursor(cursor: &str) -> Option<String> {
+    if !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+        return None;
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// 处理一个 RESP 连接
+///
+/// acl 不为空时需先通过 `AUTH user token` 认证；命令大小超过 max_request_size 字节时返回错误并关闭连接
+pub(crate) fn handle_connection<E: KvsEngine, S: Read + Write>(
+    engine: E,
+    acl: Option<Arc<Acl>>,
+    max_request_size: usize,
+    stream: S,
+) {
+    let mut reader = BufReader::new(stream);
+    let mut session = Session {
+        engine: &engine,
+        acl: acl.as_ref().map(|acl| acl.as_ref()),
+        principal: None,
+    };
+    loop {
+        let args = match read_command(&mut reader, max_request_size) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e) => {
+                error!("{}", e);
+                let mut buf = Vec::new();
+                Value::Error(format!("ERR Protocol error: {}", e))
+                    .write_to(&mut buf)
+                    .unwrap();
+                let _ = reader.get_mut().write_all(&buf);
+                break;
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let value = match session.execute(&args) {
+            Ok(value) => value,
+            Err(e) => Value::Error(format!("ERR {}", e)),
+        };
+        let mut buf = Vec::new();
+        value.write_to(&mut buf).unwrap();
+        let stream = reader.get_mut();
+        if let Err(e) = stream.write_all(&buf).and_then(|_| stream.flush()) {
+            error!("{}", e);
+            break;
+        }
+        if args[0].eq_ignore_ascii_case("QUIT") {
+            break;
+        }
+    }
+}
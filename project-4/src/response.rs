@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 /// 响应所使用的结构体
 ///
-/// status 表示响应的状态，0为正常，其他为错误
+/// status 表示响应的状态，0为正常，-2 为无权限，-3 为需重定向到 msg 中的 leader 地址，其他为错误
 ///
 /// msg 用于携带可选的消息
 #[derive(Serialize, Deserialize, Debug)]
@@ -34,4 +34,20 @@ impl Response {
             msg: Some(msg),
         }
     }
+
+    /// 认证失败或无权限时的响应
+    pub fn denied() -> Self {
+        Response {
+            status: -2,
+            msg: Some(String::from("PermissionDenied")),
+        }
+    }
+
+    /// 当前节点不是 leader 时的响应，msg 为 leader 的地址（未知时为空）
+    pub fn redirect(leader: Option<String>) -> Self {
+        Response {
+            status: -3,
+            msg: leader,
+        }
+    }
 }
@@ -1,18 +1,73 @@
+use crate::acl::{Acl, Permission, Principal};
 use crate::engines::KvsEngine;
+use crate::grpc;
+use crate::http;
+use crate::replication::Replication;
+use crate::resp;
 use crate::response::Response;
 use crate::thread_pool::ThreadPool;
-use crate::{Operation, Result};
-use std::io::{BufReader, BufWriter, Write};
+use crate::{unix_socket_path, KvsError, KvsErrorType, Operation, Result};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::io::{self, BufReader, Read, Write};
 use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+/// 处理一个连接的函数，参数为引擎、访问控制列表、请求大小上限与连接
+type Handler<E> = fn(E, Option<Arc<Acl>>, usize, TcpStream);
+
+/// RESP 与 HTTP 请求大小的默认上限（字节）
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 64 * 1024 * 1024;
+
+/// 主协议的监听方式
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// 监听 addr，addr 为 `unix:` 开头的路径时使用 Unix domain socket，否则使用 TCP
+    fn bind(addr: &str) -> io::Result<Listener> {
+        match unix_socket_path(addr) {
+            #[cfg(unix)]
+            Some(path) => Ok(Listener::Unix(bind_unix(path)?)),
+            _ => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+        }
+    }
+}
+
+/// 在 path 上创建 Unix domain socket
+///
+/// 上次运行遗留的 socket 文件会导致监听失败，因此会先将其删除
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    UnixListener::bind(path)
+}
 
 /// 用于处理数据库请求的服务器
 
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
-    thread_pool: P,
+    thread_pool: Arc<P>,
+    tls: Option<Arc<ServerConfig>>,
+    acl: Option<Arc<Acl>>,
+    backup_dir: Option<Arc<PathBuf>>,
+    resp_addr: Option<String>,
+    http_addr: Option<String>,
+    grpc_addr: Option<String>,
+    max_request_size: usize,
 }
 
-impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> KvsServer<E, P> {
     /// 创建一个 KvsServer 实例
     ///
     /// 内部引擎由 engine 决定
@@ -21,31 +76,158 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     pub fn new(engine: E, thread_pool: P) -> Self {
         KvsServer {
             engine,
-            thread_pool,
+            thread_pool: Arc::new(thread_pool),
+            tls: None,
+            acl: None,
+            backup_dir: None,
+            resp_addr: None,
+            http_addr: None,
+            grpc_addr: None,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
         }
     }
 
+    /// 开启 TLS，之后所有连接都需要先完成 TLS 握手
+    ///
+    /// config 可由 `tls::server_config` 构建
+    pub fn tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// 开启认证与访问控制
+    ///
+    /// 每个连接的第一条消息必须是 `Operation::Auth`，之后的操作按 acl 中的规则进行检查
+    pub fn acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// 允许客户端通过 `Operation::Backup` 将数据备份到 dir 中
+    ///
+    /// 备份路径只能是 dir 中的相对路径；开启访问控制时只有管理员可以备份，未设置时拒绝所有远程备份
+    pub fn backup_dir(mut self, dir: PathBuf) -> Self {
+        self.backup_dir = Some(Arc::new(dir));
+        self
+    }
+
+    /// 额外监听 addr，以 RESP (Redis 协议) 提供服务
+    ///
+    /// 与主协议共用引擎、线程池与访问控制，不使用 TLS
+    pub fn resp(mut self, addr: String) -> Self {
+        self.resp_addr = Some(addr);
+        self
+    }
+
+    /// 额外监听 addr，以 HTTP/JSON 提供服务
+    ///
+    /// 与主协议共用引擎、线程池与访问控制，不使用 TLS
+    pub fn http(mut self, addr: String) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// 额外监听 addr，以 gRPC 提供服务
+    ///
+    /// 与主协议共用引擎、线程池与访问控制，不使用 TLS
+    pub fn grpc(mut self, addr: String) -> Self {
+        self.grpc_addr = Some(addr);
+        self
+    }
+
+    /// 设置 RESP 与 HTTP 请求大小的上限（字节），默认为 DEFAULT_MAX_REQUEST_SIZE
+    pub fn max_request_size(mut self, size: usize) -> Self {
+        self.max_request_size = size;
+        self
+    }
+
     /// 启动服务器，监听 addr
+    ///
+    /// addr 可以是 TCP 地址，也可以是 `unix:/path/kvs.sock` 形式的 Unix domain socket
     pub fn run(self, addr: String) -> Result<()> {
-        let listener = match TcpListener::bind(addr) {
+        let listener = match Listener::bind(&addr) {
             Ok(l) => l,
             Err(e) => {
                 error!("{}", e);
                 std::process::exit(1);
             }
         };
+        if let Some(resp_addr) = &self.resp_addr {
+            info!("RESP listening on {}", resp_addr);
+            self.serve(TcpListener::bind(resp_addr)?, resp::handle_connection);
+        }
+        if let Some(http_addr) = &self.http_addr {
+            info!("HTTP listening on {}", http_addr);
+            self.serve(TcpListener::bind(http_addr)?, http::handle_connection);
+        }
+        if let Some(grpc_addr) = &self.grpc_addr {
+            info!("gRPC listening on {}", grpc_addr);
+            grpc::serve(
+                self.engine.clone(),
+                self.acl.clone(),
+                Arc::clone(&self.thread_pool),
+                TcpListener::bind(grpc_addr)?,
+            )?;
+        }
         info!("Server running...");
-        for stream in listener.incoming() {
-            let engine = self.engine.clone();
-            // 交由线程池处理请求
-            self.thread_pool.spawn(move || match stream {
-                Ok(stream) => Self::handle_request(engine, stream),
-                Err(e) => eprint!("{}", e),
-            })
+        match listener {
+            Listener::Tcp(listener) => {
+                for stream in listener.incoming() {
+                    self.accept(stream);
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                for stream in listener.incoming() {
+                    self.accept(stream);
+                }
+            }
         }
         Ok(())
     }
 
+    /// 将主协议的连接交由线程池处理，开启 TLS 时先进行握手
+    fn accept<S: Read + Write + Send + 'static>(&self, stream: io::Result<S>) {
+        let engine = self.engine.clone();
+        let tls = self.tls.clone();
+        let acl = self.acl.clone();
+        let backup_dir = self.backup_dir.clone();
+        // 交由线程池处理请求
+        self.thread_pool.spawn(move || match stream {
+            Ok(stream) => match tls {
+                Some(config) => match ServerConnection::new(config) {
+                    Ok(conn) => Self::handle_request(
+                        engine,
+                        acl,
+                        backup_dir,
+                        StreamOwned::new(conn, stream),
+                    ),
+                    Err(e) => error!("{}", e),
+                },
+                None => Self::handle_request(engine, acl, backup_dir, stream),
+            },
+            Err(e) => eprint!("{}", e),
+        })
+    }
+
+    /// 在新线程中接收 listener 上的连接，并交由线程池使用 handler 处理
+    fn serve(&self, listener: TcpListener, handler: Handler<E>) {
+        let engine = self.engine.clone();
+        let acl = self.acl.clone();
+        let max_request_size = self.max_request_size;
+        let thread_pool = Arc::clone(&self.thread_pool);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let engine = engine.clone();
+                let acl = acl.clone();
+                thread_pool.spawn(move || match stream {
+                    Ok(stream) => handler(engine, acl, max_request_size, stream),
+                    Err(e) => error!("{}", e),
+                })
+            }
+        });
+    }
+
     /// set 操作（用于 bench）
     pub fn set(&self, key: String, value: String) {
         let engine = self.engine.clone();
@@ -68,35 +250,217 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     }
 
     /// 处理请求
-    fn handle_request(engine: E, stream: TcpStream) {
-        let reader = BufReader::new(&stream);
-        let mut writer = BufWriter::new(&stream);
-        let msg_reader = serde_json::Deserializer::from_reader(reader).into_iter::<Operation>();
-
-        let mut send_response = |response: &Response| {
-            serde_json::to_writer(&mut writer, response).unwrap();
-            writer.flush().unwrap();
-        };
+    ///
+    /// stream 可以是任何双向字节流（TCP、Unix domain socket 或 TLS）
+    ///
+    /// acl 不为空时，第一条消息必须为认证消息，认证失败则断开连接
+    ///
+    /// backup_dir 为允许远程备份的目录
+    fn handle_request<S: Read + Write>(
+        engine: E,
+        acl: Option<Arc<Acl>>,
+        backup_dir: Option<Arc<PathBuf>>,
+        stream: S,
+    ) {
+        let mut reader = BufReader::new(stream);
+        let mut principal = None;
+
+        loop {
+            let msg = match serde_json::Deserializer::from_reader(&mut reader)
+                .into_iter::<Operation>()
+                .next()
+            {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => {
+                    // 连接被异常关闭（如 TLS 握手失败）时结束处理
+                    error!("{}", e);
+                    break;
+                }
+                None => break,
+            };
+            // 权限检查
+            let allowed = match (&acl, &msg) {
+                (None, _) => true,
+                (Some(acl), Operation::Auth { user, token }) => {
+                    principal = acl.authenticate(user, token).ok();
+                    principal.is_some()
+                }
+                (Some(_), _) => principal.is_some_and(|p| p.allows_op(&msg)),
+            };
+            let authenticated = acl.is_none() || principal.is_some();
+            let mut replication = None;
+            let response = if allowed {
+                Self::execute(
+                    &engine,
+                    msg,
+                    principal,
+                    None,
+                    backup_dir.as_deref().map(PathBuf::as_path),
+                    &mut replication,
+                )
+            } else {
+                warn!("permission denied");
+                Response::denied()
+            };
+            let buf = serde_json::to_vec(&response).unwrap();
+            let stream = reader.get_mut();
+            if let Err(e) = stream.write_all(&buf).and_then(|_| stream.flush()) {
+                error!("{}", e);
+                break;
+            }
+            if !authenticated {
+                break;
+            }
+            if let Some(entries) = replication {
+                Self::ship_log(entries, reader.get_mut());
+                break;
+            }
+        }
+    }
+
+    /// 在 engine 上执行已通过权限检查的操作，返回响应
+    ///
+    /// engine 为名为 keyspace 的 keyspace，为空时为默认 keyspace；
+    /// 请求复制日志时，将日志流放入 replication，由调用者在发送响应后继续发送日志
+    fn execute(
+        engine: &E,
+        msg: Operation,
+        principal: Option<&Principal>,
+        keyspace: Option<&str>,
+        backup_dir: Option<&Path>,
+        replication: &mut Option<Replication>,
+    ) -> Response {
+        match msg {
+            Operation::Auth { .. } => Response::ok_without_msg(),
+            Operation::Set { key, value } => match engine.set(key, value) {
+                Ok(_) => Response::ok_without_msg(),
+                Err(e) => Self::error_response(engine, e),
+            },
+            Operation::Get { key } => match engine.get(key) {
+                Ok(val) => match val {
+                    Some(value) => Response::ok(value),
+                    None => Response::err(String::from("Key not found")),
+                },
+                Err(e) => Self::error_response(engine, e),
+            },
+            Operation::Remove { key } => match engine.remove(key) {
+                Ok(_) => Response::ok_without_msg(),
+                Err(e) => Self::error_response(engine, e),
+            },
+            Operation::Scan {
+                prefix,
+                after,
+                limit,
+            } => match Self::scan_allowed(engine, prefix, after, limit, principal, keyspace) {
+                Ok(pairs) => Response::ok(serde_json::to_string(&pairs).unwrap()),
+                Err(e) => Self::error_response(engine, e),
+            },
+            Operation::Backup { path } => match backup_dir {
+                Some(dir) => match backup_path(dir, &path).and_then(|path| engine.backup(&path)) {
+                    Ok(manifest) => Response::ok(serde_json::to_string(&manifest).unwrap()),
+                    Err(e) => Self::error_response(engine, e),
+                },
+                None => {
+                    warn!("remote backup is disabled");
+                    Response::denied()
+                }
+            },
+            Operation::Replicate { position } => match engine.replicate(position) {
+                Ok(entries) => {
+                    *replication = Some(entries);
+                    Response::ok_without_msg()
+                }
+                Err(e) => Response::err(format!("{}", e)),
+            },
+            Operation::Keyspace { name, op } => match Self::open_keyspace(engine, &name, &op) {
+                Ok(tree) => {
+                    Self::execute(&tree, *op, principal, Some(&name), backup_dir, replication)
+                }
+                Err(e) => Self::error_response(engine, e),
+            },
+            Operation::DropKeyspace { name } => match engine.drop_tree(&name) {
+                Ok(existed) => Response::ok(existed.to_string()),
+                Err(e) => Self::error_response(engine, e),
+            },
+        }
+    }
+
+    /// 打开名为 name 的 keyspace 以执行 op
+    ///
+    /// 只有写入会创建 keyspace，其他操作在 keyspace 不存在时返回 KeyspaceNotFound Error，
+    /// 避免读取或复制日志时在磁盘上创建 keyspace，也避免从节点重新创建主节点上已删除的 keyspace
+    fn open_keyspace(engine: &E, name: &str, op: &Operation) -> Result<E> {
+        match op {
+            Operation::Set { .. } => {}
+            _ => {
+                if !engine.tree_names()?.iter().any(|tree| tree == name) {
+                    Err(KvsErrorType::KeyspaceNotFound)?
+                }
+            }
+        }
+        engine.open_tree(name)
+    }
+
+    /// 扫描名为 keyspace 的 keyspace，只返回有读取权限的键值对
+    ///
+    /// 先过滤再计数，没有权限的 key 不占用 limit，因此只有扫描到末尾时返回的数量才会少于 limit，
+    /// 客户端可以继续以空结果作为结束的标志
+    fn scan_allowed(
+        engine: &E,
+        prefix: String,
+        mut after: Option<String>,
+        limit: usize,
+        principal: Option<&Principal>,
+        keyspace: Option<&str>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut allowed = Vec::new();
+        while allowed.len() < limit {
+            let pairs = engine.scan(prefix.clone(), after.take(), limit)?;
+            let end = pairs.len() < limit;
+            after = pairs.last().map(|(key, _)| key.clone());
+            allowed.extend(pairs.into_iter().filter(|(key, _)| match principal {
+                Some(p) => p.allows_in(keyspace, Permission::Get, key),
+                None => true,
+            }));
+            if end {
+                break;
+            }
+        }
+        allowed.truncate(limit);
+        Ok(allowed)
+    }
+
+    /// 操作失败时的响应，集群中的非 leader 节点会将客户端重定向到 leader
+    fn error_response(engine: &E, e: KvsError) -> Response {
+        match e.kind() {
+            KvsErrorType::NotLeader => Response::redirect(engine.leader()),
+            _ => Response::err(format!("{}", e)),
+        }
+    }
 
-        for msg in msg_reader {
-            let msg = msg.unwrap().into();
-            match msg {
-                Operation::Set { key, value } => send_response(&match engine.set(key, value) {
-                    Ok(_) => Response::ok_without_msg(),
-                    Err(e) => Response::err(format!("{}", e)),
-                }),
-                Operation::Get { key } => send_response(&match engine.get(key) {
-                    Ok(val) => match val {
-                        Some(value) => Response::ok(value),
-                        None => Response::err(String::from("Key not found")),
-                    },
-                    Err(_) => Response::err(String::from("Key not found")),
-                }),
-                Operation::Remove { key } => send_response(&match engine.remove(key) {
-                    Ok(_) => Response::ok_without_msg(),
-                    Err(e) => Response::err(format!("{}", e)),
-                }),
+    /// 持续向从节点发送日志，直到连接断开
+    ///
+    /// 每个从节点会一直占用线程池中的一个线程
+    fn ship_log<W: Write>(entries: Replication, stream: &mut W) {
+        info!("follower connected");
+        for entry in entries {
+            let buf = serde_json::to_vec(&entry).unwrap();
+            if let Err(e) = stream.write_all(&buf).and_then(|_| stream.flush()) {
+                info!("follower disconnected: {}", e);
+                break;
             }
         }
     }
 }
+
+/// 客户端请求的备份路径 name 在备份目录 dir 中对应的路径
+///
+/// 只接受不含 `..` 的相对路径，避免写入备份目录之外的文件
+fn backup_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let name = Path::new(name);
+    let mut components = name.components().peekable();
+    if components.peek().is_none() || !components.all(|c| matches!(c, Component::Normal(_))) {
+        Err(KvsErrorType::InvalidArgument)?
+    }
+    Ok(dir.join(name))
+}
@@ -0,0 +1,385 @@
+//! 分片模块
+//!
+//! 使用带虚拟节点的一致性哈希将 key 分布到多个 kvs-server 上，
+//! 分片配置保存在 JSON 格式的分片表文件中：
+//!
+//! ```json
+//! {
+//!     "vnodes": 64,
+//!     "shards": [
+//!         { "name": "shard1", "addr": "127.0.0.1:4001" },
+//!         { "name": "shard2", "addr": "127.0.0.1:4002" }
+//!     ]
+//! }
+//! ```
+//!
+//! key 所属的分片只由分片名决定，因此修改某个分片的地址不会移动数据；
+//! 增加或删除分片后，停止所有写入，再使用 `rebalance` 迁移 key
+
+use crate::client::KvsClient;
+use crate::{KvsError, KvsErrorType, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+/// 每个分片默认的虚拟节点数
+const DEFAULT_VNODES: usize = 64;
+
+/// 迁移时每次扫描的 key 数量
+const SCAN_BATCH: usize = 1000;
+
+fn default_vnodes() -> usize {
+    DEFAULT_VNODES
+}
+
+/// 一个分片，即一个 kvs-server
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Shard {
+    /// 分片名，决定其在哈希环上的位置
+    pub name: String,
+    /// 服务器地址
+    pub addr: String,
+}
+
+/// 分片表
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShardMap {
+    /// 每个分片的虚拟节点数
+    #[serde(default = "default_vnodes")]
+    pub vnodes: usize,
+    /// 所有分片
+    pub shards: Vec<Shard>,
+    /// 哈希环，为 哈希值 -> 分片序号 的映射
+    #[serde(skip)]
+    ring: BTreeMap<u64, usize>,
+}
+
+/// FNV-1a 哈希，结果与平台和 Rust 版本无关
+///
+/// 相近的 key（如 `key1`、`key2`）的 FNV 结果分布不均匀，因此再经过 MurmurHash3 的 fmix64 混合
+fn hash(data: &str) -> u64 {
+    let mut hash = data.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+impl ShardMap {
+    /// 由分片列表创建分片表，每个分片使用 vnodes 个虚拟节点
+    pub fn new(shards: Vec<Shard>, vnodes: usize) -> ShardMap {
+        let mut map = ShardMap {
+            vnodes,
+            shards,
+            ring: BTreeMap::new(),
+        };
+        map.build_ring();
+        map
+    }
+
+    /// 从路径为 path 的 JSON 文件中读取分片表
+    pub fn open(path: &Path) -> Result<ShardMap> {
+        let mut map: ShardMap = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        map.build_ring();
+        Ok(map)
+    }
+
+    /// 将分片表保存到 path，先写入临时文件再替换
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        serde_json::to_writer_pretty(File::create(&tmp_path)?, self)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// key 所属的分片
+    ///
+    /// 分片表为空时返回 None
+    pub fn owner(&self, key: &str) -> Option<&Shard> {
+        let hash = hash(key);
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, index)| &self.shards[*index])
+    }
+
+    /// 构建哈希环
+    fn build_ring(&mut self) {
+        self.ring.clear();
+        for (index, shard) in self.shards.iter().enumerate() {
+            for vnode in 0..self.vnodes {
+                self.ring
+                    .insert(hash(&format!("{}#{}", shard.name, vnode)), index);
+            }
+        }
+    }
+}
+
+/// 根据分片表将请求路由到各个服务器的客户端
+///
+/// 使用方法：
+///
+/// ```no_run
+/// # use kvs::sharding::{ShardMap, ShardedClient};
+/// # use std::path::Path;
+/// let map = ShardMap::open(Path::new("shards.json")).unwrap();
+/// let mut client = ShardedClient::new(map);
+/// client.set("key".to_owned(), "value".to_owned()).unwrap();
+/// let values = client.mget(vec!["key".to_owned(), "other".to_owned()]).unwrap();
+/// let pairs = client.scan("k".to_owned(), None, 10).unwrap();
+/// ```
+pub struct ShardedClient {
+    map: ShardMap,
+    previous: Option<ShardMap>,
+    credentials: Option<(String, String)>,
+    /// 到各服务器的连接，以地址区分，首次使用时建立
+    clients: HashMap<String, KvsClient>,
+}
+
+impl ShardedClient {
+    /// 创建一个使用分片表 map 的客户端
+    pub fn new(map: ShardMap) -> ShardedClient {
+        ShardedClient {
+            map,
+            previous: None,
+            credentials: None,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// 服务器开启访问控制时，以 user 的身份连接所有服务器
+    pub fn auth(mut self, user: String, token: String) -> Self {
+        self.credentials = Some((user, token));
+        self
+    }
+
+    /// 迁移期间使用，previous 为迁移前的分片表
+    ///
+    /// 读取时新分片中不存在的 key 会再从旧分片中读取，写入与删除会清除旧分片中的 key，
+    /// 因此在 `rebalance` 完成前也能读到所有数据；`rebalance` 运行期间不能写入
+    pub fn migrating_from(mut self, previous: ShardMap) -> Self {
+        self.previous = Some(previous);
+        self
+    }
+
+    /// 获取 key 所对应的 value，不存在时返回 None
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let addr = self.addr_of(&key)?;
+        let value = self.client(&addr)?.get(key.clone())?;
+        if value.is_some() {
+            return Ok(value);
+        }
+        match self.previous_addr_of(&key) {
+            Some(previous) => self.client(&previous)?.get(key),
+            None => Ok(None),
+        }
+    }
+
+    /// 设置键值对
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let addr = self.addr_of(&key)?;
+        self.client(&addr)?.set(key.clone(), value)?;
+        self.remove_previous(key)
+    }
+
+    /// 删除 key，不存在时返回 KeyNotFound Error
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let addr = self.addr_of(&key)?;
+        let result = self.client(&addr)?.remove(key.clone());
+        match self.previous_addr_of(&key) {
+            Some(previous) => {
+                let previous = self.client(&previous)?.remove(key);
+                result.or(previous)
+            }
+            None => result,
+        }
+    }
+
+    /// 获取多个 key 对应的 value，结果与 keys 的顺序一致
+    ///
+    /// key 按所属分片分组，各分片并行读取；迁移期间新分片中不存在的 key 再从旧分片中读取
+    pub fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, key) in keys.iter().enumerate() {
+            groups.entry(self.addr_of(key)?).or_default().push(index);
+        }
+        let mut values = vec![None; keys.len()];
+        self.get_grouped(&keys, &groups, &mut values)?;
+
+        let mut previous: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, key) in keys.iter().enumerate() {
+            if values[index].is_none() {
+                if let Some(addr) = self.previous_addr_of(key) {
+                    previous.entry(addr).or_default().push(index);
+                }
+            }
+        }
+        self.get_grouped(&keys, &previous, &mut values)?;
+        Ok(values)
+    }
+
+    /// 按 key 从小到大的顺序获取所有分片中以 prefix 开头的键值对
+    ///
+    /// after 不为空时只返回大于 after 的 key，最多返回 limit 个；
+    /// 向每个分片请求 limit 个后合并，因此结果与单个服务器上的扫描一致
+    pub fn scan(
+        &mut self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let mut addrs: Vec<String> = self.map.shards.iter().map(|s| s.addr.clone()).collect();
+        if let Some(previous) = &self.previous {
+            addrs.extend(previous.shards.iter().map(|s| s.addr.clone()));
+        }
+        addrs.sort();
+        addrs.dedup();
+        let mut pairs = BTreeMap::new();
+        for addr in addrs {
+            let found = self
+                .client(&addr)?
+                .scan(prefix.clone(), after.clone(), limit)?;
+            for (key, value) in found {
+                // 迁移期间以新分片中的数据为准
+                if self.addr_of(&key)? == addr || !pairs.contains_key(&key) {
+                    pairs.insert(key, value);
+                }
+            }
+        }
+        Ok(pairs.into_iter().take(limit).collect())
+    }
+
+    /// key 在当前分片表中所属服务器的地址
+    fn addr_of(&self, key: &str) -> Result<String> {
+        match self.map.owner(key) {
+            Some(shard) => Ok(shard.addr.clone()),
+            None => Err(KvsErrorType::UnknownOperation)?,
+        }
+    }
+
+    /// 迁移期间 key 所属的旧服务器地址，与当前地址相同时返回 None
+    fn previous_addr_of(&self, key: &str) -> Option<String> {
+        let previous = self.previous.as_ref()?.owner(key)?;
+        match self.map.owner(key) {
+            Some(shard) if shard.addr == previous.addr => None,
+            _ => Some(previous.addr.clone()),
+        }
+    }
+
+    /// 清除旧分片中的 key
+    fn remove_previous(&mut self, key: String) -> Result<()> {
+        match self.previous_addr_of(&key) {
+            Some(previous) => match self.client(&previous)?.remove(key) {
+                Err(ref e) if e.kind() == KvsErrorType::KeyNotFound => Ok(()),
+                result => result,
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// 并行读取 groups 中每个服务器上的 key，结果写入 values 中对应的位置
+    ///
+    /// groups 为 服务器地址 -> keys 中的序号 的映射，每个服务器使用一个线程与自己的连接
+    fn get_grouped(
+        &mut self,
+        keys: &[String],
+        groups: &HashMap<String, Vec<usize>>,
+        values: &mut [Option<String>],
+    ) -> Result<()> {
+        for addr in groups.keys() {
+            self.client(addr)?;
+        }
+        let results = crossbeam::scope(|scope| {
+            let handles: Vec<_> = self
+                .clients
+                .iter_mut()
+                .filter_map(|(addr, client)| Some((client, groups.get(addr)?)))
+                .map(|(client, indexes)| {
+                    scope.spawn(move |_| -> Result<Vec<(usize, Option<String>)>> {
+                        indexes
+                            .iter()
+                            .map(|&index| Ok((index, client.get(keys[index].clone())?)))
+                            .collect()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(KvsError::from(KvsErrorType::Other)))
+                })
+                .collect::<Vec<_>>()
+        })
+        .map_err(|_| KvsErrorType::Other)?;
+        for result in results {
+            for (index, value) in result? {
+                values[index] = value;
+            }
+        }
+        Ok(())
+    }
+
+    /// 到 addr 的连接，不存在时建立连接并认证
+    fn client(&mut self, addr: &str) -> Result<&mut KvsClient> {
+        if !self.clients.contains_key(addr) {
+            let mut client = KvsClient::connect(addr.to_owned())?;
+            if let Some((user, token)) = &self.credentials {
+                client.auth(user.clone(), token.clone())?;
+            }
+            self.clients.insert(addr.to_owned(), client);
+        }
+        Ok(self.clients.get_mut(addr).unwrap())
+    }
+}
+
+/// 将数据从分片表 from 迁移到分片表 to，返回移动的 key 数量
+///
+/// 逐个扫描 from 中的服务器，将不再属于该服务器的 key 写入新的服务器后再从原服务器删除
+///
+/// 复制与删除之间没有原子性保证，迁移期间的写入可能丢失、删除的 key 可能被重新写入，
+/// 因此调用前必须停止所有写入；迁移期间客户端可以使用 `ShardedClient::migrating_from` 读取
+pub fn rebalance(
+    from: &ShardMap,
+    to: &ShardMap,
+    credentials: Option<(String, String)>,
+) -> Result<usize> {
+    let mut client = ShardedClient::new(to.clone());
+    client.credentials = credentials;
+    let mut moved = 0;
+    for shard in &from.shards {
+        let mut after = None;
+        loop {
+            let pairs =
+                client
+                    .client(&shard.addr)?
+                    .scan(String::new(), after.clone(), SCAN_BATCH)?;
+            let last = match pairs.last() {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            for (key, value) in pairs {
+                let addr = client.addr_of(&key)?;
+                if addr == shard.addr {
+                    continue;
+                }
+                client.client(&addr)?.set(key.clone(), value)?;
+                match client.client(&shard.addr)?.remove(key) {
+                    Err(ref e) if e.kind() == KvsErrorType::KeyNotFound => {}
+                    result => result?,
+                }
+                moved += 1;
+            }
+            after = Some(last);
+        }
+        info!("shard {} rebalanced", shard.name);
+    }
+    Ok(moved)
+}
@@ -0,0 +1,85 @@
+//! TLS 模块
+//!
+//! 基于 rustls，负责从 PEM 文件中读取证书与私钥并构建服务端、客户端的配置
+
+use crate::{KvsErrorType, Result};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+/// 从 PEM 文件中读取所有证书
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        error!("no certificate found in {}", path.display());
+        Err(KvsErrorType::TlsError)?
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// 从 PEM 文件中读取第一个私钥（支持 PKCS#8、RSA 与 EC 格式）
+pub fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    error!("no private key found in {}", path.display());
+    Err(KvsErrorType::TlsError)?
+}
+
+/// 从 PEM 格式的 CA 证书包构建根证书集合
+fn load_root_store(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)?;
+    }
+    Ok(roots)
+}
+
+/// 构建服务端 TLS 配置
+///
+/// cert 与 key 为服务端证书链与私钥
+///
+/// client_ca 不为空时开启双向认证，只接受由该 CA 签发证书的客户端
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(ca) => builder.with_client_cert_verifier(
+            AllowAnyAuthenticatedClient::new(load_root_store(ca)?).boxed(),
+        ),
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_private_key(key)?)?;
+    Ok(Arc::new(config))
+}
+
+/// 构建客户端 TLS 配置
+///
+/// ca 为用于校验服务端证书的 CA 证书包
+///
+/// identity 为客户端证书与私钥，服务端开启双向认证时需要提供
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(load_root_store(ca)?);
+    let config = match identity {
+        Some((cert, key)) => {
+            builder.with_client_auth_cert(load_certs(cert)?, load_private_key(key)?)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
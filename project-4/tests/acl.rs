@@ -0,0 +1,224 @@
+use kvs::acl::Acl;
+use kvs::client::KvsClient;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsErrorType, KvsServer, Result};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ACL: &str = r#"{
+    "users": [
+        {
+            "name": "admin",
+            "token": "admin-token",
+            "admin": true,
+            "rules": [{ "prefix": "", "keyspace": "*", "ops": ["Get", "Set", "Remove"] }]
+        },
+        {
+            "name": "app",
+            "token": "app-token",
+            "rules": [
+                { "prefix": "app/", "ops": ["Get", "Set"] },
+                { "prefix": "", "ops": ["Get"] }
+            ]
+        },
+        {
+            "name": "reader",
+            "token": "reader-token",
+            "rules": [{ "prefix": "public/", "ops": ["Get"] }]
+        },
+        {
+            "name": "reporter",
+            "token": "reporter-token",
+            "rules": [{ "prefix": "", "keyspace": "reports", "ops": ["Get", "Set", "Remove"] }]
+        }
+    ]
+}"#;
+
+fn start_server(dir: &Path, addr: &str) {
+    let acl_path = dir.join("acl.json");
+    fs::write(&acl_path, ACL).unwrap();
+    let acl = Arc::new(Acl::open(&acl_path).unwrap());
+    let store = KvStore::open(dir.join("kvs")).unwrap();
+    let backup_dir = dir.join("backups");
+    fs::create_dir(&backup_dir).unwrap();
+    let addr = addr.to_owned();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap())
+            .acl(acl)
+            .backup_dir(backup_dir)
+            .run(addr)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+}
+
+fn assert_denied<T>(result: Result<T>) {
+    match result {
+        Err(e) => assert_eq!(e.kind(), KvsErrorType::PermissionDenied),
+        Ok(_) => panic!("operation should be denied"),
+    }
+}
+
+// Operations should be checked against the rules of the authenticated user
+#[test]
+fn acl_enforces_prefix_rules() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    start_server(temp_dir.path(), "127.0.0.1:4110");
+
+    let mut admin = KvsClient::connent("127.0.0.1:4110".to_owned())?;
+    admin.auth("admin".to_owned(), "admin-token".to_owned())?;
+    admin.set("config".to_owned(), "value".to_owned())?;
+    admin.set("app/key".to_owned(), "value".to_owned())?;
+
+    let mut app = KvsClient::connent("127.0.0.1:4110".to_owned())?;
+    app.auth("app".to_owned(), "app-token".to_owned())?;
+    assert_eq!(app.get("config".to_owned())?, Some("value".to_owned()));
+    app.set("app/key".to_owned(), "new value".to_owned())?;
+    assert_eq!(app.get("app/key".to_owned())?, Some("new value".to_owned()));
+    assert_denied(app.set("config".to_owned(), "other".to_owned()));
+    assert_denied(app.remove("app/key".to_owned()));
+
+    admin.remove("app/key".to_owned())?;
+    assert_eq!(admin.get("config".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Connections must authenticate with a valid token before any other operation
+#[test]
+fn acl_requires_authentication() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    start_server(temp_dir.path(), "127.0.0.1:4111");
+
+    let mut client = KvsClient::connent("127.0.0.1:4111".to_owned())?;
+    assert_denied(client.get("key".to_owned()));
+
+    let mut client = KvsClient::connent("127.0.0.1:4111".to_owned())?;
+    assert_denied(client.auth("admin".to_owned(), "wrong-token".to_owned()));
+    // 认证失败后连接会被关闭
+    assert!(client.get("key".to_owned()).is_err());
+
+    let mut client = KvsClient::connent("127.0.0.1:4111".to_owned())?;
+    client.auth("admin".to_owned(), "admin-token".to_owned())?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    Ok(())
+}
+
+// Scans should skip keys the user cannot read without ending before the last readable key
+#[test]
+fn acl_filters_scan_before_limit() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    start_server(temp_dir.path(), "127.0.0.1:4112");
+
+    let mut admin = KvsClient::connent("127.0.0.1:4112".to_owned())?;
+    admin.auth("admin".to_owned(), "admin-token".to_owned())?;
+    for i in 0..10 {
+        admin.set(format!("private/{}", i), "value".to_owned())?;
+    }
+    admin.set("public/key".to_owned(), "value".to_owned())?;
+
+    let mut reader = KvsClient::connent("127.0.0.1:4112".to_owned())?;
+    reader.auth("reader".to_owned(), "reader-token".to_owned())?;
+    let pairs = reader.scan(String::new(), None, 3)?;
+    assert_eq!(pairs, vec![("public/key".to_owned(), "value".to_owned())]);
+    assert!(reader
+        .scan(String::new(), Some("public/key".to_owned()), 3)?
+        .is_empty());
+    Ok(())
+}
+
+// Only admins should be able to take backups, even with read and write access to every key
+#[test]
+fn acl_restricts_backup_to_admins() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    start_server(temp_dir.path(), "127.0.0.1:4113");
+
+    let mut app = KvsClient::connent("127.0.0.1:4113".to_owned())?;
+    app.auth("app".to_owned(), "app-token".to_owned())?;
+    assert_denied(app.backup("app.kvsbak".to_owned()));
+
+    let mut admin = KvsClient::connent("127.0.0.1:4113".to_owned())?;
+    admin.auth("admin".to_owned(), "admin-token".to_owned())?;
+    admin.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(admin.backup("admin.kvsbak".to_owned())?.keys, 1);
+    assert!(temp_dir.path().join("backups/admin.kvsbak").exists());
+    assert!(!temp_dir.path().join("backups/app.kvsbak").exists());
+    Ok(())
+}
+
+// Rules should only apply to the keyspaces they name
+#[test]
+fn acl_rules_are_scoped_to_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    start_server(temp_dir.path(), "127.0.0.1:4114");
+
+    let mut admin = KvsClient::connent("127.0.0.1:4114".to_owned())?;
+    admin.auth("admin".to_owned(), "admin-token".to_owned())?;
+    admin.set("key".to_owned(), "value".to_owned())?;
+    admin.use_keyspace(Some("reports".to_owned()));
+    admin.set("key".to_owned(), "report".to_owned())?;
+    admin.use_keyspace(Some("secrets".to_owned()));
+    admin.set("key".to_owned(), "secret".to_owned())?;
+
+    // 默认 keyspace 的规则不适用于其他 keyspace
+    let mut app = KvsClient::connent("127.0.0.1:4114".to_owned())?;
+    app.auth("app".to_owned(), "app-token".to_owned())?;
+    assert_eq!(app.get("key".to_owned())?, Some("value".to_owned()));
+    app.use_keyspace(Some("secrets".to_owned()));
+    assert_denied(app.get("key".to_owned()));
+    assert_denied(app.scan(String::new(), None, 10));
+    assert_denied(app.drop_keyspace("secrets".to_owned()));
+
+    let mut reporter = KvsClient::connent("127.0.0.1:4114".to_owned())?;
+    reporter.auth("reporter".to_owned(), "reporter-token".to_owned())?;
+    assert_denied(reporter.get("key".to_owned()));
+    reporter.use_keyspace(Some("reports".to_owned()));
+    assert_eq!(reporter.get("key".to_owned())?, Some("report".to_owned()));
+    reporter.set("key2".to_owned(), "value".to_owned())?;
+    reporter.use_keyspace(Some("secrets".to_owned()));
+    assert_denied(reporter.get("key".to_owned()));
+    assert!(reporter.drop_keyspace("reports".to_owned())?);
+    assert_denied(reporter.drop_keyspace("secrets".to_owned()));
+
+    admin.use_keyspace(Some("secrets".to_owned()));
+    assert_eq!(admin.get("key".to_owned())?, Some("secret".to_owned()));
+    Ok(())
+}
+
+// Scans in a keyspace need a rule for it and must not create the keyspace
+#[test]
+fn acl_denies_scans_in_ungranted_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    start_server(temp_dir.path(), "127.0.0.1:4222");
+
+    let mut reader = KvsClient::connent("127.0.0.1:4222".to_owned())?;
+    reader.auth("reader".to_owned(), "reader-token".to_owned())?;
+    reader.use_keyspace(Some("other".to_owned()));
+    assert_denied(reader.scan(String::new(), None, 10));
+
+    let mut reporter = KvsClient::connent("127.0.0.1:4222".to_owned())?;
+    reporter.auth("reporter".to_owned(), "reporter-token".to_owned())?;
+    reporter.use_keyspace(Some("reports".to_owned()));
+    match reporter.scan(String::new(), None, 10) {
+        Err(e) => assert_eq!(e.kind(), KvsErrorType::KeyspaceNotFound),
+        Ok(_) => panic!("scanning a missing keyspace should fail"),
+    }
+    assert!(!temp_dir.path().join("kvs/trees/other").exists());
+    assert!(!temp_dir.path().join("kvs/trees/reports").exists());
+    Ok(())
+}
+
+// Tokens that only share a prefix with the configured token must be rejected
+#[test]
+fn acl_rejects_partial_tokens() -> Result<()> {
+    let acl: Acl = serde_json::from_str(ACL)?;
+    assert_eq!(acl.authenticate("admin", "admin-token")?.name, "admin");
+    assert_denied(acl.authenticate("admin", "admin-toke"));
+    assert_denied(acl.authenticate("admin", "admin-token2"));
+    assert_denied(acl.authenticate("admin", ""));
+    assert_denied(acl.authenticate("app", "admin-token"));
+    Ok(())
+}
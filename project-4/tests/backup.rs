@@ -0,0 +1,304 @@
+use assert_cmd::prelude::*;
+use kvs::backup;
+use kvs::client::KvsClient;
+use kvs::engines::LsmEngine;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsErrorType, KvsServer, Result, SledServer};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// 在后台按顺序写入 key0、key1……，每写入一个 key 就更新 counter，并写入较大的值触发压缩
+fn write_in_background<E: KvsEngine>(engine: E, stop: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let padding = "x".repeat(4096);
+        let mut i = 0;
+        while !stop.load(Ordering::SeqCst) {
+            engine.set(format!("key{}", i), i.to_string()).unwrap();
+            engine.set("counter".to_owned(), i.to_string()).unwrap();
+            engine.set("padding".to_owned(), padding.clone()).unwrap();
+            i += 1;
+        }
+    })
+}
+
+/// 检查恢复出的数据是某一时刻的快照：key 连续，且 counter 与最后一个 key 相符
+fn check_snapshot<E: KvsEngine>(engine: &E) -> Result<u64> {
+    let stats = engine.stats()?;
+    let count = stats["keys"] - 2;
+    assert!(count > 0);
+    for i in 0..count {
+        assert_eq!(engine.get(format!("key{}", i))?, Some(i.to_string()));
+    }
+    assert_eq!(engine.get(format!("key{}", count))?, None);
+    let counter: u64 = engine.get("counter".to_owned())?.unwrap().parse().unwrap();
+    assert!(counter + 1 == count || counter + 2 == count);
+    Ok(count)
+}
+
+/// 在 engine 持续写入时备份，恢复到 restored 后检查一致性
+fn backup_under_writes<E: KvsEngine, R: KvsEngine>(engine: E, restored: R) -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = write_in_background(engine.clone(), Arc::clone(&stop));
+    thread::sleep(Duration::from_millis(200));
+    let path = dir.path().join("backup.kvsbak");
+    let manifest = engine.backup(&path)?;
+    thread::sleep(Duration::from_millis(100));
+    stop.store(true, Ordering::SeqCst);
+    writer.join().unwrap();
+
+    assert_eq!(manifest.engine, engine.get_type());
+    assert!(manifest.keys < engine.stats()?["keys"]);
+    assert_eq!(backup::validate(&path)?, manifest);
+    backup::restore(&path, &restored)?;
+    assert_eq!(check_snapshot(&restored)? + 2, manifest.keys);
+    Ok(())
+}
+
+// A KvStore backup taken during writes and compactions should be a consistent snapshot
+#[test]
+fn backup_kvs_under_concurrent_writes() -> Result<()> {
+    let source = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+    let engine = KvStore::open(source.path())?;
+    backup_under_writes(engine.clone(), KvStore::open(target.path())?)?;
+    assert!(engine.stats()?["file_id"] > 0, "no compaction happened");
+    Ok(())
+}
+
+// A sled backup taken during writes should be a consistent snapshot, restorable into KvStore
+#[test]
+fn backup_sled_under_concurrent_writes() -> Result<()> {
+    let source = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+    let engine = SledServer::new(sled::Db::start_default(source.path())?);
+    backup_under_writes(engine, KvStore::open(target.path())?)
+}
+
+// Directory backups need a manifest, and any modified byte should fail validation
+#[test]
+fn backup_validation() -> Result<()> {
+    let source = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(source.path())?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.remove("key50".to_owned())?;
+
+    let manifest = engine.backup(backup_dir.path())?;
+    assert_eq!(manifest.keys, 99);
+    assert_eq!(backup::validate(backup_dir.path())?, manifest);
+
+    let data_path = backup_dir.path().join("data.jsonl");
+    let data = fs::read_to_string(&data_path)?;
+    fs::write(&data_path, data.replace("value42", "value24"))?;
+    let err = backup::validate(backup_dir.path()).unwrap_err();
+    assert_eq!(err.kind(), KvsErrorType::Corrupted);
+
+    fs::write(&data_path, data)?;
+    fs::remove_file(backup_dir.path().join("manifest.json"))?;
+    assert!(backup::validate(backup_dir.path()).is_err());
+    Ok(())
+}
+
+// kvs-admin should back up a running server and restore into an empty directory only
+#[test]
+fn backup_through_server_and_restore_with_cli() -> Result<()> {
+    let source = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+    let engine = KvStore::open(source.path())?;
+    let dir = backup_dir.path().to_owned();
+    thread::spawn(move || {
+        KvsServer::new(engine, SharedQueueThreadPool::new(4).unwrap())
+            .backup_dir(dir)
+            .run("127.0.0.1:4180".to_owned())
+            .unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+    let mut client = KvsClient::connect("127.0.0.1:4180".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    // 只能写入备份目录中
+    let outside = target.path().join("outside.kvsbak");
+    for path in &[outside.to_str().unwrap(), "../outside.kvsbak", ""] {
+        assert!(client.backup(path.to_string()).is_err());
+    }
+    assert!(!outside.exists());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["backup", "backup.kvsbak", "--addr", "127.0.0.1:4180"])
+        .assert()
+        .success()
+        .stdout(contains("2 keys from kvs engine"));
+    let archive = backup_dir.path().join("backup.kvsbak");
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["restore", archive.to_str().unwrap(), "--engine", "sled"])
+        .current_dir(&target)
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(target.path().join("server.cfg"))?,
+        "sled"
+    );
+
+    // 已有数据的目录不能再次恢复
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["restore", archive.to_str().unwrap(), "--engine", "sled"])
+        .current_dir(&target)
+        .assert()
+        .failure()
+        .stderr(contains("Target is not empty."));
+
+    let engine = SledServer::new(sled::Db::start_default(target.path().join("sled"))?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Migrating between engines should copy every key with a matching checksum
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let source = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(source.path())?;
+    for i in 0..2500 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let sled = SledServer::new(sled::Db::start_default(target.path())?);
+    let manifest = backup::migrate(&engine, &sled)?;
+    assert_eq!(manifest.keys, 2500);
+    assert_eq!(manifest.engine, "kvs");
+    assert_eq!(
+        sled.get("key1234".to_owned())?,
+        Some("value1234".to_owned())
+    );
+    assert_eq!(
+        engine.backup(backup_dir.path())?.checksum,
+        manifest.checksum
+    );
+
+    // 目标引擎不为空时拒绝迁移
+    assert!(backup::migrate(&engine, &sled).is_err());
+    Ok(())
+}
+
+// kvs-server --migrate-to should switch server.cfg only after copying the data
+#[test]
+fn migrate_with_server_flag() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let engine = KvStore::open(dir.path().join("kvs"))?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    drop(engine);
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--migrate-to", "sled"])
+        .current_dir(&dir)
+        .assert()
+        .failure()
+        .stderr(contains("Nothing to migrate."));
+
+    fs::write(dir.path().join("server.cfg"), "kvs")?;
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--migrate-to", "kvs"])
+        .current_dir(&dir)
+        .assert()
+        .failure()
+        .stderr(contains("Already using kvs."));
+
+    // 迁移到只保存在内存中的引擎会丢失数据
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--migrate-to", "memory"])
+        .current_dir(&dir)
+        .assert()
+        .failure()
+        .stderr(contains("does not persist data"));
+    assert_eq!(fs::read_to_string(dir.path().join("server.cfg"))?, "kvs");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--migrate-to", "sled"])
+        .current_dir(&dir)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dir.path().join("server.cfg"))?, "sled");
+    {
+        let engine = SledServer::new(sled::Db::start_default(dir.path().join("sled"))?);
+        assert_eq!(engine.stats()?["keys"], 2);
+        engine.set("key3".to_owned(), "value3".to_owned())?;
+    }
+
+    // 迁移回 kvs 时，会覆盖之前留下的 kvs 目录
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--migrate-to", "kvs"])
+        .current_dir(&dir)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dir.path().join("server.cfg"))?, "kvs");
+    let engine = KvStore::open(dir.path().join("kvs"))?;
+    assert_eq!(engine.stats()?["keys"], 3);
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Backups, restores and migrations should carry every keyspace along with the default one
+#[test]
+fn backup_and_migrate_keyspaces() -> Result<()> {
+    let source = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let restored = TempDir::new().unwrap();
+    let migrated = TempDir::new().unwrap();
+    let engine = KvStore::open(source.path())?;
+    engine.set("key".to_owned(), "default".to_owned())?;
+    engine
+        .open_tree("users")?
+        .set("key".to_owned(), "user".to_owned())?;
+    engine.open_tree("empty")?;
+
+    let manifest = engine.backup(backup_dir.path())?;
+    assert_eq!(manifest.keys, 2);
+    assert_eq!(manifest.keyspaces, vec!["empty", "users"]);
+    assert_eq!(backup::validate(backup_dir.path())?, manifest);
+
+    let target = KvStore::open(restored.path())?;
+    backup::restore(backup_dir.path(), &target)?;
+    assert_eq!(target.tree_names()?, vec!["empty", "users"]);
+    assert_eq!(target.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(
+        target.open_tree("users")?.get("key".to_owned())?,
+        Some("user".to_owned())
+    );
+
+    // 不支持 keyspace 的引擎在写入任何数据之前拒绝恢复
+    let lsm = LsmEngine::open(restored.path().join("lsm"))?;
+    assert!(backup::restore(backup_dir.path(), &lsm).is_err());
+    assert_eq!(lsm.get("key".to_owned())?, None);
+
+    let sled = SledServer::new(sled::Db::start_default(migrated.path())?);
+    let migration = backup::migrate(&engine, &sled)?;
+    assert_eq!(migration.keyspaces, manifest.keyspaces);
+    assert_eq!(migration.checksum, manifest.checksum);
+    assert_eq!(
+        sled.open_tree("users")?.get("key".to_owned())?,
+        Some("user".to_owned())
+    );
+    Ok(())
+}
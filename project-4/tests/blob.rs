@@ -0,0 +1,189 @@
+use kvs::backup;
+use kvs::engines::{
+    EncryptionKey, EngineOptions, EngineRegistry, Keyring, KvStoreOptions, MemoryEngine,
+};
+use kvs::replication::LogEntry;
+use kvs::{KvStore, KvsEngine, Operation, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// 不小于 1KB 的 value 保存在 blob 文件中的参数
+fn separated() -> KvStoreOptions {
+    KvStoreOptions {
+        blob_threshold: Some(1024),
+        ..KvStoreOptions::default()
+    }
+}
+
+/// 100KB 的 value
+fn large_value(i: usize) -> String {
+    format!("value{}-", i).repeat(20 * 1024)[..100 * 1024].to_owned()
+}
+
+/// 文件夹中扩展名为 extension 的文件的总大小
+fn files_size(dir: &Path, extension: &str) -> Result<u64> {
+    let mut size = 0;
+    if dir.exists() {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.path().extension() == Some(extension.as_ref()) {
+                size += entry.metadata()?.len();
+            }
+        }
+    }
+    Ok(size)
+}
+
+// Large values should live in blob files, keeping the log small and out of compaction
+#[test]
+fn large_values_in_blob_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), separated())?;
+    for i in 0..20 {
+        store.set(format!("large{}", i), large_value(i))?;
+        store.set(format!("small{}", i), format!("value{}", i))?;
+    }
+    assert!(files_size(temp_dir.path(), "log")? < 10 * 1024);
+    let blob_bytes = files_size(&temp_dir.path().join("blobs"), "blob")?;
+    assert!(blob_bytes >= 20 * 100 * 1024);
+
+    // 反复覆盖小 value 触发压缩，blob 文件不会被复制
+    let stats = store.stats()?;
+    for i in 0..30000 {
+        store.set("small0".to_owned(), format!("value{}", i))?;
+    }
+    let compacted = store.stats()?;
+    assert!(compacted["file_id"] > stats["file_id"]);
+    assert_eq!(compacted["blob_bytes"], blob_bytes);
+    assert_eq!(compacted["blob_garbage_bytes"], 0);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), separated())?;
+    for i in 0..20 {
+        assert_eq!(store.get(format!("large{}", i))?, Some(large_value(i)));
+    }
+    assert_eq!(store.get("small1".to_owned())?, Some("value1".to_owned()));
+    // 已有的 blob 在不分离 value 时仍可读取
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large19".to_owned())?, Some(large_value(19)));
+    assert_eq!(store.stats()?["keys"], 40);
+    Ok(())
+}
+
+// Overwritten and removed blobs should be reclaimed without losing live values
+#[test]
+fn blob_garbage_collection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blobs = temp_dir.path().join("blobs");
+    let store = KvStore::open_with(temp_dir.path(), separated())?;
+    for i in 0..40 {
+        store.set(format!("key{}", i), large_value(i))?;
+    }
+    let before = files_size(&blobs, "blob")?;
+    for i in 0..30 {
+        if i % 2 == 0 {
+            store.remove(format!("key{}", i))?;
+        } else {
+            store.set(format!("key{}", i), "small".to_owned())?;
+        }
+    }
+    let stats = store.stats()?;
+    assert!(stats["blob_bytes"] < before / 2);
+    assert!(stats["blob_garbage_bytes"] < 1024 * 1024);
+    assert_eq!(files_size(&blobs, "blob")?, stats["blob_bytes"]);
+    for i in 30..40 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(large_value(i)));
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), separated())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("small".to_owned()));
+    for i in 30..40 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(large_value(i)));
+    }
+    for i in 30..40 {
+        store.remove(format!("key{}", i))?;
+    }
+    assert_eq!(store.stats()?["blob_files"], 0);
+    assert_eq!(files_size(&blobs, "blob")?, 0);
+    Ok(())
+}
+
+// Blob entries should be encrypted, and rewritten with the new key on rotation
+#[test]
+fn encrypted_blobs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blobs = temp_dir.path().join("blobs");
+    let (old, _) = EncryptionKey::generate();
+    let (new, _) = EncryptionKey::generate();
+    let options = |keyring| KvStoreOptions {
+        keyring: Some(keyring),
+        ..separated()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options(Keyring::new(old.clone())))?;
+    for i in 0..5 {
+        store.set(format!("secret{}", i), large_value(i))?;
+    }
+    drop(store);
+    let old_files: Vec<_> = fs::read_dir(&blobs)?.map(|e| e.unwrap().path()).collect();
+    for path in &old_files {
+        let content = fs::read_to_string(path)?;
+        assert!(!content.contains("secret"));
+        assert!(!content.contains("value1-"));
+        assert!(content.contains(old.id()));
+    }
+
+    let keyring = Keyring::new(new.clone()).with_previous(old.clone());
+    drop(KvStore::open_with(temp_dir.path(), options(keyring))?);
+    for path in &old_files {
+        assert!(!path.exists());
+    }
+    for entry in fs::read_dir(&blobs)? {
+        let content = fs::read_to_string(entry?.path())?;
+        assert!(!content.contains(old.id()));
+        assert!(content.contains(new.id()));
+    }
+    let store = KvStore::open_with(temp_dir.path(), options(Keyring::new(new)))?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("secret{}", i))?, Some(large_value(i)));
+    }
+    Ok(())
+}
+
+// Backups and replication should carry the values stored in blob files
+#[test]
+fn blob_backup_and_replication() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = EngineOptions::new();
+    options.insert("blob-threshold".to_owned(), "1024".to_owned());
+    let engine = EngineRegistry::builtin().open("kvs", temp_dir.path(), &options)?;
+    for i in 0..10 {
+        engine.set(format!("key{}", i), large_value(i))?;
+    }
+    assert_eq!(engine.stats()?["blob_files"], 1);
+
+    let backup_path = temp_dir.path().join("backup.kvs");
+    assert_eq!(engine.backup(&backup_path)?.keys, 10);
+    // 备份后回收 blob 文件不影响备份的内容
+    for i in 0..10 {
+        engine.remove(format!("key{}", i))?;
+    }
+    assert_eq!(engine.stats()?["blob_files"], 0);
+    let target = MemoryEngine::new();
+    backup::restore(&backup_path, &target)?;
+    assert_eq!(target.get("key9".to_owned())?, Some(large_value(9)));
+
+    engine.set("key".to_owned(), large_value(0))?;
+    let entries = engine.replicate(None)?.pending();
+    assert!(entries.iter().any(|entry| match entry {
+        LogEntry::Record {
+            op: Operation::Set { key, value },
+            ..
+        } => key == "key" && *value == large_value(0),
+        _ => false,
+    }));
+    Ok(())
+}
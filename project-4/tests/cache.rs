@@ -0,0 +1,125 @@
+use kvs::engines::KvStoreOptions;
+use kvs::{KvStore, KvsEngine, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tempfile::TempDir;
+
+/// 读缓存容量为 size 字节的参数
+fn cached(size: u64) -> KvStoreOptions {
+    KvStoreOptions {
+        cache_size: Some(size),
+        ..KvStoreOptions::default()
+    }
+}
+
+// Repeated reads should hit the cache, and writes should invalidate cached values
+#[test]
+fn cache_hits_and_invalidation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value1".to_owned())?;
+    for _ in 0..10 {
+        assert_eq!(store.get("key".to_owned())?, Some("value1".to_owned()));
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats["cache_misses"], 1);
+    assert_eq!(stats["cache_hits"], 9);
+    assert!(stats["cache_bytes"] > 0);
+
+    store.set("key".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value2".to_owned()));
+    assert!(store.compare_and_swap(
+        "key".to_owned(),
+        Some("value2".to_owned()),
+        Some("value3".to_owned())
+    )?);
+    assert_eq!(store.get("key".to_owned())?, Some("value3".to_owned()));
+    store.remove("key".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.stats()?["cache_bytes"], 0);
+    Ok(())
+}
+
+// The cache should stay within its capacity, and be disabled by a capacity of zero
+#[test]
+fn cache_is_bounded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), cached(16 * 1024))?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), "v".repeat(500))?;
+    }
+    for _ in 0..2 {
+        for i in 0..200 {
+            assert_eq!(store.get(format!("key{}", i))?, Some("v".repeat(500)));
+        }
+    }
+    let stats = store.stats()?;
+    assert!(stats["cache_bytes"] <= 16 * 1024);
+    assert!(stats["cache_misses"] > 200);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), cached(0))?;
+    for _ in 0..3 {
+        assert_eq!(store.get("key1".to_owned())?, Some("v".repeat(500)));
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats["cache_hits"], 0);
+    assert_eq!(stats["cache_bytes"], 0);
+    Ok(())
+}
+
+// Compaction should drop cached values, and reads afterwards should still be correct
+#[test]
+fn compaction_clears_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("hot".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("hot".to_owned())?, Some("value".to_owned()));
+    let file_id = store.stats()?["file_id"];
+    let mut i = 0;
+    while store.stats()?["file_id"] == file_id {
+        store.set("cold".to_owned(), format!("value{}", i))?;
+        i += 1;
+    }
+    assert_eq!(store.stats()?["cache_bytes"], 0);
+    assert_eq!(store.get("hot".to_owned())?, Some("value".to_owned()));
+    assert_eq!(
+        store.get("cold".to_owned())?,
+        Some(format!("value{}", i - 1))
+    );
+    Ok(())
+}
+
+// Readers racing with a writer should never see a value older than one they already read
+#[test]
+fn cache_never_serves_stale_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "0".to_owned())?;
+    let done = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || -> Result<()> {
+                let mut last = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let value: u64 = store.get("key".to_owned())?.unwrap().parse().unwrap();
+                    assert!(value >= last);
+                    last = value;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for i in 1..=2000 {
+        store.set("key".to_owned(), i.to_string())?;
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(store.get("key".to_owned())?, Some("2000".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,387 @@
+use assert_cmd::prelude::*;
+use kvs::client::KvsClient;
+use kvs::engines::{self, EncryptionKey, Keyring, KvStoreOptions, Repair};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsErrorType, KvsServer, Result};
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// 在 dir 中写入 key0 到 key9，并覆盖其中的 key0 与删除 key1
+fn populate(dir: &Path, options: KvStoreOptions) -> Result<()> {
+    let store = KvStore::open_with(dir, options)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.remove("key1".to_owned())?;
+    Ok(())
+}
+
+/// 在当前数据文件末尾追加 data
+fn append_to_log(dir: &Path, data: &[u8]) -> Result<()> {
+    let status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(dir.join("status.json"))?)?;
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(dir.join(format!("{}.log", status["cur_file_id"])))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+// A healthy directory should be clean, with its duplicates and garbage accounted for
+#[test]
+fn check_clean_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path(), KvStoreOptions::default())?;
+    let report = engines::check(temp_dir.path(), None, None)?;
+    assert!(report.is_clean());
+    assert_eq!(report.records, 12);
+    assert_eq!(report.keys, 9);
+    assert_eq!(report.duplicate_keys, 2);
+    assert!(report.garbage_bytes > 0);
+    assert!(report.garbage_bytes < report.log_bytes);
+    assert_eq!(report.corrupted_at, None);
+    Ok(())
+}
+
+// A torn record at the end of the log should be found and cut off by a truncating repair
+#[test]
+fn check_repairs_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path(), KvStoreOptions::default())?;
+    let clean = engines::check(temp_dir.path(), None, None)?;
+    append_to_log(temp_dir.path(), br#"{"Set":{"key":"key10","val"#)?;
+
+    let report = engines::check(temp_dir.path(), None, None)?;
+    assert!(!report.is_clean());
+    assert_eq!(report.corrupted_at, Some(clean.log_bytes));
+    assert_eq!(report.records, 12);
+
+    let report = engines::check(temp_dir.path(), None, Some(Repair::Truncate))?;
+    assert!(report.is_clean());
+    assert_eq!(report.log_bytes, clean.log_bytes);
+    assert_eq!(report.repaired.len(), 1);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    Ok(())
+}
+
+/// 当前数据文件的路径
+fn current_log(dir: &Path) -> Result<PathBuf> {
+    let status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(dir.join("status.json"))?)?;
+    Ok(dir.join(format!("{}.log", status["cur_file_id"])))
+}
+
+// Reading a corrupted record through the server should report the corruption, not a missing key
+#[test]
+fn server_reports_corrupted_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path(), KvStoreOptions::default())?;
+    let options = KvStoreOptions {
+        buffered_reads: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let log = current_log(temp_dir.path())?;
+    let at = fs::read(&log)?
+        .windows(6)
+        .position(|w| w == b"value5")
+        .unwrap();
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    file.seek(SeekFrom::Start(at as u64 + 5))?;
+    file.write_all(b"6")?;
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(2).unwrap())
+            .run("127.0.0.1:4221".to_owned())
+            .unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect("127.0.0.1:4221".to_owned())?;
+    assert_eq!(
+        client.get("key5".to_owned()).err().unwrap().kind(),
+        KvsErrorType::Corrupted
+    );
+    assert_eq!(client.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(client.get("missing".to_owned())?, None);
+    Ok(())
+}
+
+/// 将 dir 改写为旧版本的格式：去掉记录的校验和以及 status.json 中的 checksummed
+fn strip_checksums(dir: &Path) -> Result<()> {
+    let log = current_log(dir)?;
+    let mut content = Vec::new();
+    for record in serde_json::Deserializer::from_slice(&fs::read(&log)?).into_iter() {
+        let mut record: serde_json::Value = record?;
+        if let Some(stamped) = record.get_mut("Stamped").and_then(|v| v.as_object_mut()) {
+            stamped.remove("crc");
+        }
+        content.extend(serde_json::to_vec(&record)?);
+    }
+    fs::write(&log, content)?;
+    let mut status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(dir.join("status.json"))?)?;
+    status.as_object_mut().unwrap().remove("checksummed");
+    fs::write(dir.join("status.json"), serde_json::to_vec(&status)?)?;
+    Ok(())
+}
+
+// A record whose content no longer matches its checksum should be found even if it still parses
+#[test]
+fn check_detects_checksum_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path(), KvStoreOptions::default())?;
+    let log = current_log(temp_dir.path())?;
+    let content = fs::read(&log)?;
+    let at = content.windows(6).position(|w| w == b"value5").unwrap();
+    let mut tampered = content.clone();
+    tampered[at + 5] = b'6';
+    fs::write(&log, &tampered)?;
+
+    let report = engines::check(temp_dir.path(), None, None)?;
+    assert!(!report.is_clean());
+    assert_eq!(report.records, 5);
+    let offset = report.corrupted_at.unwrap() as usize;
+    assert!(offset < at && at < offset + 200);
+    assert_eq!(
+        KvStore::open(temp_dir.path()).err().unwrap().kind(),
+        KvsErrorType::Corrupted
+    );
+
+    let report = engines::check(temp_dir.path(), None, Some(Repair::Truncate))?;
+    assert!(report.is_clean());
+    assert_eq!(report.log_bytes, offset as u64);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, None);
+    Ok(())
+}
+
+// Checksums of encrypted records should be verified even without the key
+#[test]
+fn check_encrypted_checksums_without_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (key, _) = EncryptionKey::generate();
+    let options = KvStoreOptions {
+        keyring: Some(Keyring::new(key)),
+        ..KvStoreOptions::default()
+    };
+    populate(temp_dir.path(), options)?;
+    let log = current_log(temp_dir.path())?;
+    let mut content = fs::read(&log)?;
+    let at = content
+        .windows(8)
+        .rposition(|w| w == b"\"data\":\"")
+        .unwrap()
+        + 8;
+    content[at] = if content[at] == b'A' { b'B' } else { b'A' };
+    fs::write(&log, &content)?;
+
+    let report = engines::check(temp_dir.path(), None, None)?;
+    assert!(!report.is_clean());
+    assert_eq!(report.unverified, 11);
+    assert!(report.corrupted_at.is_some());
+    Ok(())
+}
+
+// Checksums should cover the record bytes on disk, and checksummed logs should reject records without one
+#[test]
+fn checksums_cover_raw_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path(), KvStoreOptions::default())?;
+    let log = current_log(temp_dir.path())?;
+    let content = fs::read(&log)?;
+    let at = content.windows(6).position(|w| w == b"value5").unwrap();
+
+    // 转义后解析出的记录相同，但磁盘上的字节已被修改
+    let mut escaped = content[..at].to_vec();
+    escaped.extend_from_slice(b"\\u0076alue5");
+    escaped.extend_from_slice(&content[at + 6..]);
+    fs::write(&log, &escaped)?;
+    assert_eq!(
+        KvStore::open(temp_dir.path()).err().unwrap().kind(),
+        KvsErrorType::Corrupted
+    );
+
+    // 通过内存映射读取时同样校验
+    fs::write(&log, &content)?;
+    let store = KvStore::open(temp_dir.path())?;
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    file.seek(SeekFrom::Start(at as u64 + 5))?;
+    file.write_all(b"6")?;
+    assert_eq!(
+        store.get("key5".to_owned()).unwrap_err().kind(),
+        KvsErrorType::Corrupted
+    );
+    drop(store);
+
+    fs::write(&log, &content)?;
+    append_to_log(temp_dir.path(), br#"{"Set":{"key":"key5","value":"forged"}}"#)?;
+    assert_eq!(
+        KvStore::open(temp_dir.path()).err().unwrap().kind(),
+        KvsErrorType::Corrupted
+    );
+    let report = engines::check(temp_dir.path(), None, None)?;
+    assert_eq!(report.corrupted_at, Some(content.len() as u64));
+    Ok(())
+}
+
+// Directories written before checksums existed should pass the check and be upgraded on open
+#[test]
+fn check_accepts_records_without_checksums() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path(), KvStoreOptions::default())?;
+    strip_checksums(temp_dir.path())?;
+
+    let report = engines::check(temp_dir.path(), None, None)?;
+    assert!(report.is_clean());
+    assert_eq!((report.records, report.unchecksummed), (12, 12));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    drop(store);
+    let report = engines::check(temp_dir.path(), None, None)?;
+    assert!(report.is_clean());
+    assert_eq!((report.records, report.unchecksummed), (9, 0));
+
+    // 已升级的数据库中不接受去掉了校验和的记录
+    strip_checksums(temp_dir.path())?;
+    let mut status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(temp_dir.path().join("status.json"))?)?;
+    status["checksummed"] = serde_json::Value::Bool(true);
+    fs::write(
+        temp_dir.path().join("status.json"),
+        serde_json::to_vec(&status)?,
+    )?;
+    let report = engines::check(temp_dir.path(), None, None)?;
+    assert_eq!(report.corrupted_at, Some(0));
+    Ok(())
+}
+
+// Log files left behind by an interrupted compaction should be reported and removed
+#[test]
+fn check_orphan_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path(), KvStoreOptions::default())?;
+    let orphan = temp_dir.path().join("7.log");
+    fs::write(&orphan, b"")?;
+
+    let report = engines::check(temp_dir.path(), None, None)?;
+    assert_eq!(report.orphan_logs, vec![orphan.clone()]);
+    assert!(!report.is_clean());
+
+    let report = engines::check(temp_dir.path(), None, Some(Repair::Truncate))?;
+    assert!(report.is_clean());
+    assert!(!orphan.exists());
+    Ok(())
+}
+
+// A value whose blob file is gone should be reported and dropped by a rebuild
+#[test]
+fn check_rebuilds_dangling_blob() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        blob_threshold: Some(1024),
+        ..KvStoreOptions::default()
+    };
+    populate(temp_dir.path(), options.clone())?;
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("large".to_owned(), "x".repeat(4096))?;
+    drop(store);
+    assert!(engines::check(temp_dir.path(), None, None)?.is_clean());
+    for entry in fs::read_dir(temp_dir.path().join("blobs"))? {
+        fs::remove_file(entry?.path())?;
+    }
+
+    let report = engines::check(temp_dir.path(), None, None)?;
+    assert_eq!(report.dangling_blobs, vec!["large".to_owned()]);
+    let report = engines::check(temp_dir.path(), None, Some(Repair::Rebuild))?;
+    assert!(report.is_clean());
+    assert_eq!(report.keys, 9);
+    assert_eq!(report.duplicate_keys, 0);
+    assert_eq!(report.garbage_bytes, 0);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// Encrypted records can only be verified with the key, and are never repaired without it
+#[test]
+fn check_encrypted_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (key, _) = EncryptionKey::generate();
+    let options = KvStoreOptions {
+        keyring: Some(Keyring::new(key.clone())),
+        ..KvStoreOptions::default()
+    };
+    populate(temp_dir.path(), options)?;
+
+    let report = engines::check(temp_dir.path(), None, Some(Repair::Rebuild))?;
+    assert!(report.is_clean());
+    assert_eq!(report.records, 0);
+    assert_eq!(report.unverified, 12);
+    assert!(report.repaired.is_empty());
+
+    let report = engines::check(temp_dir.path(), Some(Keyring::new(key)), None)?;
+    assert!(report.is_clean());
+    assert_eq!(report.records, 12);
+    assert_eq!(report.keys, 9);
+
+    let (other, _) = EncryptionKey::generate();
+    let report = engines::check(temp_dir.path(), Some(Keyring::new(other)), None)?;
+    assert!(report.status_error.is_some());
+    assert!(!report.is_clean());
+    Ok(())
+}
+
+// kvs-admin check should report problems in keyspaces and exit with failure until repaired
+#[test]
+fn cli_check() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("kvs");
+    let store = KvStore::open(&dir)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store
+        .open_tree("users")?
+        .set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    append_to_log(&dir.join("trees").join("users"), b"{\"Rem")?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["check", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stdout(contains("keyspace users:"))
+        .stdout(contains("corrupted record at offset"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["check", dir.to_str().unwrap(), "--repair", "fix"])
+        .assert()
+        .failure()
+        .stderr(contains("Invalid repair mode."));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["check", dir.to_str().unwrap(), "--repair", "truncate"])
+        .assert()
+        .success()
+        .stdout(contains("repaired: truncated"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["check", dir.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("ok"));
+    Ok(())
+}
@@ -129,6 +129,19 @@ fn client_cli_invalid_subcommand() {
         .failure();
 }
 
+// `kvs-client rebalance` should refuse to run unless writes are confirmed to be stopped
+#[test]
+fn client_cli_rebalance_requires_stopped_writes() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rebalance", "--from", "old.json", "--to", "new.json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--writes-stopped"));
+}
+
 // `kvs-client -V` should print the version
 #[test]
 fn client_cli_version() {
@@ -336,3 +349,10 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+#[cfg(unix)]
+fn cli_access_server_unix_socket() {
+    // 相对于 server 与 client 的工作目录
+    cli_access_server("kvs", "unix:kvs.sock");
+}
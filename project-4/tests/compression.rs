@@ -0,0 +1,144 @@
+use kvs::engines::{Compression, KvStoreOptions};
+use kvs::replication::LogEntry;
+use kvs::{KvStore, KvsEngine, KvsErrorType, Operation, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// 使用 compression 压缩 value 的参数
+fn compressed(compression: Compression) -> KvStoreOptions {
+    KvStoreOptions {
+        compression: Some(compression),
+        ..KvStoreOptions::default()
+    }
+}
+
+/// 冗余度很高的 JSON value
+fn json_value(i: usize) -> String {
+    let items: Vec<_> = (0..50)
+        .map(|j| {
+            format!(
+                "{{\"id\":{},\"name\":\"item-{}\",\"tags\":[\"a\",\"b\"]}}",
+                j, i
+            )
+        })
+        .collect();
+    format!("{{\"items\":[{}]}}", items.join(","))
+}
+
+/// 数据目录中所有数据文件的大小
+fn log_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.path().extension() == Some("log".as_ref()) {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// 写入 100 个 value 后返回数据文件的大小，并检查重新打开后仍能读取
+fn write_values(dir: &Path, compression: Compression) -> Result<u64> {
+    let store = KvStore::open_with(dir, compressed(compression))?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), json_value(i))?;
+    }
+    drop(store);
+    let store = KvStore::open(dir)?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(json_value(i)));
+    }
+    log_size(dir)
+}
+
+// Compressed stores should write much smaller logs and read values back unchanged
+#[test]
+fn compressed_log_is_smaller() -> Result<()> {
+    let plain = TempDir::new().expect("unable to create temporary working directory");
+    let lz4 = TempDir::new().expect("unable to create temporary working directory");
+    let zstd = TempDir::new().expect("unable to create temporary working directory");
+    let plain_size = write_values(plain.path(), Compression::None)?;
+    let lz4_size = write_values(lz4.path(), Compression::Lz4)?;
+    let zstd_size = write_values(zstd.path(), Compression::Zstd)?;
+    assert!(lz4_size * 2 < plain_size);
+    assert!(zstd_size * 2 < plain_size);
+    Ok(())
+}
+
+// Values that do not shrink should be stored uncompressed, and compaction should keep both kinds
+#[test]
+fn small_values_stay_uncompressed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), compressed(Compression::Lz4))?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), json_value(0))?;
+    let log = fs::read_to_string(temp_dir.path().join("0.log"))?;
+    assert!(log.contains("\"value\":\"value\""));
+    assert!(!log.contains("item-0"));
+
+    // 反复覆盖直到触发压缩
+    let mut iter = 0;
+    while fs::read_dir(temp_dir.path())?.any(|e| e.unwrap().file_name() == "0.log") {
+        store.set("large".to_owned(), json_value(iter))?;
+        iter += 1;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some(json_value(iter - 1)));
+    Ok(())
+}
+
+// A store should refuse to open with a different compression than it was created with
+#[test]
+fn compression_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open_with(temp_dir.path(), compressed(Compression::Zstd))?
+        .set("key".to_owned(), json_value(0))?;
+
+    for compression in &[Compression::None, Compression::Lz4] {
+        let err = KvStore::open_with(temp_dir.path(), compressed(*compression))
+            .err()
+            .expect("opened with mismatched compression");
+        assert_eq!(err.kind(), KvsErrorType::OptionsMismatch);
+    }
+    let store = KvStore::open_with(temp_dir.path(), compressed(Compression::Zstd))?;
+    assert_eq!(store.get("key".to_owned())?, Some(json_value(0)));
+
+    // 早于压缩功能创建的数据库视为不压缩
+    let old = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(old.path().join("status.json"), "{\"cur_file_id\":0}")?;
+    fs::write(
+        old.path().join("0.log"),
+        "{\"Set\":{\"key\":\"key\",\"value\":\"value\"}}",
+    )?;
+    assert!(KvStore::open_with(old.path(), compressed(Compression::Lz4)).is_err());
+    let store = KvStore::open_with(old.path(), compressed(Compression::None))?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Replication and backups should see decompressed values
+#[test]
+fn replicate_compressed_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), compressed(Compression::Lz4))?;
+    store.set("key".to_owned(), json_value(1))?;
+    let entries = store.replicate(None)?.pending();
+    assert!(entries.iter().any(|entry| match entry {
+        LogEntry::Record {
+            op: Operation::Set { value, .. },
+            ..
+        } => *value == json_value(1),
+        _ => false,
+    }));
+
+    let backup_path = temp_dir.path().join("backup.kvs");
+    assert_eq!(store.backup(&backup_path)?.keys, 1);
+    let target = TempDir::new().expect("unable to create temporary working directory");
+    let restored = KvStore::open(target.path())?;
+    kvs::backup::restore(&backup_path, &restored)?;
+    assert_eq!(restored.get("key".to_owned())?, Some(json_value(1)));
+    Ok(())
+}
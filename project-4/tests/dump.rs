@@ -0,0 +1,173 @@
+use assert_cmd::prelude::*;
+use kvs::engines::{
+    self, Compression, DumpFilter, DumpRecord, EncryptionKey, Keyring, KvStoreOptions,
+};
+use kvs::{KvStore, KvsEngine, Result};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+/// dir 中满足 filter 的所有记录
+fn records(dir: &Path, keyring: Option<Keyring>, filter: &DumpFilter) -> Result<Vec<DumpRecord>> {
+    let mut records = Vec::new();
+    engines::dump(dir, keyring, filter, |record| {
+        records.push(record);
+        Ok(())
+    })?;
+    Ok(records)
+}
+
+// Every kind of record should be dumped in order with its value decoded
+#[test]
+fn dump_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions {
+            compression: Some(Compression::Lz4),
+            blob_threshold: Some(1024),
+            ..KvStoreOptions::default()
+        },
+    )?;
+    store.set("a".to_owned(), "short".to_owned())?;
+    store.set("b".to_owned(), "y".repeat(512))?;
+    store.set("c".to_owned(), "z".repeat(2048))?;
+    store.remove("a".to_owned())?;
+    drop(store);
+
+    let all = records(temp_dir.path(), None, &DumpFilter::default())?;
+    let kinds: Vec<_> = all.iter().map(|record| record.kind).collect();
+    assert_eq!(kinds, vec!["set", "compressed", "blob", "remove"]);
+    assert_eq!(all[0].offset, 0);
+    for pair in all.windows(2) {
+        assert_eq!(pair[0].offset + pair[0].length, pair[1].offset);
+    }
+    assert_eq!(all[1].value, Some("y".repeat(512)));
+    assert_eq!(all[2].value, Some("z".repeat(2048)));
+    assert_eq!(all[3].key, Some("a".to_owned()));
+    assert_eq!(all[3].value, None);
+
+    // 删除 blob 文件后对应的记录无法读取
+    fs::remove_dir_all(temp_dir.path().join("blobs"))?;
+    let all = records(temp_dir.path(), None, &DumpFilter::default())?;
+    assert_eq!(all[2].kind, "dangling");
+    Ok(())
+}
+
+// Records should be filterable by file, key, prefix and offset range, and a torn tail reported
+#[test]
+fn dump_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["user1", "user2", "order1", "user1"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    drop(store);
+    // 带校验和的数据库只接受带校验和的记录，因此复制 0.log 中的第一条记录
+    let first = records(temp_dir.path(), None, &DumpFilter::default())?[0].length as usize;
+    let mut content = fs::read(temp_dir.path().join("0.log"))?[..first].to_vec();
+    content.extend_from_slice(br#"{"Set":{"#);
+    fs::write(temp_dir.path().join("7.log"), content)?;
+    let all = records(temp_dir.path(), None, &DumpFilter::default())?;
+    assert_eq!(all.len(), 6);
+    assert_eq!(
+        (all[4].file_id, all[4].value.as_deref()),
+        (7, Some("value"))
+    );
+    assert_eq!(all[5].kind, "corrupted");
+
+    let by_key = DumpFilter {
+        key: Some("user1".to_owned()),
+        ..DumpFilter::default()
+    };
+    let found = records(temp_dir.path(), None, &by_key)?;
+    let positions: Vec<_> = found.iter().map(|r| (r.file_id, r.kind)).collect();
+    assert_eq!(
+        positions,
+        vec![(0, "set"), (0, "set"), (7, "set"), (7, "corrupted")]
+    );
+
+    let by_prefix = DumpFilter {
+        file_id: Some(0),
+        prefix: Some("user".to_owned()),
+        from: Some(all[1].offset),
+        to: Some(all[3].offset),
+        ..DumpFilter::default()
+    };
+    let found = records(temp_dir.path(), None, &by_prefix)?;
+    assert_eq!(found, vec![all[1].clone()]);
+    Ok(())
+}
+
+// Encrypted records should only be readable with the key
+#[test]
+fn dump_encrypted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (key, _) = EncryptionKey::generate();
+    let keyring = Keyring::new(key);
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions {
+            keyring: Some(keyring.clone()),
+            ..KvStoreOptions::default()
+        },
+    )?;
+    store.set("key".to_owned(), "secret".to_owned())?;
+    drop(store);
+
+    let sealed = records(temp_dir.path(), None, &DumpFilter::default())?;
+    assert_eq!((sealed[0].kind, &sealed[0].key), ("sealed", &None));
+    let opened = records(temp_dir.path(), Some(keyring), &DumpFilter::default())?;
+    assert_eq!(opened[0].value, Some("secret".to_owned()));
+    Ok(())
+}
+
+// kvs-admin dump should print one line per record with a shortened value
+#[test]
+fn cli_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("kvs"))?;
+    store.set("key1".to_owned(), "v".repeat(100))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store
+        .open_tree("users")?
+        .set("alice".to_owned(), "admin".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump", temp_dir.path().to_str().unwrap(), "--width", "3"])
+        .assert()
+        .success()
+        .stdout(contains(r#"0.log 0 "#))
+        .stdout(contains(r#"set "key1" "vvv"..."#))
+        .stdout(contains(r#"set "key2" "val"..."#))
+        .stderr(contains("2 records"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump", temp_dir.path().to_str().unwrap(), "--key", "key2"])
+        .assert()
+        .success()
+        .stdout(contains(r#"set "key2" "value2""#))
+        .stderr(contains("1 records"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&[
+            "dump",
+            temp_dir.path().to_str().unwrap(),
+            "--keyspace",
+            "users",
+        ])
+        .assert()
+        .success()
+        .stdout(contains(r#"set "alice" "admin""#));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["dump", temp_dir.path().to_str().unwrap(), "--from", "x"])
+        .assert()
+        .failure()
+        .stderr(contains("Invalid from."));
+    Ok(())
+}
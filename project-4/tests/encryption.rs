@@ -0,0 +1,280 @@
+use kvs::engines::{
+    Compression, EncryptionKey, EngineOptions, EngineRegistry, Keyring, KvStoreOptions,
+};
+use kvs::{KvStore, KvsEngine, KvsErrorType, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn encrypted(keyring: Keyring) -> KvStoreOptions {
+    KvStoreOptions {
+        keyring: Some(keyring),
+        ..KvStoreOptions::default()
+    }
+}
+
+/// 当前数据文件的内容
+fn current_log(dir: &Path) -> Result<String> {
+    let status: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(dir.join("status.json"))?)?;
+    Ok(fs::read_to_string(
+        dir.join(format!("{}.log", status["cur_file_id"])),
+    )?)
+}
+
+// Encrypted stores should not leak keys or values to disk, and require the right key to open
+#[test]
+fn encrypted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (key, _) = EncryptionKey::generate();
+    let store = KvStore::open_with(temp_dir.path(), encrypted(Keyring::new(key.clone())))?;
+    store.set("secret-key".to_owned(), "secret-value".to_owned())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+    drop(store);
+
+    let log = current_log(temp_dir.path())?;
+    assert!(!log.contains("secret"));
+    assert!(!log.contains("removed"));
+
+    let store = KvStore::open_with(temp_dir.path(), encrypted(Keyring::new(key.clone())))?;
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    assert_eq!(store.get("removed".to_owned())?, None);
+    drop(store);
+
+    let (other, _) = EncryptionKey::generate();
+    for options in vec![KvStoreOptions::default(), encrypted(Keyring::new(other))] {
+        let err = KvStore::open_with(temp_dir.path(), options)
+            .err()
+            .expect("opened with the wrong key");
+        assert_eq!(err.kind(), KvsErrorType::OptionsMismatch);
+    }
+
+    // 未加密的数据库不能以密钥打开
+    let plain = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(plain.path())?.set("key".to_owned(), "value".to_owned())?;
+    let err = KvStore::open_with(plain.path(), encrypted(Keyring::new(key)))
+        .err()
+        .expect("opened a plaintext store with a key");
+    assert_eq!(err.kind(), KvsErrorType::OptionsMismatch);
+    Ok(())
+}
+
+/// 修改 log 中第 n 条记录的密文中的一个字符，长度不变
+fn tamper(path: &Path, n: usize) -> Result<()> {
+    let mut log = fs::read(path)?;
+    let marker = b"\"data\":\"";
+    let pos = log
+        .windows(marker.len())
+        .enumerate()
+        .filter(|(_, w)| *w == marker)
+        .nth(n)
+        .expect("record not found")
+        .0
+        + marker.len()
+        + 4;
+    log[pos] = if log[pos] == b'A' { b'B' } else { b'A' };
+    fs::write(path, log)?;
+    Ok(())
+}
+
+// Modified ciphertext should be detected both when reading a value and when opening the store
+#[test]
+fn tampering_is_detected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (key, _) = EncryptionKey::generate();
+    let store = KvStore::open_with(temp_dir.path(), encrypted(Keyring::new(key.clone())))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    tamper(&temp_dir.path().join("0.log"), 1)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let err = store
+        .get("key2".to_owned())
+        .err()
+        .expect("tampering not detected");
+    assert_eq!(err.kind(), KvsErrorType::Corrupted);
+    drop(store);
+
+    let err = KvStore::open_with(temp_dir.path(), encrypted(Keyring::new(key.clone())))
+        .err()
+        .expect("tampering not detected");
+    assert_eq!(err.kind(), KvsErrorType::Corrupted);
+
+    // 插入的明文记录同样视为篡改
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open_with(temp_dir.path(), encrypted(Keyring::new(key.clone())))?
+        .set("key".to_owned(), "value".to_owned())?;
+    let path = temp_dir.path().join("0.log");
+    let mut log = fs::read_to_string(&path)?;
+    log.push_str("{\"Set\":{\"key\":\"key\",\"value\":\"forged\"}}");
+    fs::write(&path, log)?;
+    let err = KvStore::open_with(temp_dir.path(), encrypted(Keyring::new(key)))
+        .err()
+        .expect("tampering not detected");
+    assert_eq!(err.kind(), KvsErrorType::Corrupted);
+    Ok(())
+}
+
+// The sequence number and time of a record are authenticated together with its ciphertext
+#[test]
+fn stamps_are_authenticated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (key, _) = EncryptionKey::generate();
+    let store = KvStore::open_with(temp_dir.path(), encrypted(Keyring::new(key.clone())))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("0.log");
+    let log = fs::read_to_string(&path)?;
+    assert!(log.contains("\"seq\":2,"));
+    fs::write(&path, log.replace("\"seq\":2,", "\"seq\":9,"))?;
+    let err = KvStore::open_with(temp_dir.path(), encrypted(Keyring::new(key)))
+        .err()
+        .expect("tampering not detected");
+    assert_eq!(err.kind(), KvsErrorType::Corrupted);
+    Ok(())
+}
+
+// Keys of the wrong length or encoding should be rejected as invalid arguments
+#[test]
+fn invalid_keys() {
+    for encoded in &["", "not base64!", "c2hvcnQ="] {
+        let err = EncryptionKey::from_base64(encoded)
+            .err()
+            .expect("accepted an invalid key");
+        assert_eq!(err.kind(), KvsErrorType::InvalidArgument);
+    }
+}
+
+// Records sealed without associated data by older versions should be re-encrypted on open
+#[test]
+fn legacy_records_are_resealed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (key, _) = EncryptionKey::generate();
+    let keyring = Keyring::new(key.clone());
+    KvStore::open_with(temp_dir.path(), encrypted(keyring.clone()))?
+        .set("key".to_owned(), "value".to_owned())?;
+
+    // 追加一条旧格式的记录，并去掉状态文件中的 bound 与 checksummed
+    let (nonce, data) = keyring.seal(br#"{"Set":{"key":"legacy","value":"old"}}"#, b"")?;
+    let record = serde_json::json!({
+        "Stamped": {
+            "seq": 2,
+            "time": 0,
+            "record": {
+                "Sealed": {
+                    "key_id": key.id(),
+                    "nonce": base64::encode(&nonce),
+                    "data": base64::encode(&data),
+                }
+            }
+        }
+    });
+    let path = temp_dir.path().join("0.log");
+    let mut log = fs::read_to_string(&path)?;
+    log.push_str(&record.to_string());
+    fs::write(&path, log)?;
+    let status_path = temp_dir.path().join("status.json");
+    let mut status: serde_json::Value = serde_json::from_str(&fs::read_to_string(&status_path)?)?;
+    status.as_object_mut().unwrap().remove("bound");
+    status.as_object_mut().unwrap().remove("checksummed");
+    fs::write(&status_path, status.to_string())?;
+
+    let store = KvStore::open_with(temp_dir.path(), encrypted(keyring.clone()))?;
+    assert_eq!(store.get("legacy".to_owned())?, Some("old".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    drop(store);
+    let log = current_log(temp_dir.path())?;
+    assert_eq!(log.matches("\"bound\":true").count(), 2);
+
+    // 迁移后不再接受旧格式的记录
+    let path = temp_dir.path().join(format!(
+        "{}.log",
+        serde_json::from_str::<serde_json::Value>(&fs::read_to_string(&status_path)?)?
+            ["cur_file_id"]
+    ));
+    let mut log = fs::read_to_string(&path)?;
+    log.push_str(&record.to_string());
+    fs::write(&path, log)?;
+    let err = KvStore::open_with(temp_dir.path(), encrypted(keyring))
+        .err()
+        .expect("accepted a legacy record after migration");
+    assert_eq!(err.kind(), KvsErrorType::Corrupted);
+    Ok(())
+}
+
+// Rotating the key should re-encrypt all records so the old key is no longer needed
+#[test]
+fn key_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (old, _) = EncryptionKey::generate();
+    let (new, _) = EncryptionKey::generate();
+    let options = KvStoreOptions {
+        compression: Some(Compression::Zstd),
+        keyring: Some(Keyring::new(old.clone())),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    assert!(current_log(temp_dir.path())?.contains(old.id()));
+
+    let keyring = Keyring::new(new.clone()).with_previous(old.clone());
+    let store = KvStore::open_with(temp_dir.path(), encrypted(keyring))?;
+    let log = current_log(temp_dir.path())?;
+    assert!(!log.contains(old.id()));
+    assert!(log.contains(new.id()));
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+
+    let err = KvStore::open_with(temp_dir.path(), encrypted(Keyring::new(old)))
+        .err()
+        .expect("opened with the retired key");
+    assert_eq!(err.kind(), KvsErrorType::OptionsMismatch);
+    let store = KvStore::open_with(temp_dir.path(), encrypted(Keyring::new(new)))?;
+    for i in 0..=100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// The kvs engine should read keys from key files and environment variables
+#[test]
+fn encryption_from_registry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_, old) = EncryptionKey::generate();
+    let (_, new) = EncryptionKey::generate();
+    let key_file = temp_dir.path().join("old.key");
+    fs::write(&key_file, format!("{}\n", old))?;
+
+    let registry = EngineRegistry::builtin();
+    let mut options = EngineOptions::new();
+    options.insert(
+        "encryption-key".to_owned(),
+        key_file.to_str().unwrap().to_owned(),
+    );
+    let engine = registry.open("kvs", temp_dir.path(), &options)?;
+    engine.set("key".to_owned(), "value".to_owned())?;
+    drop(engine);
+
+    std::env::set_var("KVS_TEST_ROTATED_KEY", &new);
+    options.insert(
+        "encryption-key".to_owned(),
+        "env:KVS_TEST_ROTATED_KEY".to_owned(),
+    );
+    assert!(registry.open("kvs", temp_dir.path(), &options).is_err());
+    options.insert(
+        "previous-keys".to_owned(),
+        key_file.to_str().unwrap().to_owned(),
+    );
+    let engine = registry.open("kvs", temp_dir.path(), &options)?;
+    assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,296 @@
+use assert_cmd::prelude::*;
+use kvs::client::KvsClient;
+use kvs::engines::{EncryptionKey, EngineOptions, EngineRegistry};
+use kvs::export::{self, ExportWriter, Format};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsErrorType, KvsServer, Result, SledServer};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// 含有需要转义字符的键值对
+fn tricky_pairs() -> Vec<(String, String)> {
+    vec![
+        ("".to_owned(), "empty key".to_owned()),
+        ("comma,key".to_owned(), "a,b,c".to_owned()),
+        ("quote\"key".to_owned(), "\"quoted\"".to_owned()),
+        ("newline".to_owned(), "line1\r\nline2\nline3".to_owned()),
+        ("plain".to_owned(), "".to_owned()),
+        ("unicode".to_owned(), "键值".to_owned()),
+    ]
+}
+
+/// 以 format 格式写入 pairs 再读取
+fn round_trip(pairs: &[(String, String)], format: Format) -> Result<Vec<(String, String)>> {
+    let mut writer = ExportWriter::new(Vec::new(), format)?;
+    for (key, value) in pairs {
+        writer.add(key.clone(), value.clone())?;
+    }
+    let buf = writer.finish()?;
+    let mut read = Vec::new();
+    let count = export::read(&buf[..], format, |key, value| {
+        read.push((key, value));
+        Ok(())
+    })?;
+    assert_eq!(count as usize, read.len());
+    Ok(read)
+}
+
+// Both formats should preserve keys and values containing separators, quotes and newlines
+#[test]
+fn export_formats_round_trip() -> Result<()> {
+    let pairs = tricky_pairs();
+    assert_eq!(round_trip(&pairs, Format::Jsonl)?, pairs);
+    assert_eq!(round_trip(&pairs, Format::Csv)?, pairs);
+
+    let csv = "key,value\r\nk1,v1\r\n\"k2\",\"v\"\"2\"\n\nk3,\r\n";
+    let mut read = Vec::new();
+    export::read(csv.as_bytes(), Format::Csv, |key, value| {
+        read.push((key, value));
+        Ok(())
+    })?;
+    assert_eq!(
+        read,
+        vec![
+            ("k1".to_owned(), "v1".to_owned()),
+            ("k2".to_owned(), "v\"2".to_owned()),
+            ("k3".to_owned(), "".to_owned()),
+        ]
+    );
+
+    for bad in &[
+        "k1,v1\n",
+        "key,value\nk1,v1,extra\n",
+        "key,value\n\"k1,v1\n",
+    ] {
+        let err = export::read(bad.as_bytes(), Format::Csv, |_, _| Ok(())).unwrap_err();
+        assert_eq!(err.kind(), KvsErrorType::Corrupted);
+    }
+    let err = export::read(&b"{\"key\":1}\n"[..], Format::Jsonl, |_, _| Ok(())).unwrap_err();
+    assert_eq!(err.kind(), KvsErrorType::Corrupted);
+    Ok(())
+}
+
+// kvs-admin should export an embedded kvs directory in key order and import it into sled
+#[test]
+fn export_import_embedded_directory() -> Result<()> {
+    let source = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+    let engine = KvStore::open(source.path().join("kvs"))?;
+    for i in (0..30).rev() {
+        engine.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    for (key, value) in tricky_pairs() {
+        engine.set(key, value)?;
+    }
+    drop(engine);
+    fs::write(source.path().join("server.cfg"), "kvs")?;
+
+    let output = Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export"])
+        .current_dir(&source)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 36);
+    assert_eq!(lines[1], r#"{"key":"comma,key","value":"a,b,c"}"#);
+    assert_eq!(lines[2], r#"{"key":"key00","value":"value0"}"#);
+
+    // server.cfg 中记录的引擎与 --engine 不符
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export", "--engine", "sled"])
+        .current_dir(&source)
+        .assert()
+        .failure()
+        .stderr(contains("Wrong engine."));
+
+    let csv_path = target.path().join("data.csv");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export", "--format", "csv", "--output"])
+        .arg(&csv_path)
+        .arg("--dir")
+        .arg(source.path())
+        .assert()
+        .success()
+        .stderr(contains("36 keys exported"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["import", "data.csv", "--format", "csv", "--engine", "sled"])
+        .args(&["--batch-size", "5"])
+        .current_dir(&target)
+        .assert()
+        .success()
+        .stdout(contains("36 keys imported"));
+
+    let engine = SledServer::new(sled::Db::start_default(target.path().join("sled"))?);
+    assert_eq!(engine.stats()?["keys"], 36);
+    assert_eq!(engine.get("key07".to_owned())?, Some("value7".to_owned()));
+    for (key, value) in tricky_pairs() {
+        assert_eq!(engine.get(key)?, Some(value));
+    }
+    Ok(())
+}
+
+// kvs-admin should open encrypted directories with the key given on the command line
+#[test]
+fn export_encrypted_directory() -> Result<()> {
+    let source = TempDir::new().unwrap();
+    let (_, key) = EncryptionKey::generate();
+    let key_file = source.path().join("data.key");
+    fs::write(&key_file, &key)?;
+    let mut options = EngineOptions::new();
+    options.insert(
+        "encryption-key".to_owned(),
+        key_file.to_str().unwrap().to_owned(),
+    );
+    let registry = EngineRegistry::builtin();
+    let engine = registry.create("kvs", source.path(), &options)?;
+    engine.set("key".to_owned(), "secret value".to_owned())?;
+    drop(engine);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export"])
+        .current_dir(&source)
+        .assert()
+        .failure()
+        .stderr(contains("Engine options do not match the data directory."));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export", "--encryption-key"])
+        .arg(&key_file)
+        .current_dir(&source)
+        .assert()
+        .success()
+        .stdout(contains(r#"{"key":"key","value":"secret value"}"#));
+    Ok(())
+}
+
+// kvs-admin should export keyspaces one at a time and refuse to skip them silently
+#[test]
+fn export_import_keyspaces() -> Result<()> {
+    let source = TempDir::new().unwrap();
+    let target = TempDir::new().unwrap();
+    let engine = KvStore::open(source.path().join("kvs"))?;
+    engine.set("key".to_owned(), "default".to_owned())?;
+    engine
+        .open_tree("users")?
+        .set("key".to_owned(), "user".to_owned())?;
+    drop(engine);
+    fs::write(source.path().join("server.cfg"), "kvs")?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export"])
+        .current_dir(&source)
+        .assert()
+        .failure()
+        .stderr(contains("keyspaces users"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export", "--skip-keyspaces"])
+        .current_dir(&source)
+        .assert()
+        .success()
+        .stdout(contains(r#"{"key":"key","value":"default"}"#));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export", "--keyspace", "missing"])
+        .current_dir(&source)
+        .assert()
+        .failure()
+        .stderr(contains("Keyspace not found."));
+
+    let path = target.path().join("users.jsonl");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export", "--keyspace", "users", "--output"])
+        .arg(&path)
+        .current_dir(&source)
+        .assert()
+        .success()
+        .stderr(contains("1 keys exported"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&[
+            "import",
+            "users.jsonl",
+            "--keyspace",
+            "users",
+            "--engine",
+            "kvs",
+        ])
+        .current_dir(&target)
+        .assert()
+        .success();
+
+    let engine = KvStore::open(target.path().join("kvs"))?;
+    assert_eq!(engine.get("key".to_owned())?, None);
+    assert_eq!(
+        engine.open_tree("users")?.get("key".to_owned())?,
+        Some("user".to_owned())
+    );
+    Ok(())
+}
+
+// kvs-admin should import into and export from a running server in batches
+#[test]
+fn export_import_live_server() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let files = TempDir::new().unwrap();
+    let engine = KvStore::open(dir.path())?;
+    thread::spawn(move || {
+        KvsServer::new(engine, SharedQueueThreadPool::new(4).unwrap())
+            .run("127.0.0.1:4181".to_owned())
+            .unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let input: String = (0..2500)
+        .map(|i| format!("{{\"key\":\"key{:04}\",\"value\":\"value{}\"}}\n", i, i))
+        .collect();
+    let input_path = files.path().join("input.jsonl");
+    fs::write(&input_path, &input)?;
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["import", input_path.to_str().unwrap()])
+        .args(&["--addr", "127.0.0.1:4181", "--batch-size", "100"])
+        .assert()
+        .success()
+        .stdout(contains("2500 keys imported"));
+
+    let mut client = KvsClient::connect("127.0.0.1:4181".to_owned())?;
+    assert_eq!(
+        client.get("key1234".to_owned())?,
+        Some("value1234".to_owned())
+    );
+
+    // 导出跨越多次扫描，结果与导入的文件一致
+    let output_path = files.path().join("output.jsonl");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["export", "--addr", "127.0.0.1:4181", "--output"])
+        .arg(&output_path)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(&output_path)?, input);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["import", "-", "--addr", "127.0.0.1:4181"])
+        .with_stdin()
+        .buffer("not json")
+        .assert()
+        .failure()
+        .stderr(contains("Invalid input."));
+    Ok(())
+}
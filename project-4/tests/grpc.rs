@@ -0,0 +1,163 @@
+use kvs::acl::Acl;
+use kvs::grpc::KvsGrpcClient;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsErrorType, KvsServer, Operation, Result, SledServer};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server<E: KvsEngine>(engine: E, addr: &str, grpc_addr: &str, acl: Option<Acl>) {
+    let addr = addr.to_owned();
+    let grpc_addr = grpc_addr.to_owned();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(4).unwrap());
+        if let Some(acl) = acl {
+            server = server.acl(Arc::new(acl));
+        }
+        server.grpc(grpc_addr).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+}
+
+fn assert_kind<T>(result: Result<T>, kind: KvsErrorType) {
+    match result {
+        Err(e) => assert_eq!(e.kind(), kind),
+        Ok(_) => panic!("operation should fail with {:?}", kind),
+    }
+}
+
+fn basic_operations(grpc_addr: &str) -> Result<()> {
+    let mut client = KvsGrpcClient::connect(grpc_addr.to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key1".to_owned(), "".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_kind(client.remove("key1".to_owned()), KvsErrorType::KeyNotFound);
+    Ok(())
+}
+
+#[test]
+fn grpc_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    start_server(store, "127.0.0.1:4140", "127.0.0.1:4141", None);
+    basic_operations("127.0.0.1:4141")
+}
+
+#[test]
+fn grpc_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let db = sled::Db::start_default(temp_dir.path())?;
+    start_server(
+        SledServer::new(db),
+        "127.0.0.1:4142",
+        "127.0.0.1:4143",
+        None,
+    );
+    basic_operations("127.0.0.1:4143")
+}
+
+// Scan should return keys in order, filtered by prefix and paginated
+#[test]
+fn grpc_scan_and_batch() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    start_server(store, "127.0.0.1:4144", "127.0.0.1:4145", None);
+    let mut client = KvsGrpcClient::connect("127.0.0.1:4145".to_owned())?;
+
+    let ops = (0..5)
+        .flat_map(|i| {
+            vec![
+                Operation::set(&format!("a/{}", i), "a".to_owned()),
+                Operation::set(&format!("b/{}", i), "b".to_owned()),
+            ]
+        })
+        .chain(vec![
+            Operation::remove(&"b/0".to_owned()),
+            Operation::get(&"a/1".to_owned()),
+            Operation::get(&"b/0".to_owned()),
+        ])
+        .collect();
+    let results = client.batch(ops)?;
+    assert_eq!(results.len(), 13);
+    assert_eq!(results[11], Some("a".to_owned()));
+    assert_eq!(results[12], None);
+
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(
+        keys(client.scan("b/".to_owned(), None, 0)?),
+        vec!["b/1", "b/2", "b/3", "b/4"]
+    );
+    assert_eq!(
+        keys(client.scan("a/".to_owned(), None, 3)?),
+        vec!["a/0", "a/1", "a/2"]
+    );
+    assert_eq!(
+        keys(client.scan("a/".to_owned(), Some("a/2".to_owned()), 3)?),
+        vec!["a/3", "a/4"]
+    );
+
+    // 批量操作在第一个失败的操作处停止
+    assert_kind(
+        client.batch(vec![
+            Operation::set(&"c".to_owned(), "c".to_owned()),
+            Operation::remove(&"missing".to_owned()),
+            Operation::set(&"d".to_owned(), "d".to_owned()),
+        ]),
+        KvsErrorType::KeyNotFound,
+    );
+    assert_eq!(client.get("c".to_owned())?, Some("c".to_owned()));
+    assert_eq!(client.get("d".to_owned())?, None);
+    Ok(())
+}
+
+// With an ACL, requests need credentials and are checked against the rules
+#[test]
+fn grpc_acl() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    let acl = serde_json::from_str(
+        r#"{"users": [{"name": "app", "token": "secret",
+            "rules": [{"prefix": "app/", "ops": ["Get", "Set"]}]}]}"#,
+    )
+    .unwrap();
+    start_server(store, "127.0.0.1:4146", "127.0.0.1:4147", Some(acl));
+
+    let mut client = KvsGrpcClient::connect("127.0.0.1:4147".to_owned())?;
+    assert_kind(
+        client.set("app/key".to_owned(), "value".to_owned()),
+        KvsErrorType::PermissionDenied,
+    );
+    client.auth("app".to_owned(), "wrong".to_owned())?;
+    assert_kind(
+        client.get("app/key".to_owned()),
+        KvsErrorType::PermissionDenied,
+    );
+
+    client.auth("app".to_owned(), "secret".to_owned())?;
+    client.set("app/key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("app/key".to_owned())?, Some("value".to_owned()));
+    assert_kind(
+        client.set("other".to_owned(), "value".to_owned()),
+        KvsErrorType::PermissionDenied,
+    );
+    assert_kind(
+        client.remove("app/key".to_owned()),
+        KvsErrorType::PermissionDenied,
+    );
+    assert_kind(
+        client.batch(vec![
+            Operation::set(&"app/a".to_owned(), "value".to_owned()),
+            Operation::set(&"other".to_owned(), "value".to_owned()),
+        ]),
+        KvsErrorType::PermissionDenied,
+    );
+    assert_eq!(client.get("app/a".to_owned())?, None);
+    Ok(())
+}
@@ -0,0 +1,278 @@
+use kvs::acl::Acl;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsServer, SledServer};
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server<E: KvsEngine>(engine: E, addr: &str, http_addr: &str, acl: Option<Acl>) {
+    let addr = addr.to_owned();
+    let http_addr = http_addr.to_owned();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(4).unwrap());
+        if let Some(acl) = acl {
+            server = server.acl(Arc::new(acl));
+        }
+        server.http(http_addr).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+}
+
+/// 发送一个 HTTP 请求，返回状态码与 JSON 响应体
+fn request(
+    addr: &str,
+    method: &str,
+    path: &str,
+    body: Option<Value>,
+    auth: Option<&str>,
+) -> (u16, Option<Value>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    if let Some(auth) = auth {
+        head.push_str(&format!(
+            "Authorization: Basic {}\r\n",
+            base64::encode(auth)
+        ));
+    }
+    stream
+        .write_all(format!("{}\r\n{}", head, body).as_bytes())
+        .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+    if body.is_empty() {
+        (status, None)
+    } else {
+        (status, Some(serde_json::from_str(body).unwrap()))
+    }
+}
+
+fn access_keys(addr: &str) {
+    let (status, _) = request(addr, "GET", "/v1/keys/key1", None, None);
+    assert_eq!(status, 404);
+
+    let (status, body) = request(
+        addr,
+        "PUT",
+        "/v1/keys/key1",
+        Some(json!({ "value": "value1" })),
+        None,
+    );
+    assert_eq!((status, body), (204, None));
+    let (status, body) = request(addr, "GET", "/v1/keys/key1", None, None);
+    assert_eq!(status, 200);
+    assert_eq!(body.unwrap(), json!({ "key": "key1", "value": "value1" }));
+
+    // 带有 / 与转义字符的 key
+    let (status, _) = request(
+        addr,
+        "PUT",
+        "/v1/keys/dir/a%20b",
+        Some(json!({ "value": "value2" })),
+        None,
+    );
+    assert_eq!(status, 204);
+    let (_, body) = request(addr, "GET", "/v1/keys/dir/a%20b", None, None);
+    assert_eq!(body.unwrap()["key"], "dir/a b");
+
+    let (status, _) = request(addr, "DELETE", "/v1/keys/key1", None, None);
+    assert_eq!(status, 204);
+    let (status, _) = request(addr, "DELETE", "/v1/keys/key1", None, None);
+    assert_eq!(status, 404);
+    let (status, _) = request(addr, "GET", "/v1/keys/key1", None, None);
+    assert_eq!(status, 404);
+
+    let (status, _) = request(addr, "PUT", "/v1/keys/key1", Some(json!("value")), None);
+    assert_eq!(status, 400);
+    let (status, _) = request(addr, "POST", "/v1/keys/key1", None, None);
+    assert_eq!(status, 405);
+    let (status, _) = request(addr, "GET", "/v2/keys/key1", None, None);
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn http_kvs_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    start_server(store, "127.0.0.1:4130", "127.0.0.1:4131", None);
+    access_keys("127.0.0.1:4131");
+}
+
+#[test]
+fn http_sled_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = sled::Db::start_default(temp_dir.path()).unwrap();
+    start_server(
+        SledServer::new(db),
+        "127.0.0.1:4132",
+        "127.0.0.1:4133",
+        None,
+    );
+    access_keys("127.0.0.1:4133");
+}
+
+// PUT with an "expected" field should only succeed when the current value matches
+#[test]
+fn http_compare_and_swap() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    start_server(store, "127.0.0.1:4134", "127.0.0.1:4135", None);
+    let addr = "127.0.0.1:4135";
+
+    let put = |body| request(addr, "PUT", "/v1/keys/key", Some(body), None).0;
+    assert_eq!(put(json!({ "value": "v1", "expected": null })), 204);
+    assert_eq!(put(json!({ "value": "v2", "expected": null })), 409);
+    assert_eq!(put(json!({ "value": "v2", "expected": "v0" })), 409);
+    assert_eq!(put(json!({ "value": "v2", "expected": "v1" })), 204);
+    let (_, body) = request(addr, "GET", "/v1/keys/key", None, None);
+    assert_eq!(body.unwrap()["value"], "v2");
+}
+
+// Keys should be listed in order, filtered by prefix and paginated
+#[test]
+fn http_list_and_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..5 {
+        store.set(format!("a/{}", i), "value".to_owned()).unwrap();
+        store.set(format!("b/{}", i), "value".to_owned()).unwrap();
+    }
+    start_server(store, "127.0.0.1:4136", "127.0.0.1:4137", None);
+    let addr = "127.0.0.1:4137";
+
+    let (status, body) = request(addr, "GET", "/v1/keys?prefix=b%2F", None, None);
+    assert_eq!(status, 200);
+    assert_eq!(
+        body.unwrap(),
+        json!({ "keys": ["b/0", "b/1", "b/2", "b/3", "b/4"], "next": null })
+    );
+
+    let (_, body) = request(addr, "GET", "/v1/keys?prefix=a/&limit=3", None, None);
+    let body = body.unwrap();
+    assert_eq!(
+        body,
+        json!({ "keys": ["a/0", "a/1", "a/2"], "next": "a/2" })
+    );
+    let (_, body) = request(
+        addr,
+        "GET",
+        "/v1/keys?prefix=a/&limit=3&after=a/2",
+        None,
+        None,
+    );
+    assert_eq!(
+        body.unwrap(),
+        json!({ "keys": ["a/3", "a/4"], "next": null })
+    );
+
+    let (status, body) = request(addr, "GET", "/v1/stats", None, None);
+    assert_eq!(status, 200);
+    let body = body.unwrap();
+    assert_eq!(body["engine"], "kvs");
+    assert_eq!(body["stats"]["keys"], 10);
+}
+
+// With an ACL, requests need Basic credentials and are checked against the rules
+#[test]
+fn http_acl() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let acl = serde_json::from_value(json!({ "users": [{
+        "name": "app",
+        "token": "secret",
+        "rules": [{ "prefix": "app/", "ops": ["Get", "Set"] }]
+    }]}))
+    .unwrap();
+    start_server(store, "127.0.0.1:4138", "127.0.0.1:4139", Some(acl));
+    let addr = "127.0.0.1:4139";
+    let body = || Some(json!({ "value": "value" }));
+
+    assert_eq!(
+        request(addr, "PUT", "/v1/keys/app/key", body(), None).0,
+        401
+    );
+    assert_eq!(
+        request(addr, "PUT", "/v1/keys/app/key", body(), Some("app:wrong")).0,
+        401
+    );
+    assert_eq!(
+        request(addr, "PUT", "/v1/keys/app/key", body(), Some("app:secret")).0,
+        204
+    );
+    assert_eq!(
+        request(addr, "GET", "/v1/keys/app/key", None, Some("app:secret")).0,
+        200
+    );
+    assert_eq!(
+        request(addr, "PUT", "/v1/keys/other", body(), Some("app:secret")).0,
+        403
+    );
+    assert_eq!(
+        request(addr, "DELETE", "/v1/keys/app/key", None, Some("app:secret")).0,
+        403
+    );
+}
+
+/// 发送只有请求头的原始请求，返回状态码
+fn raw_status(addr: &str, head: &str) -> u16 {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(head.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response[9..12].parse().unwrap()
+}
+
+// Oversized bodies should be refused before they are read, and unparsable lengths are bad requests
+#[test]
+fn http_limits_body_size() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap())
+            .http("127.0.0.1:4213".to_owned())
+            .max_request_size(1024)
+            .run("127.0.0.1:4212".to_owned())
+            .unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let put = "PUT /v1/keys/key HTTP/1.1\r\nContent-Length:";
+    assert_eq!(
+        raw_status("127.0.0.1:4213", &format!("{} 2000\r\n\r\n", put)),
+        413
+    );
+    assert_eq!(
+        raw_status(
+            "127.0.0.1:4213",
+            &format!("{} 99999999999999999999\r\n\r\n", put)
+        ),
+        400
+    );
+    assert_eq!(
+        raw_status("127.0.0.1:4213", &format!("{} -1\r\n\r\n", put)),
+        400
+    );
+
+    let value = "v".repeat(900);
+    let (status, _) = request(
+        "127.0.0.1:4213",
+        "PUT",
+        "/v1/keys/key",
+        Some(json!({ "value": value })),
+        None,
+    );
+    assert_eq!(status, 204);
+}
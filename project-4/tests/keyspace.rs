@@ -0,0 +1,183 @@
+use kvs::client::KvsClient;
+use kvs::engines::{EngineOptions, EngineRegistry, MemoryEngine};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsErrorType, KvsServer, Result, SledServer};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// 检查 engine 的 keyspace 互不影响，且可以被删除
+fn keyspaces_are_isolated<E: KvsEngine>(engine: &E) -> Result<()> {
+    let users = engine.open_tree("users")?;
+    let orders = engine.open_tree("orders")?;
+    engine.set("key".to_owned(), "default".to_owned())?;
+    users.set("key".to_owned(), "users".to_owned())?;
+    users.set("key2".to_owned(), "users2".to_owned())?;
+    assert_eq!(engine.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("users".to_owned()));
+    assert_eq!(orders.get("key".to_owned())?, None);
+    assert_eq!(
+        orders.remove("key".to_owned()).unwrap_err().kind(),
+        KvsErrorType::KeyNotFound
+    );
+    assert_eq!(
+        users.scan("".to_owned(), None, 10)?,
+        vec![
+            ("key".to_owned(), "users".to_owned()),
+            ("key2".to_owned(), "users2".to_owned()),
+        ]
+    );
+    assert_eq!(engine.stats()?["keys"], 1);
+    assert_eq!(users.stats()?["keys"], 2);
+
+    // 再次打开得到同一个 keyspace
+    let again = engine.open_tree("users")?;
+    again.set("key3".to_owned(), "users3".to_owned())?;
+    assert_eq!(users.get("key3".to_owned())?, Some("users3".to_owned()));
+    assert_eq!(engine.tree_names()?, vec!["orders", "users"]);
+
+    assert!(engine.drop_tree("users")?);
+    assert!(!engine.drop_tree("users")?);
+    assert_eq!(engine.tree_names()?, vec!["orders"]);
+    // 已打开的实例在删除后不能再使用
+    for result in &[users.get("key".to_owned()), again.get("key3".to_owned())] {
+        assert_eq!(
+            result.as_ref().unwrap_err().kind(),
+            KvsErrorType::KeyspaceNotFound
+        );
+    }
+    assert_eq!(
+        users
+            .set("key".to_owned(), "users".to_owned())
+            .unwrap_err()
+            .kind(),
+        KvsErrorType::KeyspaceNotFound
+    );
+    assert_eq!(engine.open_tree("users")?.get("key".to_owned())?, None);
+    assert_eq!(engine.get("key".to_owned())?, Some("default".to_owned()));
+
+    for name in &["", "a/b", "..", "a b"] {
+        assert_eq!(
+            engine.open_tree(name).err().unwrap().kind(),
+            KvsErrorType::InvalidKeyspace
+        );
+    }
+    // keyspace 不能嵌套
+    assert_eq!(
+        orders.open_tree("nested").err().unwrap().kind(),
+        KvsErrorType::InvalidKeyspace
+    );
+    assert_eq!(
+        orders.drop_tree("nested").unwrap_err().kind(),
+        KvsErrorType::InvalidKeyspace
+    );
+    Ok(())
+}
+
+// Keyspaces of a KvStore should be isolated from each other and survive a reopen
+#[test]
+fn kvs_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    keyspaces_are_isolated(&store)?;
+    store
+        .open_tree("orders")?
+        .set("order1".to_owned(), "1".to_owned())?;
+    assert_eq!(store.stats()?["keyspaces"], 2);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.tree_names()?, vec!["orders", "users"]);
+    let orders = store.open_tree("orders")?;
+    assert_eq!(orders.get("order1".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    Ok(())
+}
+
+// Each keyspace should keep its own log and be compacted on its own
+#[test]
+fn kvs_keyspace_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let hot = store.open_tree("hot")?;
+    let before = store.stats()?;
+    let value = "x".repeat(1024);
+    while hot.stats()?["file_id"] == 0 {
+        hot.set("padding".to_owned(), value.clone())?;
+    }
+    let after = store.stats()?;
+    assert_eq!(after["file_id"], before["file_id"]);
+    assert_eq!(after["log_bytes"], before["log_bytes"]);
+    assert_eq!(after["garbage_bytes"], before["garbage_bytes"]);
+    assert_eq!(hot.stats()?["keys"], 1);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The memory engine and engines opened from the registry should support keyspaces too
+#[test]
+fn memory_and_registry_keyspaces() -> Result<()> {
+    keyspaces_are_isolated(&MemoryEngine::new())?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = EngineRegistry::builtin().open("kvs", temp_dir.path(), &EngineOptions::new())?;
+    keyspaces_are_isolated(&engine)?;
+    assert_eq!(engine.open_tree("orders")?.get_type(), engine.get_type());
+    Ok(())
+}
+
+// Keyspaces of the sled engine should be separate trees of the same db
+#[test]
+fn sled_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledServer::new(sled::Db::start_default(temp_dir.path())?);
+    keyspaces_are_isolated(&engine)?;
+    assert_eq!(engine.open_tree("orders")?.get_type(), "sled");
+    Ok(())
+}
+
+// Clients should be able to work in a keyspace and drop it through the server
+#[test]
+fn client_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let server_store = store.clone();
+    thread::spawn(move || {
+        KvsServer::new(server_store, SharedQueueThreadPool::new(4).unwrap())
+            .run("127.0.0.1:4190".to_owned())
+            .unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connent("127.0.0.1:4190".to_owned())?;
+    client.set("key".to_owned(), "default".to_owned())?;
+    // 只有写入会创建 keyspace
+    client.use_keyspace(Some("missing".to_owned()));
+    for result in vec![
+        client.get("key".to_owned()).map(|_| ()),
+        client.scan(String::new(), None, 10).map(|_| ()),
+        client.remove("key".to_owned()),
+    ] {
+        assert_eq!(result.unwrap_err().kind(), KvsErrorType::KeyspaceNotFound);
+    }
+    assert_eq!(store.tree_names()?, Vec::<String>::new());
+    client.use_keyspace(Some("team-a".to_owned()));
+    client.set("key".to_owned(), "a".to_owned())?;
+    client.set_batch(&[("key2".to_owned(), "a2".to_owned())])?;
+    assert_eq!(client.get("key".to_owned())?, Some("a".to_owned()));
+    assert_eq!(client.scan("key".to_owned(), None, 10)?.len(), 2);
+    client.remove("key2".to_owned())?;
+    client.use_keyspace(None);
+    assert_eq!(client.get("key".to_owned())?, Some("default".to_owned()));
+
+    let team = store.open_tree("team-a")?;
+    assert_eq!(team.get("key".to_owned())?, Some("a".to_owned()));
+    assert_eq!(team.get("key2".to_owned())?, None);
+
+    assert!(client.drop_keyspace("team-a".to_owned())?);
+    assert!(!client.drop_keyspace("team-a".to_owned())?);
+    assert!(client.drop_keyspace("no/such".to_owned()).is_err());
+    assert_eq!(store.tree_names()?, Vec::<String>::new());
+    Ok(())
+}
@@ -1,14 +1,158 @@
+use kvs::engines::{
+    Compression, EncryptionKey, EvictionPolicy, Keyring, KvStoreOptions, LsmEngine, LsmOptions,
+    MemoryEngine,
+};
 use kvs::{KvStore, KvsEngine, Result};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+/// 为引擎生成整套测试
+///
+/// $open 由临时目录返回打开引擎的闭包，每次调用相当于重新打开一次引擎
+macro_rules! engine_tests {
+    ($module:ident, $open:expr) => {
+        mod $module {
+            use super::*;
+
+            #[test]
+            fn get_stored_value() -> Result<()> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                super::get_stored_value($open(&temp_dir))
+            }
+
+            #[test]
+            fn overwrite_value() -> Result<()> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                super::overwrite_value($open(&temp_dir))
+            }
+
+            #[test]
+            fn get_non_existent_value() -> Result<()> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                super::get_non_existent_value($open(&temp_dir))
+            }
+
+            #[test]
+            fn remove_non_existent_key() -> Result<()> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                super::remove_non_existent_key($open(&temp_dir))
+            }
+
+            #[test]
+            fn remove_key() -> Result<()> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                super::remove_key($open(&temp_dir))
+            }
+
+            #[test]
+            fn concurrent_set() -> Result<()> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                super::concurrent_set($open(&temp_dir))
+            }
+
+            #[test]
+            fn concurrent_get() -> Result<()> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                super::concurrent_get($open(&temp_dir))
+            }
+        }
+    };
+}
+
+/// 从 dir 中打开 KvStore
+fn open_kvs(dir: &TempDir) -> impl Fn() -> Result<KvStore> + '_ {
+    move || KvStore::open(dir.path())
+}
+
+/// value 使用 zstd 压缩的 KvStore
+fn open_kvs_zstd(dir: &TempDir) -> impl Fn() -> Result<KvStore> + '_ {
+    move || {
+        KvStore::open_with(
+            dir.path(),
+            KvStoreOptions {
+                compression: Some(Compression::Zstd),
+                ..KvStoreOptions::default()
+            },
+        )
+    }
+}
+
+/// 数据文件加密、value 使用 lz4 压缩的 KvStore
+fn open_kvs_encrypted(dir: &TempDir) -> impl Fn() -> Result<KvStore> + '_ {
+    let (key, _) = EncryptionKey::generate();
+    move || {
+        KvStore::open_with(
+            dir.path(),
+            KvStoreOptions {
+                compression: Some(Compression::Lz4),
+                keyring: Some(Keyring::new(key.clone())),
+                ..KvStoreOptions::default()
+            },
+        )
+    }
+}
+
+/// value 较长时保存在 blob 文件中的 KvStore
+fn open_kvs_blob(dir: &TempDir) -> impl Fn() -> Result<KvStore> + '_ {
+    move || {
+        KvStore::open_with(
+            dir.path(),
+            KvStoreOptions {
+                blob_threshold: Some(8),
+                ..KvStoreOptions::default()
+            },
+        )
+    }
+}
+
+/// 内存引擎不会持久化，重新打开时返回同一个实例
+fn open_memory(_: &TempDir) -> impl Fn() -> Result<MemoryEngine> {
+    let engine = MemoryEngine::new();
+    move || Ok(engine.clone())
+}
+
+/// 有内存上限的内存引擎，上限足够大，不会发生淘汰
+fn open_memory_limited(_: &TempDir) -> impl Fn() -> Result<MemoryEngine> {
+    let engine = MemoryEngine::with_limit(1024 * 1024, EvictionPolicy::Lfu);
+    move || Ok(engine.clone())
+}
+
+/// memtable 与各层都很小的 LSM 引擎，使测试中的写入会触发写入 SSTable 与压缩
+fn open_lsm(dir: &TempDir) -> impl Fn() -> Result<LsmEngine> + '_ {
+    move || {
+        LsmEngine::open_with(
+            dir.path(),
+            LsmOptions {
+                memtable_size: 4096,
+                table_size: 4096,
+                block_size: 256,
+                level0_tables: 2,
+                level_size: 16 * 1024,
+            },
+        )
+    }
+}
+
+engine_tests!(kvs_store, open_kvs);
+engine_tests!(kvs_store_zstd, open_kvs_zstd);
+engine_tests!(kvs_store_encrypted, open_kvs_encrypted);
+engine_tests!(kvs_store_blob, open_kvs_blob);
+engine_tests!(lsm_engine, open_lsm);
+engine_tests!(memory_engine, open_memory);
+engine_tests!(memory_engine_limited, open_memory_limited);
+
 // Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+fn get_stored_value<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -18,7 +162,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open()?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -26,10 +170,8 @@ fn get_stored_value() -> Result<()> {
 }
 
 // Should overwrite existent value
-#[test]
-fn overwrite_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+fn overwrite_value<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -38,7 +180,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open()?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -47,34 +189,28 @@ fn overwrite_value() -> Result<()> {
 }
 
 // Should get `None` when getting a non-existent key
-#[test]
-fn get_non_existent_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+fn get_non_existent_value<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open()?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
 }
 
-#[test]
-fn remove_non_existent_key() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+fn remove_non_existent_key<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
-#[test]
-fn remove_key() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+fn remove_key<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -127,10 +263,8 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
-#[test]
-fn concurrent_set() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+fn concurrent_set<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
     let barrier = Arc::new(Barrier::new(1001));
     for i in 0..1000 {
         let store = store.clone();
@@ -150,7 +284,7 @@ fn concurrent_set() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open()?;
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
@@ -158,10 +292,8 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
-#[test]
-fn concurrent_get() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+fn concurrent_get<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let store = open()?;
     for i in 0..100 {
         store
             .set(format!("key{}", i), format!("value{}", i))
@@ -188,7 +320,7 @@ fn concurrent_get() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open()?;
     let mut handles = Vec::new();
     for thread_id in 0..100 {
         let store = store.clone();
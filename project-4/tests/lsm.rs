@@ -0,0 +1,181 @@
+use kvs::backup;
+use kvs::engines::{EngineOptions, EngineRegistry, LsmEngine, LsmOptions, MemoryEngine};
+use kvs::{KvsEngine, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// 各层都很小的参数，少量写入即可触发写入 SSTable 与多层压缩
+fn small_options() -> LsmOptions {
+    LsmOptions {
+        memtable_size: 2048,
+        table_size: 2048,
+        block_size: 128,
+        level0_tables: 2,
+        level_size: 8 * 1024,
+    }
+}
+
+fn open(path: &Path) -> Result<LsmEngine> {
+    LsmEngine::open_with(path, small_options())
+}
+
+/// 检查引擎中的数据与 model 一致
+fn check(engine: &LsmEngine, model: &BTreeMap<String, String>) -> Result<()> {
+    for (key, value) in model {
+        assert_eq!(engine.get(key.clone())?, Some(value.clone()));
+    }
+    let pairs = engine.scan(String::new(), None, usize::MAX)?;
+    let expected: Vec<_> = model.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+    assert_eq!(pairs, expected);
+    assert_eq!(engine.stats()?["keys"], model.len() as u64);
+    Ok(())
+}
+
+// Data should survive memtable flushes, multi-level compaction and reopening
+#[test]
+fn lsm_flush_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    let mut model = BTreeMap::new();
+    for round in 0..5 {
+        for i in 0..400 {
+            let key = format!("key{:04}", (i * 7 + round * 13) % 1000);
+            let value = format!("value{}-{}", i, round);
+            engine.set(key.clone(), value.clone())?;
+            model.insert(key, value);
+        }
+        for i in 0..40 {
+            let key = format!("key{:04}", i * 25 + round);
+            if model.remove(&key).is_some() {
+                engine.remove(key)?;
+            }
+        }
+    }
+    let stats = engine.stats()?;
+    assert!(stats["tables"] > 0);
+    assert!(stats.contains_key("level2_tables"));
+    check(&engine, &model)?;
+
+    drop(engine);
+    let engine = open(temp_dir.path())?;
+    check(&engine, &model)?;
+
+    // 分页扫描与前缀扫描
+    let page = engine.scan("key0".to_owned(), Some("key0100".to_owned()), 10)?;
+    let expected: Vec<_> = model
+        .range("key0101".to_owned()..)
+        .take(10)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    assert_eq!(page, expected);
+    let count = model.keys().filter(|key| key.starts_with("key05")).count();
+    assert_eq!(
+        engine.scan("key05".to_owned(), None, usize::MAX)?.len(),
+        count
+    );
+    Ok(())
+}
+
+// Deleted keys should stay hidden after their older values are flushed to deeper levels
+#[test]
+fn lsm_tombstones_shadow_older_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    for i in 0..500 {
+        engine.set(format!("key{:04}", i), "v".repeat(20))?;
+    }
+    for i in 0..500 {
+        if i % 2 == 0 {
+            engine.remove(format!("key{:04}", i))?;
+        }
+    }
+    // 继续写入其他 key，使删除记录被压缩到更深的层
+    for i in 0..500 {
+        engine.set(format!("other{:04}", i), "v".repeat(20))?;
+    }
+    assert_eq!(engine.get("key0000".to_owned())?, None);
+    assert_eq!(engine.get("key0001".to_owned())?, Some("v".repeat(20)));
+    assert!(engine.remove("key0002".to_owned()).is_err());
+    assert_eq!(engine.scan("key".to_owned(), None, usize::MAX)?.len(), 250);
+
+    drop(engine);
+    let engine = open(temp_dir.path())?;
+    assert_eq!(engine.get("key0498".to_owned())?, None);
+    assert_eq!(engine.stats()?["keys"], 750);
+    Ok(())
+}
+
+// A torn write at the end of the write-ahead log should be discarded, and later writes kept
+#[test]
+fn lsm_recovers_from_torn_wal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    drop(engine);
+
+    let mut wal = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("wal.log"))?;
+    wal.write_all(b"{\"Set\":{\"key\":\"key3\",\"val")?;
+    drop(wal);
+
+    let engine = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    drop(engine);
+
+    let engine = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Files left over by an interrupted flush or compaction should be removed on open
+#[test]
+fn lsm_removes_stale_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    for i in 0..200 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(engine);
+    fs::write(temp_dir.path().join("9999.sst"), b"garbage")?;
+    fs::write(temp_dir.path().join("10000.tmp"), b"garbage")?;
+
+    let engine = open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("9999.sst").exists());
+    assert!(!temp_dir.path().join("10000.tmp").exists());
+    assert_eq!(engine.stats()?["keys"], 200);
+    Ok(())
+}
+
+// The registry should open the lsm engine with its options, and backups should restore elsewhere
+#[test]
+fn lsm_from_registry_and_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = EngineOptions::new();
+    options.insert("memtable-size".to_owned(), "1024".to_owned());
+    let engine = EngineRegistry::builtin().open("lsm", temp_dir.path(), &options)?;
+    assert_eq!(engine.get_type(), "lsm");
+    for i in 0..300 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(engine.stats()?["tables"] > 0);
+    assert!(temp_dir.path().join("lsm").join("MANIFEST").exists());
+
+    let backup_path = temp_dir.path().join("backup.kvs");
+    let manifest = engine.backup(&backup_path)?;
+    assert_eq!(manifest.keys, 300);
+    let target = MemoryEngine::new();
+    backup::restore(&backup_path, &target)?;
+    assert_eq!(
+        target.get("key299".to_owned())?,
+        Some("value299".to_owned())
+    );
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use assert_cmd::prelude::*;
+use kvs::client::KvsClient;
+use kvs::engines::{EngineOptions, EngineRegistry, EvictionPolicy, MemoryEngine};
+use kvs::{KvsEngine, Result};
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// 写入 count 个 key，每个键值对占 10 字节
+fn fill<E: KvsEngine>(engine: &E, count: usize) -> Result<()> {
+    for i in 0..count {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    Ok(())
+}
+
+// Reads racing with overwrites of the same key should always see a value
+#[test]
+fn memory_get_during_overwrites() -> Result<()> {
+    let engine = MemoryEngine::new();
+    engine.set("key".to_owned(), "0".to_owned())?;
+    let writer = {
+        let engine = engine.clone();
+        thread::spawn(move || {
+            for i in 1..20_000 {
+                engine.set("key".to_owned(), i.to_string()).unwrap();
+            }
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for _ in 0..20_000 {
+                    assert!(engine.get("key".to_owned()).unwrap().is_some());
+                }
+            })
+        })
+        .collect();
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    Ok(())
+}
+
+// LRU should evict the least recently read or written keys once over the limit
+#[test]
+fn memory_lru_eviction() -> Result<()> {
+    let engine = MemoryEngine::with_limit(50, EvictionPolicy::Lru);
+    fill(&engine, 5)?;
+    assert_eq!(engine.stats()?["memory_bytes"], 50);
+
+    engine.get("key0".to_owned())?;
+    engine.set("key5".to_owned(), "value5".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key0".to_owned())?, Some("value0".to_owned()));
+
+    // 较大的 value 需要淘汰多个 key
+    engine.set("key6".to_owned(), "v".repeat(16))?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    let stats = engine.stats()?;
+    assert_eq!(stats["evicted"], 3);
+    assert!(stats["memory_bytes"] <= 50);
+
+    // 超出上限的单个 value 仍会被写入，其余 key 全部被淘汰
+    engine.set("huge".to_owned(), "v".repeat(100))?;
+    assert_eq!(engine.stats()?["keys"], 1);
+    assert_eq!(engine.get("huge".to_owned())?, Some("v".repeat(100)));
+    Ok(())
+}
+
+// LFU should keep frequently read keys and evict rarely used ones
+#[test]
+fn memory_lfu_eviction() -> Result<()> {
+    let engine = MemoryEngine::with_limit(50, EvictionPolicy::Lfu);
+    fill(&engine, 5)?;
+    for _ in 0..3 {
+        engine.get("key0".to_owned())?;
+        engine.get("key1".to_owned())?;
+    }
+    engine.get("key3".to_owned())?;
+    engine.set("key5".to_owned(), "value5".to_owned())?;
+    engine.set("key6".to_owned(), "value6".to_owned())?;
+
+    // key2 与 key4 只被写入过一次，key2 更早
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.get("key4".to_owned())?, None);
+    for key in &["key0", "key1", "key3", "key6"] {
+        assert!(engine.get(key.to_string())?.is_some(), "{} evicted", key);
+    }
+
+    // 删除与覆盖会正确更新占用的内存
+    engine.remove("key0".to_owned())?;
+    engine.set("key1".to_owned(), "v".to_owned())?;
+    assert_eq!(engine.stats()?["memory_bytes"], 5 + 10 * 3);
+    Ok(())
+}
+
+// The registry should build a memory engine from its options
+#[test]
+fn memory_engine_from_registry() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let registry = EngineRegistry::builtin();
+    let mut options = EngineOptions::new();
+    options.insert("max-memory".to_owned(), "30".to_owned());
+    options.insert("eviction".to_owned(), "lfu".to_owned());
+    let engine = registry.open("memory", dir.path(), &options)?;
+    fill(&engine, 10)?;
+    let stats = engine.stats()?;
+    assert_eq!(stats["max_memory"], 30);
+    assert_eq!(stats["keys"], 3);
+
+    options.insert("eviction".to_owned(), "random".to_owned());
+    assert!(registry.create("memory", dir.path(), &options).is_err());
+    Ok(())
+}
+
+// kvs-server --engine memory should serve requests without writing data files
+#[test]
+fn server_with_memory_engine() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "memory", "--addr", "127.0.0.1:4182"])
+        .args(&["--engine-opt", "max-memory=1000"])
+        .current_dir(&dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect("127.0.0.1:4182".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    child.kill().expect("server exited before killed");
+
+    assert_eq!(fs::read_to_string(dir.path().join("server.cfg"))?, "memory");
+    let entries: Vec<_> = fs::read_dir(dir.path())?.collect();
+    assert_eq!(entries.len(), 1);
+    Ok(())
+}
@@ -0,0 +1,68 @@
+use kvs::engines::KvStoreOptions;
+use kvs::{KvStore, KvsEngine, Result};
+use tempfile::TempDir;
+
+/// 不使用读缓存的参数，使每次读取都访问数据文件
+fn uncached(buffered_reads: bool) -> KvStoreOptions {
+    KvStoreOptions {
+        cache_size: Some(0),
+        buffered_reads,
+        ..KvStoreOptions::default()
+    }
+}
+
+// Reads should come from the mapped log, including records appended after mapping and after compaction
+#[test]
+fn mapped_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), uncached(false))?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // 新写入的记录还未映射，通过缓冲读取
+    assert_eq!(store.stats()?["mapped_bytes"], 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), uncached(false))?;
+    let mapped = store.stats()?["mapped_bytes"];
+    assert_eq!(mapped, store.stats()?["log_bytes"]);
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    for i in 0..=100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // 压缩后映射新文件
+    let file_id = store.stats()?["file_id"];
+    let mut i = 0;
+    while store.stats()?["file_id"] == file_id {
+        store.set("key0".to_owned(), format!("new{}", i))?;
+        i += 1;
+    }
+    let stats = store.stats()?;
+    assert!(stats["mapped_bytes"] > 0);
+    assert!(stats["mapped_bytes"] <= stats["log_bytes"]);
+    assert_eq!(store.get("key0".to_owned())?, Some(format!("new{}", i - 1)));
+    for i in 1..=100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Buffered reads should never map the log and return the same values
+#[test]
+fn buffered_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), uncached(false))?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), uncached(true))?;
+    assert_eq!(store.stats()?["mapped_bytes"], 0);
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
@@ -0,0 +1,496 @@
+use kvs::client::KvsClient;
+use kvs::raft::{
+    Command, Entry, EntryData, MemStorage, Member, Membership, Message, MessageBody, NodeId, Raft,
+    RaftEngine, Role,
+};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsServer, Result};
+use std::collections::{BTreeMap, VecDeque};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// 节点 id 对应的成员信息，测试中的地址不会被使用
+fn member(id: NodeId) -> Member {
+    Member {
+        client_addr: format!("client{}", id),
+        raft_addr: format!("raft{}", id),
+    }
+}
+
+/// 在进程内模拟的集群
+///
+/// 消息按发送顺序投递，可以模拟网络分区与节点崩溃，结果完全由操作顺序决定
+struct Cluster {
+    membership: Membership,
+    nodes: BTreeMap<NodeId, Option<Raft<MemStorage>>>,
+    storages: BTreeMap<NodeId, MemStorage>,
+    /// 每个节点已应用的日志，崩溃后保留，模拟持久化的状态机
+    applied: BTreeMap<NodeId, Vec<Entry>>,
+    /// 每个节点所在的分区，不同分区之间的消息会被丢弃
+    groups: BTreeMap<NodeId, usize>,
+    queue: VecDeque<Message>,
+}
+
+impl Cluster {
+    /// 创建 id 为 1..=n 的 n 个节点组成的集群
+    fn new(n: u64) -> Cluster {
+        let membership: Membership = (1..=n).map(|id| (id, member(id))).collect();
+        let mut cluster = Cluster {
+            membership: membership.clone(),
+            nodes: BTreeMap::new(),
+            storages: BTreeMap::new(),
+            applied: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            queue: VecDeque::new(),
+        };
+        for id in 1..=n {
+            cluster.add_node(id, membership.clone());
+        }
+        cluster
+    }
+
+    /// 启动一个新节点
+    fn add_node(&mut self, id: NodeId, membership: Membership) {
+        let storage = MemStorage::new();
+        self.storages.insert(id, storage.clone());
+        self.applied.insert(id, Vec::new());
+        self.nodes
+            .insert(id, Some(Raft::new(id, membership, storage, 0).unwrap()));
+    }
+
+    fn node(&mut self, id: NodeId) -> &mut Raft<MemStorage> {
+        self.nodes.get_mut(&id).unwrap().as_mut().unwrap()
+    }
+
+    /// 崩溃节点 id，未投递的消息丢失
+    fn crash(&mut self, id: NodeId) {
+        self.nodes.insert(id, None);
+    }
+
+    /// 使用已持久化的存储重启节点 id
+    fn restart(&mut self, id: NodeId) {
+        let applied = self.applied[&id].len() as u64;
+        let storage = self.storages[&id].clone();
+        let raft = Raft::new(id, self.membership.clone(), storage, applied).unwrap();
+        self.nodes.insert(id, Some(raft));
+    }
+
+    /// 将节点划分为互不连通的分区
+    fn partition(&mut self, groups: &[&[NodeId]]) {
+        for (group, ids) in groups.iter().enumerate() {
+            for id in ids.iter() {
+                self.groups.insert(*id, group);
+            }
+        }
+    }
+
+    /// 恢复网络
+    fn heal(&mut self) {
+        self.groups.clear();
+    }
+
+    /// 推进 ticks 个时间单位，每个时间单位后投递所有消息
+    fn run(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            for node in self.nodes.values_mut().flatten() {
+                node.tick().unwrap();
+            }
+            self.deliver();
+        }
+    }
+
+    /// 投递消息直到网络中没有消息
+    fn deliver(&mut self) {
+        loop {
+            for (id, node) in self.nodes.iter_mut() {
+                if let Some(node) = node {
+                    self.queue.extend(node.take_messages());
+                    self.applied
+                        .get_mut(id)
+                        .unwrap()
+                        .extend(node.take_committed());
+                }
+            }
+            let msg = match self.queue.pop_front() {
+                Some(msg) => msg,
+                None => return,
+            };
+            if self.groups.get(&msg.from) != self.groups.get(&msg.to) {
+                continue;
+            }
+            if let Some(Some(node)) = self.nodes.get_mut(&msg.to) {
+                node.step(msg).unwrap();
+            }
+        }
+    }
+
+    /// 运行中且任期最大的 leader
+    fn leader(&self) -> Option<NodeId> {
+        self.nodes
+            .values()
+            .flatten()
+            .filter(|node| node.role() == Role::Leader)
+            .max_by_key(|node| node.term())
+            .map(|node| node.id())
+    }
+
+    /// 由节点 id 提议一个 Set 命令
+    fn set(&mut self, id: NodeId, key: &str) -> Result<u64> {
+        let command = Command::Set {
+            key: key.to_owned(),
+            value: "value".to_owned(),
+        };
+        let index = self.node(id).propose(EntryData::Command(command))?;
+        self.deliver();
+        Ok(index)
+    }
+
+    /// 节点 id 已应用的命令中的 key
+    fn applied_keys(&self, id: NodeId) -> Vec<String> {
+        self.applied[&id]
+            .iter()
+            .filter_map(|entry| match &entry.data {
+                EntryData::Command(Command::Set { key, .. }) => Some(key.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// 检查任意两个节点已应用的日志中，一个是另一个的前缀
+    fn check_consistency(&self) {
+        for a in self.applied.values() {
+            for b in self.applied.values() {
+                let len = a.len().min(b.len());
+                assert_eq!(a[..len], b[..len]);
+            }
+        }
+    }
+}
+
+// A cluster should elect exactly one leader that every node agrees on
+#[test]
+fn raft_elects_single_leader() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(50);
+    let leader = cluster.leader().expect("no leader elected");
+    let term = cluster.node(leader).term();
+    for id in 1..=3 {
+        let node = cluster.node(id);
+        assert_eq!(node.leader(), Some(leader));
+        assert_eq!(node.term(), term);
+        assert_eq!(node.role() == Role::Leader, id == leader);
+    }
+
+    // 没有故障时 leader 保持不变
+    cluster.run(100);
+    assert_eq!(cluster.leader(), Some(leader));
+    assert_eq!(cluster.node(leader).term(), term);
+}
+
+// Committed commands should be applied on every node in the same order
+#[test]
+fn raft_replicates_commands() -> Result<()> {
+    let mut cluster = Cluster::new(3);
+    cluster.run(50);
+    let leader = cluster.leader().unwrap();
+    let follower = if leader == 1 { 2 } else { 1 };
+    assert!(cluster.set(follower, "key").is_err());
+
+    let keys: Vec<String> = (0..10).map(|i| format!("key{}", i)).collect();
+    for key in &keys {
+        cluster.set(leader, key)?;
+    }
+    cluster.run(10);
+    for id in 1..=3 {
+        assert_eq!(cluster.applied_keys(id), keys);
+    }
+    cluster.check_consistency();
+    Ok(())
+}
+
+// A partitioned leader should not commit, and its entries should be replaced after healing
+#[test]
+fn raft_leader_partition() -> Result<()> {
+    let mut cluster = Cluster::new(5);
+    cluster.run(50);
+    let old_leader = cluster.leader().unwrap();
+    cluster.set(old_leader, "before")?;
+    cluster.run(5);
+
+    let minority: Vec<NodeId> = vec![old_leader, old_leader % 5 + 1];
+    let majority: Vec<NodeId> = (1..=5).filter(|id| !minority.contains(id)).collect();
+    cluster.partition(&[&minority, &majority]);
+    cluster.set(old_leader, "lost")?;
+    cluster.run(50);
+    assert_eq!(cluster.node(old_leader).role(), Role::Leader);
+
+    let new_leader = cluster.leader().unwrap();
+    assert!(majority.contains(&new_leader));
+    cluster.set(new_leader, "after")?;
+    cluster.run(5);
+    for id in &majority {
+        assert_eq!(cluster.applied_keys(*id), vec!["before", "after"]);
+    }
+    assert_eq!(cluster.applied_keys(old_leader), vec!["before"]);
+
+    // 恢复后 leader 仍在原多数派中，旧 leader 的日志较旧，无法再当选
+    cluster.heal();
+    cluster.run(50);
+    let leader = cluster.leader().unwrap();
+    assert!(majority.contains(&leader));
+    assert_eq!(cluster.node(old_leader).role(), Role::Follower);
+    for id in 1..=5 {
+        assert_eq!(cluster.applied_keys(id), vec!["before", "after"]);
+    }
+    cluster.check_consistency();
+    Ok(())
+}
+
+// Crashed nodes should recover their log from storage and catch up
+#[test]
+fn raft_crash_and_restart() -> Result<()> {
+    let mut cluster = Cluster::new(3);
+    cluster.run(50);
+    let leader = cluster.leader().unwrap();
+    let follower = if leader == 1 { 2 } else { 1 };
+    cluster.set(leader, "key1")?;
+    cluster.run(5);
+
+    cluster.crash(follower);
+    cluster.set(leader, "key2")?;
+    cluster.run(5);
+    cluster.restart(follower);
+    cluster.run(20);
+    assert_eq!(cluster.applied_keys(follower), vec!["key1", "key2"]);
+
+    // leader 崩溃后其余节点选出新的 leader，已提交的日志不会丢失
+    cluster.crash(leader);
+    cluster.run(50);
+    let new_leader = cluster.leader().unwrap();
+    assert_ne!(new_leader, leader);
+    cluster.set(new_leader, "key3")?;
+    cluster.run(5);
+    cluster.restart(leader);
+    cluster.run(50);
+    for id in 1..=3 {
+        assert_eq!(cluster.applied_keys(id), vec!["key1", "key2", "key3"]);
+    }
+    cluster.check_consistency();
+    Ok(())
+}
+
+// A leader should step down when a candidate with a higher term asks for its vote
+#[test]
+fn raft_leader_steps_down_on_vote() -> Result<()> {
+    let mut cluster = Cluster::new(3);
+    cluster.run(50);
+    let leader = cluster.leader().unwrap();
+    let follower = if leader == 1 { 2 } else { 1 };
+    let term = cluster.node(leader).term();
+    let last_log_index = cluster.node(leader).last_index();
+    cluster.node(leader).step(Message {
+        from: follower,
+        to: leader,
+        term: term + 1,
+        body: MessageBody::RequestVote {
+            last_log_index,
+            last_log_term: term,
+        },
+    })?;
+    assert_eq!(cluster.node(leader).role(), Role::Follower);
+    assert_eq!(cluster.node(leader).term(), term + 1);
+
+    // 状态机已应用的序号超过本地日志时不会越界
+    let mut raft = Raft::new(1, cluster.membership.clone(), MemStorage::new(), 5)?;
+    assert!(raft.take_committed().is_empty());
+    Ok(())
+}
+
+// Reads should only be confirmed while a majority still follows the leader
+#[test]
+fn raft_read_index() -> Result<()> {
+    let mut cluster = Cluster::new(5);
+    cluster.run(50);
+    let old_leader = cluster.leader().unwrap();
+    let index = cluster.set(old_leader, "key")?;
+    cluster.node(old_leader).read_index(1)?;
+    cluster.deliver();
+    assert_eq!(
+        cluster.node(old_leader).take_reads(),
+        vec![(1, Some(index))]
+    );
+    let follower = old_leader % 5 + 1;
+    assert!(cluster.node(follower).read_index(2).is_err());
+
+    // 被分区的旧 leader 仍认为自己是 leader，但无法确认读请求
+    let minority: Vec<NodeId> = vec![old_leader, follower];
+    let majority: Vec<NodeId> = (1..=5).filter(|id| !minority.contains(id)).collect();
+    cluster.partition(&[&minority, &majority]);
+    cluster.run(50);
+    let new_leader = cluster.leader().unwrap();
+    assert!(majority.contains(&new_leader));
+    cluster.set(new_leader, "after")?;
+    assert_eq!(cluster.node(old_leader).role(), Role::Leader);
+    cluster.node(old_leader).read_index(3)?;
+    cluster.run(20);
+    assert!(cluster.node(old_leader).take_reads().is_empty());
+
+    // 恢复后旧 leader 退位，未确认的读请求需要重试
+    cluster.heal();
+    cluster.run(50);
+    assert_eq!(cluster.node(old_leader).role(), Role::Follower);
+    assert_eq!(cluster.node(old_leader).take_reads(), vec![(3, None)]);
+    Ok(())
+}
+
+// Nodes should be added and removed one at a time, including the leader itself
+#[test]
+fn raft_membership_change() -> Result<()> {
+    let mut cluster = Cluster::new(3);
+    cluster.run(50);
+    let leader = cluster.leader().unwrap();
+    cluster.set(leader, "key1")?;
+
+    // 新节点以空的配置启动，由 leader 加入集群后接收日志
+    cluster.add_node(4, Membership::new());
+    cluster.node(leader).add_member(4, member(4))?;
+    assert!(cluster.node(leader).remove_member(1).is_err());
+    cluster.run(20);
+    assert_eq!(cluster.applied_keys(4), vec!["key1"]);
+    for id in 1..=4 {
+        assert_eq!(cluster.node(id).membership().len(), 4);
+    }
+
+    // 删除 leader 后由剩余节点选出新的 leader
+    cluster.node(leader).remove_member(leader)?;
+    cluster.run(50);
+    let new_leader = cluster.leader().unwrap();
+    assert_ne!(new_leader, leader);
+    assert!(!cluster.node(new_leader).membership().contains_key(&leader));
+    cluster.set(new_leader, "key2")?;
+    cluster.run(50);
+    assert_eq!(cluster.leader(), Some(new_leader));
+    for id in (1..=4).filter(|id| *id != leader) {
+        assert_eq!(cluster.applied_keys(id), vec!["key1", "key2"]);
+    }
+    cluster.check_consistency();
+    Ok(())
+}
+
+// kvs-servers backed by RaftEngine should replicate writes and redirect clients to the leader
+#[test]
+fn raft_cluster_redirects_clients() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let membership: Membership = (1..=3)
+        .map(|id| {
+            let member = Member {
+                client_addr: format!("127.0.0.1:{}", 4159 + id),
+                raft_addr: format!("127.0.0.1:{}", 4162 + id),
+            };
+            (id, member)
+        })
+        .collect();
+    let mut engines = Vec::new();
+    for (id, dir) in (1..=3).zip(&dirs) {
+        let store = KvStore::open(dir.path().join("kvs"))?;
+        let raft_addr = membership[&id].raft_addr.clone();
+        let engine = RaftEngine::start(
+            store,
+            id,
+            &raft_addr,
+            membership.clone(),
+            dir.path().join("raft"),
+        )?;
+        engines.push(engine.clone());
+        let addr = membership[&id].client_addr.clone();
+        thread::spawn(move || {
+            KvsServer::new(engine, SharedQueueThreadPool::new(4).unwrap())
+                .run(addr)
+                .unwrap();
+        });
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let leader = loop {
+        if let Some(leader) = engines[0].leader() {
+            break leader;
+        }
+        assert!(Instant::now() < deadline, "no leader elected");
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    // 连接任意节点的客户端都会被重定向到 leader
+    for member in membership.values() {
+        let mut client = KvsClient::connect(member.client_addr.clone())?;
+        client.set(member.client_addr.clone(), "value".to_owned())?;
+        assert_eq!(
+            client.get(member.client_addr.clone())?,
+            Some("value".to_owned())
+        );
+    }
+    let follower = engines
+        .iter()
+        .find(|engine| engine.get("x".to_owned()).is_err())
+        .unwrap();
+    assert_eq!(follower.leader(), Some(leader));
+
+    // 写入最终应用到所有节点
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while engines
+        .iter()
+        .any(|engine| engine.stats().unwrap()["keys"] < 3)
+    {
+        assert!(Instant::now() < deadline, "writes were not replicated");
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+// Peers sending a different token should be ignored by the rest of the cluster
+#[test]
+fn raft_cluster_rejects_wrong_token() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let membership: Membership = (1..=3)
+        .map(|id| {
+            let member = Member {
+                client_addr: format!("127.0.0.1:{}", 4214 + id),
+                raft_addr: format!("127.0.0.1:{}", 4217 + id),
+            };
+            (id, member)
+        })
+        .collect();
+    let mut engines = Vec::new();
+    for (id, dir) in (1..=3).zip(&dirs) {
+        let store = KvStore::open(dir.path().join("kvs"))?;
+        let token = if id == 3 { "wrong" } else { "secret" };
+        engines.push(RaftEngine::start_with_token(
+            store,
+            id,
+            &membership[&id].raft_addr,
+            membership.clone(),
+            dir.path().join("raft"),
+            Some(token.to_owned()),
+        )?);
+    }
+
+    // 节点 1 与 2 仍是多数，可以选出 leader 并提交写入
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let leader = loop {
+        if let Some(engine) = engines[..2]
+            .iter()
+            .find(|engine| engine.set("key".to_owned(), "value".to_owned()).is_ok())
+        {
+            break engine;
+        }
+        assert!(Instant::now() < deadline, "no leader elected");
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(leader.get("key".to_owned())?, Some("value".to_owned()));
+
+    // 节点 3 收不到任何消息
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(engines[2].leader(), None);
+    assert_eq!(engines[2].stats()?["keys"], 0);
+    Ok(())
+}
@@ -0,0 +1,355 @@
+use assert_cmd::prelude::*;
+use kvs::engines::{self, DumpFilter, KvStoreOptions, MemoryEngine, RecoveryTarget};
+use kvs::{KvStore, KvsEngine, KvsErrorType, Result};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+
+/// 归档到 archive 的参数，不小于 1KB 的 value 保存在 blob 文件中
+fn archived(archive: &Path) -> KvStoreOptions {
+    KvStoreOptions {
+        blob_threshold: Some(1024),
+        archive: Some(archive.to_owned()),
+        ..KvStoreOptions::default()
+    }
+}
+
+/// 反复覆盖同一个 key，直到发生压缩
+fn force_compaction(store: &KvStore) -> Result<()> {
+    let file_id = store.stats()?["file_id"];
+    let value = "x".repeat(512);
+    while store.stats()?["file_id"] == file_id {
+        store.set("padding".to_owned(), value.clone())?;
+    }
+    Ok(())
+}
+
+/// 当前的 Unix 时间戳（毫秒）
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// Sequence numbers should keep growing across compactions that drop removes and across reopens
+#[test]
+fn sequence_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.remove("key".to_owned())?;
+    assert!(store.remove("key".to_owned()).is_err());
+    assert_eq!(store.stats()?["seq"], 2);
+    force_compaction(&store)?;
+    let seq = store.stats()?["seq"];
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?["seq"], seq);
+    store.set("key".to_owned(), "again".to_owned())?;
+    assert_eq!(store.stats()?["seq"], seq + 1);
+    Ok(())
+}
+
+// Compaction and blob collection should move old files into the archive instead of deleting them
+#[test]
+fn archive_keeps_old_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), archived(archive.path()))?;
+    let large = "y".repeat(64 * 1024);
+    for _ in 0..40 {
+        store.set("large".to_owned(), large.clone())?;
+    }
+    force_compaction(&store)?;
+    assert!(archive.path().join("0.log").exists());
+    assert!(!temp_dir.path().join("0.log").exists());
+    assert!(archive.path().join("blobs").join("0.blob").exists());
+    assert!(store.stats()?["blob_files"] <= 1);
+
+    let users = store.open_tree("users")?;
+    force_compaction(&users)?;
+    assert!(archive.path().join("trees/users/0.log").exists());
+    drop(store);
+
+    // 归档的 blob 文件 id 不会被再次使用
+    let store = KvStore::open_with(temp_dir.path(), archived(archive.path()))?;
+    store.set("large2".to_owned(), large)?;
+    assert!(!temp_dir.path().join("blobs").join("0.blob").exists());
+    Ok(())
+}
+
+// Records written when collecting blob files should keep the stamp of the value they move
+#[test]
+fn blob_collection_keeps_stamps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), archived(archive.path()))?;
+    let kept = "k".repeat(64 * 1024);
+    store.set("kept".to_owned(), kept.clone())?;
+    let large = "y".repeat(64 * 1024);
+    for _ in 0..40 {
+        store.set("large".to_owned(), large.clone())?;
+    }
+    assert!(archive.path().join("blobs").join("0.blob").exists());
+    drop(store);
+
+    let mut records = Vec::new();
+    engines::dump(temp_dir.path(), None, &DumpFilter::default(), |record| {
+        records.push(record);
+        Ok(())
+    })?;
+    assert!(records.iter().all(|record| record.seq.is_some()));
+    let moved: Vec<_> = records
+        .iter()
+        .filter(|record| record.key.as_deref() == Some("kept"))
+        .collect();
+    assert!(!moved.is_empty());
+    assert!(moved.iter().all(|record| record.seq == Some(1)));
+
+    let store = KvStore::open_with(temp_dir.path(), archived(archive.path()))?;
+    assert_eq!(store.get("kept".to_owned())?, Some(kept.clone()));
+    let engine = MemoryEngine::new();
+    let logs = vec![archive.path().to_owned(), temp_dir.path().to_owned()];
+    let report = engines::recover(None, &logs, None, RecoveryTarget::Seq(41), &engine)?;
+    assert_eq!((report.replayed, report.seq, report.gap), (41, 41, None));
+    assert_eq!(engine.get("kept".to_owned())?, Some(kept));
+    Ok(())
+}
+
+// Archiving should never overwrite a file that is already in the archive
+#[test]
+fn archive_refuses_to_overwrite() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), archived(archive.path()))?;
+    force_compaction(&store)?;
+    assert!(archive.path().join("0.log").exists());
+
+    // 其他数据库不能使用同一个归档目录
+    let other = TempDir::new().expect("unable to create temporary working directory");
+    let err = KvStore::open_with(other.path(), archived(archive.path()))
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), KvsErrorType::OptionsMismatch);
+
+    // 归档目录中已有同名文件时，压缩后的旧文件保留在数据目录中
+    fs::write(archive.path().join("1.log"), "other")?;
+    let value = "x".repeat(512);
+    let err = (0..100_000)
+        .map(|_| store.set("padding".to_owned(), value.clone()))
+        .find(Result::is_err)
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(err.kind(), KvsErrorType::IOError);
+    assert_eq!(fs::read_to_string(archive.path().join("1.log"))?, "other");
+    assert!(temp_dir.path().join("1.log").exists());
+    assert_eq!(store.get("padding".to_owned())?, Some(value));
+    drop(store);
+    KvStore::open_with(temp_dir.path(), archived(archive.path()))?;
+    Ok(())
+}
+
+// A base backup plus the archived logs should rebuild the store as of any sequence number
+#[test]
+fn recover_to_sequence() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), archived(archive.path()))?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let base = backup_dir.path().join("base.kvsbak");
+    let manifest = store.backup(&base)?;
+    assert_eq!(manifest.seq, Some(10));
+
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    store.set("blob".to_owned(), "z".repeat(4096))?;
+    force_compaction(&store)?;
+    let before = store.stats()?["seq"];
+    // 误删
+    for i in 1..5 {
+        store.remove(format!("key{}", i))?;
+    }
+    force_compaction(&store)?;
+    store.set("later".to_owned(), "value".to_owned())?;
+
+    let logs = vec![archive.path().to_owned(), temp_dir.path().to_owned()];
+    let engine = MemoryEngine::new();
+    let report = engines::recover(
+        Some(&base),
+        &logs,
+        None,
+        RecoveryTarget::Seq(before),
+        &engine,
+    )?;
+    assert_eq!((report.base_seq, report.base_keys), (10, 10));
+    assert_eq!(report.seq, before);
+    assert_eq!(report.replayed, before - 10);
+    assert_eq!(report.gap, None);
+    assert_eq!(engine.get("key0".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("blob".to_owned())?, Some("z".repeat(4096)));
+    assert_eq!(engine.get("later".to_owned())?, None);
+
+    // 不使用备份时从第一条记录开始重放
+    let engine = MemoryEngine::new();
+    let report = engines::recover(None, &logs, None, RecoveryTarget::Seq(before + 2), &engine)?;
+    assert_eq!((report.base_seq, report.seq), (0, before + 2));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // 备份晚于目标时无法恢复
+    let err = engines::recover(
+        Some(&base),
+        &logs,
+        None,
+        RecoveryTarget::Seq(5),
+        &MemoryEngine::new(),
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), KvsErrorType::InvalidArgument);
+
+    // 只能恢复到空的引擎中
+    let err =
+        engines::recover(None, &logs, None, RecoveryTarget::Seq(before), &engine).unwrap_err();
+    assert_eq!(err.kind(), KvsErrorType::TargetNotEmpty);
+    Ok(())
+}
+
+// Recovering to a time should replay exactly the records written up to it
+#[test]
+fn recover_to_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), archived(archive.path()))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    thread::sleep(Duration::from_millis(20));
+    let moment = now_millis();
+    thread::sleep(Duration::from_millis(20));
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "changed".to_owned())?;
+
+    let engine = MemoryEngine::new();
+    let logs = vec![archive.path().to_owned(), temp_dir.path().to_owned()];
+    let report = engines::recover(None, &logs, None, RecoveryTarget::Time(moment), &engine)?;
+    assert_eq!((report.replayed, report.seq), (2, 2));
+    assert!(report.time.unwrap() <= moment);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Keyspaces should be replayed into keyspaces of the same name, up to the same moment
+#[test]
+fn recover_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), archived(archive.path()))?;
+    let users = store.open_tree("users")?;
+    let orders = store.open_tree("orders")?;
+    users.set("key".to_owned(), "value1".to_owned())?;
+    orders.set("key".to_owned(), "order".to_owned())?;
+    force_compaction(&users)?;
+    store.set("key".to_owned(), "default".to_owned())?;
+    thread::sleep(Duration::from_millis(20));
+    let moment = now_millis();
+    thread::sleep(Duration::from_millis(20));
+    users.set("key".to_owned(), "value2".to_owned())?;
+    store
+        .open_tree("later")?
+        .set("key".to_owned(), "later".to_owned())?;
+    assert!(store.drop_tree("orders")?);
+
+    let engine = MemoryEngine::new();
+    let logs = vec![archive.path().to_owned(), temp_dir.path().to_owned()];
+    let report = engines::recover(None, &logs, None, RecoveryTarget::Time(moment), &engine)?;
+    assert_eq!(report.replayed, 1);
+    assert_eq!(report.keyspaces.keys().collect::<Vec<_>>(), vec!["users"]);
+    assert_eq!(report.keyspaces["users"].gap, None);
+    // 已删除的 keyspace 与目标时刻之后创建的 keyspace 不会被恢复
+    assert_eq!(engine.tree_names()?, vec!["users"]);
+    let tree = engine.open_tree("users")?;
+    assert_eq!(tree.get("key".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(tree.get("padding".to_owned())?, Some("x".repeat(512)));
+    assert_eq!(engine.get("key".to_owned())?, Some("default".to_owned()));
+
+    // 按序号恢复时，keyspace 恢复到默认 keyspace 最后重放的记录的写入时间
+    let engine = MemoryEngine::new();
+    let report = engines::recover(None, &logs, None, RecoveryTarget::Seq(1), &engine)?;
+    assert_eq!(report.seq, 1);
+    assert_eq!(
+        engine.open_tree("users")?.get("key".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    // 已有 keyspace 的引擎不能作为恢复目标
+    let err =
+        engines::recover(None, &logs, None, RecoveryTarget::Time(moment), &engine).unwrap_err();
+    assert_eq!(err.kind(), KvsErrorType::TargetNotEmpty);
+    Ok(())
+}
+
+// kvs-admin recover should rebuild a server directory and refuse backups without a sequence number
+#[test]
+fn cli_recover() -> Result<()> {
+    let source = TempDir::new().expect("unable to create temporary working directory");
+    let archive = TempDir::new().expect("unable to create temporary working directory");
+    let target = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(source.path().join("kvs"), archived(archive.path()))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let mut manifest: serde_json::Value = serde_json::from_str(&format!(
+        "{{\"version\":1,\"engine\":\"sled\",\"keys\":0,\"checksum\":{},\"created\":0}}",
+        0xcbf2_9ce4_8422_2325u64
+    ))?;
+    let other = source.path().join("other");
+    fs::create_dir(&other)?;
+    fs::write(other.join("data.jsonl"), "")?;
+    fs::write(other.join("manifest.json"), manifest.to_string())?;
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["recover", "--archive", archive.path().to_str().unwrap()])
+        .args(&["--backup", other.to_str().unwrap(), "--to-seq", "2"])
+        .current_dir(&target)
+        .assert()
+        .failure()
+        .stderr(contains("Backup has no sequence number"));
+    manifest["seq"] = 0.into();
+    fs::write(other.join("manifest.json"), manifest.to_string())?;
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["recover", "--archive", archive.path().to_str().unwrap()])
+        .args(&["--backup", other.to_str().unwrap(), "--to-seq", "2"])
+        .args(&["--logs", source.path().to_str().unwrap()])
+        .current_dir(&target)
+        .assert()
+        .success()
+        .stdout(contains("2 records replayed, recovered to seq 2"));
+    let store = KvStore::open(target.path().join("kvs"))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["recover", "--archive", archive.path().to_str().unwrap()])
+        .args(&["--to-seq", "3"])
+        .current_dir(&target)
+        .assert()
+        .failure()
+        .stderr(contains("Target is not empty."));
+    Ok(())
+}
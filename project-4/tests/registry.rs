@@ -0,0 +1,59 @@
+use kvs::engines::{AnyEngine, EngineOptions, EngineRegistry};
+use kvs::{KvStore, KvsEngine, KvsErrorType, Result};
+use std::fs;
+use tempfile::TempDir;
+
+// Built-in engines should open through the registry and respect server.cfg
+#[test]
+fn registry_checks_marker() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let registry = EngineRegistry::builtin();
+    assert_eq!(registry.names(), vec!["kvs", "lsm", "memory", "sled"]);
+
+    let engine = registry.open("kvs", dir.path(), &EngineOptions::new())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get_type(), "kvs");
+    assert_eq!(fs::read_to_string(dir.path().join("server.cfg"))?, "kvs");
+    drop(engine);
+
+    let err = registry
+        .open("sled", dir.path(), &EngineOptions::new())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), KvsErrorType::WrongEngine);
+    let err = registry
+        .open("missing", dir.path(), &EngineOptions::new())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), KvsErrorType::UnknownEngine);
+
+    let engine = registry.open("kvs", dir.path(), &EngineOptions::new())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Engines registered by library users should get their options and their own type id
+#[test]
+fn registry_custom_engine() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let mut registry = EngineRegistry::builtin();
+    registry.register("custom", "custom-v1", |dir, options| {
+        let engine = KvStore::open(dir.join("custom"))?;
+        if let Some(value) = options.get("seed") {
+            engine.set("seed".to_owned(), value.clone())?;
+        }
+        Ok(AnyEngine::new(engine))
+    });
+    assert_eq!(registry.name_of_type("custom-v1"), Some("custom"));
+
+    let mut options = EngineOptions::new();
+    options.insert("seed".to_owned(), "42".to_owned());
+    let engine = registry.open("custom", dir.path(), &options)?;
+    assert_eq!(engine.get("seed".to_owned())?, Some("42".to_owned()));
+    assert_eq!(
+        fs::read_to_string(dir.path().join("server.cfg"))?,
+        "custom-v1"
+    );
+    assert!(dir.path().join("custom").is_dir());
+    Ok(())
+}
@@ -0,0 +1,283 @@
+use kvs::client::KvsClient;
+use kvs::replication::{Follower, LogEntry, ReadOnly};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsServer, Result};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// 将主节点当前所有可用的日志应用到从节点
+fn sync<E: KvsEngine>(leader: &KvStore, follower: &mut Follower<E>) -> Result<Vec<LogEntry>> {
+    let entries = leader.replicate(follower.position())?.pending();
+    for entry in entries.clone() {
+        follower.apply(entry)?;
+    }
+    Ok(entries)
+}
+
+/// 重复覆盖同一个 key，直到主节点完成压缩
+fn force_compaction(store: &KvStore) -> Result<()> {
+    let file_id = store.stats()?["file_id"];
+    let value = "x".repeat(1024);
+    while store.stats()?["file_id"] == file_id {
+        store.set("padding".to_owned(), value.clone())?;
+    }
+    Ok(())
+}
+
+// New writes on the leader should be streamed to a subscribed follower
+#[test]
+fn replication_streams_new_writes() -> Result<()> {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let leader = KvStore::open(leader_dir.path())?;
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+
+    let engine = KvStore::open(follower_dir.path().join("kvs"))?;
+    let mut follower = Follower::new(engine.clone(), follower_dir.path().join("replica.json"))?;
+    let mut replication = leader.replicate(follower.position())?;
+    for entry in replication.pending() {
+        follower.apply(entry)?;
+    }
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    leader.set("key1".to_owned(), "value3".to_owned())?;
+    leader.remove("key2".to_owned())?;
+    let entries = replication.pending();
+    assert_eq!(entries.len(), 3);
+    for entry in entries {
+        follower.apply(entry)?;
+    }
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    let stats = leader.stats()?;
+    let position = follower.position().unwrap();
+    assert_eq!(position.file_id, stats["file_id"]);
+    assert_eq!(position.offset, stats["log_bytes"]);
+    Ok(())
+}
+
+// A restarted follower should only receive the records after its saved offset
+#[test]
+fn replication_resumes_from_offset() -> Result<()> {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let state_path = follower_dir.path().join("replica.json");
+    let leader = KvStore::open(leader_dir.path())?;
+    for i in 0..10 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    {
+        let engine = KvStore::open(follower_dir.path().join("kvs"))?;
+        let mut follower = Follower::new(engine, state_path.clone())?;
+        sync(&leader, &mut follower)?;
+    }
+
+    leader.set("key10".to_owned(), "value10".to_owned())?;
+    leader.remove("key0".to_owned())?;
+
+    let engine = KvStore::open(follower_dir.path().join("kvs"))?;
+    let mut follower = Follower::new(engine.clone(), state_path)?;
+    assert!(follower.position().is_some());
+    let entries = sync(&leader, &mut follower)?;
+    assert_eq!(entries.len(), 3);
+    match &entries[..2] {
+        [LogEntry::Keyspaces { names }, LogEntry::Record { .. }] if names.is_empty() => {}
+        entries => panic!("unexpected entries {:?}", entries),
+    }
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert_eq!(engine.get("key5".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(engine.get("key10".to_owned())?, Some("value10".to_owned()));
+    Ok(())
+}
+
+// A follower whose offset was compacted away should bootstrap from a snapshot
+#[test]
+fn replication_bootstraps_from_snapshot() -> Result<()> {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let leader = KvStore::open(leader_dir.path())?;
+    leader.set("kept".to_owned(), "value".to_owned())?;
+    leader.set("removed".to_owned(), "value".to_owned())?;
+
+    let engine = KvStore::open(follower_dir.path().join("kvs"))?;
+    let mut follower = Follower::new(engine.clone(), follower_dir.path().join("replica.json"))?;
+    sync(&leader, &mut follower)?;
+    assert_eq!(engine.get("removed".to_owned())?, Some("value".to_owned()));
+
+    // 从节点离线期间，主节点删除了 key 并完成压缩
+    leader.remove("removed".to_owned())?;
+    leader.set("added".to_owned(), "value".to_owned())?;
+    force_compaction(&leader)?;
+
+    let entries = sync(&leader, &mut follower)?;
+    match &entries[..2] {
+        [LogEntry::Keyspaces { .. }, LogEntry::Snapshot] => {}
+        entries => panic!("unexpected entries {:?}", entries),
+    }
+    assert_eq!(engine.get("kept".to_owned())?, Some("value".to_owned()));
+    assert_eq!(engine.get("added".to_owned())?, Some("value".to_owned()));
+    assert_eq!(engine.get("removed".to_owned())?, None);
+
+    // 压缩后的写入继续以日志的形式发送
+    let mut replication = leader.replicate(follower.position())?;
+    leader.set("after".to_owned(), "value".to_owned())?;
+    let entries = replication.pending();
+    assert_eq!(entries.len(), 2);
+    match &entries[..] {
+        [LogEntry::Keyspaces { .. }, LogEntry::Record { .. }] => {}
+        entries => panic!("unexpected entries {:?}", entries),
+    }
+    for entry in entries {
+        follower.apply(entry)?;
+    }
+    assert_eq!(engine.get("after".to_owned())?, Some("value".to_owned()));
+    assert_eq!(leader.get("after".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A subscriber that falls too far behind should be cut off after its queued entries
+#[test]
+fn replication_drops_lagging_subscriber() -> Result<()> {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let leader = KvStore::open(leader_dir.path())?;
+    for i in 0..100 {
+        leader.set(format!("key{}", i), "value".to_owned())?;
+    }
+    // 已有的日志在取出时才读取，订阅后写入不受影响
+    let mut replication = leader.replicate(None)?;
+    for i in 0..20_000 {
+        leader.set(format!("key{}", i % 100), i.to_string())?;
+    }
+    let entries = replication.pending();
+    assert!(entries.len() > 100);
+    assert!(entries.len() < 100 + 20_000);
+    assert!(replication.next().is_none());
+
+    // 重新订阅后从已应用的位置继续
+    let engine = KvStore::open(follower_dir.path().join("kvs"))?;
+    let mut follower = Follower::new(engine.clone(), follower_dir.path().join("replica.json"))?;
+    for entry in entries {
+        follower.apply(entry)?;
+    }
+    sync(&leader, &mut follower)?;
+    for i in 0..100 {
+        assert_eq!(
+            engine.get(format!("key{}", i))?,
+            Some((19_900 + i).to_string())
+        );
+    }
+    Ok(())
+}
+
+// A follower server should replicate over the network and reject writes
+#[test]
+fn replication_follower_server() -> Result<()> {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let leader = KvStore::open(leader_dir.path())?;
+    thread::spawn(move || {
+        KvsServer::new(leader, SharedQueueThreadPool::new(4).unwrap())
+            .run("127.0.0.1:4150".to_owned())
+            .unwrap();
+    });
+    let engine = KvStore::open(follower_dir.path().join("kvs"))?;
+    let follower = Follower::new(engine.clone(), follower_dir.path().join("replica.json"))?;
+    thread::spawn(move || follower.run("127.0.0.1:4150".to_owned()));
+    thread::spawn(move || {
+        KvsServer::new(
+            ReadOnly::new(engine),
+            SharedQueueThreadPool::new(4).unwrap(),
+        )
+        .run("127.0.0.1:4151".to_owned())
+        .unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect("127.0.0.1:4150".to_owned())?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    let mut client = KvsClient::connect("127.0.0.1:4151".to_owned())?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while client.get("key".to_owned())? != Some("value".to_owned()) {
+        assert!(Instant::now() < deadline, "value was not replicated");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(client.set("key".to_owned(), "other".to_owned()).is_err());
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Keyspaces should be created and dropped on the follower along with the leader
+#[test]
+fn replication_follows_keyspaces() -> Result<()> {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let leader = KvStore::open(leader_dir.path())?;
+    let users = leader.open_tree("users")?;
+    users.set("key".to_owned(), "value".to_owned())?;
+
+    let engine = KvStore::open(follower_dir.path().join("kvs"))?;
+    let mut follower = Follower::new(engine.clone(), follower_dir.path().join("replica.json"))?;
+    sync(&leader, &mut follower)?;
+    assert_eq!(engine.tree_names()?, vec!["users".to_owned()]);
+    let mut tree_follower = follower.keyspace("users")?;
+    sync(&users, &mut tree_follower)?;
+    let tree = engine.open_tree("users")?;
+    assert_eq!(tree.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(engine.get("key".to_owned())?, None);
+
+    // 重新打开后从该 keyspace 已应用的位置继续
+    users.set("key".to_owned(), "other".to_owned())?;
+    let mut tree_follower = follower.keyspace("users")?;
+    assert!(tree_follower.position().is_some());
+    assert_eq!(sync(&users, &mut tree_follower)?.len(), 1);
+    assert_eq!(tree.get("key".to_owned())?, Some("other".to_owned()));
+
+    assert!(leader.drop_tree("users")?);
+    sync(&leader, &mut follower)?;
+    assert!(engine.tree_names()?.is_empty());
+    assert!(tree.get("key".to_owned()).is_err());
+    Ok(())
+}
+
+// A follower server should replicate the keyspaces created on the leader
+#[test]
+fn replication_follower_server_keyspaces() -> Result<()> {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let leader = KvStore::open(leader_dir.path())?;
+    thread::spawn(move || {
+        KvsServer::new(leader, SharedQueueThreadPool::new(4).unwrap())
+            .run("127.0.0.1:4152".to_owned())
+            .unwrap();
+    });
+    let engine = KvStore::open(follower_dir.path().join("kvs"))?;
+    let follower = Follower::new(engine.clone(), follower_dir.path().join("replica.json"))?;
+    thread::spawn(move || follower.run("127.0.0.1:4152".to_owned()));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect("127.0.0.1:4152".to_owned())?;
+    client.use_keyspace(Some("users".to_owned()));
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        // keyspace 由从节点创建后才能打开
+        let replicated = if engine.tree_names()?.contains(&"users".to_owned()) {
+            engine.open_tree("users")?.get("key".to_owned())?
+        } else {
+            None
+        };
+        if replicated == Some("value".to_owned()) {
+            break;
+        }
+        assert!(Instant::now() < deadline, "keyspace was not replicated");
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
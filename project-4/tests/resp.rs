@@ -0,0 +1,349 @@
+use kvs::acl::Acl;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, KvsServer, SledServer};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// RESP 回复
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+fn ok() -> Reply {
+    Reply::Simple("OK".to_owned())
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(Some(s.to_owned()))
+}
+
+/// 手写的 RESP 客户端
+struct RespClient {
+    reader: BufReader<TcpStream>,
+}
+
+impl RespClient {
+    fn connect(addr: &str) -> RespClient {
+        RespClient {
+            reader: BufReader::new(TcpStream::connect(addr).unwrap()),
+        }
+    }
+
+    fn call(&mut self, args: &[&str]) -> Reply {
+        let mut buf = format!("*{}\r\n", args.len());
+        for arg in args {
+            buf.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.reader.get_mut().write_all(buf.as_bytes()).unwrap();
+        self.read_reply()
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with("\r\n"));
+        line.truncate(line.len() - 2);
+        line
+    }
+
+    fn read_reply(&mut self) -> Reply {
+        let line = self.read_line();
+        let (kind, rest) = line.split_at(1);
+        match kind {
+            "+" => Reply::Simple(rest.to_owned()),
+            "-" => Reply::Error(rest.to_owned()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "$" => {
+                let len: i64 = rest.parse().unwrap();
+                if len < 0 {
+                    return Reply::Bulk(None);
+                }
+                let mut buf = vec![0; len as usize + 2];
+                self.reader.read_exact(&mut buf).unwrap();
+                buf.truncate(len as usize);
+                Reply::Bulk(Some(String::from_utf8(buf).unwrap()))
+            }
+            "*" => {
+                let len: usize = rest.parse().unwrap();
+                Reply::Array((0..len).map(|_| self.read_reply()).collect())
+            }
+            _ => panic!("unexpected reply {}", line),
+        }
+    }
+}
+
+fn start_server<E: KvsEngine>(engine: E, addr: &str, resp_addr: &str, acl: Option<Acl>) {
+    let addr = addr.to_owned();
+    let resp_addr = resp_addr.to_owned();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(8).unwrap());
+        if let Some(acl) = acl {
+            server = server.acl(Arc::new(acl));
+        }
+        server.resp(resp_addr).run(addr).unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+}
+
+fn basic_commands(resp_addr: &str, engine: &str) {
+    let mut client = RespClient::connect(resp_addr);
+    assert_eq!(client.call(&["PING"]), Reply::Simple("PONG".to_owned()));
+    assert_eq!(client.call(&["PING", "hello"]), bulk("hello"));
+
+    assert_eq!(client.call(&["SET", "key1", "value1"]), ok());
+    assert_eq!(client.call(&["GET", "key1"]), bulk("value1"));
+    assert_eq!(client.call(&["GET", "key2"]), Reply::Bulk(None));
+    assert_eq!(client.call(&["set", "key2", "value 2\r\n"]), ok());
+    assert_eq!(client.call(&["get", "key2"]), bulk("value 2\r\n"));
+
+    assert_eq!(
+        client.call(&["EXISTS", "key1", "key2", "key3"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(
+        client.call(&["MGET", "key1", "key3", "key2"]),
+        Reply::Array(vec![bulk("value1"), Reply::Bulk(None), bulk("value 2\r\n")])
+    );
+    assert_eq!(client.call(&["DEL", "key1", "key3"]), Reply::Integer(1));
+    assert_eq!(client.call(&["GET", "key1"]), Reply::Bulk(None));
+
+    assert_eq!(client.call(&["SET", "key2", "v", "NX"]), Reply::Bulk(None));
+    assert_eq!(client.call(&["SET", "key3", "v", "XX"]), Reply::Bulk(None));
+    assert_eq!(client.call(&["SET", "key3", "v", "NX"]), ok());
+    assert_eq!(client.call(&["SET", "key3", "w", "XX"]), ok());
+    assert_eq!(client.call(&["GET", "key3"]), bulk("w"));
+
+    assert_eq!(client.call(&["INCR", "counter"]), Reply::Integer(1));
+    assert_eq!(client.call(&["INCR", "counter"]), Reply::Integer(2));
+    assert_eq!(client.call(&["GET", "counter"]), bulk("2"));
+    match client.call(&["INCR", "key3"]) {
+        Reply::Error(e) => assert!(e.starts_with("ERR value is not an integer")),
+        r => panic!("unexpected reply {:?}", r),
+    }
+
+    match client.call(&["INFO"]) {
+        Reply::Bulk(Some(info)) => assert!(info.contains(&format!("engine:{}", engine))),
+        r => panic!("unexpected reply {:?}", r),
+    }
+    match client.call(&["GET"]) {
+        Reply::Error(e) => assert!(e.starts_with("ERR wrong number of arguments")),
+        r => panic!("unexpected reply {:?}", r),
+    }
+    match client.call(&["FLUSHALL"]) {
+        Reply::Error(e) => assert!(e.starts_with("ERR unknown command")),
+        r => panic!("unexpected reply {:?}", r),
+    }
+}
+
+#[test]
+fn resp_kvs_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    start_server(store, "127.0.0.1:4120", "127.0.0.1:4121", None);
+    basic_commands("127.0.0.1:4121", "kvs");
+}
+
+#[test]
+fn resp_sled_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = sled::Db::start_default(temp_dir.path()).unwrap();
+    start_server(
+        SledServer::new(db),
+        "127.0.0.1:4122",
+        "127.0.0.1:4123",
+        None,
+    );
+    basic_commands("127.0.0.1:4123", "sled");
+}
+
+/// 从 cursor 开始用 SCAN 遍历到结束，返回所有 key
+fn scan_from(client: &mut RespClient, mut cursor: String, extra: &[&str]) -> Vec<String> {
+    let mut keys = Vec::new();
+    loop {
+        let mut args = vec!["SCAN", &cursor];
+        args.extend_from_slice(extra);
+        match client.call(&args) {
+            Reply::Array(mut reply) => {
+                if let Reply::Array(page) = reply.pop().unwrap() {
+                    for key in page {
+                        if let Reply::Bulk(Some(key)) = key {
+                            keys.push(key);
+                        }
+                    }
+                }
+                cursor = match reply.pop().unwrap() {
+                    Reply::Bulk(Some(cursor)) => cursor,
+                    r => panic!("unexpected cursor {:?}", r),
+                };
+            }
+            r => panic!("unexpected reply {:?}", r),
+        }
+        if cursor == "0" {
+            return keys;
+        }
+    }
+}
+
+// SCAN should walk every key exactly once, page by page, even with writes between pages
+#[test]
+fn resp_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..25 {
+        store
+            .set(format!("user:{:02}", i), "value".to_owned())
+            .unwrap();
+        store
+            .set(format!("item:{:02}", i), "value".to_owned())
+            .unwrap();
+    }
+    start_server(store, "127.0.0.1:4124", "127.0.0.1:4125", None);
+    let mut client = RespClient::connect("127.0.0.1:4125");
+
+    let keys = scan_from(&mut client, "0".to_owned(), &["COUNT", "7"]);
+    assert_eq!(keys.len(), 50);
+    let keys = scan_from(&mut client, "0".to_owned(), &["MATCH", "user:*"]);
+    let expected: Vec<String> = (0..25).map(|i| format!("user:{:02}", i)).collect();
+    assert_eq!(keys, expected);
+    let keys = scan_from(
+        &mut client,
+        "0".to_owned(),
+        &["MATCH", "*:1?", "COUNT", "100"],
+    );
+    assert_eq!(keys.len(), 20);
+
+    // 两次调用之间在 cursor 之前写入的 key 不会导致已有的 key 被跳过或重复返回
+    let cursor = match client.call(&["SCAN", "0", "COUNT", "10"]) {
+        Reply::Array(mut reply) => match reply.remove(0) {
+            Reply::Bulk(Some(cursor)) => cursor,
+            r => panic!("unexpected cursor {:?}", r),
+        },
+        r => panic!("unexpected reply {:?}", r),
+    };
+    for i in 0..5 {
+        client.call(&["SET", &format!("aaa:{}", i), "value"]);
+    }
+    let keys = scan_from(&mut client, cursor, &["COUNT", "10"]);
+    assert_eq!(keys.len(), 40);
+    assert_eq!(keys[0], "item:10");
+    match client.call(&["SCAN", "zz"]) {
+        Reply::Error(e) => assert!(e.contains("invalid cursor")),
+        r => panic!("unexpected reply {:?}", r),
+    }
+}
+
+// INCR from concurrent connections should not lose updates
+#[test]
+fn resp_concurrent_incr() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    start_server(store, "127.0.0.1:4126", "127.0.0.1:4127", None);
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(|| {
+                let mut client = RespClient::connect("127.0.0.1:4127");
+                for _ in 0..50 {
+                    client.call(&["INCR", "counter"]);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let mut client = RespClient::connect("127.0.0.1:4127");
+    assert_eq!(client.call(&["GET", "counter"]), bulk("200"));
+}
+
+// With an ACL, RESP clients must AUTH and are subject to the same prefix rules
+#[test]
+fn resp_acl() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..10 {
+        store
+            .set(format!("admin/{}", i), "value".to_owned())
+            .unwrap();
+    }
+    let acl = serde_json::from_str(
+        r#"{"users": [{"name": "app", "token": "secret",
+            "rules": [{"prefix": "app/", "ops": ["Get", "Set"]}]}]}"#,
+    )
+    .unwrap();
+    start_server(store, "127.0.0.1:4128", "127.0.0.1:4129", Some(acl));
+
+    let mut client = RespClient::connect("127.0.0.1:4129");
+    assert_eq!(client.call(&["PING"]), Reply::Simple("PONG".to_owned()));
+    match client.call(&["GET", "app/key"]) {
+        Reply::Error(e) => assert!(e.starts_with("NOAUTH")),
+        r => panic!("unexpected reply {:?}", r),
+    }
+    match client.call(&["AUTH", "app", "wrong"]) {
+        Reply::Error(e) => assert!(e.starts_with("WRONGPASS")),
+        r => panic!("unexpected reply {:?}", r),
+    }
+    assert_eq!(client.call(&["AUTH", "app", "secret"]), ok());
+    assert_eq!(client.call(&["SET", "app/key", "value"]), ok());
+    assert_eq!(client.call(&["GET", "app/key"]), bulk("value"));
+    match client.call(&["SET", "other", "value"]) {
+        Reply::Error(e) => assert!(e.starts_with("NOPERM")),
+        r => panic!("unexpected reply {:?}", r),
+    }
+    match client.call(&["DEL", "app/key"]) {
+        Reply::Error(e) => assert!(e.starts_with("NOPERM")),
+        r => panic!("unexpected reply {:?}", r),
+    }
+    // 前几页的 key 全部被过滤时遍历仍会继续
+    assert_eq!(
+        scan_from(&mut client, "0".to_owned(), &["COUNT", "3"]),
+        vec!["app/key".to_owned()]
+    );
+}
+
+/// 发送原始字节后读取回复，回复应为协议错误
+fn assert_protocol_error(resp_addr: &str, raw: &[u8]) {
+    let mut client = RespClient::connect(resp_addr);
+    client.reader.get_mut().write_all(raw).unwrap();
+    match client.read_reply() {
+        Reply::Error(e) => assert!(e.starts_with("ERR Protocol error")),
+        r => panic!("unexpected reply {:?}", r),
+    }
+}
+
+// Malformed or oversized commands should be rejected before allocating their contents
+#[test]
+fn resp_rejects_invalid_lengths() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap())
+            .resp("127.0.0.1:4211".to_owned())
+            .max_request_size(1024)
+            .run("127.0.0.1:4210".to_owned())
+            .unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    assert_protocol_error("127.0.0.1:4211", b"*1\r\n$-5\r\n");
+    assert_protocol_error("127.0.0.1:4211", b"*-1\r\n");
+    assert_protocol_error("127.0.0.1:4211", b"*99999999\r\n");
+    assert_protocol_error("127.0.0.1:4211", b"*1\r\n$4000000000\r\n");
+    assert_protocol_error("127.0.0.1:4211", b"*2\r\n$3\r\nGET\r\n$1100\r\n");
+    assert_protocol_error("127.0.0.1:4211", b"*1\r\n$4\r\nPINGxx");
+    assert_protocol_error("127.0.0.1:4211", &[b'a'; 1026]);
+
+    let mut client = RespClient::connect("127.0.0.1:4211");
+    let value = "v".repeat(1000);
+    assert_eq!(client.call(&["SET", "key", &value]), ok());
+    assert_eq!(client.call(&["GET", "key"]), bulk(&value));
+}
@@ -0,0 +1,160 @@
+use kvs::client::KvsClient;
+use kvs::sharding::{self, Shard, ShardMap, ShardedClient};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsServer, Result};
+use std::collections::BTreeMap;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// 由 (分片名, 端口) 构建分片表
+fn shard_map(shards: &[(&str, u16)]) -> ShardMap {
+    let shards = shards
+        .iter()
+        .map(|(name, port)| Shard {
+            name: name.to_string(),
+            addr: format!("127.0.0.1:{}", port),
+        })
+        .collect();
+    ShardMap::new(shards, 64)
+}
+
+/// 在 port 上启动一个使用 KvStore 的服务器
+fn start_server(dir: &TempDir, port: u16) -> Result<()> {
+    let engine = KvStore::open(dir.path())?;
+    thread::spawn(move || {
+        KvsServer::new(engine, SharedQueueThreadPool::new(4).unwrap())
+            .run(format!("127.0.0.1:{}", port))
+            .unwrap();
+    });
+    Ok(())
+}
+
+/// 服务器上所有的 key
+fn keys_on(addr: &str) -> Result<Vec<String>> {
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    let pairs = client.scan(String::new(), None, 10000)?;
+    Ok(pairs.into_iter().map(|(key, _)| key).collect())
+}
+
+// Keys should spread evenly, and adding a shard should only move keys onto it
+#[test]
+fn shard_map_consistent_hashing() -> Result<()> {
+    let keys: Vec<String> = (0..3000).map(|i| format!("key{}", i)).collect();
+    let map = shard_map(&[("a", 1), ("b", 2), ("c", 3)]);
+    let mut counts = BTreeMap::new();
+    for key in &keys {
+        *counts
+            .entry(map.owner(key).unwrap().name.clone())
+            .or_insert(0) += 1;
+    }
+    assert_eq!(counts.len(), 3);
+    for count in counts.values() {
+        assert!(*count > 600 && *count < 1400, "uneven shards: {:?}", counts);
+    }
+
+    let bigger = shard_map(&[("a", 1), ("b", 2), ("c", 3), ("d", 4)]);
+    let mut moved = 0;
+    for key in &keys {
+        let (before, after) = (map.owner(key).unwrap(), bigger.owner(key).unwrap());
+        if before != after {
+            assert_eq!(after.name, "d");
+            moved += 1;
+        }
+    }
+    assert!(moved > 400 && moved < 1200, "{} keys moved", moved);
+
+    // 分片表可以保存并重新读取
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("shards.json");
+    bigger.save(&path)?;
+    let reopened = ShardMap::open(&path)?;
+    for key in &keys {
+        assert_eq!(reopened.owner(key), bigger.owner(key));
+    }
+    Ok(())
+}
+
+// ShardedClient should route single-key operations and merge mget/scan results
+#[test]
+fn sharded_client_routes_and_fans_out() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    for (dir, port) in dirs.iter().zip(4170..) {
+        start_server(dir, port)?;
+    }
+    thread::sleep(Duration::from_secs(1));
+    let map = shard_map(&[("a", 4170), ("b", 4171), ("c", 4172)]);
+    let mut client = ShardedClient::new(map.clone());
+
+    let keys: Vec<String> = (0..100).map(|i| format!("key{:03}", i)).collect();
+    for key in &keys {
+        client.set(key.clone(), format!("value of {}", key))?;
+    }
+    for shard in &map.shards {
+        for key in keys_on(&shard.addr)? {
+            assert_eq!(map.owner(&key), Some(shard));
+        }
+    }
+
+    let values = client.mget(vec!["key042".to_owned(), "missing".to_owned()])?;
+    assert_eq!(values, vec![Some("value of key042".to_owned()), None]);
+
+    let pairs = client.scan("key0".to_owned(), Some("key010".to_owned()), 5)?;
+    let scanned: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(
+        scanned,
+        vec!["key011", "key012", "key013", "key014", "key015"]
+    );
+
+    client.remove("key042".to_owned())?;
+    assert_eq!(client.get("key042".to_owned())?, None);
+    assert!(client.remove("key042".to_owned()).is_err());
+    Ok(())
+}
+
+// Rebalancing onto a new shard should move keys while clients keep reading them
+#[test]
+fn sharded_client_online_rebalance() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    for (dir, port) in dirs.iter().zip(4173..) {
+        start_server(dir, port)?;
+    }
+    thread::sleep(Duration::from_secs(1));
+    let old_map = shard_map(&[("a", 4173), ("b", 4174)]);
+    let new_map = shard_map(&[("a", 4173), ("b", 4174), ("c", 4175)]);
+
+    let keys: Vec<String> = (0..200).map(|i| format!("key{}", i)).collect();
+    let mut client = ShardedClient::new(old_map.clone());
+    for key in &keys {
+        client.set(key.clone(), "value".to_owned())?;
+    }
+
+    // 迁移开始前，使用新分片表的客户端也能读到所有 key
+    let mut client = ShardedClient::new(new_map.clone()).migrating_from(old_map.clone());
+    for key in &keys {
+        assert_eq!(client.get(key.clone())?, Some("value".to_owned()));
+    }
+    client.set("key0".to_owned(), "updated".to_owned())?;
+
+    let moved = sharding::rebalance(&old_map, &new_map, None)?;
+    assert!(moved > 0);
+    let on_new_shard = keys
+        .iter()
+        .filter(|key| new_map.owner(key).unwrap().name == "c")
+        .count();
+    assert_eq!(keys_on("127.0.0.1:4175")?.len(), on_new_shard);
+    for shard in &new_map.shards {
+        for key in keys_on(&shard.addr)? {
+            assert_eq!(new_map.owner(&key), Some(shard));
+        }
+    }
+
+    let mut client = ShardedClient::new(new_map);
+    assert_eq!(client.get("key0".to_owned())?, Some("updated".to_owned()));
+    let values = client.mget(keys[1..].to_vec())?;
+    assert!(values
+        .iter()
+        .all(|value| value == &Some("value".to_owned())));
+    assert_eq!(client.scan(String::new(), None, 1000)?.len(), keys.len());
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use kvs::client::KvsClient;
+use kvs::replication::Follower;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{tls, KvStore, KvsEngine, KvsServer, Result};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, SanType};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// 测试用的证书：一个 CA 以及由其签发的服务端、客户端证书
+struct TestCerts {
+    ca: PathBuf,
+    server_cert: PathBuf,
+    server_key: PathBuf,
+    client_cert: PathBuf,
+    client_key: PathBuf,
+}
+
+fn write_leaf(dir: &Path, name: &str, ca: &Certificate) -> (PathBuf, PathBuf) {
+    let mut params = CertificateParams::new(vec!["localhost".to_owned()]);
+    params
+        .subject_alt_names
+        .push(SanType::IpAddress("127.0.0.1".parse().unwrap()));
+    let cert = Certificate::from_params(params).unwrap();
+    let cert_path = dir.join(format!("{}.pem", name));
+    let key_path = dir.join(format!("{}.key", name));
+    fs::write(&cert_path, cert.serialize_pem_with_signer(ca).unwrap()).unwrap();
+    fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+fn generate_certs(dir: &Path) -> TestCerts {
+    let mut params = CertificateParams::new(vec![]);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(params).unwrap();
+    let ca_path = dir.join("ca.pem");
+    fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+
+    let (server_cert, server_key) = write_leaf(dir, "server", &ca);
+    let (client_cert, client_key) = write_leaf(dir, "client", &ca);
+    TestCerts {
+        ca: ca_path,
+        server_cert,
+        server_key,
+        client_cert,
+        client_key,
+    }
+}
+
+fn start_server(dir: &Path, addr: &str, certs: &TestCerts, verify_client: bool) {
+    let client_ca = if verify_client {
+        Some(certs.ca.as_path())
+    } else {
+        None
+    };
+    let config = tls::server_config(&certs.server_cert, &certs.server_key, client_ca).unwrap();
+    let store = KvStore::open(dir.join("kvs")).unwrap();
+    let addr = addr.to_owned();
+    thread::spawn(move || {
+        KvsServer::new(store, SharedQueueThreadPool::new(4).unwrap())
+            .tls(config)
+            .run(addr)
+            .unwrap();
+    });
+    thread::sleep(Duration::from_secs(1));
+}
+
+// Client and server should exchange requests over TLS
+#[test]
+fn tls_access_server() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = generate_certs(temp_dir.path());
+    start_server(temp_dir.path(), "127.0.0.1:4100", &certs, false);
+
+    let mut client = KvsClient::connect_with_ca("127.0.0.1:4100".to_owned(), &certs.ca)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert!(client.remove("key1".to_owned()).is_err());
+
+    let config = tls::client_config(&certs.ca, None)?;
+    let mut client = KvsClient::connect_tls("127.0.0.1:4100".to_owned(), "localhost", config)?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Plaintext clients and clients not trusting the server CA should fail
+#[test]
+fn tls_rejects_untrusted_peers() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = generate_certs(temp_dir.path());
+    start_server(temp_dir.path(), "127.0.0.1:4101", &certs, false);
+
+    let mut client = KvsClient::connent("127.0.0.1:4101".to_owned())?;
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+
+    let other_dir = TempDir::new().unwrap();
+    let other_certs = generate_certs(other_dir.path());
+    let mut client = KvsClient::connect_with_ca("127.0.0.1:4101".to_owned(), &other_certs.ca)?;
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+    Ok(())
+}
+
+// With client verification enabled, only clients holding a certificate signed by the CA are accepted
+#[test]
+fn mutual_tls() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = generate_certs(temp_dir.path());
+    start_server(temp_dir.path(), "127.0.0.1:4102", &certs, true);
+
+    let config = tls::client_config(&certs.ca, Some((&certs.client_cert, &certs.client_key)))?;
+    let mut client = KvsClient::connect_tls("127.0.0.1:4102".to_owned(), "localhost", config)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let mut client = KvsClient::connect_with_ca("127.0.0.1:4102".to_owned(), &certs.ca)?;
+    assert!(client.get("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Followers should replicate from a leader that requires TLS with client certificates
+#[test]
+fn tls_follower_replicates() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = generate_certs(temp_dir.path());
+    start_server(temp_dir.path(), "127.0.0.1:4214", &certs, true);
+
+    let engine = KvStore::open(temp_dir.path().join("follower"))?;
+    let config = tls::client_config(&certs.ca, Some((&certs.client_cert, &certs.client_key)))?;
+    let follower = Follower::new(engine.clone(), temp_dir.path().join("replica.json"))?
+        .tls(Arc::clone(&config));
+    thread::spawn(move || follower.run("127.0.0.1:4214".to_owned()));
+
+    let mut client = KvsClient::connect_tls("127.0.0.1:4214".to_owned(), "localhost", config)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while engine.get("key".to_owned())? != Some("value".to_owned()) {
+        assert!(Instant::now() < deadline, "value was not replicated");
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
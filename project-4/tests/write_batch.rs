@@ -0,0 +1,187 @@
+use kvs::engines::KvStoreOptions;
+use kvs::replication::{Follower, LogEntry};
+use kvs::{KvStore, KvsEngine, KvsErrorType, Result};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use tempfile::TempDir;
+
+/// 每个线程写入的 key 数量
+const KEYS_PER_THREAD: usize = 500;
+
+/// 并发写入，线程 t 设置 t-0 到 t-499，再删除其中的偶数项与一个不存在的 key
+fn concurrent_writes(store: &KvStore, threads: usize) -> Result<()> {
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..KEYS_PER_THREAD {
+                    store.set(format!("{}-{}", t, i), format!("value{}", i))?;
+                }
+                for i in (0..KEYS_PER_THREAD).step_by(2) {
+                    store.remove(format!("{}-{}", t, i))?;
+                }
+                let missing = store.remove(format!("{}-missing", t)).unwrap_err();
+                assert_eq!(missing.kind(), KvsErrorType::KeyNotFound);
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    Ok(())
+}
+
+/// 检查 concurrent_writes 之后的内容
+fn check(store: &KvStore, threads: usize) -> Result<()> {
+    for t in 0..threads {
+        for i in 0..KEYS_PER_THREAD {
+            let expected = if i % 2 == 0 {
+                None
+            } else {
+                Some(format!("value{}", i))
+            };
+            assert_eq!(store.get(format!("{}-{}", t, i))?, expected);
+        }
+    }
+    assert_eq!(
+        store.stats()?["keys"],
+        (threads * KEYS_PER_THREAD / 2) as u64
+    );
+    Ok(())
+}
+
+// Writes from many threads should all be applied and survive a reopen
+#[test]
+fn concurrent_writes_are_durable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    concurrent_writes(&store, 8)?;
+    check(&store, 8)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store, 8)?;
+    Ok(())
+}
+
+// Batched writes should still reach subscribers one record at a time, in log order
+#[test]
+fn concurrent_writes_are_replicated_in_order() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = KvStore::open(leader_dir.path())?;
+    let mut replication = leader.replicate(None)?;
+    replication.pending();
+
+    concurrent_writes(&leader, 4)?;
+    let entries = replication.pending();
+    assert_eq!(entries.len(), 4 * (KEYS_PER_THREAD + KEYS_PER_THREAD / 2));
+    let mut last = 0;
+    for entry in &entries {
+        match entry {
+            LogEntry::Record { position, .. } => {
+                assert!(position.offset > last);
+                last = position.offset;
+            }
+            entry => panic!("unexpected entry {:?}", entry),
+        }
+    }
+    assert_eq!(last, leader.stats()?["log_bytes"]);
+
+    let engine = KvStore::open(follower_dir.path().join("kvs"))?;
+    let mut follower = Follower::new(engine.clone(), follower_dir.path().join("replica.json"))?;
+    for entry in entries {
+        follower.apply(entry)?;
+    }
+    check(&engine, 4)?;
+    Ok(())
+}
+
+/// 并发写入小的 value，同时另一个线程写入大的 value，返回每个小的 value 是否写入成功
+fn writes_with_failing_blobs(store: &KvStore, round: usize) -> Vec<(String, bool)> {
+    let blob_writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 0..KEYS_PER_THREAD {
+                let err = store
+                    .set(format!("blob-{}-{}", round, i), "x".repeat(2048))
+                    .unwrap_err();
+                assert_eq!(err.kind(), KvsErrorType::IOError);
+            }
+        })
+    };
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                (0..KEYS_PER_THREAD)
+                    .map(|i| {
+                        let key = format!("{}-{}-{}", round, t, i);
+                        let written = store.set(key.clone(), key.clone()).is_ok();
+                        (key, written)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    blob_writer.join().unwrap();
+    handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect()
+}
+
+/// 检查写入成功的 key 都可以读取，失败的 key 都不存在，且序号只分配给了写入成功的记录
+fn check_written(store: &KvStore, written: &[(String, bool)]) -> Result<()> {
+    for (key, ok) in written {
+        let expected = if *ok { Some(key.clone()) } else { None };
+        assert_eq!(store.get(key.clone())?, expected);
+    }
+    let count = written.iter().filter(|(_, ok)| *ok).count();
+    assert_eq!(store.stats()?["seq"], count as u64);
+    Ok(())
+}
+
+// A batch that fails part-way through should leave the log, the offsets and the sequence untouched
+#[test]
+fn failed_batches_are_discarded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |path: &Path| {
+        KvStore::open_with(
+            path,
+            KvStoreOptions {
+                blob_threshold: Some(1024),
+                ..KvStoreOptions::default()
+            },
+        )
+    };
+    let store = open(temp_dir.path())?;
+    // blob 目录的位置被普通文件占用，写入 blob 文件失败，与之合并为一批的写入也随之失败
+    fs::write(temp_dir.path().join("blobs"), "")?;
+    let mut written = Vec::new();
+    for round in 0..10 {
+        written.extend(writes_with_failing_blobs(&store, round));
+        if written.iter().any(|(_, ok)| !ok) {
+            break;
+        }
+    }
+    assert!(written.iter().any(|(_, ok)| !ok));
+    check_written(&store, &written)?;
+
+    fs::remove_file(temp_dir.path().join("blobs"))?;
+    store.set("after".to_owned(), "x".repeat(2048))?;
+    written.push(("after".to_owned(), true));
+    assert_eq!(store.get("after".to_owned())?, Some("x".repeat(2048)));
+    drop(store);
+
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get("after".to_owned())?, Some("x".repeat(2048)));
+    written.pop();
+    for (key, ok) in &written {
+        let expected = if *ok { Some(key.clone()) } else { None };
+        assert_eq!(store.get(key.clone())?, expected);
+    }
+    Ok(())
+}